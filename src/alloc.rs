@@ -0,0 +1,140 @@
+//! allocation tracing.
+//!
+//! `TracingAllocator` counts allocations and frees per thread and
+//! periodically records them as `spall/alloc` markers, so memory behavior
+//! lines up with the scopes on the same timeline.
+//!
+//! ```no_run
+//! #[global_allocator]
+//! static ALLOC: spall::TracingAllocator = spall::TracingAllocator::new(std::alloc::System);
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+
+/// wraps a `GlobalAlloc` and records allocation activity.
+///
+/// each thread records at most one marker per `interval`, with args like
+/// `allocs=12 frees=10 alloc_bytes=4096 free_bytes=2048 live_bytes=1048576`.
+/// counts are since the thread's previous marker,
+/// `live_bytes` is the process-wide total currently allocated.
+/// allocations made while spall records events, including spall's own,
+/// only count towards `live_bytes`.
+pub struct TracingAllocator<A = System> {
+    inner: A,
+    interval: Duration,
+}
+
+impl<A> TracingAllocator<A> {
+    /// records at most once per millisecond.
+    pub const fn new(inner: A) -> Self {
+        Self { inner, interval: Duration::from_millis(1) }
+    }
+
+    pub const fn with_interval(inner: A, interval: Duration) -> Self {
+        Self { inner, interval }
+    }
+}
+
+
+#[derive(Clone, Copy)]
+struct Stats {
+    allocs:      u64,
+    frees:       u64,
+    alloc_bytes: u64,
+    free_bytes:  u64,
+    // timestamp of the last marker.
+    last: u64,
+}
+
+thread_local! {
+    static STATS: Cell<Stats> = const { Cell::new(Stats {
+        allocs: 0, frees: 0, alloc_bytes: 0, free_bytes: 0, last: 0,
+    }) };
+}
+
+static LIVE_BYTES: AtomicI64 = AtomicI64::new(0);
+static USED: AtomicBool = AtomicBool::new(false);
+
+// bytes currently allocated through a `TracingAllocator`, if there is one.
+pub(crate) fn live_bytes() -> Option<i64> {
+    if !USED.load(Ordering::Relaxed) {
+        return None;
+    }
+    return Some(LIVE_BYTES.load(Ordering::Relaxed));
+}
+
+
+impl<A> TracingAllocator<A> {
+    #[inline]
+    fn record(&self, allocs: u64, alloc_bytes: usize, frees: u64, free_bytes: usize) {
+        LIVE_BYTES.fetch_add(alloc_bytes as i64 - free_bytes as i64, Ordering::Relaxed);
+        if !USED.load(Ordering::Relaxed) {
+            USED.store(true, Ordering::Relaxed);
+        }
+
+        if crate::busy() {
+            return;
+        }
+
+        _ = STATS.try_with(|stats| {
+            let mut s = stats.get();
+            s.allocs      += allocs;
+            s.frees       += frees;
+            s.alloc_bytes += alloc_bytes as u64;
+            s.free_bytes  += free_bytes as u64;
+
+            let now = crate::now();
+            let elapsed = now.wrapping_sub(s.last) as f64 * crate::timestamp_unit();
+            if elapsed < self.interval.as_secs_f64() * 1e6 {
+                stats.set(s);
+                return;
+            }
+
+            crate::marker("spall/alloc", format_args!(
+                "allocs={} frees={} alloc_bytes={} free_bytes={} live_bytes={}",
+                s.allocs, s.frees, s.alloc_bytes, s.free_bytes,
+                LIVE_BYTES.load(Ordering::Relaxed)));
+
+            stats.set(Stats { allocs: 0, frees: 0, alloc_bytes: 0, free_bytes: 0, last: now });
+        });
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            self.record(1, layout.size(), 0, 0);
+        }
+        return ptr;
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            self.record(1, layout.size(), 0, 0);
+        }
+        return ptr;
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        self.record(0, 0, 1, layout.size());
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            self.record(1, new_size, 1, layout.size());
+        }
+        return new_ptr;
+    }
+}
@@ -0,0 +1,452 @@
+//! aggregate timing analysis over loaded traces.
+//!
+//! `name_stats` and `top` summarize time by scope name, `report` prints
+//! them as a table. `utilization` shows how busy each thread was over time.
+//! `diff` compares two traces scope by scope, to evaluate a change.
+
+use std::collections::HashMap;
+
+use crate::reader::Trace;
+
+
+/// timing summary for all scopes sharing a name.
+/// times are in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameStats {
+    pub name:  String,
+    pub count: u64,
+    /// sum of durations.
+    /// recursive scopes are counted once per level.
+    pub total: f64,
+    /// sum of durations, excluding time spent in child scopes.
+    pub self_time: f64,
+    pub min:  f64,
+    pub max:  f64,
+    pub mean: f64,
+    pub p95:  f64,
+}
+
+/// computes `NameStats` for every scope name in the trace,
+/// ordered by descending total time.
+/// spall's own markers, named `spall/...`, are skipped.
+pub fn name_stats(trace: &Trace) -> Vec<NameStats> {
+    let scopes = trace.scopes();
+
+    let mut child_time = vec![0.0; scopes.len()];
+    for scope in scopes {
+        if let Some(parent) = scope.parent {
+            child_time[parent] += scope.duration();
+        }
+    }
+
+    let mut groups = HashMap::<&str, (Vec<f64>, f64)>::new();
+    for (index, scope) in scopes.iter().enumerate() {
+        if scope.name.starts_with("spall/") {
+            continue;
+        }
+
+        let duration = scope.duration();
+        let (durations, self_time) = groups.entry(&scope.name).or_default();
+        durations.push(duration);
+        *self_time += (duration - child_time[index]).max(0.0);
+    }
+
+    let mut result = groups.into_iter()
+        .map(|(name, (mut durations, self_time))| {
+            durations.sort_by(f64::total_cmp);
+
+            let count = durations.len();
+            let total = durations.iter().sum::<f64>();
+            NameStats {
+                name:  name.to_string(),
+                count: count as u64,
+                total,
+                self_time,
+                min:   durations[0],
+                max:   durations[count - 1],
+                mean:  total / count as f64,
+                p95:   percentile(&durations, 0.95),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    result.sort_by(|a, b| b.total.total_cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+    return result;
+}
+
+/// what `top` ranks names by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rank {
+    Total,
+    SelfTime,
+    Count,
+    Mean,
+}
+
+/// the `n` hottest scope names, by `rank`.
+/// like `name_stats`, without spall's markers, so frequent ones
+/// like `spall/flush` don't crowd out the program's scopes.
+pub fn top(trace: &Trace, n: usize, rank: Rank) -> Vec<NameStats> {
+    let mut stats = name_stats(trace);
+    match rank {
+        Rank::Total    => (),
+        Rank::SelfTime => stats.sort_by(|a, b| b.self_time.total_cmp(&a.self_time).then_with(|| a.name.cmp(&b.name))),
+        Rank::Count    => stats.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name))),
+        Rank::Mean     => stats.sort_by(|a, b| b.mean.total_cmp(&a.mean).then_with(|| a.name.cmp(&b.name))),
+    }
+    stats.truncate(n);
+    return stats;
+}
+
+/// formats `stats` as a text table, for quick triage in a terminal.
+///
+/// ```text
+/// name     count      total       self       mean        max
+/// frame       50   125.00ms    80.50ms     2.50ms     2.50ms
+/// parse       50    44.50ms    44.50ms   890.00us   890.00us
+/// ```
+pub fn report(stats: &[NameStats]) -> String {
+    let width = stats.iter().map(|s| s.name.chars().count()).max().unwrap_or(0).max(4);
+
+    let mut out = format!("{:<width$} {:>8} {:>10} {:>10} {:>10} {:>10}\n",
+        "name", "count", "total", "self", "mean", "max");
+    for s in stats {
+        out += &format!("{:<width$} {:>8} {:>10} {:>10} {:>10} {:>10}\n",
+            s.name, s.count, Time(s.total), Time(s.self_time), Time(s.mean), Time(s.max));
+    }
+    return out;
+}
+
+// microseconds, in a readable unit.
+struct Time(f64);
+
+impl std::fmt::Display for Time {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let us = self.0;
+        let text =
+            if us < 1e3      { format!("{:.2}us", us) }
+            else if us < 1e6 { format!("{:.2}ms", us / 1e3) }
+            else             { format!("{:.2}s",  us / 1e6) };
+        f.pad(&text)
+    }
+}
+
+/// per thread, how much of each time interval was covered by scopes.
+/// threads that are mostly idle or untracked while others are busy
+/// point at serialization, like lock contention or uneven work.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utilization {
+    /// the start of the first bucket, in microseconds.
+    pub start:  f64,
+    /// the length of each bucket, in microseconds.
+    pub bucket: f64,
+    pub threads: Vec<ThreadUtilization>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadUtilization {
+    pub pid: u32,
+    pub tid: u32,
+    /// from the thread's `spall/thread_start` marker, if any.
+    pub name: Option<String>,
+    /// the covered fraction of each bucket, in `[0, 1]`.
+    pub busy: Vec<f64>,
+}
+
+/// buckets the trace's time range into `buckets` intervals,
+/// and computes how much of each a thread spent in scopes.
+pub fn utilization(trace: &Trace, buckets: usize) -> Utilization {
+    let (start, end) = trace.time_range().unwrap_or((0.0, 0.0));
+    let buckets = buckets.max(1);
+    let bucket = ((end - start) / buckets as f64).max(f64::MIN_POSITIVE);
+
+    let scopes = trace.scopes();
+    let threads = trace.threads().iter()
+        .map(|thread| {
+            let mut busy = vec![0.0; buckets];
+            // nested scopes are inside their top level scope.
+            for scope in thread.scopes.iter().map(|i| &scopes[*i]).filter(|s| s.depth == 0) {
+                let first = ((scope.start - start) / bucket) as usize;
+                let last  = (((scope.end - start) / bucket) as usize).min(buckets - 1);
+                for (i, busy) in busy.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let b0 = start + i as f64 * bucket;
+                    let covered = scope.end.min(b0 + bucket) - scope.start.max(b0);
+                    *busy += covered.max(0.0) / bucket;
+                }
+            }
+            for busy in &mut busy {
+                *busy = busy.min(1.0);
+            }
+
+            let name = thread.scopes.iter().map(|i| &scopes[*i])
+                .find(|s| s.name == "spall/thread_start")
+                .and_then(|s| crate::args::parse(&s.args).into_iter().find(|(k, _)| *k == "name").map(|(_, v)| v.to_string()));
+
+            ThreadUtilization { pid: thread.pid, tid: thread.tid, name, busy }
+        })
+        .collect();
+
+    return Utilization { start, bucket, threads };
+}
+
+impl ThreadUtilization {
+    /// the covered fraction of the whole range.
+    pub fn mean(&self) -> f64 {
+        self.busy.iter().sum::<f64>() / self.busy.len().max(1) as f64
+    }
+
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.tid),
+            None       => self.tid.to_string(),
+        }
+    }
+}
+
+impl Utilization {
+    /// one line per thread, with a shaded block per bucket and
+    /// the mean utilization.
+    ///
+    /// ```text
+    /// main (1) ████████      ██████  70%
+    /// 2             ███████████      55%
+    /// ```
+    pub fn render_text(&self) -> String {
+        const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+
+        let labels = self.threads.iter().map(|t| t.label()).collect::<Vec<_>>();
+        let width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+
+        let mut out = String::new();
+        for (thread, label) in self.threads.iter().zip(&labels) {
+            out += &format!("{:<width$} ", label);
+            for busy in &thread.busy {
+                out.push(SHADES[(busy * 4.0).round() as usize]);
+            }
+            out += &format!(" {:>3.0}%\n", thread.mean() * 100.0);
+        }
+        return out;
+    }
+
+    /// a standalone html page with a row of cells per thread,
+    /// shaded by utilization, with the times on hover.
+    pub fn render_html(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>thread utilization</title>\n");
+        out.push_str("<style>body{font-family:sans-serif} table{border-collapse:collapse} td{padding:0;height:18px}");
+        out.push_str(" td.n{padding-right:8px;white-space:nowrap} td.b{width:6px}</style></head><body>\n");
+        out += &format!("<p>{} buckets of {:.3}ms</p>\n<table>\n", self.threads.first().map_or(0, |t| t.busy.len()), self.bucket / 1e3);
+        for thread in &self.threads {
+            out += &format!("<tr><td class=\"n\">{} &mdash; {:.0}%</td>", escape(&thread.label()), thread.mean() * 100.0);
+            for (i, busy) in thread.busy.iter().enumerate() {
+                let t0 = (self.start + i as f64 * self.bucket) / 1e3;
+                out += &format!("<td class=\"b\" style=\"background:rgba(40,110,220,{:.2})\" title=\"{:.3}ms: {:.0}%\"></td>", busy, t0, busy * 100.0);
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table></body></html>\n");
+        return out;
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// how the scopes at one stack changed between two traces.
+/// times are in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeDiff {
+    /// the names of the scope's ancestors and its own, outermost first,
+    /// joined by `;`, like `frame;update;physics`.
+    pub stack: String,
+    pub before_count: u64,
+    pub after_count:  u64,
+    /// `NaN` without scopes.
+    pub before_mean: f64,
+    pub after_mean:  f64,
+    /// the total time in `after` minus the total in `before`.
+    pub delta_total: f64,
+    /// the relative change of the mean, like `0.1` for 10% slower.
+    pub change: f64,
+    /// the two-sided p-value of welch's t-test for equal means. small
+    /// values, like below `0.01`, mean the change is unlikely to be noise.
+    /// `NaN` with fewer than two scopes on a side.
+    pub p_value: f64,
+}
+
+impl ScopeDiff {
+    /// whether the mean changed with a p-value below `alpha`.
+    pub fn is_significant(&self, alpha: f64) -> bool {
+        self.p_value < alpha
+    }
+}
+
+/// matches the scopes of two traces by their stack of names, and
+/// compares their durations, ordered by the largest change in total
+/// time first. stacks only in one trace have a count of 0 in the other.
+pub fn diff(before: &Trace, after: &Trace) -> Vec<ScopeDiff> {
+    let before = durations_by_stack(before);
+    let mut after = durations_by_stack(after);
+
+    let mut result = Vec::new();
+    for (stack, before) in before {
+        let after = after.remove(&stack).unwrap_or_default();
+        result.push(compare(stack, &before, &after));
+    }
+    for (stack, after) in after {
+        result.push(compare(stack, &[], &after));
+    }
+
+    result.sort_by(|a, b| b.delta_total.abs().total_cmp(&a.delta_total.abs()).then_with(|| a.stack.cmp(&b.stack)));
+    return result;
+}
+
+/// formats `diffs` as a text table, with a `*` on changes with a
+/// p-value below `alpha`.
+pub fn diff_report(diffs: &[ScopeDiff], alpha: f64) -> String {
+    let width = diffs.iter().map(|d| d.stack.chars().count()).max().unwrap_or(0).max(5);
+
+    let mut out = format!("{:<width$} {:>8} {:>8} {:>10} {:>10} {:>8} {:>8}\n",
+        "stack", "before", "after", "mean", "total", "change", "p");
+    for d in diffs {
+        let change = if d.change.is_finite() { format!("{:+.1}%", d.change * 100.0) } else { "-".to_string() };
+        let p      = if d.p_value.is_nan() { "-".to_string() } else { format!("{:.3}", d.p_value) };
+        let mark   = if d.is_significant(alpha) { " *" } else { "" };
+        let mean   = if d.after_count > 0 { d.after_mean } else { d.before_mean };
+        out += &format!("{:<width$} {:>8} {:>8} {:>10} {:>10} {:>8} {:>8}{}\n",
+            d.stack, d.before_count, d.after_count, Time(mean), Delta(d.delta_total), change, p, mark);
+    }
+    return out;
+}
+
+fn durations_by_stack(trace: &Trace) -> HashMap<String, Vec<f64>> {
+    let scopes = trace.scopes();
+
+    let mut stacks = HashMap::<String, Vec<f64>>::new();
+    let mut names = Vec::new();
+    for scope in scopes {
+        names.clear();
+        names.push(scope.name.as_str());
+        let mut at = scope.parent;
+        while let Some(i) = at {
+            names.push(&scopes[i].name);
+            at = scopes[i].parent;
+        }
+        names.reverse();
+
+        stacks.entry(names.join(";")).or_default().push(scope.duration());
+    }
+    return stacks;
+}
+
+fn compare(stack: String, before: &[f64], after: &[f64]) -> ScopeDiff {
+    let (n1, m1, v1) = moments(before);
+    let (n2, m2, v2) = moments(after);
+
+    let p_value = if n1 < 2.0 || n2 < 2.0 {
+        f64::NAN
+    }
+    else {
+        let (s1, s2) = (v1 / n1, v2 / n2);
+        if s1 + s2 == 0.0 {
+            if m1 == m2 { 1.0 } else { 0.0 }
+        }
+        else {
+            let t  = (m2 - m1) / (s1 + s2).sqrt();
+            let df = (s1 + s2).powi(2) / (s1*s1 / (n1 - 1.0) + s2*s2 / (n2 - 1.0));
+            incomplete_beta(df / 2.0, 0.5, df / (df + t*t))
+        }
+    };
+
+    ScopeDiff {
+        stack,
+        before_count: before.len() as u64,
+        after_count:  after.len() as u64,
+        before_mean:  m1,
+        after_mean:   m2,
+        delta_total:  after.iter().sum::<f64>() - before.iter().sum::<f64>(),
+        change:       m2 / m1 - 1.0,
+        p_value,
+    }
+}
+
+// count, mean, and sample variance.
+fn moments(values: &[f64]) -> (f64, f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+    return (n, mean, var);
+}
+
+// the regularized incomplete beta function `I_x(a, b)`,
+// with the continued fraction from numerical recipes.
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 { return 0.0 }
+    if x >= 1.0 { return 1.0 }
+
+    // the fraction converges quickly on this side, use the symmetry otherwise.
+    if x > (a + 1.0) / (a + b + 2.0) {
+        return 1.0 - incomplete_beta(b, a, 1.0 - x);
+    }
+    let ln_front = ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln();
+
+    let tiny = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < tiny { d = tiny }
+    d = 1.0 / d;
+    let mut f = d;
+    for m in 1..300 {
+        let m = m as f64;
+        for numerator in [
+            m * (b - m) * x / ((a + 2.0*m - 1.0) * (a + 2.0*m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0*m) * (a + 2.0*m + 1.0)),
+        ] {
+            d = 1.0 + numerator * d;
+            if d.abs() < tiny { d = tiny }
+            c = 1.0 + numerator / c;
+            if c.abs() < tiny { c = tiny }
+            d = 1.0 / d;
+            f *= c * d;
+        }
+        if (c * d - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    return ln_front.exp() * f / a;
+}
+
+// lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const G: [f64; 6] = [76.18009172947146, -86.50532032941677, 24.01409824083091,
+        -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    for (i, g) in G.iter().enumerate() {
+        ser += g / (x + 1.0 + i as f64);
+    }
+    return -tmp + (2.5066282746310005 * ser / x).ln();
+}
+
+// a signed `Time`.
+struct Delta(f64);
+
+impl std::fmt::Display for Delta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0.0 { "-" } else { "+" };
+        f.pad(&format!("{}{}", sign, Time(self.0.abs())))
+    }
+}
+
+/// nearest-rank percentile of sorted values. `p` is in `[0, 1]`.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    return sorted[rank.clamp(1, sorted.len()) - 1];
+}
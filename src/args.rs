@@ -0,0 +1,213 @@
+//! structured scope args.
+//!
+//! `trace_scope!("load", { path = p, bytes = n })` records args as
+//! `path=assets/a.png bytes=1024`. values that are empty or contain
+//! whitespace, `"` or `\` are quoted, with `"` and `\` escaped by `\`.
+//! `parse` reverses the encoding, so exporters can map args to attributes.
+//!
+//! with the `serde` feature, `Json` attaches any `Serialize` value as json.
+
+use std::borrow::Cow;
+use std::fmt::{self, Display, Write};
+
+
+/// the most bytes of args a scope can have. longer args are truncated.
+pub const MAX_LEN: usize = 255;
+
+/// ends names and args that were truncated, within their 255 bytes.
+/// truncation happens on char boundaries, so they stay valid utf-8.
+pub const TRUNCATION_MARK: &str = "…";
+
+
+#[doc(hidden)]
+pub struct KeyValues<'a>(pub &'a [(&'static str, &'a dyn Display)]);
+
+impl Display for KeyValues<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            f.write_str(key)?;
+            f.write_char('=')?;
+
+            // formatted twice, to decide on quotes without buffering.
+            let mut scan = Scan { empty: true, quote: false };
+            _ = write!(scan, "{}", value);
+            if !scan.empty && !scan.quote {
+                write!(f, "{}", value)?;
+                continue;
+            }
+
+            f.write_char('"')?;
+            #[cfg(feature = "serde")]
+            ESCAPED.set(true);
+            let res = write!(Escape(&mut *f), "{}", value);
+            #[cfg(feature = "serde")]
+            ESCAPED.set(false);
+            res?;
+            f.write_char('"')?;
+        }
+        Ok(())
+    }
+}
+
+fn needs_quotes(c: char) -> bool {
+    c.is_whitespace() || c == '"' || c == '\\'
+}
+
+fn write_value(f: &mut impl Write, value: &str) -> fmt::Result {
+    if !value.is_empty() && !value.chars().any(needs_quotes) {
+        return f.write_str(value);
+    }
+
+    f.write_char('"')?;
+    Escape(&mut *f).write_str(value)?;
+    f.write_char('"')
+}
+
+// whether a value needs quotes. stops formatting once it does.
+struct Scan {
+    empty: bool,
+    quote: bool,
+}
+
+impl Write for Scan {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.empty &= s.is_empty();
+        if s.chars().any(needs_quotes) {
+            self.quote = true;
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+// escapes `"` and `\` with `\`, for quoted values.
+struct Escape<W>(W);
+
+impl<W: Write> Write for Escape<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            if c == '"' || c == '\\' {
+                self.0.write_str(&s[start..i])?;
+                self.0.write_char('\\')?;
+                start = i;
+            }
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+
+// the inverse of `parse`.
+pub(crate) fn format(pairs: &[(&str, Cow<'_, str>)]) -> String {
+    let mut out = String::new();
+    for (i, (key, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        if key.is_empty() {
+            out.push_str(value);
+            continue;
+        }
+        out.push_str(key);
+        out.push('=');
+        _ = write_value(&mut out, value);
+    }
+    return out;
+}
+
+/// splits `k=v` args into pairs.
+/// words without `=` are returned with an empty key,
+/// so free form args round trip too.
+pub fn parse(args: &str) -> Vec<(&str, Cow<'_, str>)> {
+    let mut result = Vec::new();
+    let mut rest = args.trim_start();
+
+    while !rest.is_empty() {
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (key, value_start) = match rest[..word_end].find('=') {
+            Some(eq) => (&rest[..eq], eq + 1),
+            None     => ("", 0),
+        };
+
+        let value = &rest[value_start..];
+        let (value, len) =
+            if value.starts_with('"') && !key.is_empty() { unquote(value) }
+            else {
+                let len = value.find(char::is_whitespace).unwrap_or(value.len());
+                (Cow::Borrowed(&value[..len]), len)
+            };
+
+        result.push((key, value));
+        rest = rest[value_start + len..].trim_start();
+    }
+
+    return result;
+}
+
+// parses a quoted value, returns it and the number of bytes consumed.
+// unterminated values, as from truncated args, run to the end.
+fn unquote(value: &str) -> (Cow<'_, str>, usize) {
+    let mut result = String::new();
+    let mut chars = value.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"'  => return (Cow::Owned(result), i + 1),
+            '\\' => if let Some((_, c)) = chars.next() { result.push(c) },
+            c    => result.push(c),
+        }
+    }
+    return (Cow::Owned(result), value.len());
+}
+
+
+
+/// formats a `Serialize` value as json, for scope args.
+///
+/// ```no_run
+/// let config = std::collections::BTreeMap::from([("threads", 4), ("depth", 2)]);
+/// spall::trace_scope!("configure", "{}", spall::args::Json(&config));
+/// ```
+///
+/// in scope args, values that don't fit in the rest of the args, after
+/// what was written before them, like `key=` and its quote, would be cut
+/// into invalid json, so they're replaced by `{"spall_truncated":<len>}`.
+/// formatted elsewhere, the json is never replaced.
+#[cfg(feature = "serde")]
+pub struct Json<'a, T: ?Sized>(pub &'a T);
+
+// the bytes left in the args being recorded on this thread, unlimited
+// outside of them, and whether `KeyValues` is escaping a value.
+#[cfg(feature = "serde")]
+thread_local! {
+    static ROOM:    std::cell::Cell<usize> = const { std::cell::Cell::new(usize::MAX) };
+    static ESCAPED: std::cell::Cell<bool>  = const { std::cell::Cell::new(false) };
+}
+
+// set by `push_args` as it writes, and back to `usize::MAX` after.
+#[cfg(feature = "serde")]
+pub(crate) fn set_room(room: usize) {
+    ROOM.set(room);
+}
+
+#[cfg(feature = "serde")]
+impl<T: ?Sized + serde::Serialize> Display for Json<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Ok(json) = serde_json::to_string(self.0) else {
+            return f.write_str("null");
+        };
+
+        let mut len = json.len();
+        if ESCAPED.get() {
+            // the escapes, and the closing quote.
+            len += json.bytes().filter(|b| matches!(b, b'"' | b'\\')).count() + 1;
+        }
+        if len > ROOM.get() {
+            return write!(f, "{{\"spall_truncated\":{}}}", json.len());
+        }
+        return f.write_str(&json);
+    }
+}
@@ -0,0 +1,212 @@
+// the event buffer of a thread.
+//
+// an allocation with a cursor, the unsafe core of recording. the memory
+// is zeroed when allocated, so all of it can be handed out as slices,
+// and every write is checked against the allocation, so a wrong size
+// can't write out of bounds. the check is a compare the callers'
+// `reserve` already did, so it's free in practice.
+// the allocation may be larger than what's recorded into it, the
+// `limit`, with room for a checkpoint and for padding to whole blocks
+// for direct i/o.
+// freed buffers are kept in a small pool, so threads that come and go
+// don't allocate and zero a buffer each. reused ones start with stale
+// bytes, which is fine, only recorded bytes are ever read.
+
+use std::alloc::Layout;
+use std::mem::size_of;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+use zerocopy::IntoBytes;
+
+use crate::{EventType, PadSkipEvent};
+
+
+// direct i/o writes whole blocks from aligned memory.
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+pub(crate) struct Buffer {
+    ptr:    NonNull<u8>,
+    layout: Layout,
+    len:    usize,
+    limit:  usize,
+}
+
+// owns its memory, like a `Vec<u8>`.
+unsafe impl Send for Buffer {}
+
+impl Buffer {
+    // `limit` bytes to record into, and `extra` bytes past it.
+    // aligned and with room to pad to whole blocks, for `direct`.
+    pub(crate) fn new(limit: usize, extra: usize, direct: bool) -> Option<Buffer> {
+        let size = limit.checked_add(extra)?;
+        let layout = match direct {
+            true  => Layout::from_size_align(padded_len(size), BLOCK_SIZE).ok()?,
+            false => Layout::from_size_align(size.max(1), 1).ok()?,
+        };
+        return Self::allocate(layout, limit);
+    }
+
+    fn allocate(layout: Layout, limit: usize) -> Option<Buffer> {
+        let pooled = POOL.lock().ok().and_then(|mut pool| {
+            let index = pool.iter().position(|p| p.layout == layout)?;
+            Some(pool.swap_remove(index).ptr)
+        });
+        let ptr = match pooled {
+            Some(ptr) => ptr,
+            None      => NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) })?,
+        };
+        return Some(Buffer { ptr, layout, len: 0, limit });
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    // bytes left before the limit.
+    #[inline(always)]
+    pub(crate) fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.len)
+    }
+
+    #[inline(always)]
+    fn allocation(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    // the recorded bytes.
+    #[inline(always)]
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.len;
+        &mut self.allocation()[..len]
+    }
+
+    // the first `len` bytes of the allocation, like after `pad_blocks`.
+    #[inline(always)]
+    pub(crate) fn head(&self, len: usize) -> &[u8] {
+        assert!(len <= self.layout.size());
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), len) }
+    }
+
+    // the bytes after the recorded ones, up to the end of the
+    // allocation, for writing in place before `commit`.
+    #[inline(always)]
+    pub(crate) fn spare_mut(&mut self) -> &mut [u8] {
+        let len = self.len;
+        &mut self.allocation()[len..]
+    }
+
+    // adds `n` bytes written into `spare_mut`.
+    #[inline(always)]
+    pub(crate) fn commit(&mut self, n: usize) {
+        assert!(n <= self.layout.size() - self.len);
+        self.len += n;
+    }
+
+    #[inline(always)]
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.spare_mut()[..bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    #[inline(always)]
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    #[inline(always)]
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // moves the recorded bytes into a new allocation with double the
+    // limit, up to `max`. false if that's not `size` more bytes.
+    pub(crate) fn grow(&mut self, size: usize, max: usize) -> bool {
+        let limit = self.limit.saturating_mul(2).min(max);
+        if limit < self.len.saturating_add(size) {
+            return false;
+        }
+
+        let extra = self.layout.size() - self.limit;
+        let Some(mut grown) = Buffer::new(limit, extra, self.layout.align() == BLOCK_SIZE) else {
+            return false;
+        };
+        grown.push(self.as_slice());
+        *self = grown;
+        return true;
+    }
+
+    // writes a `PadSkip` over the rest of the block after the recorded
+    // bytes, returns the padded length. only for direct buffers.
+    pub(crate) fn pad_blocks(&mut self) -> usize {
+        let len = self.len;
+        let padded = padded_len(len);
+        let skip = padded - len - size_of::<PadSkipEvent>();
+
+        let event = PadSkipEvent {
+            ty: EventType::PadSkip as u8,
+            size: skip as u32,
+        };
+        let allocation = self.allocation();
+        allocation[len..padded - skip].copy_from_slice(event.as_bytes());
+        allocation[padded - skip..padded].fill(0);
+        return padded;
+    }
+}
+
+// for registering buffers with io_uring.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl Buffer {
+    // another buffer with the same layout.
+    pub(crate) fn like(other: &Buffer) -> Option<Buffer> {
+        return Self::allocate(other.layout, other.limit);
+    }
+
+    // the whole allocation.
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Ok(mut pool) = POOL.lock() {
+            let pooled = pool.iter().map(|p| p.layout.size()).sum::<usize>();
+            if pooled + self.layout.size() <= POOL_SIZE {
+                pool.push(Pooled { ptr: self.ptr, layout: self.layout });
+                return;
+            }
+        }
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+
+// allocations of dropped buffers, at most `POOL_SIZE` bytes.
+static POOL: Mutex<Vec<Pooled>> = Mutex::new(Vec::new());
+
+const POOL_SIZE: usize = 4 << 20;
+
+struct Pooled {
+    ptr:    NonNull<u8>,
+    layout: Layout,
+}
+
+// owned by the pool, like by the buffer before.
+unsafe impl Send for Pooled {}
+
+// with room for a `PadSkip` to the end of the block.
+fn padded_len(len: usize) -> usize {
+    (len + size_of::<PadSkipEvent>()).next_multiple_of(BLOCK_SIZE)
+}
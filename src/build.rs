@@ -0,0 +1,100 @@
+//! build information as trace metadata.
+//!
+//! `build_info!()` records the calling crate's name and version, and how
+//! it was built, so traces of different builds can be told apart.
+//! the git hash and target triple are only known to build scripts,
+//! so for those, call `emit` from the crate's `build.rs`:
+//!
+//! ```no_run
+//! // in `main` of build.rs, with spall in [build-dependencies].
+//! spall::build::emit();
+//! ```
+
+
+/// as recorded by `build_info!`.
+#[derive(Clone, Debug)]
+pub struct BuildInfo {
+    pub name:     &'static str,
+    pub version:  &'static str,
+    pub git_hash: Option<&'static str>,
+    /// `debug` or `release`.
+    pub profile:  &'static str,
+    pub target:   Option<&'static str>,
+}
+
+impl BuildInfo {
+    /// records the fields as `metadata`, `crate`, `version`,
+    /// `git_hash`, `profile` and `target`.
+    pub fn set_metadata(&self) {
+        use crate::metadata::set;
+
+        set("crate",   self.name);
+        set("version", self.version);
+        if let Some(git_hash) = self.git_hash {
+            set("git_hash", git_hash);
+        }
+        set("profile", self.profile);
+        if let Some(target) = self.target {
+            set("target", target);
+        }
+    }
+}
+
+/// records the `BuildInfo` of the calling crate as metadata,
+/// for the current and all later trace files.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build::BuildInfo {
+            name:     env!("CARGO_PKG_NAME"),
+            version:  env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("SPALL_GIT_HASH"),
+            profile:
+                match option_env!("SPALL_PROFILE") {
+                    Some(profile) => profile,
+                    None => if cfg!(debug_assertions) { "debug" } else { "release" },
+                },
+            target:   option_env!("SPALL_TARGET"),
+        }.set_metadata()
+    };
+}
+
+
+/// for build scripts, passes the git hash, profile and target triple
+/// to `build_info!` through environment variables.
+/// the hash is missing outside of git repositories.
+///
+/// this tells cargo to rerun the build script when git's `HEAD` changes,
+/// which replaces the default of rerunning on any change in the package.
+pub fn emit() {
+    use std::process::Command;
+
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    if let Some(hash) = git(&["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=SPALL_GIT_HASH={}", hash);
+
+        // rerun on commits and checkouts.
+        if let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}", head);
+        }
+        if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            if let Some(path) = git(&["rev-parse", "--git-path", &branch]) {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+
+    if let Ok(profile) = std::env::var("PROFILE") {
+        println!("cargo:rustc-env=SPALL_PROFILE={}", profile);
+    }
+    if let Ok(target) = std::env::var("TARGET") {
+        println!("cargo:rustc-env=SPALL_TARGET={}", target);
+    }
+}
@@ -0,0 +1,71 @@
+//! recovery checkpoints.
+//!
+//! with `Options::checkpoints`, each flush ends with a `CustomData` event
+//! tagged `TAG`, with the thread, a sequence number counting its flushes
+//! from 1 in each file, when the flush started, and the size of the
+//! flushed events.
+//! a flush is one write, so a checkpoint confirms the events before it
+//! were written completely.
+//!
+//! after a crash, `Trace::parse_lossy` reports per thread what the
+//! checkpoints confirm, see `reader::ThreadSalvage`: the last complete
+//! flush, flushes that went missing or were damaged, and events written
+//! after the last checkpoint, by a flush that didn't complete.
+//! events still buffered at the crash leave no trace, but they're all
+//! after the thread's last checkpoint.
+
+use std::mem::size_of;
+
+use crate::{CustomDataEvent, EventType};
+
+
+/// the custom data tag of checkpoints. the payload is the pid and tid
+/// as `u32`, the sequence number as `u64`, the size of the events
+/// before it in the flush as `u32`, and when the flush started,
+/// as an `f64` timestamp, all little endian.
+pub const TAG: u32 = u32::from_le_bytes(*b"ckpt");
+
+const PAYLOAD_LEN: usize = 4*size_of::<u32>() + size_of::<u64>() + size_of::<f64>();
+
+// the size of a checkpoint event.
+pub(crate) const EVENT_LEN: usize = size_of::<CustomDataEvent>() + PAYLOAD_LEN;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Checkpoint {
+    pub pid:  u32,
+    pub tid:  u32,
+    pub seq:  u64,
+    pub len:  u32,
+    pub when: f64,
+}
+
+// the bytes of the event.
+pub(crate) fn event(checkpoint: Checkpoint) -> [u8; EVENT_LEN] {
+    let mut event = [0u8; EVENT_LEN];
+    event[0] = EventType::CustomData as u8;
+    event[1..5].copy_from_slice(&(PAYLOAD_LEN as u32).to_le_bytes());
+    event[5..9].copy_from_slice(&TAG.to_le_bytes());
+    event[9..13].copy_from_slice(&checkpoint.pid.to_le_bytes());
+    event[13..17].copy_from_slice(&checkpoint.tid.to_le_bytes());
+    event[17..25].copy_from_slice(&checkpoint.seq.to_le_bytes());
+    event[25..29].copy_from_slice(&checkpoint.len.to_le_bytes());
+    event[29..37].copy_from_slice(&checkpoint.when.to_le_bytes());
+    return event;
+}
+
+// the checkpoint in a custom data payload, if it is one.
+pub(crate) fn parse(data: &[u8]) -> Option<Checkpoint> {
+    let data = data.strip_prefix(&TAG.to_le_bytes())?;
+    if data.len() != PAYLOAD_LEN - size_of::<u32>() {
+        return None;
+    }
+
+    Some(Checkpoint {
+        pid:  u32::from_le_bytes(data[0..4].try_into().ok()?),
+        tid:  u32::from_le_bytes(data[4..8].try_into().ok()?),
+        seq:  u64::from_le_bytes(data[8..16].try_into().ok()?),
+        len:  u32::from_le_bytes(data[16..20].try_into().ok()?),
+        when: f64::from_le_bytes(data[20..28].try_into().ok()?),
+    })
+}
@@ -0,0 +1,69 @@
+//! export of traces as chrome trace event json.
+//!
+//! `chrome://tracing`, perfetto and tracy's `import-chrome` load the
+//! output, so traces recorded with spall can be inspected in those tools.
+//! scopes become complete events, `spall/thread_start` markers name
+//! the threads. see `Format::ChromeJson` for recording json directly.
+
+use std::fmt::Write;
+use std::io::Error;
+use std::path::Path;
+
+use crate::json::{push_args, push_ids, push_str};
+use crate::reader::Trace;
+
+
+/// writes `trace` as json to `path`.
+pub fn export(trace: &Trace, path: impl AsRef<Path>) -> Result<(), Error> {
+    std::fs::write(path, encode(trace))
+}
+
+/// encodes `trace` as a json object with a `traceEvents` array.
+pub fn encode(trace: &Trace) -> Vec<u8> {
+    let scopes = trace.scopes();
+
+    let mut order = (0..scopes.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        let (a, b) = (&scopes[*a], &scopes[*b]);
+        a.start.total_cmp(&b.start).then(a.depth.cmp(&b.depth))
+    });
+
+    let mut out = String::new();
+    out.push_str("{\"traceEvents\":[");
+    let mut first = true;
+    let mut separate = |out: &mut String| {
+        if !first {
+            out.push(',');
+        }
+        out.push('\n');
+        first = false;
+    };
+
+    for scope in trace.scopes_named("spall/thread_start") {
+        let args = crate::args::parse(&scope.args);
+        let Some((_, name)) = args.iter().find(|(k, _)| *k == "name") else { continue };
+
+        separate(&mut out);
+        out.push_str("{\"name\":\"thread_name\",\"ph\":\"M\"");
+        _ = write!(out, ",\"pid\":{},\"tid\":{}", scope.pid, scope.tid);
+        out.push_str(",\"args\":{\"name\":");
+        push_str(&mut out, name);
+        out.push_str("}}");
+    }
+
+    for index in order {
+        let scope = &scopes[index];
+
+        separate(&mut out);
+        out.push_str("{\"name\":");
+        push_str(&mut out, &scope.name);
+        out.push_str(",\"ph\":\"X\"");
+        push_ids(&mut out, scope.pid, scope.tid, scope.start);
+        _ = write!(out, ",\"dur\":{:.3}", scope.duration());
+        push_args(&mut out, &scope.args);
+        out.push('}');
+    }
+
+    out.push_str("\n]}\n");
+    return out.into_bytes();
+}
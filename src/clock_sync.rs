@@ -0,0 +1,147 @@
+//! aligning traces recorded on several machines.
+//!
+//! the clocks of different machines don't agree, so their traces don't
+//! line up. when the application exchanges messages between them anyway,
+//! it can record when each message was `sent` and `received`, under an id
+//! both sides know, like a request id. a message can't arrive before it
+//! was sent, so messages in both directions bound the offset between two
+//! clocks, the tighter the faster the fastest messages were.
+//! `merge` combines the traces on the clock of the first one.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::reader::Trace;
+
+
+const NAME: &str = "spall/clock_sync";
+
+/// records that the message `id` is being sent.
+#[inline]
+pub fn sent(id: u64) {
+    crate::marker(NAME, format_args!("id={} dir=send", id));
+}
+
+/// records that the message `id` was received.
+#[inline]
+pub fn received(id: u64) {
+    crate::marker(NAME, format_args!("id={} dir=recv", id));
+}
+
+
+/// the correction for a trace's timestamps, in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Alignment {
+    /// added to the trace's timestamps to get the first trace's time.
+    pub offset: f64,
+    /// the true offset is within `offset ± error`.
+    pub error: f64,
+}
+
+/// aligns `traces` to the first one.
+///
+/// each trace must be connected to the first one by messages in both
+/// directions, directly or through other traces. errors add up along
+/// the way, the path with the smallest error is used. traces whose
+/// messages contradict each other, with no possible offset, aren't
+/// connected.
+pub fn align(traces: &[Trace]) -> Result<Vec<Alignment>, Error> {
+    let messages = traces.iter().map(messages).collect::<Vec<_>>();
+
+    let mut result = vec![None; traces.len()];
+    if let Some(first) = result.first_mut() {
+        *first = Some(Alignment { offset: 0.0, error: 0.0 });
+    }
+
+    // like dijkstra, there are few traces.
+    loop {
+        let mut best: Option<(usize, Alignment)> = None;
+        for (i, known) in result.iter().enumerate() {
+            let Some(known) = known else { continue };
+
+            for j in 0..traces.len() {
+                if result[j].is_some() {
+                    continue;
+                }
+                let Some(step) = bounds(&messages[i], &messages[j]) else { continue };
+
+                let candidate = Alignment {
+                    offset: known.offset + step.offset,
+                    error:  known.error  + step.error,
+                };
+                if best.map(|(_, b)| candidate.error < b.error).unwrap_or(true) {
+                    best = Some((j, candidate));
+                }
+            }
+        }
+
+        let Some((j, alignment)) = best else { break };
+        result[j] = Some(alignment);
+    }
+
+    return result.into_iter().enumerate()
+        .map(|(i, a)| a.ok_or_else(|| Error::new(ErrorKind::InvalidData,
+            format!("trace {} isn't connected to the first trace by messages in both directions", i))))
+        .collect();
+}
+
+/// combines trace files of different machines into one,
+/// on the clock of the first input. see `reader::merge`.
+pub fn merge<P: AsRef<Path>>(inputs: &[P], output: impl AsRef<Path>) -> Result<Vec<Alignment>, Error> {
+    let traces = inputs.iter().map(Trace::open).collect::<Result<Vec<_>, _>>()?;
+    let alignments = align(&traces)?;
+
+    let offsets = alignments.iter().map(|a| a.offset).collect::<Vec<_>>();
+    crate::reader::merge_shifted(inputs, &offsets, output)?;
+    return Ok(alignments);
+}
+
+
+#[derive(Default)]
+struct Messages {
+    sent:     HashMap<u64, f64>,
+    received: HashMap<u64, f64>,
+}
+
+fn messages(trace: &Trace) -> Messages {
+    let mut result = Messages::default();
+    for scope in trace.scopes_named(NAME) {
+        let args = crate::args::parse(&scope.args);
+        let get = |key: &str| args.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_ref());
+
+        let Some(id) = get("id").and_then(|id| id.parse().ok()) else { continue };
+        let times = match get("dir") {
+            Some("send") => &mut result.sent,
+            Some("recv") => &mut result.received,
+            _ => continue,
+        };
+        times.entry(id).or_insert(scope.start);
+    }
+    return result;
+}
+
+// the offset from `b`'s clock to `a`'s.
+fn bounds(a: &Messages, b: &Messages) -> Option<Alignment> {
+    // a to b: sent at `s` on a, received at `r` on b,
+    // so `r + offset >= s`.
+    let lower = a.sent.iter()
+        .filter_map(|(id, s)| Some(s - b.received.get(id)?))
+        .fold(None, |m: Option<f64>, x| Some(m.map_or(x, |m| m.max(x))))?;
+
+    // b to a: `s + offset <= r`.
+    let upper = b.sent.iter()
+        .filter_map(|(id, s)| Some(a.received.get(id)? - s))
+        .fold(None, |m: Option<f64>, x| Some(m.map_or(x, |m| m.min(x))))?;
+
+    // messages that contradict each other, like after a clock jumped,
+    // bound nothing.
+    if lower > upper {
+        return None;
+    }
+
+    Some(Alignment {
+        offset: (lower + upper) / 2.0,
+        error:  (upper - lower) / 2.0,
+    })
+}
@@ -0,0 +1,173 @@
+//! options from a config file.
+//!
+//! ```toml
+//! path = "traces/game_$.spall"   # relative to the config file
+//! buffer_size = 65536
+//! overflow = "block"             # or "drop", or { grow = { max_buffer_size = 1048576 } }
+//! max_file_size = 268435456      # rotate after 256 MiB
+//! per_thread_files = false
+//! lazy_file = false
+//! sample_rate = 1.0
+//! filter = "render/*,!render/particles"
+//! level = "verbose"              # or "normal", "coarse"
+//! min_duration_us = 1.0
+//! rebase_timestamps = false
+//! flush_interval_ms = 100
+//! sequential_tids = false
+//! checkpoints = false
+//! record_cpu = false
+//! process_metadata = false
+//! redact = false
+//! redact_dictionary = "names.txt"  # relative to the config file
+//! format = "spall"               # or "chrome_json"
+//! direct_io = false
+//! silent = false
+//! signals = true                 # see `signal::install_handlers`
+//! panic_hook = true              # see `install_panic_hook`
+//! memory_interval_ms = 100       # see `memory::sample`
+//! cpu_interval_ms = 100          # see `cpu::sample`
+//!
+//! [live]                         # see `live::serve`, needs the `live` feature
+//! addr = "127.0.0.1:9099"
+//! queue_buffers = 64
+//! ```
+//!
+//! all keys are optional, except for `path`. unknown keys are an error.
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{Format, Options, Overflow};
+use crate::filter::Level;
+
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub path: Option<String>,
+    pub buffer_size: Option<usize>,
+    pub overflow: Overflow,
+    pub max_file_size: Option<u64>,
+    pub per_thread_files: bool,
+    pub lazy_file: bool,
+    pub sample_rate: Option<f64>,
+    pub filter: Option<String>,
+    pub level: Option<Level>,
+    pub min_duration_us: Option<f64>,
+    pub rebase_timestamps: bool,
+    pub flush_interval_ms: Option<u64>,
+    pub sequential_tids: bool,
+    pub checkpoints: bool,
+    pub record_cpu: bool,
+    pub process_metadata: bool,
+    pub redact: bool,
+    pub redact_dictionary: Option<String>,
+    pub format: Format,
+    pub direct_io: bool,
+    pub silent: bool,
+    pub signals: bool,
+    pub panic_hook: bool,
+    pub memory_interval_ms: Option<u64>,
+    pub cpu_interval_ms: Option<u64>,
+    pub live: Option<LiveConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LiveConfig {
+    pub addr: String,
+    #[serde(default = "default_queue_buffers")]
+    pub queue_buffers: usize,
+}
+
+fn default_queue_buffers() -> usize { 64 }
+
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        toml::from_str(text).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn options(&self) -> Options {
+        let default = Options::default();
+        Options {
+            buffer_size: self.buffer_size.unwrap_or(default.buffer_size),
+            overflow: self.overflow,
+            max_file_size: self.max_file_size,
+            per_thread_files: self.per_thread_files,
+            lazy_file: self.lazy_file,
+            sample_rate: self.sample_rate.unwrap_or(default.sample_rate),
+            filter: self.filter.clone(),
+            level: self.level.unwrap_or(default.level),
+            min_duration: self.min_duration_us.map(|us| Duration::from_secs_f64(us.max(0.0) / 1e6)),
+            rebase_timestamps: self.rebase_timestamps,
+            flush_interval: self.flush_interval_ms.map(Duration::from_millis),
+            sequential_tids: self.sequential_tids,
+            checkpoints: self.checkpoints,
+            record_cpu: self.record_cpu,
+            process_metadata: self.process_metadata,
+            redact: self.redact,
+            redact_dictionary: self.redact_dictionary.as_ref().map(PathBuf::from),
+            format: self.format,
+            direct_io: self.direct_io,
+            silent: self.silent,
+            clock: None,
+        }
+    }
+
+    /// initializes tracing like `init_with`, then starts the configured
+    /// extras. a relative `path` is relative to `base_dir`.
+    pub fn init(&self, base_dir: &Path) -> Result<bool, Error> {
+        let path = self.path.as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "spall config has no path"))?;
+        let path = base_dir.join(path);
+
+        #[cfg(not(feature = "live"))]
+        if self.live.is_some() {
+            return Err(Error::new(ErrorKind::Unsupported, "spall config uses live, but the `live` feature is disabled"));
+        }
+
+        let mut options = self.options();
+        options.redact_dictionary = options.redact_dictionary.map(|p| base_dir.join(p));
+
+        if !crate::init_with(&path, options)? {
+            return Ok(false);
+        }
+
+        #[cfg(feature = "live")]
+        if let Some(live) = &self.live {
+            crate::live::serve(live.addr.as_str(), live.queue_buffers)?;
+        }
+
+        #[cfg(unix)]
+        if self.signals {
+            crate::signal::install_handlers()?;
+        }
+
+        if self.panic_hook {
+            crate::install_panic_hook();
+        }
+
+        if let Some(ms) = self.memory_interval_ms {
+            crate::memory::sample(Duration::from_millis(ms))?;
+        }
+
+        if let Some(ms) = self.cpu_interval_ms {
+            crate::cpu::sample(Duration::from_millis(ms))?;
+        }
+
+        return Ok(true);
+    }
+}
+
+
+/// initializes tracing from a toml config file, see `config`.
+pub fn init_from_file(path: impl AsRef<Path>) -> Result<bool, Error> {
+    let path = path.as_ref();
+    let config = Config::parse(&std::fs::read_to_string(path)?)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    config.init(base_dir)
+}
@@ -0,0 +1,187 @@
+//! hardware counters over scopes, on linux.
+//!
+//! `scope` records a scope with the cpu cycles, instructions, and cache
+//! misses of the calling thread during it, with args like
+//! `cycles=81234 instructions=190211 cache_misses=312`.
+//! the counters are opened with `perf_event_open` on a thread's first
+//! counted scope, and only count that thread, in user space.
+//! they are unavailable in many containers and vms, or with a
+//! `perf_event_paranoid` above 2, then the scope has no args.
+//! counters that fail to open on their own are left out.
+//!
+//! like `io`, the scope is only written when it ends, so counted
+//! scopes shouldn't contain other scopes. they're meant for leaves,
+//! like a hot loop.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::now;
+
+
+/// a counted scope, recorded when dropped.
+#[must_use]
+pub struct Scope<N: AsRef<str> = &'static str> {
+    name:   N,
+    t0:     u64,
+    start:  Option<Values>,
+    // counted on this thread.
+    _local: std::marker::PhantomData<*const ()>,
+}
+
+/// begins a counted scope on this thread.
+/// names are like those of `trace_scope_impl`, kept until the scope ends.
+pub fn scope<N: AsRef<str>>(name: N) -> Scope<N> {
+    let start = with_group(Group::read);
+    Scope { name, t0: now(), start, _local: std::marker::PhantomData }
+}
+
+/// whether this thread could open its counters.
+pub fn available() -> bool {
+    with_group(|_| Some(())).is_some()
+}
+
+impl<N: AsRef<str>> Drop for Scope<N> {
+    fn drop(&mut self) {
+        let end = with_group(Group::read);
+        let name = self.name.as_ref();
+        match (&self.start, end) {
+            (Some(start), Some(end)) => crate::scope_since(name, self.t0, format_args!("{}", Deltas(start, &end))),
+            _                        => crate::scope_since(name, self.t0, format_args!("")),
+        }
+    }
+}
+
+
+
+// perf_event:
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+
+// the leader first.
+const COUNTERS: [(&str, u64); 3] = [
+    ("cycles",       0), // PERF_COUNT_HW_CPU_CYCLES
+    ("instructions", 1), // PERF_COUNT_HW_INSTRUCTIONS
+    ("cache_misses", 3), // PERF_COUNT_HW_CACHE_MISSES
+];
+
+const PERF_FORMAT_GROUP: u64 = 1 << 3;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+const EXCLUDE_KERNEL: u64 = 1 << 5;
+const EXCLUDE_HV:     u64 = 1 << 6;
+
+// PERF_ATTR_SIZE_VER0, the fields after `flags` are zero.
+#[repr(C)]
+struct Attr {
+    ty:          u32,
+    size:        u32,
+    config:      u64,
+    period:      u64,
+    sample_type: u64,
+    read_format: u64,
+    flags:       u64,
+    wakeup:      u32,
+    bp_type:     u32,
+    bp_addr:     u64,
+}
+
+struct Group {
+    // the leader first.
+    fds:   Vec<libc::c_int>,
+    names: Vec<&'static str>,
+}
+
+// the counters' values, in the group's order.
+#[derive(Clone, Copy)]
+struct Values {
+    len:    usize,
+    names:  [&'static str; COUNTERS.len()],
+    values: [u64; COUNTERS.len()],
+}
+
+thread_local! {
+    // `None` if the leader failed to open.
+    static GROUP: RefCell<Option<Option<Group>>> = const { RefCell::new(None) };
+}
+
+fn with_group<R>(f: impl FnOnce(&Group) -> Option<R>) -> Option<R> {
+    GROUP.try_with(|group| {
+        let mut group = group.try_borrow_mut().ok()?;
+        let group = group.get_or_insert_with(Group::open);
+        f(group.as_ref()?)
+    }).ok().flatten()
+}
+
+impl Group {
+    fn open() -> Option<Group> {
+        let mut group = Group { fds: Vec::new(), names: Vec::new() };
+        for (name, config) in COUNTERS {
+            let leader = group.fds.first().copied().unwrap_or(-1);
+            let attr = Attr {
+                ty:          PERF_TYPE_HARDWARE,
+                size:        std::mem::size_of::<Attr>() as u32,
+                config,
+                period:      0,
+                sample_type: 0,
+                read_format: PERF_FORMAT_GROUP,
+                flags:       EXCLUDE_KERNEL | EXCLUDE_HV,
+                wakeup:      0,
+                bp_type:     0,
+                bp_addr:     0,
+            };
+
+            // this thread, on any cpu.
+            let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, &attr as *const Attr, 0 as libc::pid_t, -1 as libc::c_int, leader, PERF_FLAG_FD_CLOEXEC) };
+            if fd < 0 {
+                if leader < 0 {
+                    return None;
+                }
+                continue;
+            }
+
+            group.fds.push(fd as libc::c_int);
+            group.names.push(name);
+        }
+        return Some(group);
+    }
+
+    fn read(&self) -> Option<Values> {
+        // `nr`, then the values.
+        let mut buf = [0u64; 1 + COUNTERS.len()];
+        let size = std::mem::size_of_val(&buf);
+        let n = unsafe { libc::read(self.fds[0], buf.as_mut_ptr().cast(), size) };
+        if n < 8 || buf[0] as usize != self.fds.len() {
+            return None;
+        }
+
+        let mut values = Values { len: self.fds.len(), names: [""; COUNTERS.len()], values: [0; COUNTERS.len()] };
+        values.names[..values.len].copy_from_slice(&self.names);
+        values.values[..values.len].copy_from_slice(&buf[1..1 + values.len]);
+        return Some(values);
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        for fd in self.fds.iter().rev() {
+            unsafe { libc::close(*fd) };
+        }
+    }
+}
+
+
+struct Deltas<'a>(&'a Values, &'a Values);
+
+impl fmt::Display for Deltas<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Deltas(start, end) = self;
+        for (i, name) in end.names[..end.len].iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", name, end.values[i].wrapping_sub(start.values[i]))?;
+        }
+        return Ok(());
+    }
+}
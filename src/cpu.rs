@@ -0,0 +1,153 @@
+//! background cpu usage sampling.
+//!
+//! `sample` starts a thread that periodically records how busy the
+//! process and its threads were since the last sample, on its own track,
+//! to tell whether a slow region was computing or waiting.
+//! usage is in cores, 1.0 is one core busy for the whole interval.
+//!
+//! each sample is a `spall/cpu` marker with args like
+//! `process=1.85 user=1.62 system=0.23`, then on linux a
+//! `spall/thread_cpu` marker per thread, like
+//! `os_tid=48213 name="worker" usage=0.98`. the os tid matches
+//! the `spall/tid` markers of `Options::sequential_tids`.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+
+
+/// starts sampling on a background thread, every `interval`, until
+/// the session ends, see `shutdown`. does nothing without a session,
+/// or when sampling already started in this one.
+///
+/// process usage is available on unix and windows,
+/// thread usage only on linux.
+pub fn sample(interval: Duration) -> Result<(), Error> {
+    static STARTED: AtomicU64 = AtomicU64::new(0);
+
+    // the first call only takes the sample the next one compares to.
+    let mut last = None;
+    return crate::spawn_sampler("spall/cpu", &STARTED, interval, move || {
+        let next = Sample::now();
+        if let Some(last) = &last {
+            record(last, &next);
+        }
+        last = Some(next);
+    });
+}
+
+
+struct Sample {
+    when: Instant,
+    // user and system time, in seconds.
+    process: Option<(f64, f64)>,
+    // by os tid, the name and cpu time in seconds.
+    threads: HashMap<u64, (String, f64)>,
+}
+
+impl Sample {
+    fn now() -> Sample {
+        Sample {
+            when:    Instant::now(),
+            process: process_times(),
+            threads: thread_times(),
+        }
+    }
+}
+
+fn record(last: &Sample, next: &Sample) {
+    let elapsed = next.when.duration_since(last.when).as_secs_f64();
+    if elapsed <= 0.0 {
+        return;
+    }
+
+    if let (Some((user0, system0)), Some((user1, system1))) = (last.process, next.process) {
+        let user   = (user1 - user0).max(0.0) / elapsed;
+        let system = (system1 - system0).max(0.0) / elapsed;
+        crate::marker("spall/cpu", format_args!("process={:.2} user={:.2} system={:.2}",
+            user + system, user, system));
+    }
+
+    let mut threads = next.threads.iter()
+        .filter_map(|(tid, (name, time))| {
+            let (_, before) = last.threads.get(tid)?;
+            Some((*tid, name, (time - before).max(0.0) / elapsed))
+        })
+        .collect::<Vec<_>>();
+    threads.sort_by_key(|(tid, _, _)| *tid);
+
+    for (os_tid, name, usage) in threads {
+        let usage = format_args!("{:.2}", usage);
+        crate::marker("spall/thread_cpu", crate::trace_args!({ os_tid = os_tid, name = name, usage = usage }));
+    }
+}
+
+
+
+// platforms:
+
+#[cfg(unix)]
+fn process_times() -> Option<(f64, f64)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+
+    let secs = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    return Some((secs(usage.ru_utime), secs(usage.ru_stime)));
+}
+
+#[cfg(windows)]
+fn process_times() -> Option<(f64, f64)> {
+    extern "system" {
+        fn GetCurrentProcess() -> isize;
+        fn GetProcessTimes(process: isize, creation: *mut u64, exit: *mut u64, kernel: *mut u64, user: *mut u64) -> i32;
+    }
+
+    let (mut creation, mut exit, mut kernel, mut user) = (0, 0, 0, 0);
+    let ok = unsafe { GetProcessTimes(GetCurrentProcess(), &mut creation, &mut exit, &mut kernel, &mut user) };
+    if ok == 0 {
+        return None;
+    }
+
+    // in 100 ns units.
+    return Some((user as f64 / 1e7, kernel as f64 / 1e7));
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_times() -> Option<(f64, f64)> {
+    None
+}
+
+
+#[cfg(target_os = "linux")]
+fn thread_times() -> HashMap<u64, (String, f64)> {
+    let mut threads = HashMap::new();
+
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return threads;
+    }
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else { return threads };
+
+    for task in tasks.filter_map(Result::ok) {
+        let Some(tid) = task.file_name().to_str().and_then(|tid| tid.parse::<u64>().ok()) else { continue };
+        let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) else { continue };
+
+        // "tid (name) state ... utime stime ...", the name may contain anything.
+        let (Some(open), Some(close)) = (stat.find('('), stat.rfind(')')) else { continue };
+        let name = &stat[open + 1..close];
+        let mut fields = stat[close + 1..].split_whitespace().skip(11);
+        let (Some(utime), Some(stime)) = (fields.next(), fields.next()) else { continue };
+        let (Ok(utime), Ok(stime)) = (utime.parse::<u64>(), stime.parse::<u64>()) else { continue };
+
+        threads.insert(tid, (name.to_string(), (utime + stime) as f64 / ticks as f64));
+    }
+    return threads;
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_times() -> HashMap<u64, (String, f64)> {
+    HashMap::new()
+}
@@ -0,0 +1,175 @@
+//! scopes as etw events on windows, for correlating them with system
+//! activity like context switches and dpcs in wpa.
+//!
+//! with the `etw` feature, each scope is also written as a tracelogging
+//! `Begin` event with `name` and `args` fields, with the start opcode,
+//! and an `End` event with the stop opcode, from the recording thread.
+//! the provider is `Spall`, `{4d636c12-c9b4-5bf5-c2a8-4e70eb9eb022}`,
+//! so `wpr` or `tracelog` can enable it as `*Spall`. without a session
+//! listening, this costs a check per event.
+//!
+//! the events carry what spall records, so names are hashed with
+//! `Options::redact`, and scopes dropped by `Options::min_duration`
+//! still show up in etw.
+
+use std::ffi::c_void;
+use std::sync::OnceLock;
+
+
+/// the tracelogging provider name.
+pub const PROVIDER_NAME: &str = "Spall";
+
+// the eventsource style guid of `PROVIDER_NAME`.
+const PROVIDER_ID: Guid = Guid {
+    data1: 0x4d636c12,
+    data2: 0xc9b4,
+    data3: 0x5bf5,
+    data4: [0xc2, 0xa8, 0x4e, 0x70, 0xeb, 0x9e, 0xb0, 0x22],
+};
+
+#[repr(C)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+#[repr(C)]
+struct EventDescriptor {
+    id:      u16,
+    version: u8,
+    channel: u8,
+    level:   u8,
+    opcode:  u8,
+    task:    u16,
+    keyword: u64,
+}
+
+#[repr(C)]
+struct EventDataDescriptor {
+    ptr:  u64,
+    size: u32,
+    // the type in the low byte.
+    kind: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(provider: *const Guid, callback: *const c_void, context: *mut c_void, handle: *mut u64) -> u32;
+    fn EventSetInformation(handle: u64, class: u32, info: *const c_void, size: u32) -> u32;
+    fn EventEnabled(handle: u64, descriptor: *const EventDescriptor) -> u8;
+    fn EventWriteTransfer(handle: u64, descriptor: *const EventDescriptor,
+        activity: *const Guid, related: *const Guid, count: u32, data: *const EventDataDescriptor) -> u32;
+}
+
+const EVENT_PROVIDER_SET_TRAITS: u32 = 2;
+
+const DATA_USER:              u32 = 0;
+const DATA_EVENT_METADATA:    u32 = 1;
+const DATA_PROVIDER_METADATA: u32 = 2;
+
+// tracelogging's channel, verbose level, and TlgInCOUNTEDANSISTRING
+// chained with TlgOutUTF8.
+const CHANNEL: u8 = 11;
+const LEVEL:   u8 = 5;
+const UTF8_FIELD: [u8; 2] = [23 | 0x80, 35];
+
+static BEGIN: EventDescriptor = EventDescriptor { id: 0, version: 0, channel: CHANNEL, level: LEVEL, opcode: 1, task: 0, keyword: 0 };
+static END:   EventDescriptor = EventDescriptor { id: 0, version: 0, channel: CHANNEL, level: LEVEL, opcode: 2, task: 0, keyword: 0 };
+
+
+struct Provider {
+    handle: u64,
+    traits: Vec<u8>,
+    begin:  Vec<u8>,
+    end:    Vec<u8>,
+}
+
+// the handle is 0 if registration failed.
+fn provider() -> &'static Provider {
+    static PROVIDER: OnceLock<Provider> = OnceLock::new();
+    PROVIDER.get_or_init(|| {
+        let traits = metadata(|m| {
+            m.extend_from_slice(PROVIDER_NAME.as_bytes());
+            m.push(0);
+        });
+        // no tags, the event name, and the fields.
+        let begin = metadata(|m| {
+            m.extend_from_slice(b"\0Begin\0");
+            for field in ["name", "args"] {
+                m.extend_from_slice(field.as_bytes());
+                m.push(0);
+                m.extend_from_slice(&UTF8_FIELD);
+            }
+        });
+        let end = metadata(|m| m.extend_from_slice(b"\0End\0"));
+
+        let mut handle = 0;
+        unsafe {
+            if EventRegister(&PROVIDER_ID, std::ptr::null(), std::ptr::null_mut(), &mut handle) != 0 {
+                handle = 0;
+            }
+            else {
+                EventSetInformation(handle, EVENT_PROVIDER_SET_TRAITS,
+                    traits.as_ptr().cast(), traits.len() as u32);
+            }
+        }
+
+        Provider { handle, traits, begin, end }
+    })
+}
+
+// a metadata blob, which starts with its size.
+fn metadata(f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut blob = vec![0, 0];
+    f(&mut blob);
+    let size = blob.len() as u16;
+    blob[..2].copy_from_slice(&size.to_le_bytes());
+    return blob;
+}
+
+fn data(bytes: &[u8], kind: u32) -> EventDataDescriptor {
+    EventDataDescriptor { ptr: bytes.as_ptr() as u64, size: bytes.len() as u32, kind }
+}
+
+
+#[inline]
+pub(crate) fn begin(name: &[u8], args: &[u8]) {
+    let provider = provider();
+    if provider.handle == 0 || unsafe { EventEnabled(provider.handle, &BEGIN) } == 0 {
+        return;
+    }
+
+    let name_len = (name.len() as u16).to_le_bytes();
+    let args_len = (args.len() as u16).to_le_bytes();
+    let data = [
+        data(&provider.traits, DATA_PROVIDER_METADATA),
+        data(&provider.begin, DATA_EVENT_METADATA),
+        data(&name_len, DATA_USER),
+        data(name, DATA_USER),
+        data(&args_len, DATA_USER),
+        data(args, DATA_USER),
+    ];
+    unsafe {
+        EventWriteTransfer(provider.handle, &BEGIN, std::ptr::null(), std::ptr::null(),
+            data.len() as u32, data.as_ptr());
+    }
+}
+
+#[inline]
+pub(crate) fn end() {
+    let provider = provider();
+    if provider.handle == 0 || unsafe { EventEnabled(provider.handle, &END) } == 0 {
+        return;
+    }
+
+    let data = [
+        data(&provider.traits, DATA_PROVIDER_METADATA),
+        data(&provider.end, DATA_EVENT_METADATA),
+    ];
+    unsafe {
+        EventWriteTransfer(provider.handle, &END, std::ptr::null(), std::ptr::null(),
+            data.len() as u32, data.as_ptr());
+    }
+}
@@ -0,0 +1,90 @@
+//! fibers, coroutines and green threads.
+//!
+//! scopes of fibers that share a thread would interleave on its track,
+//! and a fiber that resumes on another thread ends its scopes there.
+//! `switch`, called where the engine switches fibers, moves the thread
+//! to the next fiber's own track, see `track`. the scopes a fiber has
+//! open stay open on its track while it's suspended, and end there
+//! when it resumes, on any thread.
+//!
+//! ```no_run
+//! # let (main, worker) = (0, 1);
+//! spall::fiber::switch(main, worker);
+//! // the worker fiber runs, its scopes are on its track.
+//! spall::fiber::switch(worker, main);
+//! ```
+//!
+//! id 0 is the thread itself, on its own track. other ids are tracks
+//! named like `fiber 3`, separate from the ids of `track`.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+
+// a suspended fiber's part of the thread state.
+pub(crate) struct Saved {
+    pub session: u64,
+    pub tid:     u32,
+    // the number of open scopes, and their depth for the debug checks.
+    pub open:    usize,
+    pub depth:   u32,
+}
+
+// suspended fibers, by id.
+static FIBERS: Mutex<Option<HashMap<u64, Saved>>> = Mutex::new(None);
+
+thread_local! {
+    // the thread itself, while it runs a fiber.
+    static THREAD: Cell<Option<Saved>> = const { Cell::new(None) };
+}
+
+
+/// switches the current thread from running fiber `from` to fiber `to`.
+/// `from` must be the fiber the thread ran, 0 for the thread itself.
+pub fn switch(from: u64, to: u64) {
+    if from == to || !crate::ENABLED {
+        return;
+    }
+
+    let session = crate::session();
+    let resumed = take(to).filter(|saved| saved.session == session);
+    let (saved, name) = match resumed {
+        Some(saved) => (saved, None),
+        None        => new(to, session),
+    };
+
+    if let Some(suspended) = crate::swap_fiber(saved, name.as_deref()) {
+        if name.is_some() {
+            crate::track::named(to, crate::track::Kind::Fiber, session);
+        }
+        put(from, suspended);
+    }
+}
+
+fn take(id: u64) -> Option<Saved> {
+    if id == 0 {
+        return THREAD.with(|t| t.take());
+    }
+    FIBERS.lock().unwrap().as_mut()?.remove(&id)
+}
+
+fn put(id: u64, saved: Saved) {
+    if id == 0 {
+        THREAD.with(|t| t.set(Some(saved)));
+        return;
+    }
+    FIBERS.lock().unwrap().get_or_insert_with(Default::default).insert(id, saved);
+}
+
+// a fiber without open scopes, and the name to record for its track.
+fn new(id: u64, session: u64) -> (Saved, Option<String>) {
+    let (tid, name) = match id {
+        0 => (crate::thread_tid(), None),
+        _ => {
+            let (tid, unnamed) = crate::track::resolve(id, crate::track::Kind::Fiber);
+            (tid, unnamed.map(|_| format!("fiber {}", id)))
+        }
+    };
+    return (Saved { session, tid, open: 0, depth: 0 }, name);
+}
@@ -0,0 +1,373 @@
+//! runtime scope name filtering.
+//!
+//! a filter is a comma separated list of glob patterns, like
+//! `SPALL_FILTER="render/*,!render/particles"`. `*` matches any sequence,
+//! `?` matches one character, and a leading `!` excludes matching names.
+//! the last matching pattern decides. names matching no pattern are
+//! recorded only if the filter has no including patterns.
+//!
+//! scopes can also have a `Level`, like
+//! `trace_scope!(level = Verbose, "particle")`, and only levels up to
+//! the threshold are recorded, see `set_level`. one set of scopes then
+//! serves both an overview of a whole session at `Coarse`, and every
+//! detail of a frame at `Verbose`. levels above `MAX_LEVEL` are
+//! compiled out.
+//!
+//! the macros' call sites know their `module_path!()`, their target.
+//! a `target=level` entry sets the threshold for a module and its
+//! submodules, like loggers, `off` records nothing from them:
+//! `SPALL_FILTER="my_app::render=verbose,sqlx=off"`. the longest
+//! matching target decides, others use the threshold of `set_level`.
+//! scopes without a call site, like from `trace_scope_impl`, have no
+//! target.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+use arc_swap::ArcSwapOption;
+
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    has_includes: bool,
+    targets: Vec<Target>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: String,
+    include: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Target {
+    path: String,
+    // `None` for `off`.
+    level: Option<Level>,
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Self {
+        let mut rules = Vec::new();
+        let mut targets = Vec::new();
+        for p in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            if let Some(target) = parse_target(p) {
+                targets.push(target);
+                continue;
+            }
+
+            rules.push(match p.strip_prefix('!') {
+                Some(p) => Rule { pattern: p.trim().to_string(), include: false },
+                None    => Rule { pattern: p.to_string(),        include: true  },
+            });
+        }
+
+        let has_includes = rules.iter().any(|r| r.include);
+        Self { rules, has_includes, targets }
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.rules.iter().rev()
+            .find(|r| glob_match(r.pattern.as_bytes(), name.as_bytes()))
+            .map(|r| r.include)
+            .unwrap_or(!self.has_includes)
+    }
+
+    /// the threshold of scopes from `target`,
+    /// `None` if they're off, `threshold` if no target matches.
+    pub fn target_level(&self, target: &str, threshold: Level) -> Option<Level> {
+        self.targets.iter()
+            .filter(|t| in_target(&t.path, target))
+            .max_by_key(|t| t.path.len())
+            .map(|t| t.level)
+            .unwrap_or(Some(threshold))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.targets.is_empty()
+    }
+}
+
+// `path=level`, entries with other values are name patterns.
+fn parse_target(entry: &str) -> Option<Target> {
+    let (path, level) = entry.split_once('=')?;
+    let level = match level.trim() {
+        "off" => None,
+        level => Some(Level::parse(level)?),
+    };
+    return Some(Target { path: path.trim().to_string(), level });
+}
+
+// whether `target` is the module `path` or one of its submodules.
+fn in_target(path: &str, target: &str) -> bool {
+    target.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    // greedy matching with backtracking to the last `*`.
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+
+            _ => {
+                let Some((sp, sn)) = star else { return false };
+                p = sp + 1;
+                n = sn + 1;
+                star = Some((sp, sn + 1));
+            }
+        }
+    }
+
+    return pattern[p..].iter().all(|c| *c == b'*');
+}
+
+
+
+static FILTER: ArcSwapOption<Filter> = ArcSwapOption::const_empty();
+// 0 while there is no filter. bumped on each change.
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(1);
+
+/// replaces the active filter. `None` or an empty spec records everything.
+pub fn set_filter(spec: Option<&str>) {
+    let filter = spec.map(Filter::parse).filter(|f| !f.is_empty());
+
+    let generation =
+        if filter.is_some() { NEXT_GENERATION.fetch_add(1, Ordering::Relaxed) }
+        else { 0 };
+
+    FILTER.store(filter.map(Arc::new));
+    GENERATION.store(generation, Ordering::Release);
+}
+
+/// whether a scope named `name` without a level passes the level
+/// threshold and the active filter.
+#[inline]
+pub fn allows(name: &str) -> bool {
+    allows_at(Level::Normal, name)
+}
+
+/// whether a scope with `level` named `name` passes the level
+/// threshold and the active filter.
+#[inline]
+pub fn allows_at(level: Level, name: &str) -> bool {
+    let allowed =
+        if GENERATION.load(Ordering::Relaxed) == 0 { level_enabled(level) }
+        else { allows_slow("", level, name) };
+    if !allowed {
+        crate::count_dropped(crate::Dropped::Filtered);
+    }
+    return allowed;
+}
+
+#[cold]
+fn allows_slow(target: &str, level: Level, name: &str) -> bool {
+    let filter = FILTER.load();
+    let Some(filter) = filter.as_ref() else { return level_enabled(level) };
+
+    let threshold = filter.target_level(target, self::level());
+    if !threshold.is_some_and(|threshold| level <= threshold && level <= MAX_LEVEL) {
+        return false;
+    }
+    return filter.allows(name);
+}
+
+
+
+
+// levels:
+
+/// how detailed a scope is.
+/// scopes without a level are `Normal`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum Level {
+    /// the outline of a session, like frames or requests.
+    Coarse = 1,
+    #[default]
+    Normal = 2,
+    /// details, like the scopes of every item processed.
+    Verbose = 3,
+}
+
+impl Level {
+    /// `coarse`, `normal` or `verbose`, in any case.
+    pub fn parse(level: &str) -> Option<Level> {
+        match level.trim().to_ascii_lowercase().as_str() {
+            "coarse"  => Some(Level::Coarse),
+            "normal"  => Some(Level::Normal),
+            "verbose" => Some(Level::Verbose),
+            _ => None,
+        }
+    }
+}
+
+/// the highest level compiled in, see the `max-level-*` and
+/// `release-max-level-*` features. scopes above it cost nothing,
+/// and `set_level` can't enable them.
+pub const MAX_LEVEL: Level = {
+    let release = !cfg!(debug_assertions);
+    if cfg!(feature = "max-level-coarse") || (release && cfg!(feature = "release-max-level-coarse")) {
+        Level::Coarse
+    }
+    else if cfg!(feature = "max-level-normal") || (release && cfg!(feature = "release-max-level-normal")) {
+        Level::Normal
+    }
+    else {
+        Level::Verbose
+    }
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Verbose as u8);
+
+/// records only scopes with levels up to `level`.
+/// the default, `Verbose`, records all of them.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+
+    // call sites cache decisions that depend on the level.
+    if GENERATION.load(Ordering::Relaxed) != 0 {
+        GENERATION.store(NEXT_GENERATION.fetch_add(1, Ordering::Relaxed), Ordering::Release);
+    }
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Coarse,
+        2 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+/// whether scopes with `level` are recorded.
+#[inline]
+pub fn level_enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL as u8 && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+
+
+/// per-call-site cache of the filter decision, used by the macros.
+///
+/// names may differ between calls of a call site, so only what depends
+/// on the level and the target is cached, and names are matched each
+/// time, except with `allows_static`.
+#[doc(hidden)]
+pub struct CallSite {
+    // generation << 3 | the bits below, 0 if unknown.
+    state:  AtomicU64,
+    // the `module_path!()`, or empty.
+    target: &'static str,
+}
+
+// the level passes the target's threshold.
+const PASSES: u64 = 1;
+// the filter has name patterns.
+const PATTERNS: u64 = 2;
+// the name passes them, for `allows_static`.
+const NAME_PASSES: u64 = 4;
+
+impl CallSite {
+    pub const fn new(target: &'static str) -> Self {
+        Self { state: AtomicU64::new(0), target }
+    }
+
+    pub fn target(&self) -> &'static str {
+        self.target
+    }
+
+    #[inline]
+    pub fn allows(&self, name: &str) -> bool {
+        self.allows_at(Level::Normal, name)
+    }
+
+    // `level` must be the same on each call.
+    #[inline]
+    pub fn allows_at(&self, level: Level, name: &str) -> bool {
+        self.check(level, |state| state & PATTERNS == 0 || allows_name(name), None)
+    }
+
+    // for call sites with one name, whose decision is cached whole.
+    // `level` and `name` must be the same on each call.
+    #[inline]
+    pub fn allows_static(&self, level: Level, name: &'static str) -> bool {
+        self.check(level, |state| state & PATTERNS == 0 || state & NAME_PASSES != 0, Some(name))
+    }
+
+    #[inline(always)]
+    fn check(&self, level: Level, name_passes: impl FnOnce(u64) -> bool, name: Option<&'static str>) -> bool {
+        if !crate::ENABLED || level > MAX_LEVEL {
+            return false;
+        }
+
+        let generation = GENERATION.load(Ordering::Relaxed);
+        let allowed =
+            if generation == 0 { level_enabled(level) }
+            else {
+                let state = self.cached(generation, level, name);
+                state & PASSES != 0 && name_passes(state)
+            };
+        if !allowed {
+            crate::count_dropped(crate::Dropped::Filtered);
+        }
+        return allowed;
+    }
+
+    #[inline]
+    fn cached(&self, generation: u32, level: Level, name: Option<&'static str>) -> u64 {
+        let state = self.state.load(Ordering::Relaxed);
+        if state >> 3 == generation as u64 {
+            return state;
+        }
+        self.update(generation, level, name)
+    }
+
+    #[cold]
+    fn update(&self, generation: u32, level: Level, name: Option<&'static str>) -> u64 {
+        let mut state = (generation as u64) << 3;
+
+        // may be newer than `generation`, which is then recomputed
+        // at the next call.
+        let filter = FILTER.load();
+        match filter.as_ref() {
+            Some(filter) => {
+                let threshold = filter.target_level(self.target, self::level());
+                if threshold.is_some_and(|threshold| level <= threshold) {
+                    state |= PASSES;
+                }
+                if !filter.rules.is_empty() {
+                    state |= PATTERNS;
+                }
+                if name.is_some_and(|name| filter.allows(name)) {
+                    state |= NAME_PASSES;
+                }
+            }
+
+            None => {
+                if level_enabled(level) {
+                    state |= PASSES;
+                }
+            }
+        }
+
+        self.state.store(state, Ordering::Relaxed);
+        return state;
+    }
+}
+
+// whether `name` passes the patterns of the active filter.
+fn allows_name(name: &str) -> bool {
+    FILTER.load().as_ref().is_none_or(|filter| filter.allows(name))
+}
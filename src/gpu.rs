@@ -0,0 +1,86 @@
+//! gpu timestamps.
+//!
+//! a `Queue` records intervals measured on the gpu, like with vulkan or
+//! wgpu timestamp queries, on a track of its own, so they line up with
+//! the cpu scopes. gpu ticks are converted with a `Calibration`, a gpu
+//! timestamp and a `now()` taken at the same time.
+//!
+//! ```no_run
+//! # let (gpu_now, period, (begin, end)) = (0, 1.0, (10, 20));
+//! let calibration = spall::gpu::Calibration { gpu: gpu_now, cpu: spall::now(), period_ns: period };
+//! let queue = spall::gpu::Queue::new(0, "gpu graphics", calibration);
+//! // once the queries are resolved:
+//! queue.interval("shadow pass", begin, end);
+//! ```
+//!
+//! each queue id gets its own tid, separate from the ids of `track`,
+//! and is named like a thread, when its first interval of a session
+//! is recorded. intervals are recorded by the calling thread, into its
+//! buffer, so a queue's intervals should be submitted by one thread,
+//! in order, and not overlap. gpu and cpu clocks drift apart, so long
+//! runs should `calibrate` again now and then.
+
+use crate::track::{self, Kind};
+
+
+/// a gpu timestamp and the `now()` at the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct Calibration {
+    /// a gpu timestamp, in ticks.
+    pub gpu: u64,
+    /// `now()` at the same time.
+    pub cpu: u64,
+    /// nanoseconds per gpu tick, like vulkan's `timestampPeriod`.
+    pub period_ns: f64,
+}
+
+/// a gpu queue's track, see the module docs.
+#[derive(Clone, Debug)]
+pub struct Queue {
+    id: u64,
+    name: String,
+    calibration: Calibration,
+}
+
+impl Queue {
+    /// the track of queue `id`, named `name`, like `gpu graphics`.
+    pub fn new(id: u64, name: &str, calibration: Calibration) -> Queue {
+        Queue { id, name: name.to_string(), calibration }
+    }
+
+    pub fn calibrate(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    /// the tid intervals on the queue are recorded with.
+    pub fn tid(&self) -> u32 {
+        track::resolve_tid(self.id, Kind::Gpu)
+    }
+
+    /// the `now()` at gpu timestamp `ticks`.
+    pub fn to_cpu(&self, ticks: u64) -> u64 {
+        let Calibration { gpu, cpu, period_ns } = self.calibration;
+        // signed, for timestamps before the calibration.
+        let ns = ticks.wrapping_sub(gpu) as i64 as f64 * period_ns;
+        let cpu = cpu as f64 + ns / 1000.0 / crate::timestamp_unit();
+        return cpu.max(0.0) as u64;
+    }
+
+    /// records a scope from gpu timestamp `begin` to `end`.
+    pub fn interval(&self, name: &str, begin: u64, end: u64) {
+        self.interval_args(name, begin, end, format_args!(""));
+    }
+
+    pub fn interval_args(&self, name: &str, begin: u64, end: u64, args: std::fmt::Arguments) {
+        if !crate::ENABLED {
+            return;
+        }
+
+        let (tid, unnamed) = track::resolve(self.id, Kind::Gpu);
+        let track = unnamed.and(Some(self.name.as_str()));
+        let recorded = crate::complete_on(tid, track, name, self.to_cpu(begin), self.to_cpu(end), args);
+        if let (Some(session), true) = (unnamed, recorded) {
+            track::named(self.id, Kind::Gpu, session);
+        }
+    }
+}
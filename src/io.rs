@@ -0,0 +1,180 @@
+//! instrumented i/o.
+//!
+//! `Traced` wraps a reader or writer and records each call as a scope,
+//! `io/read`, `io/write`, `io/flush`, `io/seek`, or `io/sync`,
+//! with args like `name=data.bin bytes=4096`, or `error=<kind>` on failure.
+//! `File` is a traced `std::fs::File` named after its path.
+//!
+//! calls on a `Traced` shouldn't record other scopes,
+//! as the scope is only written once the call returns.
+
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::now;
+
+
+pub struct Traced<T> {
+    name: String,
+    inner: T,
+}
+
+/// a `std::fs::File` that records its calls.
+pub type File = Traced<std::fs::File>;
+
+impl<T> Traced<T> {
+    pub fn new(name: impl Into<String>, inner: T) -> Self {
+        Self { name: name.into(), inner }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    #[inline]
+    fn finish<R>(&self, scope: &str, t0: u64, result: &io::Result<R>, bytes: impl FnOnce(&R) -> Option<u64>) {
+        match result {
+            Ok(r) => match bytes(r) {
+                Some(bytes) => crate::scope_since(scope, t0, format_args!("name={} bytes={}", self.name, bytes)),
+                None        => crate::scope_since(scope, t0, format_args!("name={}", self.name)),
+            },
+
+            Err(e) => crate::scope_since(scope, t0, format_args!("name={} error={:?}", self.name, e.kind())),
+        }
+    }
+}
+
+impl Traced<std::fs::File> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self::new(path.display().to_string(), std::fs::File::open(path)?))
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self::new(path.display().to_string(), std::fs::File::create(path)?))
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        let t0 = now();
+        let result = self.inner.sync_all();
+        self.finish("io/sync", t0, &result, |_| None);
+        return result;
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        let t0 = now();
+        let result = self.inner.sync_data();
+        self.finish("io/sync", t0, &result, |_| None);
+        return result;
+    }
+
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.inner.set_len(size)
+    }
+
+    pub fn metadata(&self) -> io::Result<std::fs::Metadata> {
+        self.inner.metadata()
+    }
+}
+
+
+impl<T: Read> Read for Traced<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.read(buf);
+        self.finish("io/read", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.read_vectored(bufs);
+        self.finish("io/read", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+
+    // the default impls would record each inner `read`.
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let t0 = now();
+        let result = self.inner.read_exact(buf);
+        self.finish("io/read", t0, &result, |_| Some(buf.len() as u64));
+        return result;
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.read_to_end(buf);
+        self.finish("io/read", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.read_to_string(buf);
+        self.finish("io/read", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+}
+
+impl<T: Write> Write for Traced<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.write(buf);
+        self.finish("io/write", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let t0 = now();
+        let result = self.inner.write_vectored(bufs);
+        self.finish("io/write", t0, &result, |n| Some(*n as u64));
+        return result;
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let t0 = now();
+        let result = self.inner.write_all(buf);
+        self.finish("io/write", t0, &result, |_| Some(buf.len() as u64));
+        return result;
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let t0 = now();
+        let result = self.inner.flush();
+        self.finish("io/flush", t0, &result, |_| None);
+        return result;
+    }
+}
+
+impl<T: Seek> Seek for Traced<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let t0 = now();
+        let result = self.inner.seek(pos);
+        self.finish("io/seek", t0, &result, |_| None);
+        return result;
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Traced<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Traced")
+            .field("name", &self.name)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
@@ -0,0 +1,143 @@
+// chrome trace event json, for `Format::ChromeJson` and `chrome`.
+//
+// threads buffer the binary events, as usual, and convert
+// them when flushing, so the hot path is the same for both formats.
+// files start with a `process_name` event, each event after that
+// is preceded by a comma, and the closing bracket comes when the file
+// is done. chrome also loads files without it, after a crash.
+
+use std::fmt::Write;
+use std::mem::size_of;
+
+use crate::{read_at, BeginEvent, CustomDataEvent, EndEvent, EventType, OverwriteTimestampEvent, PadSkipEvent};
+
+
+pub(crate) fn header(pid: u32) -> Vec<u8> {
+    let exe = std::env::current_exe().ok();
+    let name = exe.as_ref()
+        .and_then(|exe| exe.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "spall".to_string());
+
+    let mut out = String::new();
+    out.push_str("[{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":");
+    _ = write!(out, "{}", pid);
+    out.push_str(",\"args\":{\"name\":");
+    push_str(&mut out, &name);
+    out.push_str("}}");
+    return out.into_bytes();
+}
+
+pub(crate) const FOOTER: &[u8] = b"\n]\n";
+
+
+// appends the binary events in `bytes` to `out`, as json.
+// `unit` converts their timestamps to microseconds.
+pub(crate) fn transcode(bytes: &[u8], unit: f64, out: &mut Vec<u8>) {
+    let mut text = String::new();
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let ty = bytes[offset];
+
+        let size =
+            if ty == EventType::Begin as u8 {
+                let Some(event) = read_at::<BeginEvent>(bytes, offset) else { break };
+                let name = offset + size_of::<BeginEvent>();
+                let args = name + event.name_len as usize;
+                let end  = args + event.args_len as usize;
+                if end > bytes.len() { break }
+
+                text.push_str(",\n{\"name\":");
+                push_str(&mut text, &String::from_utf8_lossy(&bytes[name..args]));
+                text.push_str(",\"ph\":\"B\"");
+                push_ids(&mut text, event.pid, event.tid, event.when * unit);
+                push_args(&mut text, &String::from_utf8_lossy(&bytes[args..end]));
+                text.push('}');
+
+                end - offset
+            }
+            else if ty == EventType::End as u8 {
+                let Some(event) = read_at::<EndEvent>(bytes, offset) else { break };
+
+                text.push_str(",\n{\"ph\":\"E\"");
+                push_ids(&mut text, event.pid, event.tid, event.when * unit);
+                text.push('}');
+
+                size_of::<EndEvent>()
+            }
+            // metadata and other custom data have no place in the json.
+            else if ty == EventType::CustomData as u8 {
+                let Some(event) = read_at::<CustomDataEvent>(bytes, offset) else { break };
+                size_of::<CustomDataEvent>() + event.size as usize
+            }
+            else if ty == EventType::PadSkip as u8 {
+                let Some(event) = read_at::<PadSkipEvent>(bytes, offset) else { break };
+                size_of::<PadSkipEvent>() + event.size as usize
+            }
+            else if ty == EventType::OverwriteTimestamp as u8 {
+                size_of::<OverwriteTimestampEvent>()
+            }
+            // unknown, from `raw::write`. the rest can't be read.
+            else { break };
+
+        offset += size;
+    }
+
+    out.extend_from_slice(text.as_bytes());
+}
+
+pub(crate) fn push_ids(out: &mut String, pid: u32, tid: u32, ts: f64) {
+    _ = write!(out, ",\"pid\":{},\"tid\":{},\"ts\":{:.3}", pid, tid, ts);
+}
+
+// `k=v` args become fields, other words go into `text`.
+pub(crate) fn push_args(out: &mut String, args: &str) {
+    if args.is_empty() {
+        return;
+    }
+
+    out.push_str(",\"args\":{");
+    let mut text = String::new();
+    let mut first = true;
+    for (key, value) in crate::args::parse(args) {
+        if key.is_empty() {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(&value);
+            continue;
+        }
+
+        if !first {
+            out.push(',');
+        }
+        first = false;
+        push_str(out, key);
+        out.push(':');
+        push_str(out, &value);
+    }
+    if !text.is_empty() {
+        if !first {
+            out.push(',');
+        }
+        out.push_str("\"text\":");
+        push_str(out, &text);
+    }
+    out.push('}');
+}
+
+pub(crate) fn push_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -1,97 +1,1028 @@
-#[forbid(unsafe_op_in_unsafe_fn)]
+#![forbid(unsafe_op_in_unsafe_fn)]
+#![allow(clippy::needless_return)]
 
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
 use std::mem::size_of;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use arc_swap::ArcSwapOption;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+use crate::buffer::Buffer;
+
+pub mod reader;
+pub mod analysis;
+pub mod pprof;
+pub mod chrome;
+pub mod table;
+pub mod perf;
+pub mod raw;
+pub mod redact;
+pub mod args;
+pub mod build;
+pub mod checkpoint;
+pub mod clock_sync;
+pub mod cpu;
+pub mod fiber;
+pub mod filter;
+pub mod gpu;
+pub mod alloc;
+pub mod memory;
+pub mod metadata;
+pub mod name;
+pub mod sync;
+pub mod io;
+pub mod task;
+pub mod testing;
+pub mod thread;
+pub mod track;
+pub mod writer;
+
+mod buffer;
+mod json;
+mod queue;
+mod template;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+#[cfg(feature = "live")]
+pub mod live;
+
+#[cfg(unix)]
+pub mod signal;
+
+#[cfg(all(unix, feature = "profiler"))]
+pub mod profiler;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod counters;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod sched;
+
+#[cfg(feature = "config")]
+pub mod config;
+
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+
+#[cfg(all(windows, feature = "etw"))]
+pub mod etw;
+
+#[cfg(feature = "config")]
+pub use config::init_from_file;
+
+pub use alloc::TracingAllocator;
+pub use fiber::switch as fiber_switch;
+pub use name::ScopeName;
+pub use thread::spawn;
+pub use track::track;
+pub use writer::SpallWriter;
+
+
+/// configuration for `init_with`.
+///
+/// scopes that sampling, filtering or `min_duration` drop, and names
+/// and args that are truncated, are counted per thread, and recorded
+/// when it flushes, as a `spall/dropped` marker like `filtered=385 truncated=2`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// size of each thread's event buffer, in bytes.
+    /// threads can choose their own with `set_thread_buffer_size`.
+    /// the buffers of exited threads are kept for new threads, up to
+    /// 4 MiB of them, so threads that come and go don't allocate each.
+    pub buffer_size: usize,
+
+    /// what a thread does when its buffer fills faster than
+    /// the trace is written, see `Overflow`.
+    pub overflow: Overflow,
+
+    /// once the trace file reaches this many bytes, `rotate` to a new one.
+    /// the check happens when a thread flushes, so files can be larger
+    /// by about one `buffer_size`.
+    pub max_file_size: Option<u64>,
+
+    /// write each thread's events into its own file,
+    /// `trace.<tid>.spall` for a trace initialized as `trace.spall`.
+    /// threads then never share a file handle or interfere when flushing.
+    /// use `reader::merge` to combine the files.
+    pub per_thread_files: bool,
+
+    /// create the trace file at the first event instead of in `init`,
+    /// so runs that never record don't leave an empty file behind.
+    /// `init` only checks that the directory exists, errors creating
+    /// the file are reported on stderr.
+    /// the wall clock anchor is recorded when the file is created.
+    /// with `per_thread_files`, threads create their files at their
+    /// first event either way.
+    pub lazy_file: bool,
+
+    /// the fraction of scopes to record, chosen at random.
+    /// recorded scopes have `sample_rate=<rate>` at the start of their args.
+    /// see `trace_scope_sampled!` for per-call-site sampling.
+    pub sample_rate: f64,
+
+    /// only record scopes whose names pass this filter,
+    /// like `"render/*,!render/particles"`, or with levels per module,
+    /// like `"my_app::render=verbose,sqlx=off"`. see `filter` for the syntax.
+    /// the `SPALL_FILTER` environment variable takes precedence.
+    pub filter: Option<String>,
+
+    /// only record scopes with levels up to this, see `filter::Level`.
+    /// the `SPALL_LEVEL` environment variable takes precedence.
+    pub level: filter::Level,
+
+    /// drop scopes shorter than this.
+    /// only scopes without recorded children can be dropped,
+    /// and only while their begin event is still buffered.
+    pub min_duration: Option<std::time::Duration>,
+
+    /// record timestamps relative to `init`, instead of the timer's epoch,
+    /// which may be boot. timestamps are `f64`, so this keeps full
+    /// precision for longer, but traces from different processes
+    /// no longer line up.
+    pub rebase_timestamps: bool,
+
+    /// `request_flush` at this interval from a background thread,
+    /// so the file doesn't fall far behind, like when a viewer follows it.
+    /// threads flush at their next event, so a thread that stays quiet
+    /// keeps its buffer until it records again or exits.
+    pub flush_interval: Option<std::time::Duration>,
+
+    /// number threads 1, 2, 3, ... in the order they first record
+    /// or are spawned with `spawn`, instead of using the std thread id.
+    /// each thread starts with a `spall/tid` marker with its os tid,
+    /// like `os_tid=48213 name=main`.
+    pub sequential_tids: bool,
+
+    /// end each flush with a checkpoint, so `Trace::parse_lossy` can tell
+    /// which events were written completely after a crash.
+    /// see `checkpoint`. costs 37 bytes per flush.
+    pub checkpoints: bool,
+
+    /// record the cpu each scope began on, in its begin event's category,
+    /// to find migrations between cores. see `reader::Scope::category`.
+    /// cpus past 255 wrap around.
+    /// only on linux and windows, elsewhere the category stays 0.
+    pub record_cpu: bool,
+
+    /// record the process name, command line, working directory,
+    /// and hostname as `metadata`, see `metadata::set_process`.
+    pub process_metadata: bool,
+
+    /// record scope names and args as hashes, see `redact`.
+    /// metadata isn't redacted.
+    pub redact: bool,
+
+    /// with `redact`, append each name's hash to this dictionary file,
+    /// to `redact::restore` traces later.
+    pub redact_dictionary: Option<PathBuf>,
+
+    /// the format of the trace file.
+    pub format: Format,
+
+    /// write spall files with `O_DIRECT` on linux, bypassing the page cache,
+    /// so tracing i/o heavy code doesn't evict its data or add writeback.
+    /// each write is padded to whole 4 KiB blocks with a `PadSkip` event,
+    /// so files are larger if threads flush small buffers often.
+    /// falls back to buffered writes where the file system doesn't
+    /// support it, like tmpfs. ignored for chrome json and elsewhere.
+    pub direct_io: bool,
+
+    /// don't report errors on stderr.
+    /// they still go to the handler of `set_error_handler`.
+    pub silent: bool,
+
+    /// the time source, instead of the platform's timer, see `Clock`.
+    /// it can only be set once per process, as timestamps of different
+    /// clocks don't line up. later sessions keep it.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+/// the format `init_with` writes traces in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum Format {
+    /// spall's binary format.
+    #[default]
+    Spall,
+
+    /// chrome's trace event json, which `chrome://tracing` and perfetto
+    /// load as is. the files are much larger and flushing is slower,
+    /// and `reader` can't read them. metadata and other custom data
+    /// aren't recorded, and the timestamps use the timer's unit at
+    /// the time of the flush, where spall files get corrected later.
+    ChromeJson,
+}
+
+/// what a thread does when its buffer is full, but the trace isn't
+/// written fast enough, like to a slow disk or a network file system.
+///
+/// except with `Block`, full buffers are written on a background thread,
+/// with up to 16 writes queued, and threads only drop or grow while
+/// the queue is full. flushes other than of full buffers, like at exit
+/// or by `request_flush`, always wait for room in the queue.
+/// `flush` and `shutdown` wait for the queued writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum Overflow {
+    /// the thread writes its buffer itself, and waits for the write.
+    #[default]
+    Block,
+
+    /// drop the scopes that began and ended since the thread's last
+    /// flush, and keep recording. they're counted as `overflow=<n>`
+    /// in the `spall/dropped` marker. scopes still open, metadata
+    /// and spall's own markers are kept.
+    Drop,
+
+    /// double the buffer, up to `max_buffer_size` bytes, and keep
+    /// recording. past that, the thread waits for the queue.
+    /// the thread's next buffer has its usual size again.
+    Grow { max_buffer_size: usize },
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            buffer_size: 64*1024,
+            overflow: Overflow::Block,
+            max_file_size: None,
+            per_thread_files: false,
+            lazy_file: false,
+            sample_rate: 1.0,
+            filter: None,
+            level: filter::Level::Verbose,
+            min_duration: None,
+            rebase_timestamps: false,
+            flush_interval: None,
+            sequential_tids: false,
+            checkpoints: false,
+            record_cpu: false,
+            process_metadata: false,
+            redact: false,
+            redact_dictionary: None,
+            format: Format::Spall,
+            direct_io: false,
+            silent: false,
+            clock: None,
+        }
+    }
+}
+
+const MIN_BUFFER_SIZE: usize = 1024;
+
+
+/// whether recording is compiled in, see the `disable`, `release-disable`
+/// and `force-enable` features. when false, `init` does nothing and
+/// the macros compile to almost nothing.
+pub const ENABLED: bool =
+    cfg!(feature = "force-enable")
+    || !(cfg!(feature = "disable") || (cfg!(feature = "release-disable") && !cfg!(debug_assertions)));
 
 
-pub fn init(path: &str) -> Result<bool, std::io::Error> {
+pub fn init(path: impl AsRef<Path>) -> Result<bool, std::io::Error> {
+    init_with(path, Options::default())
+}
+
+/// starts recording into `path`, unless spall is already initialized.
+///
+/// `path` may contain placeholders:
+/// - `{pid}`, the process id.
+/// - `{exe}`, the executable's name, without extension.
+/// - `{date}`, the current time in utc, like `20261014-153012`.
+/// - `{hostname}`, the machine's hostname.
+/// - `{seq}`, the lowest number from `0001` not yet used by a trace.
+/// - `$`, the unix time in microseconds.
+///
+/// with `{seq}` or `$`, the file must not exist yet.
+/// `{{` and `}}` are literal braces, other placeholders are an error.
+pub fn init_with(path: impl AsRef<Path>, options: Options) -> Result<bool, std::io::Error> {
+    if !ENABLED {
+        return Ok(false);
+    }
+
     // init timer for non-specialized platforms.
     now();
+    CALIBRATION_ANCHOR.get_or_init(|| (now(), Instant::now()));
+
+    static EXIT_HANDLER: std::sync::Once = std::sync::Once::new();
+    EXIT_HANDLER.call_once(register_exit_handler);
 
-    let mut state = GLOBAL_STATE.write().unwrap();
-    if state.is_some() {
+    let _init = INIT_LOCK.lock().unwrap();
+    if GLOBAL_STATE.load().is_some() {
         return Ok(false);
     }
 
-    // init trace file.
-    let trace_path = {
-        use std::io::Write;
+    if let Some(clock) = &options.clock {
+        let current = CLOCK.get_or_init(|| clock.clone());
+        if !Arc::ptr_eq(current, clock) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "spall clock can only be set once per process"));
+        }
+        // calibrated for the platform's timer, if at all.
+        TIMESTAMP_UNIT.store(0, Ordering::Relaxed);
+    }
 
-        let (path, new) =
-            if path.contains("$") {
-                let time = {
-                    let time = std::time::SystemTime::now();
-                    let unix = time.duration_since(std::time::UNIX_EPOCH)
-                        .expect("system time can't be before unix epoch");
-                    unix.as_micros().to_string()
-                };
-                (path.replace("$", &time), true)
-            }
-            else { (path.to_string(), false) };
+    match CLOCK.get() {
+        Some(clock) => metadata::set("clock", clock.name()),
+        None        => metadata::set("clock", timer::source()),
+    }
+    if options.process_metadata {
+        metadata::set_process();
+    }
+
+    // init trace file.
+    let template = template::Template::parse(path.as_ref().as_os_str())?;
+    let new = template.new;
+    let mut seq = 1;
+    let (trace_path, file) = loop {
+        let path = template.fill(seq);
+        let path = path.as_path();
+        if template.has_seq() && path_taken(path, options.per_thread_files) {
+            seq += 1;
+            continue;
+        }
 
-        let mut f = std::fs::OpenOptions::new()
-            .create(!new)
-            .create_new(new)
-            .write(true)
-            .truncate(true)
-            .open(&path)?;
+        if options.per_thread_files || options.lazy_file {
+            // validate the path, the files are created on demand.
+            let dir = path.parent().filter(|p| !p.as_os_str().is_empty());
+            let dir = std::fs::canonicalize(dir.unwrap_or(Path::new(".")))?;
+            break (dir.join(path.file_name().unwrap_or_default()), None);
+        }
 
-        let hz = timer_frequency();
-        let micros = 1_000_000.0 / hz;
+        match TraceFile::create(path, new, options.format, options.direct_io) {
+            Ok((path, file)) => break (path, Some(file)),
 
-        let header = SpallHeader {
-            magic_header:   0x0BADF00D,
-            version:        1,
-            timestamp_unit: micros,
-            must_be_0:      0,
-        };
-        f.write(unsafe {
-            std::slice::from_raw_parts(
-                &header as *const _ as *const u8,
-                std::mem::size_of_val(&header))
-        })?;
+            // taken by another process since.
+            Err(e) if template.has_seq() && e.kind() == std::io::ErrorKind::AlreadyExists => seq += 1,
 
-        std::fs::canonicalize(path)?
+            Err(e) => return Err(e),
+        }
     };
+    let pending_file = (options.lazy_file && !options.per_thread_files).then(|| (trace_path.clone(), new));
 
-    let pid = std::process::id();
+    SEQUENTIAL_TIDS.store(options.sequential_tids, Ordering::Relaxed);
 
-    *state = Some(GlobalState {
-        trace_path,
-        buffer_size: 64*1024,
-        pid,
-        silent: false,
+    if options.redact {
+        redact::set_dictionary(options.redact_dictionary.as_deref())?;
+    }
+
+    match std::env::var("SPALL_FILTER") {
+        Ok(spec) => filter::set_filter(Some(&spec)),
+        Err(_)   => filter::set_filter(options.filter.as_deref()),
+    }
+
+    let level = std::env::var("SPALL_LEVEL").ok().and_then(|level| {
+        let parsed = filter::Level::parse(&level);
+        if parsed.is_none() {
+            report(options.silent, std::io::ErrorKind::InvalidInput,
+                format_args!("spall ignored unknown SPALL_LEVEL {:?}", level));
+        }
+        parsed
     });
+    filter::set_level(level.unwrap_or(options.level));
+
+    let session = SESSION.load(Ordering::Relaxed) + 1;
+    GLOBAL_STATE.store(Some(Arc::new(GlobalState {
+        session,
+        base_path: trace_path,
+        file: ArcSwapOption::new(file),
+        per_thread_files: options.per_thread_files,
+        lazy_file: options.lazy_file,
+        pending_file: Mutex::new(pending_file),
+        file_seq: Mutex::new(0),
+        buffer_size: options.buffer_size.max(MIN_BUFFER_SIZE),
+        overflow: options.overflow,
+        max_file_size: options.max_file_size,
+        sample_rate: options.sample_rate.clamp(0.0, 1.0),
+        min_duration: options.min_duration.map(|d| d.as_secs_f64() * 1e6).unwrap_or(0.0),
+        time_base: if options.rebase_timestamps { now() } else { 0 },
+        checkpoints: options.checkpoints,
+        record_cpu: options.record_cpu,
+        redact: options.redact,
+        format: options.format,
+        direct_io: options.direct_io,
+        silent: options.silent,
+        pid: AtomicU32::new(0),
+    })));
+    SESSION.store(session, Ordering::Release);
+
+    // flushes record more anchors, but the last one is never written.
+    // lazy files record it when they're created.
+    if !options.lazy_file {
+        let when = now();
+        let unix = unix_micros();
+        ThreadState::with(|s| s.complete("spall/wall_clock", when, when, format_args!("unix_us={}", unix)));
+    }
+
+    if let Some(interval) = options.flush_interval {
+        std::thread::Builder::new()
+            .name("spall/flush".into())
+            .spawn(move || {
+                while SESSION.load(Ordering::Acquire) == session {
+                    std::thread::sleep(interval);
+                    request_flush();
+                }
+            })?;
+    }
 
     return Ok(true);
 }
 
+/// ends the trace started by `init`, so `init` can start a new one,
+/// with a new path and options.
+///
+/// the calling thread flushes right away. other threads flush into the
+/// old file at their next event, which then goes into the new trace,
+/// or when they exit. the old file is terminated once all have.
+/// metadata, the filter, and pausing are reset too.
+/// scopes still open stay open in the old file.
+///
+/// returns false if spall isn't initialized.
+pub fn shutdown() -> bool {
+    let _init = INIT_LOCK.lock().unwrap();
+    let Some(global) = GLOBAL_STATE.swap(None) else { return false };
+
+    // threads finish the session at their next event.
+    SESSION.fetch_add(1, Ordering::Release);
+    FLUSH_EPOCH.fetch_add(1, Ordering::Release);
+    ThreadState::with_existing(|this| drop(this.take()));
+    queue::sync();
+
+    PAUSED.store(false, Ordering::Relaxed);
+    ROTATE_PENDING.store(false, Ordering::Relaxed);
+    metadata::clear();
+    filter::set_filter(None);
+    filter::set_level(filter::Level::Verbose);
+    _ = redact::set_dictionary(None);
+
+    drop(global);
+    return true;
+}
+
+/// `init_with` for libraries, which never fails or creates the file
+/// right away, like with `Options::lazy_file`. errors go to the handler
+/// of `set_error_handler`, or to stderr.
+pub fn init_lazy(path: impl AsRef<Path>, options: Options) {
+    let silent = options.silent;
+    if let Err(e) = init_with(path, Options { lazy_file: true, ..options }) {
+        report(silent, e.kind(), format_args!("spall init failed {:?}", e));
+    }
+}
+
+/// closes the current trace file and continues in a new one.
+///
+/// for a trace initialized as `trace.spall`, the files are named
+/// `trace.0001.spall`, `trace.0002.spall`, and so on.
+/// threads switch to the new file at their next event,
+/// after flushing their buffers into the old one.
+/// once all threads have switched, the old file is terminated
+/// with a `StreamOver` event.
+///
+/// with `Options::per_thread_files`, each thread numbers its files
+/// independently, and `None` is returned.
+/// with `Options::lazy_file`, nothing happens until the file was created.
+///
+/// returns the new path, or `None` if spall isn't initialized.
+pub fn rotate() -> Result<Option<PathBuf>, std::io::Error> {
+    rotate_from(None)
+}
+
+// rotates, unless `generation` is given and no longer current.
+fn rotate_from(generation: Option<u64>) -> Result<Option<PathBuf>, std::io::Error> {
+    let Some(global) = GLOBAL_STATE.load_full() else { return Ok(None) };
+
+    let mut file_seq = global.file_seq.lock().unwrap();
+    let current = GENERATION.load(Ordering::Acquire);
+    if generation.is_some_and(|g| g != current) {
+        return Ok(None);
+    }
+    if !global.per_thread_files && global.file.load().is_none() {
+        return Ok(None);
+    }
+
+    let mut result = None;
+    if global.file.load().is_some() {
+        let seq = *file_seq + 1;
+        let (path, file) = TraceFile::create(&rotated_path(&global.base_path, seq), false, global.format, global.direct_io)?;
+
+        // publish the file before the generation,
+        // threads read them in the opposite order.
+        global.file.store(Some(file));
+        *file_seq = seq;
+        result = Some(path);
+    }
+
+    GENERATION.store(current + 1, Ordering::Release);
+    FLUSH_EPOCH.fetch_add(1, Ordering::Release);
+
+    return Ok(result);
+}
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// stops recording on all threads, to leave out noisy phases
+/// like startup or shutdown. threads stop at their next event.
+///
+/// scopes that are already open still record their end,
+/// so the trace stays balanced. the calling thread records
+/// `spall/pause` and `spall/resume` markers.
+/// metadata and `raw` events are still recorded.
+pub fn pause() {
+    if PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    marker("spall/pause", format_args!(""));
+    PAUSED.store(true, Ordering::Relaxed);
+}
+
+/// continues recording after `pause`.
+pub fn resume() {
+    if PAUSED.swap(false, Ordering::Relaxed) {
+        marker("spall/resume", format_args!(""));
+    }
+}
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// stops recording on the current thread while held, like `pause`,
+/// so maintenance threads can call traced code without filling the trace.
+///
+/// ```no_run
+/// let _quiet = spall::quiet();
+/// ```
+#[must_use]
+pub fn quiet() -> Quiet {
+    QUIET.with(|q| q.set(q.get() + 1));
+    Quiet { _thread: std::marker::PhantomData }
+}
+
+/// see `quiet`. guards nest.
+pub struct Quiet {
+    // released on the thread that acquired it.
+    _thread: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for Quiet {
+    fn drop(&mut self) {
+        _ = QUIET.try_with(|q| q.set(q.get() - 1));
+    }
+}
+
+/// records the session's events under this pid instead of the process
+/// id, to group processes in the viewer. threads switch at their next
+/// flush, so their buffers have one pid each. 0 switches back to the
+/// process id, as does the next session.
+pub fn set_pid(pid: u32) {
+    if let Some(global) = GLOBAL_STATE.load().as_ref() {
+        global.pid.store(pid, Ordering::Relaxed);
+    }
+}
+
+/// records the events of `f` on the current thread under this pid,
+/// instead of the one from `set_pid` or the process id.
+pub fn with_pid<R>(pid: u32, f: impl FnOnce() -> R) -> R {
+    let prev = ThreadState::with(|s| {
+        let prev = s.thread_pid.replace(pid);
+        s.pid = pid;
+        prev
+    });
+
+    struct Restore(Option<Option<u32>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let Some(prev) = self.0 else { return };
+            ThreadState::with(|s| {
+                s.thread_pid = prev;
+                s.pid = prev.unwrap_or_else(|| s.global.pid());
+            });
+        }
+    }
+    let _restore = Restore(prev);
+
+    f()
+}
+
+/// asks all threads to flush their buffers at their next event.
+///
+/// only touches atomics, so this is safe to call from signal handlers.
+#[inline]
+pub fn request_flush() {
+    FLUSH_EPOCH.fetch_add(1, Ordering::Release);
+}
+
+/// asks for a `rotate` at the next event of any thread.
+///
+/// only touches atomics, so this is safe to call from signal handlers.
+#[inline]
+pub fn request_rotate() {
+    ROTATE_PENDING.store(true, Ordering::Release);
+    FLUSH_EPOCH.fetch_add(1, Ordering::Release);
+}
+
+/// writes the current thread's buffered events to the trace file,
+/// and waits for the writes queued by `Options::overflow`.
+pub fn flush() {
+    ThreadState::with_existing(|this| {
+        if let Some(this) = this {
+            this.flush();
+        }
+    });
+    queue::sync();
+}
+
+/// sets the size of the current thread's event buffer, in bytes,
+/// instead of `Options::buffer_size`, like a larger one for a thread
+/// with many events, or a smaller one for a thread with few.
+/// applies right away if the thread already records, flushing it
+/// if the buffer shrinks, and in later sessions too.
+/// 0 switches back to `Options::buffer_size`.
+pub fn set_thread_buffer_size(size: usize) {
+    _ = THREAD_BUFFER_SIZE.try_with(|s| s.set(size));
+    ThreadState::with_existing(|this| {
+        if let Some(this) = this {
+            this.resize_buffer();
+        }
+    });
+}
+
+/// records a `spall/panic` marker on panicking threads, with args like
+/// `message="index out of bounds" location="src/main.rs:12:5"`,
+/// and flushes the thread, then calls the previous hook.
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info.payload_as_str().unwrap_or("");
+        match info.location() {
+            Some(location) => marker("spall/panic", trace_args!({ message = message, location = location })),
+            None           => marker("spall/panic", trace_args!({ message = message })),
+        }
+        flush();
+
+        previous(info);
+    }));
+}
+
+type ErrorHandler = Arc<dyn Fn(&std::io::Error) + Send + Sync>;
+
+static ERROR_HANDLER: Mutex<Option<ErrorHandler>> = Mutex::new(None);
+
+/// handles errors while recording, like failed writes, which are
+/// otherwise reported on stderr. the error's message says what failed.
+/// events recorded by the handler are dropped.
+pub fn set_error_handler(handler: impl Fn(&std::io::Error) + Send + Sync + 'static) {
+    *ERROR_HANDLER.lock().unwrap() = Some(Arc::new(handler));
+}
+
+#[cold]
+pub(crate) fn report(silent: bool, kind: std::io::ErrorKind, message: std::fmt::Arguments) {
+    let handler = ERROR_HANDLER.lock().unwrap().clone();
+    match handler {
+        Some(handler) => handler(&std::io::Error::new(kind, message.to_string())),
+        None if !silent => eprintln!("{}", message),
+        None => (),
+    }
+}
+
+// thread-local destructors don't reliably run for the main thread,
+// so the thread calling `exit` (usually main) is flushed explicitly.
+// other threads must finish (or call `flush`) before the process exits.
+fn register_exit_handler() {
+    extern "C" fn on_exit() {
+        _ = std::panic::catch_unwind(|| {
+            // dropping the state flushes it.
+            // the thread won't trace again, as this session was tried.
+            ThreadState::with_existing(|this| drop(this.take()));
+            queue::sync();
+
+            let files = OPEN_FILES.lock().unwrap().iter()
+                .filter_map(Weak::upgrade)
+                .collect::<Vec<_>>();
+            for file in files {
+                file.write_final_unit();
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    unsafe { libc::atexit(on_exit); }
+
+    #[cfg(windows)]
+    unsafe {
+        extern "C" {
+            fn atexit(f: extern "C" fn()) -> std::ffi::c_int;
+        }
+        atexit(on_exit);
+    }
+}
+
+fn rotated_path(base: &Path, seq: u64) -> PathBuf {
+    let stem = base.file_stem().unwrap_or_default();
+
+    let mut name = stem.to_os_string();
+    name.push(format!(".{:04}", seq));
+    if let Some(ext) = base.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    return base.with_file_name(name);
+}
+
+// whether a trace already uses `path`, for `{seq}`.
+fn path_taken(path: &Path, per_thread_files: bool) -> bool {
+    if path.exists() {
+        return true;
+    }
+    if !per_thread_files {
+        return false;
+    }
+
+    // any `<stem>.<tid>.<ext>` next to it.
+    let stem = path.file_stem().unwrap_or_default().as_encoded_bytes();
+    let ext = path.extension().map(|ext| ext.as_encoded_bytes()).unwrap_or_default();
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let Ok(entries) = std::fs::read_dir(dir) else { return false };
+    return entries.filter_map(Result::ok).any(|entry| {
+        let name = entry.file_name();
+        let rest = name.as_encoded_bytes().strip_prefix(stem).and_then(|rest| rest.strip_prefix(b"."));
+        let tid = match ext.is_empty() {
+            true  => rest,
+            false => rest.and_then(|rest| rest.strip_suffix(ext)).and_then(|rest| rest.strip_suffix(b".")),
+        };
+        tid.is_some_and(|tid| !tid.is_empty() && tid.iter().all(u8::is_ascii_digit))
+    });
+}
+
+fn thread_path(base: &Path, tid: u32) -> PathBuf {
+    let stem = base.file_stem().unwrap_or_default();
+
+    let mut name = stem.to_os_string();
+    name.push(format!(".{}", tid));
+    if let Some(ext) = base.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    return base.with_file_name(name);
+}
+
 
 
+/// records a scope until the end of the enclosing block.
+///
+/// the name is a `&str`, or an owned name like a `String` or `Cow<str>`.
+/// args are a format string, or key-value pairs encoded as `k=v`,
+/// see `args` for the encoding.
+/// a leading `level = <Level>` sets the scope's `filter::Level`.
+/// when spall is disabled, the name and args aren't evaluated.
+///
+/// ```no_run
+/// # let (path, bytes, stage, id) = ("a.png", 1024, 2, 7);
+/// spall::trace_scope!("frame");
+/// spall::trace_scope!(format!("stage {}", stage));
+/// spall::trace_scope!("load", "{} ({} bytes)", path, bytes);
+/// spall::trace_scope!("load", { path = path, bytes = bytes });
+/// spall::trace_scope!(level = Verbose, "particle", "id={}", id);
+/// ```
 #[macro_export]
 macro_rules! trace_scope {
+    (level = $level:ident, $name:expr $(, $($args:tt)+)?) => {
+        let _trace_scope = if $crate::ENABLED && $crate::filter::Level::$level <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            Some($crate::trace_scope_site_impl(&SITE, $crate::filter::Level::$level, $name,
+                $crate::trace_scope!(@args $($($args)+)?)))
+        } else { None };
+    };
+
     ($name:expr) => {
-        let _trace_scope = $crate::trace_scope_impl($name);
+        let _trace_scope = if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            Some($crate::trace_scope_site_impl(&SITE, $crate::filter::Level::Normal, $name, None))
+        } else { None };
     };
 
     ($name:expr, $($args:tt)+) => {
-        let _trace_scope = $crate::trace_scope_args_impl($name, format_args!($($args)+));
+        let _trace_scope = if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            Some($crate::trace_scope_site_impl(&SITE, $crate::filter::Level::Normal, $name, Some($crate::trace_args!($($args)+))))
+        } else { None };
+    };
+
+    (@args) => { None };
+    (@args $($args:tt)+) => { Some($crate::trace_args!($($args)+)) };
+}
+
+/// like `trace_scope!`, but only records the scope if the condition holds.
+/// the condition is a `bool` or a closure returning one.
+/// when it's false, the name and args aren't evaluated,
+/// nor is the condition when spall is disabled.
+///
+/// ```no_run
+/// # let items = [1, 2, 3];
+/// for (i, item) in items.iter().enumerate() {
+///     spall::trace_scope_if!(i == 2, "item", "{}", item);
+///     spall::trace_scope_if!(|| *item > 1, "big item");
+/// }
+/// ```
+#[macro_export]
+macro_rules! trace_scope_if {
+    ($cond:expr, $name:expr) => {
+        let _trace_scope = {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL && $crate::TraceCondition::eval($cond) {
+                Some($crate::trace_scope_site_impl(&SITE, $crate::filter::Level::Normal, $name, None))
+            }
+            else { None }
+        };
+    };
+
+    ($cond:expr, $name:expr, $($args:tt)+) => {
+        let _trace_scope = {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL && $crate::TraceCondition::eval($cond) {
+                Some($crate::trace_scope_site_impl(&SITE, $crate::filter::Level::Normal, $name, Some($crate::trace_args!($($args)+))))
+            }
+            else { None }
+        };
+    };
+}
+
+/// records a scope around an expression and returns its value.
+///
+/// ```no_run
+/// # fn parse(_: &str) -> u32 { 0 }
+/// # let cfg = "";
+/// let x = spall::trace_expr!("parse config", parse(cfg));
+/// ```
+#[macro_export]
+macro_rules! trace_expr {
+    ($name:expr, $e:expr) => {{
+        $crate::trace_scope!($name);
+        $e
+    }};
+}
+
+/// wraps a future, recording a scope around each of its polls
+/// on the polling thread. see `task` for tracing tasks on their own tracks.
+///
+/// ```no_run
+/// # async fn get(_: &str) {}
+/// # async fn run() {
+/// spall::trace_async!("fetch", async {
+///     get("a").await;
+///     get("b").await;
+/// }).await;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_async {
+    ($name:expr, $future:expr) => {
+        $crate::task::Traced::on_thread($name, $future)
+    };
+}
+
+/// records a message as an instant named `log`, with the message as
+/// its args, like a cheap log on the timeline. messages are truncated
+/// to 255 bytes. a leading `level = <Level>` sets its `filter::Level`.
+///
+/// ```no_run
+/// # let (key, n) = ("a.png", 3);
+/// spall::trace_log!("cache miss for {key}");
+/// spall::trace_log!(level = Verbose, "retry {}", n);
+/// ```
+#[macro_export]
+macro_rules! trace_log {
+    (level = $level:ident, $($args:tt)+) => {
+        if $crate::ENABLED && $crate::filter::Level::$level <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            $crate::trace_log_impl(&SITE, $crate::filter::Level::$level, format_args!($($args)+));
+        }
+    };
+
+    ($($args:tt)+) => {
+        if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            $crate::trace_log_impl(&SITE, $crate::filter::Level::Normal, format_args!($($args)+));
+        }
     };
 }
 
+#[doc(hidden)]
+#[inline]
+pub fn trace_log_impl(site: &filter::CallSite, level: filter::Level, message: std::fmt::Arguments) {
+    if site.allows_static(level, "log") {
+        marker("log", message);
+    }
+}
+
+#[doc(hidden)]
+pub trait TraceCondition {
+    fn eval(self) -> bool;
+}
+
+impl TraceCondition for bool {
+    #[inline(always)]
+    fn eval(self) -> bool { self }
+}
+
+impl<F: FnOnce() -> bool> TraceCondition for F {
+    #[inline(always)]
+    fn eval(self) -> bool { self() }
+}
+
+
+
+/// a time source for `Options::clock`, like virtual time in a simulator,
+/// a ptp disciplined clock, or a counter spall doesn't know about.
+///
+/// `now` is called for every event, so it should be cheap.
+/// it must not go backwards on a thread.
+pub trait Clock: Send + Sync {
+    /// the current time, in ticks.
+    fn now(&self) -> u64;
+
+    /// ticks per second.
+    fn frequency(&self) -> f64;
+
+    /// recorded as the `clock` metadata.
+    fn name(&self) -> &str {
+        "custom"
+    }
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Clock").field(&self.name()).finish()
+    }
+}
 
+static CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
 
 #[inline(always)]
 pub fn now() -> u64 {
-    timer::now()
+    match CLOCK.get() {
+        Some(clock) => clock.now(),
+        None        => timer::now(),
+    }
 }
 
 /// in Hz
 #[inline(always)]
 pub fn timer_frequency() -> f64 {
-    timer::timer_frequency()
+    match CLOCK.get() {
+        Some(clock) => clock.frequency(),
+        None        => timer::timer_frequency(),
+    }
+}
+
+// a clock's own frequency is taken as exact.
+#[inline]
+fn needs_calibration() -> bool {
+    CLOCK.get().is_none() && timer::needs_calibration()
+}
+
+/// microseconds per `now()` tick.
+///
+/// starts out as `1/timer_frequency()`. for hardware counters with an
+/// imprecise nominal frequency, this is refined against the os clock
+/// as the run goes on, and traces receive `OverwriteTimestamp` events
+/// with the improved value, and a final one when the file is done
+/// or the process exits.
+#[inline]
+pub fn timestamp_unit() -> f64 {
+    match TIMESTAMP_UNIT.load(Ordering::Relaxed) {
+        0    => 1_000_000.0 / timer_frequency(),
+        bits => f64::from_bits(bits),
+    }
+}
+
+// f64 bits, 0 until first calibrated.
+static TIMESTAMP_UNIT: AtomicU64 = AtomicU64::new(0);
+static CALIBRATION_ANCHOR: OnceLock<(u64, Instant)> = OnceLock::new();
+
+// refines `TIMESTAMP_UNIT` using the time since `CALIBRATION_ANCHOR`.
+#[cold]
+fn calibrate() {
+    if !needs_calibration() {
+        return;
+    }
+
+    let Some((t0, i0)) = CALIBRATION_ANCHOR.get() else { return };
+    let elapsed = i0.elapsed();
+    let ticks = now().wrapping_sub(*t0);
+    if elapsed < std::time::Duration::from_millis(100) || ticks == 0 {
+        return;
+    }
+
+    let unit = elapsed.as_secs_f64() * 1_000_000.0 / ticks as f64;
+    TIMESTAMP_UNIT.store(unit.to_bits(), Ordering::Relaxed);
 }
 
 
@@ -99,6 +1030,11 @@ pub fn timer_frequency() -> f64 {
 
 // data structures:
 
+// the events derive zerocopy's traits, which check at compile time that
+// they have no padding and any bytes are valid, so they're cast from and
+// to bytes without `unsafe` layout assumptions.
+
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct SpallHeader {
     pub magic_header:   u64, // = 0x0BADF00D
@@ -107,6 +1043,13 @@ pub struct SpallHeader {
     pub must_be_0:      u64, // = 0
 }
 
+impl SpallHeader {
+    #[inline]
+    pub(crate) fn new(timestamp_unit: f64) -> Self {
+        Self { magic_header: 0x0BADF00D, version: 1, timestamp_unit, must_be_0: 0 }
+    }
+}
+
 pub enum EventType {
     Invalid            = 0,
     CustomData         = 1, // Basic readers can skip this.
@@ -120,6 +1063,7 @@ pub enum EventType {
     PadSkip            = 7,
 }
 
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct BeginEvent {
     pub ty:       u8, // = SpallEventType_Begin
@@ -133,6 +1077,7 @@ pub struct BeginEvent {
     pub args_len: u8,
 }
 
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct BeginEventMax {
     pub event: BeginEvent,
@@ -140,6 +1085,7 @@ pub struct BeginEventMax {
     pub args: [u8; 255],
 }
 
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct EndEvent {
     pub ty:   u8, // = SpallEventType_End
@@ -148,309 +1094,2164 @@ pub struct EndEvent {
     pub when: f64,
 }
 
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[repr(C, packed)]
+pub struct OverwriteTimestampEvent {
+    pub ty:             u8, // = SpallEventType_Overwrite_Timestamp
+    pub timestamp_unit: f64,
+}
+
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct PadSkipEvent {
     pub ty:   u8, // = SpallEventType_Pad_Skip
-    pub size: u32,
+    pub size: u32, // bytes of padding after this event.
+}
+
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[repr(C, packed)]
+pub struct CustomDataEvent {
+    pub ty:   u8, // = SpallEventType_Custom_Data
+    pub size: u32, // bytes of payload after this event.
+}
+
+
+
+// appends the raw bytes of an event struct.
+#[inline]
+pub(crate) fn push_as_bytes<T: IntoBytes + Immutable>(buffer: &mut Vec<u8>, v: T) {
+    buffer.extend_from_slice(v.as_bytes());
+}
+
+// the event struct at `offset`, if `data` is long enough.
+#[inline]
+pub(crate) fn read_at<T: FromBytes>(data: &[u8], offset: usize) -> Option<T> {
+    T::read_from_prefix(data.get(offset..)?).ok().map(|(v, _)| v)
 }
 
+// like `push_as_bytes`, for `raw::write`.
+// `ptr` must have room for a `T`.
+#[inline(always)]
+unsafe fn write_to_ptr<T: IntoBytes + Immutable>(ptr: *mut u8, v: T) { unsafe {
+    std::ptr::copy_nonoverlapping(v.as_bytes().as_ptr(), ptr, size_of::<T>());
+}}
+
 
 
-static GLOBAL_STATE: RwLock<Option<GlobalState>> = RwLock::new(None);
+// replaced as a whole by `init`, read without locking.
+static GLOBAL_STATE: ArcSwapOption<GlobalState> = ArcSwapOption::const_empty();
+static INIT_LOCK: Mutex<()> = Mutex::new(());
+static SEQUENTIAL_TIDS: AtomicBool = AtomicBool::new(false);
+// bumped by `init`, so threads without state know when to retry,
+// and by `shutdown`, so threads with state let go of it.
+static SESSION: AtomicU64 = AtomicU64::new(0);
+
+// bumped to make threads flush at their next event.
+static FLUSH_EPOCH: AtomicU32 = AtomicU32::new(0);
+static ROTATE_PENDING: AtomicBool = AtomicBool::new(false);
+// bumped when the trace file changes.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
 
 struct GlobalState {
-    trace_path: std::path::PathBuf,
+    // the `SESSION` this state belongs to.
+    session: u64,
+    base_path: PathBuf,
+    // the current shared file, `None` with per-thread files,
+    // or until it's created with `lazy_file`. changes on rotation.
+    file: ArcSwapOption<TraceFile>,
+    per_thread_files: bool,
+    lazy_file: bool,
+    // with `lazy_file`, the path of the shared file and whether it must
+    // be new, until it's created. `None` afterwards, also if that failed.
+    pending_file: Mutex<Option<(PathBuf, bool)>>,
+    // also serializes rotations.
+    file_seq: Mutex<u64>,
     buffer_size: usize,
-    pid: u32,
+    overflow: Overflow,
+    max_file_size: Option<u64>,
+    sample_rate: f64,
+    // in microseconds, 0 if disabled.
+    min_duration: f64,
+    // subtracted from timestamps.
+    time_base: u64,
+    checkpoints: bool,
+    record_cpu: bool,
+    redact: bool,
+    format: Format,
+    direct_io: bool,
     silent: bool,
+    // set by `set_pid`, 0 for the process id.
+    pid: AtomicU32,
 }
 
+// the room past a buffer's limit, for what `flush` adds.
+const BUFFER_EXTRA: usize = size_of::<OverwriteTimestampEvent>() + checkpoint::EVENT_LEN;
 
-struct ThreadState {
-    pid: u32,
-    tid: u32,
-    file: File,
-    buffer: *mut u8,
-    buffer_size: usize,
-    write_ptr: *mut u8,
-    write_rem: usize,
-    silent: bool,
+// the buffer size for this thread, see `set_thread_buffer_size`.
+fn thread_buffer_size(global: &GlobalState) -> usize {
+    match THREAD_BUFFER_SIZE.try_with(Cell::get).unwrap_or(0) {
+        0    => global.buffer_size,
+        size => size.max(MIN_BUFFER_SIZE),
+    }
 }
 
-impl ThreadState {
-    #[inline]
-    fn with(f: impl FnOnce(&mut ThreadState)) {
-        thread_local! {
-            static THIS: UnsafeCell<Option<ThreadState>> = UnsafeCell::new(ThreadState::init());
-        }
+// whether recording on this thread would create a file of `lazy_file`.
+pub(crate) fn file_pending() -> bool {
+    let global = GLOBAL_STATE.load();
+    let Some(global) = global.as_ref().filter(|global| global.lazy_file) else { return false };
+    if global.per_thread_files {
+        let mut has_state = false;
+        ThreadState::with_existing(|this| has_state = this.is_some());
+        return !has_state;
+    }
+    return global.pending_file.lock().unwrap().is_some();
+}
 
-        THIS.with(|this| {
-            if let Some(this) = unsafe { &mut *this.get() } {
-                f(this);
-            }
-        })
+impl GlobalState {
+    fn pid(&self) -> u32 {
+        match self.pid.load(Ordering::Relaxed) {
+            0   => std::process::id(),
+            pid => pid,
+        }
     }
 
+    // the shared file of `lazy_file`, created by the first thread
+    // to get here, and whether this thread created it.
     #[cold]
-    fn init() -> Option<Self> {
-        let global = GLOBAL_STATE.read().ok()?;
-        let global = global.as_ref()?;
+    fn create_pending_file(&self) -> Option<(Arc<TraceFile>, bool)> {
+        let mut pending = self.pending_file.lock().unwrap();
+        if let Some(file) = self.file.load_full() {
+            return Some((file, false));
+        }
 
-        let file = match
-            std::fs::OpenOptions::new()
-                .append(true)
-                .open(&global.trace_path)
-        {
-            Ok(f) => f,
+        let (path, new) = pending.take()?;
+        match TraceFile::create(&path, new, self.format, self.direct_io) {
+            Ok((_, file)) => {
+                self.file.store(Some(file.clone()));
+                Some((file, true))
+            }
 
             Err(e) => {
-                if !global.silent {
-                    eprintln!("spall thread init failed to open file {:?} with error {:?}",
-                        global.trace_path, e);
-                }
-                return None;
+                report(self.silent, e.kind(), format_args!("spall failed to create file {:?} with error {:?}", path, e));
+                None
             }
-        };
+        }
+    }
+}
 
-        let buffer_size = global.buffer_size;
-        let buffer = unsafe {
-            let ptr = std::alloc::alloc(
-                std::alloc::Layout::from_size_align(buffer_size, 1).unwrap());
 
-            if ptr.is_null() {
-                if !global.silent {
-                    eprintln!("spall thread init failed allocate buffer");
-                }
-                return None;
-            }
+// a trace file, shared by all threads writing into it.
+// whoever lets go of it last terminates the stream.
+struct TraceFile {
+    file: File,
+    size: AtomicU64,
+    // ids of `ScopeName`s up to this are in the file's dictionary,
+    // or in a thread's buffer on the way there.
+    names: AtomicU32,
+    format: Format,
+    // opened with `O_DIRECT`, all writes are whole blocks.
+    direct: bool,
+}
 
-            ptr
+impl TraceFile {
+    fn create(path: &Path, new: bool, format: Format, direct: bool) -> Result<(PathBuf, Arc<Self>), std::io::Error> {
+        let open = |direct: bool| {
+            let mut options = std::fs::OpenOptions::new();
+            options.create(!new).create_new(new).append(true);
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if direct {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.custom_flags(libc::O_DIRECT);
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let _ = direct;
+            options.open(path)
         };
 
-        let tid = unsafe {
-            let tid = std::thread::current().id();
-            std::mem::transmute::<_, u64>(tid) as u32
+        let direct = direct && format == Format::Spall && cfg!(any(target_os = "linux", target_os = "android"));
+        let (f, direct) = match open(direct) {
+            // unsupported by the file system.
+            Err(e) if direct && e.kind() == std::io::ErrorKind::InvalidInput => (open(false)?, false),
+            f => (f?, direct),
         };
+        f.set_len(0)?;
+
+        let mut head = Vec::new();
+        let mut names = 0;
+        match format {
+            Format::Spall => {
+                push_as_bytes(&mut head, SpallHeader::new(timestamp_unit()));
+                head.extend_from_slice(&metadata::events());
+                names = name::count();
+                head.extend_from_slice(&name::events(1, names));
+            }
 
-        Some(Self {
-            pid: global.pid,
-            tid,
-            file,
-            buffer,
-            buffer_size,
-            write_ptr: buffer,
-            write_rem: buffer_size,
-            silent: global.silent,
-        })
+            Format::ChromeJson =>
+                head = json::header(std::process::id()),
+        }
+        let size = write_file(&f, direct, &head)?;
+
+        let path = std::fs::canonicalize(path)?;
+        let file = Arc::new(Self {
+            file: f,
+            size: AtomicU64::new(size as u64),
+            names: AtomicU32::new(names),
+            format,
+            direct,
+        });
+
+        let mut files = OPEN_FILES.lock().unwrap();
+        files.retain(|file| file.strong_count() > 0);
+        files.push(Arc::downgrade(&file));
+
+        return Ok((path, file));
+    }
+
+    // appends the current unit, calibrated over the whole run,
+    // so long traces aren't skewed by an earlier estimate.
+    fn write_final_unit(&self) {
+        if self.format != Format::Spall || !needs_calibration() {
+            return;
+        }
+
+        calibrate();
+        let mut event = Vec::with_capacity(size_of::<OverwriteTimestampEvent>());
+        push_as_bytes(&mut event, OverwriteTimestampEvent {
+            ty: EventType::OverwriteTimestamp as u8,
+            timestamp_unit: timestamp_unit(),
+        });
+        _ = write_file(&self.file, self.direct, &event);
+    }
+}
+
+impl Drop for TraceFile {
+    fn drop(&mut self) {
+        self.write_final_unit();
+        let end: &[u8] = match self.format {
+            Format::Spall      => &[EventType::StreamOver as u8],
+            Format::ChromeJson => json::FOOTER,
+        };
+        _ = write_file(&self.file, self.direct, end);
+    }
+}
+
+// for `write_final_unit` at exit, as the files in use are never dropped.
+static OPEN_FILES: Mutex<Vec<Weak<TraceFile>>> = Mutex::new(Vec::new());
+
+
+// returns the number of bytes written, with padding.
+fn write_file(file: &File, direct: bool, bytes: &[u8]) -> Result<usize, std::io::Error> {
+    use std::io::Write;
+
+    if !direct {
+        (&*file).write_all(bytes)?;
+        return Ok(bytes.len());
+    }
+
+    let Some(mut buffer) = Buffer::new(bytes.len(), 0, true) else {
+        return Err(std::io::ErrorKind::OutOfMemory.into());
+    };
+    buffer.push(bytes);
+    let len = buffer.pad_blocks();
+    (&*file).write_all(buffer.head(len))?;
+    return Ok(len);
+}
+
+
+thread_local! {
+    static THREAD_STATE: UnsafeCell<Option<ThreadState>> = const { UnsafeCell::new(None) };
+    // the last `SESSION` init was attempted for.
+    static SESSION_TRIED: Cell<u64> = const { Cell::new(0) };
+    // set while the state is borrowed. events recorded from within,
+    // like by a tracing allocator or a traced `Display` impl, are dropped.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+    // `Quiet` guards held by the thread.
+    static QUIET: Cell<u32> = const { Cell::new(0) };
+    // set by `set_thread_buffer_size`, 0 if not.
+    static THREAD_BUFFER_SIZE: Cell<usize> = const { Cell::new(0) };
+}
+
+// whether the thread state is borrowed.
+#[inline]
+pub(crate) fn busy() -> bool {
+    BUSY.try_with(|b| b.get()).unwrap_or(true)
+}
+
+struct BusyGuard;
+
+impl BusyGuard {
+    #[inline(always)]
+    fn acquire() -> Option<Self> {
+        if BUSY.replace(true) {
+            return None;
+        }
+        Some(BusyGuard)
+    }
+}
+
+impl Drop for BusyGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        BUSY.set(false);
+    }
+}
+
+struct ThreadState {
+    pid: u32,
+    // set by `with_pid`.
+    thread_pid: Option<u32>,
+    tid: u32,
+    global: Arc<GlobalState>,
+    file: Arc<TraceFile>,
+    per_thread_file: bool,
+    file_seq: u64,
+    // last unit written into `file`.
+    timestamp_unit: f64,
+    generation: u64,
+    flush_epoch: u32,
+    buffer: Buffer,
+    // the buffer's limit after a flush.
+    buffer_size: usize,
+    max_file_size: Option<u64>,
+    sample_rate: f64,
+    min_duration: f64,
+    time_base: u64,
+    // the last checkpoint's sequence number, if enabled.
+    checkpoint: Option<u64>,
+    record_cpu: bool,
+    // offsets of the begin events of open scopes, if min_duration
+    // is enabled. `None` once the event was flushed.
+    open_scopes: Vec<Option<usize>>,
+    #[cfg(debug_assertions)]
+    depth: u32,
+    // flushed events, for json files.
+    json: Vec<u8>,
+    // set up once the buffer first fills, so short-lived threads
+    // don't set one up. `None` until then, or if setting it up failed.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    ring: Option<uring::Ring>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    ring_tried: bool,
+    // for `spall/thread_exit`, the thread may be gone by then.
+    thread_name: Option<String>,
+    redact: bool,
+    silent: bool,
+}
+
+impl ThreadState {
+    #[inline]
+    fn with<R>(f: impl FnOnce(&mut ThreadState) -> R) -> Option<R> {
+        if !ENABLED {
+            return None;
+        }
+
+        // the state is gone during thread exit.
+        THREAD_STATE.try_with(|this| {
+            let _busy = BusyGuard::acquire()?;
+            let this = unsafe { &mut *this.get() };
+
+            if this.as_ref().is_some_and(ThreadState::session_ended) {
+                // dropping the state flushes it into the old file.
+                *this = None;
+            }
+
+            if this.is_none() {
+                let session = SESSION.load(Ordering::Acquire);
+                if session == SESSION_TRIED.get() {
+                    return None;
+                }
+                SESSION_TRIED.set(session);
+                *this = ThreadState::init();
+            }
+
+            let this = this.as_mut()?;
+            if this.flush_epoch != FLUSH_EPOCH.load(Ordering::Relaxed) {
+                this.handle_requests();
+            }
+            Some(f(this))
+        }).ok().flatten()
+    }
+
+    // after `shutdown`, which also requests a flush.
+    #[inline(always)]
+    fn session_ended(&self) -> bool {
+        self.flush_epoch != FLUSH_EPOCH.load(Ordering::Relaxed)
+            && self.global.session != SESSION.load(Ordering::Acquire)
+    }
+
+    // `with`, unless recording is paused or the thread is quiet.
+    // open scopes still end with `with`.
+    #[inline]
+    fn record<R>(f: impl FnOnce(&mut ThreadState) -> R) -> Option<R> {
+        if PAUSED.load(Ordering::Relaxed) || QUIET.try_with(|q| q.get() != 0).unwrap_or(true) {
+            return None;
+        }
+        Self::with(f)
+    }
+
+    // only if the thread already has a state.
+    #[inline]
+    fn with_existing(f: impl FnOnce(&mut Option<ThreadState>)) {
+        _ = THREAD_STATE.try_with(|this| {
+            let Some(_busy) = BusyGuard::acquire() else { return };
+            f(unsafe { &mut *this.get() })
+        });
+    }
+
+    #[cold]
+    fn init() -> Option<Self> {
+        let global = GLOBAL_STATE.load_full()?;
+
+        let flush_epoch = FLUSH_EPOCH.load(Ordering::Acquire);
+        let generation  = GENERATION.load(Ordering::Acquire);
+
+        // with room for a checkpoint when full.
+        let buffer_size = thread_buffer_size(&global);
+        let Some(buffer) = Buffer::new(buffer_size, BUFFER_EXTRA, global.direct_io) else {
+            report(global.silent, std::io::ErrorKind::OutOfMemory, format_args!("spall thread init failed allocate buffer"));
+            return None;
+        };
+
+        let thread = std::thread::current();
+        let tid = thread_tid();
+
+        let per_thread_file = global.per_thread_files;
+        let mut created = per_thread_file && global.lazy_file;
+        let file = match global.file.load_full() {
+            Some(file) => file,
+
+            None if !per_thread_file => {
+                let (file, new) = global.create_pending_file()?;
+                created = new;
+                file
+            }
+
+            None => {
+                let path = thread_path(&global.base_path, tid);
+                match TraceFile::create(&path, false, global.format, global.direct_io) {
+                    Ok((_, file)) => file,
+
+                    Err(e) => {
+                        report(global.silent, e.kind(), format_args!("spall thread init failed to create file {:?} with error {:?}", path, e));
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let mut state = Self {
+            pid: global.pid(),
+            thread_pid: None,
+            tid,
+            file,
+            per_thread_file,
+            file_seq: 0,
+            timestamp_unit: timestamp_unit(),
+            generation,
+            flush_epoch,
+            max_file_size: global.max_file_size,
+            sample_rate: global.sample_rate,
+            min_duration: global.min_duration,
+            time_base: global.time_base,
+            checkpoint: global.checkpoints.then_some(0),
+            record_cpu: global.record_cpu,
+            open_scopes: Vec::new(),
+            #[cfg(debug_assertions)]
+            depth: 0,
+            json: Vec::new(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring: None,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring_tried: false,
+            buffer,
+            buffer_size,
+            thread_name: thread.name().map(str::to_string),
+            redact: global.redact,
+            silent: global.silent,
+            global,
+        };
+
+        let name = state.thread_name.take();
+        let when = now();
+        if SEQUENTIAL_TIDS.load(Ordering::Relaxed) {
+            let name = name.as_deref().unwrap_or("");
+            match os_tid() {
+                Some(os_tid) => state.complete("spall/tid", when, when, trace_args!({ os_tid = os_tid, name = name })),
+                None         => state.complete("spall/tid", when, when, trace_args!({ name = name })),
+            }
+        }
+        state.lifecycle_marker("spall/thread_start", when, name.as_deref());
+        state.thread_name = name;
+
+        if created {
+            let unix = unix_micros();
+            state.complete("spall/wall_clock", when, when, format_args!("unix_us={}", unix));
+        }
+
+        Some(state)
+    }
+
+    #[cold]
+    fn handle_requests(&mut self) {
+        self.pid = self.thread_pid.unwrap_or_else(|| self.global.pid());
+
+        if ROTATE_PENDING.swap(false, Ordering::AcqRel) {
+            if let Err(e) = rotate() {
+                report(self.silent, e.kind(), format_args!("spall rotate failed {:?}", e));
+            }
+        }
+
+        self.flush_epoch = FLUSH_EPOCH.load(Ordering::Acquire);
+        self.flush();
+
+        if self.generation != GENERATION.load(Ordering::Acquire) {
+            self.reopen();
+        }
+    }
+
+    #[cold]
+    fn reopen(&mut self) {
+        let global = &self.global;
+        self.generation = GENERATION.load(Ordering::Acquire);
+
+        // checkpoints are numbered in each file.
+        if let Some(seq) = &mut self.checkpoint {
+            *seq = 0;
+        }
+
+        if let Some(file) = global.file.load_full() {
+            self.file = file;
+            // force an overwrite, the file's header may be older.
+            self.timestamp_unit = f64::NAN;
+            return;
+        }
+
+        let seq  = self.file_seq + 1;
+        let path = thread_path(&rotated_path(&global.base_path, seq), self.tid);
+        match TraceFile::create(&path, false, global.format, global.direct_io) {
+            Ok((_, file)) => {
+                self.file = file;
+                self.file_seq = seq;
+                self.timestamp_unit = timestamp_unit();
+            }
+
+            Err(e) => {
+                report(self.silent, e.kind(), format_args!("spall failed to create file {:?} with error {:?}", path, e));
+            }
+        }
     }
 
     #[inline(always)]
     fn reserve(&mut self, size: usize) {
-        if size > self.write_rem {
-            self.flush();
+        if size > self.buffer.remaining() {
+            self.flush_full(size);
+        }
+        debug_assert!(self.buffer.remaining() >= size);
+    }
+
+    // makes room for `size` bytes.
+    #[cold]
+    fn flush_full(&mut self, size: usize) {
+        // while earlier writes are still queued, see `Overflow`.
+        if self.global.overflow != Overflow::Block && queue::is_full() {
+            match self.global.overflow {
+                Overflow::Block => (),
+
+                Overflow::Drop => {
+                    self.drop_scopes();
+                    if self.buffer.remaining() >= size {
+                        return;
+                    }
+                }
+
+                Overflow::Grow { max_buffer_size } => {
+                    if self.buffer.grow(size, max_buffer_size) {
+                        return;
+                    }
+                }
+            }
+        }
+
+        self.flush();
+
+        // the writer thread writes the buffers otherwise.
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if !self.ring_tried && self.global.overflow == Overflow::Block {
+            self.ring_tried = true;
+            self.ring = match uring::Ring::new(&self.buffer, self.silent) {
+                Ok(ring) => Some(ring),
+
+                Err(e) => {
+                    report(self.silent, e.kind(), format_args!("spall io_uring setup failed, flushing with write {:?}", e));
+                    None
+                }
+            };
         }
-        debug_assert!(self.write_rem >= size);
     }
 
     #[inline(always)]
-    unsafe fn push_bytes(&mut self, bytes: &[u8]) { unsafe {
-        let len = bytes.len();
-        debug_assert!(self.write_rem >= len);
-        std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.write_ptr, len);
-        self.write_ptr = self.write_ptr.add(len);
-        self.write_rem -= len;
-    }}
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.push(bytes);
+    }
 
     #[inline(always)]
-    unsafe fn push_as_bytes<T>(&mut self, v: T) { unsafe {
-        let len = size_of::<T>();
-        debug_assert!(self.write_rem >= len);
-        self.write_ptr.cast::<T>().write_unaligned(v);
-        self.write_ptr = self.write_ptr.add(len);
-        self.write_rem -= len;
-    }}
+    fn push_as_bytes<T: IntoBytes + Immutable>(&mut self, v: T) {
+        self.buffer.push(v.as_bytes());
+    }
+
+    // for `raw::write`. false if `size` doesn't fit in the buffer.
+    #[inline]
+    unsafe fn push_raw(&mut self, size: usize, f: impl FnOnce(*mut u8)) -> bool {
+        if size > self.buffer.remaining() {
+            self.flush();
+            if size > self.buffer.remaining() {
+                return false;
+            }
+        }
+
+        f(self.buffer.spare_mut()[..size].as_mut_ptr());
+        self.buffer.commit(size);
+        return true;
+    }
 
     #[inline]
     fn push_args(&mut self, max_len: usize, args: std::fmt::Arguments) -> usize {
         use std::fmt::Write;
 
-        struct Writer {
-            ptr: *mut u8,
-            rem: usize,
+        struct Writer<'a> {
+            out: &'a mut [u8],
+            len: usize,
+            truncated: bool,
         }
 
-        impl std::fmt::Write for Writer {
+        impl std::fmt::Write for Writer<'_> {
             #[inline]
-            fn write_str(&mut self, s: &str) -> std::fmt::Result { unsafe {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                let (s, truncated) = truncate(s, self.out.len() - self.len);
                 let bytes = s.as_bytes();
 
-                let len = bytes.len().min(self.rem);
-                std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr, len);
-                self.ptr = self.ptr.add(len);
-                self.rem -= len;
+                self.out[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+                self.len += bytes.len();
+                #[cfg(feature = "serde")]
+                args::set_room(self.out.len() - self.len);
 
+                if truncated {
+                    // stops formatting.
+                    self.truncated = true;
+                    return Err(std::fmt::Error);
+                }
                 Ok(())
-            }}
+            }
         }
 
+        let limit = self.buffer.remaining().min(max_len);
         let mut writer = Writer {
-            ptr: self.write_ptr,
-            rem: self.write_rem.min(max_len),
+            out: &mut self.buffer.spare_mut()[..limit],
+            len: 0,
+            truncated: false,
         };
+        #[cfg(feature = "serde")]
+        args::set_room(limit);
         _ = writer.write_fmt(args);
+        #[cfg(feature = "serde")]
+        args::set_room(usize::MAX);
+
+        let mut len = writer.len;
+        if writer.truncated && limit >= args::TRUNCATION_MARK.len() {
+            // make room for the mark, on a char boundary.
+            let written = &mut writer.out[..];
+            let mut end = len.min(limit - args::TRUNCATION_MARK.len());
+            // past `len` are stale bytes, and `len` is a boundary.
+            while end < len && written[end] & 0xc0 == 0x80 {
+                end -= 1;
+            }
+
+            let mark = args::TRUNCATION_MARK.as_bytes();
+            written[end..end + mark.len()].copy_from_slice(mark);
+            len = end + mark.len();
+        }
 
-        let len = writer.ptr as usize - self.write_ptr as usize;
-        self.write_ptr = writer.ptr;
-        self.write_rem -= len;
+        if writer.truncated {
+            count_dropped(Dropped::Truncated);
+        }
 
+        self.buffer.commit(len);
         return len;
     }
 
+    #[inline(always)]
+    fn push_name(&mut self, name: Name) {
+        self.push_bytes(name.bytes);
+        if name.truncated {
+            self.push_bytes(args::TRUNCATION_MARK.as_bytes());
+            count_dropped(Dropped::Truncated);
+        }
+    }
+
     #[inline]
-    unsafe fn push_begin_event(&mut self, when: u64, name_len: u8, args_len: u8) -> *mut u8 { unsafe {
-        let ptr = self.write_ptr;
+    // returns the event's offset.
+    fn push_begin_event(&mut self, when: u64, name_len: u8, args_len: u8) -> usize {
+        let offset = self.buffer.len();
         self.push_as_bytes(BeginEvent {
             ty: EventType::Begin as u8,
-            category: 0,
+            category: if self.record_cpu { current_cpu() } else { 0 },
             pid: self.pid,
             tid: self.tid,
-            when: when as f64,
+            when: when.saturating_sub(self.time_base) as f64,
             name_len,
             args_len,
         });
-        return ptr;
-    }}
+        return offset;
+    }
 
     #[inline]
-    unsafe fn patch_begin_args_len(&mut self, begin: *mut u8, args_len: u8) {
+    fn patch_begin_args_len(&mut self, begin: usize, args_len: u8) {
         let offset = std::mem::offset_of!(BeginEvent, args_len);
-        unsafe { begin.add(offset).write(args_len) }
+        self.buffer.as_mut_slice()[begin + offset] = args_len;
     }
 
     #[inline]
-    unsafe fn push_end_event(&mut self, when: u64) { unsafe {
+    fn push_end_event(&mut self, when: u64) {
         self.push_as_bytes(EndEvent {
             ty: EventType::End as u8,
             pid: self.pid,
             tid: self.tid,
-            when: when as f64,
+            when: when.saturating_sub(self.time_base) as f64,
         });
-    }}
+    }
 
-    #[cold]
-    fn flush(&mut self) {
-        use std::io::Write;
+    #[inline]
+    fn begin(&mut self, name: &str) {
+        let mut hashed = [0; redact::HASH_LEN];
+        let name = recorded_name(self.redact, name, &mut hashed);
+        self.reserve(size_of::<BeginEvent>() + name.len());
+
+        let begin = self.push_begin_event(now(), name.len() as u8, 0);
+        self.push_name(name);
+        self.etw_begin(begin, None);
+        self.push_open_scope(begin);
+        self.debug_begin();
+    }
 
-        let t0 = now();
+    #[inline]
+    fn begin_args(&mut self, name: &str, args: std::fmt::Arguments) {
+        let mut hashed = [0; redact::HASH_LEN];
+        let recorded = recorded_name(self.redact, name, &mut hashed);
+        self.reserve(size_of::<BeginEvent>() + recorded.len() + 255);
+
+        let begin = self.push_begin_event(now(), recorded.len() as u8, 0);
+        self.push_name(recorded);
+
+        let args_len = self.push_args(255, args);
+        let args_len = self.recorded_args_len(name, args_len);
+        self.patch_begin_args_len(begin, args_len as u8);
+        self.etw_begin(begin, None);
+        self.push_open_scope(begin);
+        self.debug_begin();
+    }
 
-        let len = self.write_ptr as usize - self.buffer as usize;
-        let res = self.file.write(unsafe { core::slice::from_raw_parts(self.buffer, len) });
-        if let Err(e) = res {
-            if !self.silent {
-                eprintln!("spall file write failed {:?}", e);
-            }
+    // a scope named by an id.
+    #[inline]
+    fn begin_id(&mut self, name: &ScopeName, args: Option<std::fmt::Arguments>) {
+        let id = name.id();
+        if id > self.file.names.load(Ordering::Relaxed) {
+            self.push_names(id);
         }
 
-        self.write_ptr = self.buffer;
-        self.write_rem = self.buffer_size;
+        let mut recorded = [0; name::ID_LEN];
+        recorded[1..].copy_from_slice(&id.to_le_bytes());
+        self.reserve(size_of::<BeginEvent>() + name::ID_LEN + 255);
 
-        unsafe {
-            let name = "spall/flush";
-            self.push_begin_event(t0, name.len() as u8, 0);
-            self.push_bytes(name.as_bytes());
+        let begin = self.push_begin_event(now(), name::ID_LEN as u8, 0);
+        self.push_bytes(&recorded);
 
-            let t1 = now();
-            self.push_end_event(t1);
+        if let Some(args) = args {
+            let args_len = self.push_args(255, args);
+            self.patch_begin_args_len(begin, args_len as u8);
         }
+        self.etw_begin(begin, Some(name.name()));
+        self.push_open_scope(begin);
+        self.debug_begin();
     }
-}
 
-impl Drop for ThreadState {
-    fn drop(&mut self) {
-        self.flush();
+    // adds the names up to `id` to the file's dictionary.
+    #[cold]
+    fn push_names(&mut self, id: u32) {
+        let first = self.file.names.fetch_max(id, Ordering::Relaxed) + 1;
+        for id in first..=id {
+            let events = name::events(id, id);
+            self.reserve(events.len());
+            self.push_bytes(&events);
+        }
     }
-}
 
+    // mirrors the begin event at `begin`, with `etw`.
+    // `name` replaces an id.
+    #[inline(always)]
+    fn etw_begin(&self, begin: usize, name: Option<&str>) {
+        #[cfg(all(windows, feature = "etw"))]
+        {
+            let bytes = self.buffer.as_slice();
+            let Some(event) = read_at::<BeginEvent>(bytes, begin) else { return };
+            let recorded = begin + size_of::<BeginEvent>();
+            let args = recorded + event.name_len as usize;
+            let name = match name {
+                Some(name) => name.as_bytes(),
+                None       => &bytes[recorded..args],
+            };
+            etw::begin(name, &bytes[args..args + event.args_len as usize]);
+        }
 
+        #[cfg(not(all(windows, feature = "etw")))]
+        let _ = (begin, name);
+    }
 
-pub struct TraceScope;
+    #[inline(always)]
+    fn etw_end(&self) {
+        #[cfg(all(windows, feature = "etw"))]
+        etw::end();
+    }
 
-impl Drop for TraceScope {
+    // replaces the args just written with their hash, when redacting.
+    #[inline(always)]
+    fn recorded_args_len(&mut self, name: &str, args_len: usize) -> usize {
+        if !self.redact || args_len == 0 || redact::keeps(name) {
+            return args_len;
+        }
+        self.redact_args(args_len)
+    }
+
+    // there is room for 255 bytes of args.
+    #[cold]
+    fn redact_args(&mut self, args_len: usize) -> usize {
+        let args = self.buffer.len() - args_len;
+        let mut hashed = [0; redact::HASH_LEN];
+        redact::hash_into(&self.buffer.as_slice()[args..], &mut hashed);
+
+        self.buffer.truncate(args);
+        self.push_bytes(&hashed);
+        return redact::HASH_LEN;
+    }
+
+    #[inline(always)]
+    fn push_open_scope(&mut self, begin: usize) {
+        if self.min_duration > 0.0 {
+            self.open_scopes.push(Some(begin));
+        }
+    }
+
+    // a scope from `t0` to `t1`, written at once.
+    // min_duration doesn't apply.
     #[inline]
-    fn drop(&mut self) {
-        ThreadState::with(|s| unsafe {
-            s.reserve(size_of::<EndEvent>());
-            s.push_end_event(now());
-        });
+    fn complete(&mut self, name: &str, t0: u64, t1: u64, args: std::fmt::Arguments) {
+        let mut hashed = [0; redact::HASH_LEN];
+        let recorded = recorded_name(self.redact, name, &mut hashed);
+        self.reserve(size_of::<BeginEvent>() + recorded.len() + 255 + size_of::<EndEvent>());
+
+        let begin = self.push_begin_event(t0, recorded.len() as u8, 0);
+        self.push_name(recorded);
+
+        let args_len = self.push_args(255, args);
+        let args_len = self.recorded_args_len(name, args_len);
+        self.patch_begin_args_len(begin, args_len as u8);
+        self.push_end_event(t1);
+
+        self.etw_begin(begin, None);
+        self.etw_end();
     }
-}
 
-#[inline]
-pub fn trace_scope_impl(name: &str) -> TraceScope {
-    ThreadState::with(|s| unsafe {
-        let name_len = name.len().min(255);
-        s.reserve(size_of::<BeginEvent>() + name_len);
+    // `spall/dropped`, if events were dropped since the last one.
+    fn dropped_marker(&mut self, when: u64) {
+        let Some(counts) = take_dropped() else { return };
+        self.complete("spall/dropped", when, when, format_args!("{}", counts));
+    }
 
-        s.push_begin_event(now(), name_len as u8, 0);
-        s.push_bytes(&name.as_bytes()[..name_len]);
-    });
-    TraceScope
-}
+    // `spall/thread_start` and `spall/thread_exit`.
+    fn lifecycle_marker(&mut self, name: &str, when: u64, thread: Option<&str>) {
+        match thread {
+            Some(thread) => self.complete(name, when, when, trace_args!({ name = thread })),
+            None         => self.complete(name, when, when, format_args!("")),
+        }
+    }
 
-#[inline]
-pub fn trace_scope_args_impl(name: &str, args: std::fmt::Arguments) -> TraceScope {
-    ThreadState::with(|s| unsafe {
-        let name_len = name.len().min(255);
-        s.reserve(size_of::<BeginEvent>() + name_len + 255);
+    // a scope, maybe sampled, returns whether it was recorded.
+    #[inline]
+    fn begin_in(&mut self, name: &str, args: Option<std::fmt::Arguments>) -> bool {
+        if self.sample_rate < 1.0 {
+            return self.begin_sampled(name, args);
+        }
+        match args {
+            Some(args) => self.begin_args(name, args),
+            None       => self.begin(name),
+        }
+        return true;
+    }
 
-        let begin = s.push_begin_event(now(), name_len as u8, 0);
-        s.push_bytes(&name.as_bytes()[..name_len]);
+    // global sampling, returns whether the scope was recorded.
+    #[cold]
+    fn begin_sampled(&mut self, name: &str, args: Option<std::fmt::Arguments>) -> bool {
+        let rate = self.sample_rate;
+        if !sample_probability(rate) {
+            count_dropped(Dropped::Sampled);
+            return false;
+        }
 
-        let args_len = s.push_args(255, args);
-        s.patch_begin_args_len(begin, args_len as u8);
-    });
-    TraceScope
-}
+        match args {
+            Some(args) => self.begin_args(name, format_args!("sample_rate={} {}", rate, args)),
+            None       => self.begin_args(name, format_args!("sample_rate={}", rate)),
+        }
+        return true;
+    }
+
+    #[inline]
+    fn end(&mut self) {
+        if !self.debug_end() {
+            return;
+        }
 
+        self.etw_end();
 
+        let when = now();
+        if self.min_duration > 0.0 && self.drop_short_scope(when) {
+            count_dropped(Dropped::Short);
+            return;
+        }
 
-#[cfg(target_arch = "aarch64")]
-mod timer {
+        self.reserve(size_of::<EndEvent>());
+        self.push_end_event(when);
+    }
+
+    // debug builds check that begins and ends are balanced.
     #[inline(always)]
-    pub fn now() -> u64 {
-        let tsc: u64;
-        unsafe {
-            std::arch::asm!(
-                "mrs {tsc}, cntvct_el0",
-                tsc = out(reg) tsc,
-            );
+    fn debug_begin(&mut self) {
+        #[cfg(debug_assertions)] {
+            self.depth += 1;
         }
-        tsc
     }
 
+    // false for an end without a begin, which isn't recorded.
     #[inline(always)]
-    pub fn timer_frequency() -> f64 {
-        let freq: u64;
-        unsafe {
-            std::arch::asm!(
-                "mrs {freq}, cntfrq_el0",
-                freq = out(reg) freq,
-            );
+    fn debug_end(&mut self) -> bool {
+        #[cfg(debug_assertions)] {
+            if self.depth == 0 {
+                self.unbalanced(format_args!("end without an open scope"));
+                return false;
+            }
+            self.depth -= 1;
         }
-        freq as f64
+        return true;
     }
-}
 
-#[cfg(not(target_arch = "aarch64"))]
-mod timer {
-    use std::sync::OnceLock;
-    use std::time::Instant;
+    // reports on stderr and as a `spall/unbalanced` marker.
+    #[cfg(debug_assertions)]
+    #[cold]
+    fn unbalanced(&mut self, what: std::fmt::Arguments) {
+        report(self.silent, std::io::ErrorKind::Other, format_args!("spall unbalanced scopes on thread {}: {}", self.tid, what));
+        let when = now();
+        self.complete("spall/unbalanced", when, when, what);
+    }
 
-    static T0: OnceLock<Instant> = OnceLock::new();
+    // removes the innermost scope from the buffer
+    // if it ends at `when`, is too short, and has no events after its begin.
+    #[inline]
+    fn drop_short_scope(&mut self, when: u64) -> bool {
+        let Some(Some(begin)) = self.open_scopes.pop() else { return false };
+        let Some(event) = read_at::<BeginEvent>(self.buffer.as_slice(), begin) else { return false };
+        let size = size_of::<BeginEvent>() + event.name_len as usize + event.args_len as usize;
+        if begin + size != self.buffer.len() {
+            return false;
+        }
+
+        let when = when.saturating_sub(self.time_base) as f64;
+        let duration = (when - event.when) * timestamp_unit();
+        if duration >= self.min_duration {
+            return false;
+        }
+
+        self.buffer.truncate(begin);
+        return true;
+    }
+
+    #[cold]
+    fn flush(&mut self) {
+        use std::io::Write;
+
+        let t0 = now();
+        let unix_t0 = unix_micros();
+
+        // if there's room, otherwise after the flush.
+        if self.buffer.remaining() >= DROPPED_MARKER_LEN {
+            self.dropped_marker(t0);
+        }
+
+        calibrate();
+        let unit = timestamp_unit();
+
+        // into the room past the limit, so direct i/o doesn't write a block for it.
+        let stale = self.file.format == Format::Spall && (self.timestamp_unit.is_nan()
+            || ((unit - self.timestamp_unit) / unit).abs() >= 1e-6);
+        if stale {
+            self.buffer.push(OverwriteTimestampEvent {
+                ty: EventType::OverwriteTimestamp as u8,
+                timestamp_unit: unit,
+            }.as_bytes());
+            self.timestamp_unit = unit;
+        }
+
+        if let Some(seq) = &mut self.checkpoint {
+            if self.file.format == Format::Spall {
+                *seq += 1;
+                // into the room past the limit.
+                self.buffer.push(&checkpoint::event(checkpoint::Checkpoint {
+                    pid:  self.pid,
+                    tid:  self.tid,
+                    seq:  *seq,
+                    len:  self.buffer.len() as u32,
+                    when: t0.saturating_sub(self.time_base) as f64,
+                }));
+            }
+        }
+
+        #[cfg(feature = "live")]
+        live::publish(self.buffer.as_slice(), self.pid, t0.saturating_sub(self.time_base));
+
+        let out_len = match self.file.format {
+            Format::Spall if self.file.direct => self.buffer.pad_blocks(),
+
+            Format::Spall => self.buffer.len(),
+
+            Format::ChromeJson => {
+                self.json.clear();
+                json::transcode(self.buffer.as_slice(), unit, &mut self.json);
+                self.json.len()
+            }
+        };
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let submitted = match &mut self.ring {
+            // records into the other buffer while this one is written.
+            Some(ring) if self.file.format == Format::Spall => {
+                ring.submit(&self.file, &mut self.buffer, out_len);
+                true
+            }
+            _ => false,
+        };
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let submitted = false;
+
+        let submitted = submitted || (self.global.overflow != Overflow::Block && self.queue_write(out_len));
+        if !submitted {
+            let out = match self.file.format {
+                Format::Spall      => self.buffer.head(out_len),
+                Format::ChromeJson => &self.json,
+            };
+            let res = (&self.file.file).write_all(out);
+            if let Err(e) = res {
+                report(self.silent, e.kind(), format_args!("spall file write failed {:?}", e));
+            }
+        }
+
+        if let Some(max_file_size) = self.max_file_size {
+            let len = out_len as u64;
+            let size = self.file.size.fetch_add(len, Ordering::Relaxed) + len;
+            if size >= max_file_size {
+                // the buffer is empty, so we can switch files right away.
+                if !self.per_thread_file {
+                    if let Err(e) = rotate_from(Some(self.generation)) {
+                        report(self.silent, e.kind(), format_args!("spall rotate failed {:?}", e));
+                    }
+                }
+                self.reopen();
+            }
+        }
+
+        self.buffer.clear();
+        for begin in &mut self.open_scopes {
+            *begin = None;
+        }
+        self.pid = self.thread_pid.unwrap_or_else(|| self.global.pid());
+
+        // with a wall clock anchor, see `Trace::unix_time`.
+        let name = "spall/flush";
+        let begin = self.push_begin_event(t0, name.len() as u8, 0);
+        self.push_bytes(name.as_bytes());
+
+        let args_len = self.push_args(255, format_args!("unix_us={}", unix_t0));
+        self.patch_begin_args_len(begin, args_len as u8);
+
+        let t1 = now();
+        self.push_end_event(t1);
+        self.dropped_marker(t1);
+
+        #[cfg(all(unix, feature = "profiler"))]
+        for (when, stack) in profiler::take_samples() {
+            if !PAUSED.load(Ordering::Relaxed) {
+                self.complete("spall/sample", when, when, format_args!("{}", stack));
+            }
+        }
+    }
+
+    // moves the events into a buffer of the size for the thread.
+    #[cold]
+    fn resize_buffer(&mut self) {
+        let size = thread_buffer_size(&self.global);
+        if size == self.buffer_size {
+            return;
+        }
+        if self.buffer.len() > size {
+            self.flush();
+        }
+
+        let Some(mut buffer) = Buffer::new(size, BUFFER_EXTRA, self.global.direct_io) else {
+            report(self.silent, std::io::ErrorKind::OutOfMemory, format_args!("spall failed to allocate buffer"));
+            return;
+        };
+        buffer.push(self.buffer.as_slice());
+        self.buffer = buffer;
+        self.buffer_size = size;
+
+        // registered the old buffer, set up again when the new one fills.
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            self.ring = None;
+            self.ring_tried = false;
+        }
+    }
+
+    // hands the first `len` bytes of the flush to the writer thread,
+    // and records into a new buffer. false if it can't be allocated.
+    fn queue_write(&mut self, len: usize) -> bool {
+        let data = match self.file.format {
+            Format::Spall => {
+                let Some(next) = Buffer::new(self.buffer_size, BUFFER_EXTRA, self.global.direct_io) else {
+                    return false;
+                };
+                queue::Data::Buffer(std::mem::replace(&mut self.buffer, next), len)
+            }
+
+            Format::ChromeJson => queue::Data::Bytes(std::mem::take(&mut self.json)),
+        };
+
+        queue::push(queue::Write { file: self.file.clone(), data, silent: self.silent });
+        return true;
+    }
+
+    // drops the scopes that began and ended in the buffer, for
+    // `Overflow::Drop`. unmatched begins and ends are kept, like all
+    // other events, so the scopes around them stay balanced.
+    // spall's own markers are kept too, the reader needs them.
+    #[cold]
+    fn drop_scopes(&mut self) {
+        let bytes = self.buffer.as_slice();
+
+        // the offset, size, and whether to keep each event,
+        // and the indices of the open begins by tid, and whether they're markers.
+        let mut events = Vec::<(usize, usize, bool)>::new();
+        let mut open = Vec::<(u32, Vec<(usize, bool)>)>::new();
+        let mut dropped = 0;
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let ty = bytes[offset];
+            let mut keep = true;
+
+            let size =
+                if ty == EventType::Begin as u8 {
+                    let Some(event) = read_at::<BeginEvent>(bytes, offset) else { break };
+                    let size = size_of::<BeginEvent>() + event.name_len as usize + event.args_len as usize;
+                    if offset + size > bytes.len() { break }
+
+                    let name = &bytes[offset + size_of::<BeginEvent>()..][..event.name_len as usize];
+                    let marker = name.starts_with(b"spall/");
+
+                    let tid = event.tid;
+                    let index = match open.iter().position(|(t, _)| *t == tid) {
+                        Some(index) => index,
+                        None        => { open.push((tid, Vec::new())); open.len() - 1 }
+                    };
+                    open[index].1.push((events.len(), marker));
+                    size
+                }
+                else if ty == EventType::End as u8 {
+                    let Some(event) = read_at::<EndEvent>(bytes, offset) else { break };
+
+                    let tid = event.tid;
+                    let begin = open.iter_mut().find(|(t, _)| *t == tid).and_then(|(_, begins)| begins.pop());
+                    if let Some((begin, false)) = begin {
+                        events[begin].2 = false;
+                        keep = false;
+                        dropped += 1;
+                    }
+                    size_of::<EndEvent>()
+                }
+                else if ty == EventType::CustomData as u8 {
+                    let Some(event) = read_at::<CustomDataEvent>(bytes, offset) else { break };
+                    size_of::<CustomDataEvent>() + event.size as usize
+                }
+                else if ty == EventType::PadSkip as u8 {
+                    let Some(event) = read_at::<PadSkipEvent>(bytes, offset) else { break };
+                    size_of::<PadSkipEvent>() + event.size as usize
+                }
+                else if ty == EventType::OverwriteTimestamp as u8 {
+                    size_of::<OverwriteTimestampEvent>()
+                }
+                // unknown, from `raw::write`. the rest is kept as is.
+                else { break };
+
+            events.push((offset, size, keep));
+            offset += size;
+        }
+        if offset < bytes.len() {
+            events.push((offset, bytes.len() - offset, true));
+        }
+        if dropped == 0 {
+            return;
+        }
+
+        let kept = events.iter()
+            .filter(|(_, _, keep)| *keep)
+            .flat_map(|&(offset, size, _)| &bytes[offset..offset + size])
+            .copied()
+            .collect::<Vec<u8>>();
+        self.buffer.clear();
+        self.buffer.push(&kept);
+        for begin in &mut self.open_scopes {
+            *begin = None;
+        }
+
+        // recorded at the next flush.
+        add_dropped(Dropped::Overflow, dropped);
+    }
+}
+
+impl Drop for ThreadState {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        if self.depth != 0 {
+            let depth = self.depth;
+            self.unbalanced(format_args!("exited with {} open scopes", depth));
+        }
+
+        // the thread goes on after `shutdown`.
+        if !PAUSED.load(Ordering::Relaxed) && self.global.session == SESSION.load(Ordering::Acquire) {
+            let name = self.thread_name.take();
+            self.lifecycle_marker("spall/thread_exit", now(), name.as_deref());
+        }
+        // the last flush has room for `spall/dropped`, which is lost after it.
+        if self.buffer.remaining() < DROPPED_MARKER_LEN {
+            self.flush();
+        }
+        self.flush();
+    }
+}
+
+
+
+pub struct TraceScope {
+    // whether a begin event was recorded.
+    active: bool,
+}
+
+impl TraceScope {
+    /// ends the scope before the guard goes out of scope.
+    ///
+    /// ```no_run
+    /// let scope = spall::trace_scope_impl("load");
+    /// // ...
+    /// scope.end();
+    /// // untraced work until the end of the block.
+    /// ```
+    #[inline]
+    pub fn end(self) {
+        drop(self);
+    }
+}
+
+impl Drop for TraceScope {
+    #[inline]
+    fn drop(&mut self) {
+        if self.active {
+            ThreadState::with(|s| s.end());
+        }
+    }
+}
+
+/// records a scope until the returned guard is dropped.
+///
+/// the name is a `&str`, or an owned name, like a `String` or `Cow<str>`,
+/// which is copied into the buffer, so it doesn't need to outlive the scope.
+///
+/// ```no_run
+/// # let job = 3;
+/// let _scope = spall::trace_scope_impl(format!("job {}", job));
+/// ```
+#[inline]
+pub fn trace_scope_impl(name: impl AsRef<str>) -> TraceScope {
+    let name = name.as_ref();
+    if !filter::allows(name) {
+        return TraceScope { active: false };
+    }
+    begin_scope(name, None)
+}
+
+#[inline]
+pub fn trace_scope_args_impl(name: impl AsRef<str>, args: std::fmt::Arguments) -> TraceScope {
+    let name = name.as_ref();
+    if !filter::allows(name) {
+        return TraceScope { active: false };
+    }
+    begin_scope(name, Some(args))
+}
+
+// caches the filter decision in the call site.
+#[doc(hidden)]
+#[inline]
+pub fn trace_scope_site_impl(site: &filter::CallSite, level: filter::Level, name: impl AsRef<str>, args: Option<std::fmt::Arguments>) -> TraceScope {
+    let name = name.as_ref();
+    if !site.allows_at(level, name) {
+        return TraceScope { active: false };
+    }
+    begin_scope(name, args)
+}
+
+/// like `trace_scope_impl`, but the name is only produced if the
+/// thread is recording, so building it costs nothing when it isn't.
+/// the filter applies to the produced name.
+///
+/// ```no_run
+/// # let items = vec![1, 2, 3];
+/// let _scope = spall::trace_scope_with(|| format!("batch of {}", items.len()));
+/// ```
+#[inline]
+pub fn trace_scope_with<N: AsRef<str>>(name: impl FnOnce() -> N) -> TraceScope {
+    begin_lazy(name, None::<fn() -> &'static str>)
+}
+
+/// like `trace_scope_with`, with args that are only produced if the
+/// scope is recorded, after filtering and sampling.
+///
+/// ```no_run
+/// # let items = vec![1, 2, 3];
+/// let _scope = spall::trace_scope_args_with(|| "process", || {
+///     format!("sum={}", items.iter().sum::<i32>())
+/// });
+/// ```
+#[inline]
+pub fn trace_scope_args_with<N: AsRef<str>, A: std::fmt::Display>(name: impl FnOnce() -> N, args: impl FnOnce() -> A) -> TraceScope {
+    begin_lazy(name, Some(args))
+}
+
+#[inline]
+fn begin_lazy<N: AsRef<str>, A: std::fmt::Display>(name: impl FnOnce() -> N, args: Option<impl FnOnce() -> A>) -> TraceScope {
+    if !ENABLED {
+        return TraceScope { active: false };
+    }
+
+    let active = ThreadState::record(|s| {
+        let name = name();
+        let name = name.as_ref();
+        if !filter::allows(name) {
+            return false;
+        }
+        match args {
+            Some(args) => s.begin_in(name, Some(format_args!("{}", LazyArgs(Cell::new(Some(args)))))),
+            None       => s.begin_in(name, None),
+        }
+    });
+    TraceScope { active: active.unwrap_or(false) }
+}
+
+// formats the closure's result, the first time it's formatted.
+struct LazyArgs<F>(Cell<Option<F>>);
+
+impl<F: FnOnce() -> A, A: std::fmt::Display> std::fmt::Display for LazyArgs<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.take() {
+            Some(args) => write!(f, "{}", args()),
+            None       => Ok(()),
+        }
+    }
+}
+
+/// a process-wide unique id of a scope, to reference it from logs,
+/// metrics or other tracing systems. recorded at the start of the
+/// scope's args as `scope_id=<id>`, see `reader::Scope::id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScopeId(pub u64);
+
+impl std::fmt::Display for ScopeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+static NEXT_SCOPE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// a scope guard with the scope's id, see `trace_scope_id`.
+pub struct IdScope {
+    scope: TraceScope,
+    id:    Option<ScopeId>,
+}
+
+impl IdScope {
+    /// the id, `None` if the scope isn't recorded.
+    #[inline]
+    pub fn id(&self) -> Option<ScopeId> {
+        self.id
+    }
+
+    #[inline]
+    pub fn end(self) {
+        self.scope.end();
+    }
+}
+
+/// records a scope with a new `ScopeId`, until the guard is dropped.
+/// with `Options::redact`, the args are hashed, id included.
+///
+/// ```no_run
+/// let scope = spall::trace_scope_id("request");
+/// if let Some(id) = scope.id() {
+///     println!("handling request, trace scope {}", id);
+/// }
+/// ```
+#[inline]
+pub fn trace_scope_id(name: impl AsRef<str>) -> IdScope {
+    begin_with_id(name.as_ref(), None)
+}
+
+#[inline]
+pub fn trace_scope_id_args(name: impl AsRef<str>, args: std::fmt::Arguments) -> IdScope {
+    begin_with_id(name.as_ref(), Some(args))
+}
+
+fn begin_with_id(name: &str, args: Option<std::fmt::Arguments>) -> IdScope {
+    if !filter::allows(name) {
+        return IdScope { scope: TraceScope { active: false }, id: None };
+    }
+
+    let mut id = None;
+    let active = ThreadState::record(|s| {
+        let scope_id = ScopeId(NEXT_SCOPE_ID.fetch_add(1, Ordering::Relaxed));
+        let recorded = match args {
+            Some(args) => s.begin_in(name, Some(format_args!("scope_id={} {}", scope_id, args))),
+            None       => s.begin_in(name, Some(format_args!("scope_id={}", scope_id))),
+        };
+        id = recorded.then_some(scope_id);
+        recorded
+    });
+    IdScope { scope: TraceScope { active: active.unwrap_or(false) }, id }
+}
+
+#[inline]
+fn begin_scope(name: &str, args: Option<std::fmt::Arguments>) -> TraceScope {
+    let active = ThreadState::record(|s| s.begin_in(name, args));
+    TraceScope { active: active.unwrap_or(false) }
+}
+
+// for `ScopeName`. sampled and redacted scopes, and json files, record the name.
+#[inline]
+pub(crate) fn begin_interned(name: &ScopeName, args: Option<std::fmt::Arguments>) -> TraceScope {
+    let active = ThreadState::record(|s| {
+        if s.sample_rate < 1.0 {
+            return s.begin_sampled(name.name(), args);
+        }
+        let ids = !s.redact && s.file.format == Format::Spall;
+        match args {
+            _ if ids   => s.begin_id(name, args),
+            Some(args) => s.begin_args(name.name(), args),
+            None       => s.begin(name.name()),
+        }
+        true
+    });
+    TraceScope { active: active.unwrap_or(false) }
+}
+
+// a name as recorded, maybe hashed or truncated.
+#[derive(Clone, Copy)]
+struct Name<'a> {
+    bytes: &'a [u8],
+    // followed by `args::TRUNCATION_MARK`.
+    truncated: bool,
+}
+
+impl Name<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.bytes.len() + if self.truncated { args::TRUNCATION_MARK.len() } else { 0 }
+    }
+}
+
+// a hash when redacting.
+#[inline(always)]
+fn recorded_name<'a>(redact: bool, name: &'a str, hashed: &'a mut [u8; redact::HASH_LEN]) -> Name<'a> {
+    if redact && !redact::keeps(name) {
+        return redact_name(name, hashed);
+    }
+    let (name, truncated) = truncate(name, 255);
+    Name { bytes: name.as_bytes(), truncated }
+}
+
+#[cold]
+fn redact_name<'a>(name: &str, hashed: &'a mut [u8; redact::HASH_LEN]) -> Name<'a> {
+    redact::hash_into(name.as_bytes(), hashed);
+    redact::record(name, hashed);
+    return Name { bytes: hashed, truncated: false };
+}
+
+// cuts `s` to at most `max` bytes, on a char boundary.
+// leaves room for `args::TRUNCATION_MARK` if it's cut.
+#[inline]
+pub(crate) fn truncate(s: &str, max: usize) -> (&str, bool) {
+    if s.len() <= max {
+        return (s, false);
+    }
+
+    let mut end = max.saturating_sub(args::TRUNCATION_MARK.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    return (&s[..end], true);
+}
+
+// microseconds since the unix epoch.
+fn unix_micros() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or(0)
+}
+
+// the tid recorded for the current thread.
+pub(crate) fn thread_tid() -> u32 {
+    if !SEQUENTIAL_TIDS.load(Ordering::Relaxed) {
+        return id_tid(std::thread::current().id());
+    }
+
+    return TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(NEXT_TID.fetch_add(1, Ordering::Relaxed));
+        }
+        tid.get()
+    });
+}
+
+// the tid for a thread about to be spawned, which it takes with
+// `set_thread_tid`, or `None` if it's known from the thread's id.
+pub(crate) fn reserve_tid() -> Option<u32> {
+    SEQUENTIAL_TIDS.load(Ordering::Relaxed).then(|| NEXT_TID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub(crate) fn set_thread_tid(tid: u32) {
+    TID.with(|cell| cell.set(tid));
+}
+
+// the tid without `Options::sequential_tids`.
+pub(crate) fn id_tid(id: std::thread::ThreadId) -> u32 {
+    let id = unsafe { std::mem::transmute::<std::thread::ThreadId, u64>(id) };
+    return id as u32;
+}
+
+// for `Options::sequential_tids`, from 1. threads keep theirs across sessions.
+static NEXT_TID: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    // 0 until assigned.
+    static TID: Cell<u32> = const { Cell::new(0) };
+}
+
+// the thread's id according to the os, for `Options::sequential_tids`.
+fn os_tid() -> Option<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return Some(unsafe { libc::syscall(libc::SYS_gettid) } as u64);
+
+    #[cfg(target_vendor = "apple")]
+    return {
+        let mut tid = 0u64;
+        let res = unsafe { libc::pthread_threadid_np(0, &mut tid) };
+        if res == 0 { Some(tid) } else { None }
+    };
+
+    #[cfg(windows)]
+    return {
+        extern "system" {
+            fn GetCurrentThreadId() -> u32;
+        }
+        Some(unsafe { GetCurrentThreadId() } as u64)
+    };
+
+    #[allow(unreachable_code)]
+    None
+}
+
+// for `Options::record_cpu`.
+#[inline]
+fn current_cpu() -> u8 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return unsafe { libc::sched_getcpu() }.max(0) as u8;
+
+    #[cfg(windows)]
+    return {
+        extern "system" {
+            fn GetCurrentProcessorNumber() -> u32;
+        }
+        unsafe { GetCurrentProcessorNumber() as u8 }
+    };
+
+    #[allow(unreachable_code)]
+    0
+}
+
+// records a zero length scope on the current thread.
+#[inline]
+pub(crate) fn marker(name: &str, args: std::fmt::Arguments) {
+    ThreadState::record(|s| {
+        let when = now();
+        s.complete(name, when, when, args);
+    });
+}
+
+// events that weren't recorded, or only partly, which each thread
+// records in a `spall/dropped` marker when it flushes.
+#[derive(Clone, Copy)]
+pub(crate) enum Dropped {
+    // by sampling.
+    Sampled,
+    // by the filter or level.
+    Filtered,
+    // by `Options::min_duration`.
+    Short,
+    // names and args with truncated text.
+    Truncated,
+    // by `Overflow::Drop`.
+    Overflow,
+}
+
+const DROPPED_KEYS: [&str; 5] = ["sampled", "filtered", "short", "truncated", "overflow"];
+
+// what `complete` reserves for the marker.
+const DROPPED_MARKER_LEN: usize = size_of::<BeginEvent>() + "spall/dropped".len() + 255 + size_of::<EndEvent>();
+
+thread_local! {
+    static DROPPED: [Cell<u32>; 5] = const { [const { Cell::new(0) }; 5] };
+}
+
+#[inline]
+pub(crate) fn count_dropped(what: Dropped) {
+    add_dropped(what, 1);
+}
+
+#[inline]
+fn add_dropped(what: Dropped, n: u32) {
+    _ = DROPPED.try_with(|dropped| {
+        let count = &dropped[what as usize];
+        count.set(count.get().saturating_add(n));
+    });
+}
+
+struct DroppedCounts([u32; 5]);
+
+impl std::fmt::Display for DroppedCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for (key, count) in DROPPED_KEYS.iter().zip(self.0) {
+            if count != 0 {
+                write!(f, "{}{}={}", if first { "" } else { " " }, key, count)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn take_dropped() -> Option<DroppedCounts> {
+    let counts = DROPPED.try_with(|dropped| dropped.each_ref().map(|count| count.take())).ok()?;
+    counts.iter().any(|count| *count != 0).then_some(DroppedCounts(counts))
+}
+
+// for the per-call-site sampling of the macros.
+#[doc(hidden)]
+#[cold]
+pub fn sampled_out() {
+    count_dropped(Dropped::Sampled);
+}
+
+// records a scope from `t0` until now, for when the args
+// are only known at the end. events recorded since `t0` end up
+// out of order, so this is only for leaf scopes.
+#[inline]
+pub(crate) fn scope_since(name: &str, t0: u64, args: std::fmt::Arguments) {
+    if !filter::allows(name) {
+        return;
+    }
+
+    ThreadState::record(|s| {
+        let t1 = now();
+        if s.min_duration > 0.0 && t1.saturating_sub(t0) as f64 * timestamp_unit() < s.min_duration {
+            return;
+        }
+        s.complete(name, t0, t1, args);
+    });
+}
+
+// begins a scope on another track, like a task's.
+// returns whether it was recorded, and so needs an `end_on`.
+#[inline]
+pub(crate) fn begin_on(tid: u32, name: &str, args: std::fmt::Arguments) -> bool {
+    if !filter::allows(name) {
+        return false;
+    }
+
+    ThreadState::record(|s| {
+        let thread = std::mem::replace(&mut s.tid, tid);
+        s.begin_args(name, args);
+        s.tid = thread;
+    }).is_some()
+}
+
+#[inline]
+pub(crate) fn end_on(tid: u32) {
+    ThreadState::with(|s| {
+        let thread = std::mem::replace(&mut s.tid, tid);
+        s.end();
+        s.tid = thread;
+    });
+}
+
+// records a finished scope on the track `tid`, first naming the track
+// if `track` is `Some`. returns whether it was recorded.
+pub(crate) fn complete_on(tid: u32, track: Option<&str>, name: &str, t0: u64, t1: u64, args: std::fmt::Arguments) -> bool {
+    if !filter::allows(name) {
+        return false;
+    }
+
+    ThreadState::record(|s| {
+        let thread = std::mem::replace(&mut s.tid, tid);
+        if track.is_some() {
+            s.lifecycle_marker("spall/thread_start", t0, track);
+        }
+        s.complete(name, t0, t1, args);
+        s.tid = thread;
+    }).is_some()
+}
+
+// makes the thread record on the track `tid`, and names it if `name`
+// is `Some`. returns the thread's previous tid, `None` if it isn't recording.
+pub(crate) fn enter_track(tid: u32, name: Option<&str>) -> Option<u32> {
+    ThreadState::record(|s| {
+        let previous = std::mem::replace(&mut s.tid, tid);
+        if name.is_some() {
+            s.lifecycle_marker("spall/thread_start", now(), name);
+        }
+        previous
+    })
+}
+
+// switches the thread's track and open scopes to a fiber's,
+// and names the track if `name` is `Some`. returns the previous ones,
+// `None` if the thread has no state.
+pub(crate) fn swap_fiber(to: fiber::Saved, name: Option<&str>) -> Option<fiber::Saved> {
+    ThreadState::with(|s| {
+        let open = s.open_scopes.len();
+        // the begin events may be on another thread's buffer.
+        s.open_scopes.clear();
+        s.open_scopes.resize(to.open, None);
+
+        #[cfg(debug_assertions)]
+        let depth = std::mem::replace(&mut s.depth, to.depth);
+        #[cfg(not(debug_assertions))]
+        let depth = to.depth;
+
+        let tid = std::mem::replace(&mut s.tid, to.tid);
+        if name.is_some() {
+            s.lifecycle_marker("spall/thread_start", now(), name);
+        }
+        fiber::Saved { session: s.global.session, tid, open, depth }
+    })
+}
+
+pub(crate) fn exit_track(tid: u32, previous: u32) {
+    // unless the session ended in between.
+    ThreadState::with(|s| {
+        if s.tid == tid {
+            s.tid = previous;
+        }
+    });
+}
+
+// the current session, changed by `init_with` and `shutdown`.
+pub(crate) fn session() -> u64 {
+    SESSION.load(Ordering::Relaxed)
+}
+
+// calls `sample` every `interval` on a thread named `name`, until the
+// session ends, like the flush thread. `started` is the session the
+// sampler last started in, so it starts once per session, and not
+// without one.
+pub(crate) fn spawn_sampler(name: &str, started: &'static AtomicU64, interval: std::time::Duration, mut sample: impl FnMut() + Send + 'static) -> Result<(), std::io::Error> {
+    let Some(session) = GLOBAL_STATE.load().as_ref().map(|global| global.session) else { return Ok(()) };
+    if started.swap(session, Ordering::Relaxed) == session {
+        return Ok(());
+    }
+
+    let spawned = std::thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            while SESSION.load(Ordering::Acquire) == session {
+                sample();
+                std::thread::sleep(interval);
+            }
+        });
+    if let Err(e) = spawned {
+        started.store(0, Ordering::Relaxed);
+        return Err(e);
+    }
+    return Ok(());
+}
+
+// for scopes that already passed per-call-site sampling.
+#[doc(hidden)]
+#[inline]
+pub fn trace_scope_sampled_impl(name: impl AsRef<str>, sample_rate: f64, args: Option<std::fmt::Arguments>) -> TraceScope {
+    let name = name.as_ref();
+    let active = ThreadState::record(|s| {
+        match args {
+            Some(args) => s.begin_args(name, format_args!("sample_rate={} {}", sample_rate, args)),
+            None       => s.begin_args(name, format_args!("sample_rate={}", sample_rate)),
+        }
+        true
+    });
+    TraceScope { active: active.unwrap_or(false) }
+}
+
+
+
+// sampling:
+
+/// records only some invocations of a scope.
+///
+/// `every = n` records every n-th invocation (per thread),
+/// `probability = p` records each invocation with probability `p`.
+/// recorded scopes have `sample_rate=<rate>` at the start of their args,
+/// so tools can scale counts and durations back up.
+/// these scopes aren't subject to `Options::sample_rate`.
+/// like with `trace_scope!`, nothing is evaluated when spall is disabled.
+///
+/// ```no_run
+/// # let x = 1;
+/// spall::trace_scope_sampled!(every = 100, "hash");
+/// spall::trace_scope_sampled!(probability = 0.01, "lookup", "key={}", x);
+/// ```
+#[macro_export]
+macro_rules! trace_scope_sampled {
+    (every = $n:expr, $name:expr $(, $($args:tt)+)?) => {
+        let _trace_scope = if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL {
+            ::std::thread_local! {
+                static COUNTER: ::std::cell::Cell<u32> = const { ::std::cell::Cell::new(0) };
+            }
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            let name = $name;
+            let name: &str = ::std::convert::AsRef::as_ref(&name);
+            let n: u32 = $n;
+            if !SITE.allows(name) { None }
+            else if COUNTER.with(|c| $crate::sample_every(c, n)) {
+                Some($crate::trace_scope_sampled_impl(name, 1.0 / n.max(1) as f64,
+                    $crate::trace_scope_sampled!(@args $($($args)+)?)))
+            }
+            else { $crate::sampled_out(); None }
+        } else { None };
+    };
+
+    (probability = $p:expr, $name:expr $(, $($args:tt)+)?) => {
+        let _trace_scope = if $crate::ENABLED && $crate::filter::Level::Normal <= $crate::filter::MAX_LEVEL {
+            static SITE: $crate::filter::CallSite = $crate::filter::CallSite::new(module_path!());
+            let name = $name;
+            let name: &str = ::std::convert::AsRef::as_ref(&name);
+            let p: f64 = $p;
+            if !SITE.allows(name) { None }
+            else if $crate::sample_probability(p) {
+                Some($crate::trace_scope_sampled_impl(name, p,
+                    $crate::trace_scope_sampled!(@args $($($args)+)?)))
+            }
+            else { $crate::sampled_out(); None }
+        } else { None };
+    };
+
+    (@args) => { None };
+    (@args $($args:tt)+) => { Some($crate::trace_args!($($args)+)) };
+}
+
+// format args or key-value pairs, as `fmt::Arguments`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! trace_args {
+    ({ $($key:ident = $value:expr),* $(,)? }) => {
+        format_args!("{}", $crate::args::KeyValues(&[
+            $((stringify!($key), &$value as &dyn ::std::fmt::Display)),*
+        ]))
+    };
+
+    ($($args:tt)+) => { format_args!($($args)+) };
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn sample_every(counter: &Cell<u32>, n: u32) -> bool {
+    let count = counter.get();
+    if count + 1 >= n {
+        counter.set(0);
+        return true;
+    }
+    counter.set(count + 1);
+    return false;
+}
+
+/// returns true with probability `p`, using a fast thread-local generator.
+#[inline]
+pub fn sample_probability(p: f64) -> bool {
+    thread_local! {
+        static STATE: Cell<u64> = const { Cell::new(0) };
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        if x == 0 {
+            // seed from the thread's stack address and the clock.
+            x = (&x as *const u64 as u64) ^ now().rotate_left(32) | 1;
+        }
+
+        // xorshift64*
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+
+        let r = x.wrapping_mul(0x2545F4914F6CDD1D) >> 11;
+        (r as f64) < p * (1u64 << 53) as f64
+    })
+}
+
+
+
+#[cfg(target_arch = "aarch64")]
+mod timer {
+    pub fn source() -> &'static str {
+        "cntvct"
+    }
+
+    // cntfrq is only the nominal frequency.
+    #[inline]
+    pub fn needs_calibration() -> bool {
+        true
+    }
+
+    #[cfg(not(feature = "serialized"))]
+    #[inline(always)]
+    pub fn now() -> u64 {
+        let tsc: u64;
+        unsafe {
+            std::arch::asm!(
+                "mrs {tsc}, cntvct_el0",
+                tsc = out(reg) tsc,
+            );
+        }
+        tsc
+    }
+
+    #[cfg(feature = "serialized")]
+    #[inline(always)]
+    pub fn now() -> u64 {
+        let tsc: u64;
+        unsafe {
+            std::arch::asm!(
+                "isb",
+                "mrs {tsc}, cntvct_el0",
+                "isb",
+                tsc = out(reg) tsc,
+            );
+        }
+        tsc
+    }
+
+    #[inline(always)]
+    pub fn timer_frequency() -> f64 {
+        let freq: u64;
+        unsafe {
+            std::arch::asm!(
+                "mrs {freq}, cntfrq_el0",
+                freq = out(reg) freq,
+            );
+        }
+        freq as f64
+    }
+}
+
+#[cfg(all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))]
+mod timer {
+    use std::sync::OnceLock;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86 as arch;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64 as arch;
+
+    static FREQUENCY: OnceLock<f64> = OnceLock::new();
+
+    // 0 until probed, then one of these.
+    static SOURCE: AtomicU8 = AtomicU8::new(0);
+    const TSC: u8 = 1;
+    const OS:  u8 = 2;
+
+    // the os clock, for cpus without an invariant tsc.
+    static T0: OnceLock<Instant> = OnceLock::new();
+
+    pub fn source() -> &'static str {
+        if use_tsc() { "rdtsc" } else { "instant" }
+    }
+
+    #[inline]
+    pub fn needs_calibration() -> bool {
+        use_tsc()
+    }
+
+    #[inline(always)]
+    fn use_tsc() -> bool {
+        match SOURCE.load(Ordering::Relaxed) {
+            TSC => true,
+            OS  => false,
+            _   => probe(),
+        }
+    }
+
+    // without an invariant tsc (constant rate, and not stopping in deep
+    // sleep states), like on old cpus and some vms, timestamps would drift
+    // with frequency changes, or jump between cores.
+    #[cold]
+    fn probe() -> bool {
+        #[allow(unused_unsafe)]
+        let invariant = unsafe {
+            arch::__cpuid(0x8000_0000).eax >= 0x8000_0007
+            && arch::__cpuid(0x8000_0007).edx & (1 << 8) != 0
+        };
+
+        let source = if invariant { TSC } else { OS };
+        // racing probes agree.
+        SOURCE.store(source, Ordering::Relaxed);
+        if !invariant {
+            T0.get_or_init(Instant::now);
+        }
+        return invariant;
+    }
+
+    #[inline(always)]
+    pub fn now() -> u64 {
+        if !use_tsc() {
+            return os_now();
+        }
+        rdtsc()
+    }
+
+    #[cfg(not(feature = "serialized"))]
+    #[inline(always)]
+    fn rdtsc() -> u64 {
+        unsafe { arch::_rdtsc() }
+    }
+
+    // the first lfence waits for earlier instructions to complete,
+    // the second keeps later ones from starting before the read.
+    #[cfg(feature = "serialized")]
+    #[inline(always)]
+    fn rdtsc() -> u64 {
+        unsafe {
+            arch::_mm_lfence();
+            let tsc = arch::_rdtsc();
+            arch::_mm_lfence();
+            tsc
+        }
+    }
+
+    #[inline]
+    fn os_now() -> u64 {
+        let t0 = T0.get_or_init(Instant::now);
+        t0.elapsed().as_nanos() as u64
+    }
+
+    #[inline(always)]
+    pub fn timer_frequency() -> f64 {
+        if !use_tsc() {
+            return 1_000_000_000.0;
+        }
+        *FREQUENCY.get_or_init(estimate_frequency)
+    }
+
+    // a rough estimate for the header.
+    // `calibrate` refines it over the course of the run.
+    #[cold]
+    fn estimate_frequency() -> f64 {
+        let i0 = Instant::now();
+        let t0 = rdtsc();
+        let mut elapsed = i0.elapsed();
+        while elapsed < Duration::from_millis(2) {
+            elapsed = i0.elapsed();
+        }
+        let t1 = rdtsc();
+        (t1 - t0) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+// unaffected by ntp slewing, and a single vdso call.
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "aarch64",
+        all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64"))))))]
+mod timer {
+    pub fn source() -> &'static str {
+        "clock_monotonic_raw"
+    }
+
+    #[inline]
+    pub fn needs_calibration() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn now() -> u64 {
+        let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+
+    #[inline(always)]
+    pub fn timer_frequency() -> f64 {
+        1_000_000_000.0
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_arch = "aarch64",
+    all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))))]
+mod timer {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static T0: OnceLock<Instant> = OnceLock::new();
+
+    pub fn source() -> &'static str {
+        "instant"
+    }
+
+    #[inline]
+    pub fn needs_calibration() -> bool {
+        false
+    }
 
     #[inline(always)]
     pub fn now() -> u64 {
-        let t0 = T0.get_or_init(|| Instant::now());
+        let t0 = T0.get_or_init(Instant::now);
         t0.elapsed().as_nanos() as u64
     }
 
@@ -0,0 +1,161 @@
+//! live event streaming over websocket.
+//!
+//! every flushed buffer is forwarded to all connected clients as a binary
+//! message. new clients first receive a spall header, so the concatenation
+//! of all messages is a valid trace.
+//!
+//! each client has a bounded queue. when a client can't keep up, whole
+//! buffers are dropped (events are never split) and a `spall/live/dropped`
+//! marker on tid 0 reports how much data the client missed.
+
+use std::io::Error;
+use std::mem::size_of;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use crate::{SpallHeader, EventType, BeginEvent, EndEvent, push_as_bytes};
+
+
+struct Client {
+    queue: SyncSender<Arc<[u8]>>,
+    dropped_buffers: u64,
+    dropped_bytes:   u64,
+}
+
+static CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+
+
+/// starts the websocket server on a background thread.
+///
+/// `queue_buffers` is the number of flushed buffers each client may lag
+/// behind before data is dropped for that client.
+///
+/// returns the bound address, which is useful when binding to port 0.
+pub fn serve(addr: impl ToSocketAddrs, queue_buffers: usize) -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+
+    std::thread::Builder::new()
+        .name("spall/live".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                _ = stream.set_nodelay(true);
+                accept(stream, queue_buffers);
+            }
+        })?;
+
+    return Ok(local);
+}
+
+fn accept(stream: std::net::TcpStream, queue_buffers: usize) {
+    let (send, recv) = sync_channel::<Arc<[u8]>>(queue_buffers.max(1));
+
+    // register before the handshake, so no buffer flushed
+    // after this point is missed.
+    _ = send.try_send(header().into());
+    CLIENTS.lock().unwrap().push(Client {
+        queue: send,
+        dropped_buffers: 0,
+        dropped_bytes:   0,
+    });
+
+    _ = std::thread::Builder::new()
+        .name("spall/live/client".into())
+        .spawn(move || {
+            use tungstenite::Message;
+
+            let Ok(mut ws) = tungstenite::accept(stream) else { return };
+            for buffer in recv {
+                if ws.send(Message::Binary(buffer.to_vec().into())).is_err() {
+                    break;
+                }
+            }
+            _ = ws.close(None);
+        });
+}
+
+
+/// forwards a flushed buffer to all clients.
+pub(crate) fn publish(bytes: &[u8], pid: u32, when: u64) {
+    let mut clients = CLIENTS.lock().unwrap();
+    if clients.is_empty() || bytes.is_empty() {
+        return;
+    }
+
+    let buffer: Arc<[u8]> = bytes.into();
+    clients.retain_mut(|client| {
+        if client.dropped_buffers != 0 {
+            let marker = dropped_marker(pid, when, client.dropped_buffers, client.dropped_bytes);
+            match client.queue.try_send(marker.into()) {
+                Ok(()) => {
+                    client.dropped_buffers = 0;
+                    client.dropped_bytes   = 0;
+                }
+                Err(TrySendError::Full(_)) => (),
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        if client.dropped_buffers != 0 {
+            client.dropped_buffers += 1;
+            client.dropped_bytes   += bytes.len() as u64;
+            return true;
+        }
+
+        match client.queue.try_send(buffer.clone()) {
+            Ok(()) => true,
+
+            Err(TrySendError::Full(_)) => {
+                client.dropped_buffers += 1;
+                client.dropped_bytes   += bytes.len() as u64;
+                true
+            }
+
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    });
+}
+
+
+fn header() -> Vec<u8> {
+    let header = SpallHeader {
+        magic_header:   0x0BADF00D,
+        version:        1,
+        timestamp_unit: crate::timestamp_unit(),
+        must_be_0:      0,
+    };
+    let mut result = Vec::with_capacity(size_of::<SpallHeader>());
+    push_as_bytes(&mut result, header);
+    return result;
+}
+
+fn dropped_marker(pid: u32, when: u64, buffers: u64, bytes: u64) -> Vec<u8> {
+    let name = "spall/live/dropped";
+    let args = format!("buffers={} bytes={}", buffers, bytes);
+    let args = &args.as_bytes()[..args.len().min(255)];
+
+    let mut result = Vec::with_capacity(
+        size_of::<BeginEvent>() + name.len() + args.len() + size_of::<EndEvent>());
+
+    push_as_bytes(&mut result, BeginEvent {
+        ty: EventType::Begin as u8,
+        category: 0,
+        pid,
+        tid: 0,
+        when: when as f64,
+        name_len: name.len() as u8,
+        args_len: args.len() as u8,
+    });
+    result.extend_from_slice(name.as_bytes());
+    result.extend_from_slice(args);
+
+    push_as_bytes(&mut result, EndEvent {
+        ty: EventType::End as u8,
+        pid,
+        tid: 0,
+        when: when as f64,
+    });
+    return result;
+}
@@ -0,0 +1,58 @@
+//! background process memory sampling.
+//!
+//! `sample` starts a thread that periodically records a `spall/memory`
+//! marker on its own track, so memory growth lines up with the code that
+//! was running at that moment.
+
+use std::io::Error;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+
+/// starts sampling on a background thread, every `interval`, until
+/// the session ends, see `shutdown`. does nothing without a session,
+/// or when sampling already started in this one.
+///
+/// markers have args like `rss_bytes=1048576 heap_bytes=524288`.
+/// `rss_bytes` is only available on linux,
+/// `heap_bytes` only with `TracingAllocator` as the global allocator.
+pub fn sample(interval: Duration) -> Result<(), Error> {
+    static STARTED: AtomicU64 = AtomicU64::new(0);
+    return crate::spawn_sampler("spall/memory", &STARTED, interval, record);
+}
+
+fn record() {
+    use std::fmt::Write;
+
+    let mut args = String::new();
+    if let Some(rss) = rss_bytes() {
+        _ = write!(args, "rss_bytes={}", rss);
+    }
+    if let Some(heap) = crate::alloc::live_bytes() {
+        let sep = if args.is_empty() { "" } else { " " };
+        _ = write!(args, "{}heap_bytes={}", sep, heap);
+    }
+
+    if !args.is_empty() {
+        crate::marker("spall/memory", format_args!("{}", args));
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+fn rss_bytes() -> Option<u64> {
+    // "size resident shared ...", in pages.
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    return Some(resident * page_size as u64);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes() -> Option<u64> {
+    None
+}
@@ -0,0 +1,116 @@
+//! trace metadata.
+//!
+//! key value pairs describing a recording, like its clock source, stored
+//! as `CustomData` events tagged with `TAG`, which the viewer skips.
+//! each trace file starts with the metadata set before it was created.
+//! `Trace::metadata` reads them back.
+
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::{CustomDataEvent, EventType, push_as_bytes};
+
+
+/// the custom data tag of metadata events. the payload is
+/// the key and the value, separated by a zero byte.
+pub const TAG: u32 = u32::from_le_bytes(*b"meta");
+
+static METADATA: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// sets `key` to `value` in the current trace and all later trace files.
+///
+/// with `Options::per_thread_files`, only the current thread's file
+/// receives it right away, other threads' files when they're created.
+/// with `Options::lazy_file`, setting it doesn't create the file.
+pub fn set(key: &str, value: &str) {
+    {
+        let mut metadata = METADATA.lock().unwrap();
+        match metadata.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None         => metadata.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    // otherwise it's in the header.
+    if !crate::file_pending() {
+        crate::raw::custom_data(TAG, &payload(key, value));
+    }
+}
+
+// for `shutdown`, the next trace starts without.
+pub(crate) fn clear() {
+    METADATA.lock().unwrap().clear();
+}
+
+/// records the process name, command line, working directory and
+/// hostname, as `process`, `cmdline`, `cwd` and `hostname`.
+/// see `Options::process_metadata`.
+pub fn set_process() {
+    let exe = std::env::current_exe().ok();
+    let name = exe.as_ref().and_then(|exe| exe.file_name());
+    if let Some(name) = name {
+        set("process", &name.to_string_lossy());
+    }
+
+    let mut cmdline = String::new();
+    for arg in std::env::args_os() {
+        let arg = arg.to_string_lossy();
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            cmdline.push_str(&format!("{:?}", arg));
+        }
+        else {
+            cmdline.push_str(&arg);
+        }
+    }
+    set("cmdline", &cmdline);
+
+    if let Ok(cwd) = std::env::current_dir() {
+        set("cwd", &cwd.to_string_lossy());
+    }
+
+    if let Some(hostname) = hostname() {
+        set("hostname", &hostname);
+    }
+}
+
+pub(crate) fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    return {
+        let mut buffer = [0u8; 256];
+        let res = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+        if res != 0 {
+            return None;
+        }
+        let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+        Some(String::from_utf8_lossy(&buffer[..len]).into_owned())
+    };
+
+    #[cfg(not(unix))]
+    return std::env::var("COMPUTERNAME").ok();
+}
+
+// the events for the start of a new file.
+pub(crate) fn events() -> Vec<u8> {
+    let mut events = Vec::new();
+    for (key, value) in METADATA.lock().unwrap().iter() {
+        let payload = payload(key, value);
+        push_as_bytes(&mut events, CustomDataEvent {
+            ty:   EventType::CustomData as u8,
+            size: (size_of::<u32>() + payload.len()) as u32,
+        });
+        events.extend_from_slice(&TAG.to_le_bytes());
+        events.extend_from_slice(&payload);
+    }
+    return events;
+}
+
+fn payload(key: &str, value: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(key.len() + 1 + value.len());
+    payload.extend_from_slice(key.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(value.as_bytes());
+    return payload;
+}
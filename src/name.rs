@@ -0,0 +1,182 @@
+//! interned scope names.
+//!
+//! ```no_run
+//! static PARSE: spall::ScopeName = spall::name!("parse");
+//!
+//! fn parse() {
+//!     let _scope = PARSE.scope();
+//! }
+//! ```
+//!
+//! a `ScopeName` gets an id on first use. each trace file receives the
+//! name once, in a `CustomData` event tagged `TAG`, and events record the
+//! 5 byte id instead of the name, which makes hot scopes with long names
+//! cheaper to record and store.
+//!
+//! `reader::Trace` resolves the ids. the spall viewer doesn't know them,
+//! use `expand` to write a copy with the names filled in.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{CustomDataEvent, EventType, TraceScope, filter, push_as_bytes};
+use crate::reader::{Parser, RawEvent};
+
+
+/// the custom data tag of the name dictionary. the payload is
+/// the id, as 4 little endian bytes, followed by the name.
+pub const TAG: u32 = u32::from_le_bytes(*b"name");
+
+/// the length of a recorded id, a zero byte and the id as 4 little endian bytes.
+pub const ID_LEN: usize = 5;
+
+/// see `name!`.
+pub struct ScopeName {
+    name: &'static str,
+    // 0 until registered.
+    id:   AtomicU32,
+    site: filter::CallSite,
+}
+
+/// a `ScopeName` for a static.
+#[macro_export]
+macro_rules! name {
+    ($name:expr) => {
+        $crate::ScopeName::with_target($name, module_path!())
+    };
+}
+
+impl ScopeName {
+    pub const fn new(name: &'static str) -> Self {
+        Self::with_target(name, "")
+    }
+
+    /// with a target for the filter, see `filter`.
+    pub const fn with_target(name: &'static str, target: &'static str) -> Self {
+        Self { name, id: AtomicU32::new(0), site: filter::CallSite::new(target) }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn id(&self) -> u32 {
+        match self.id.load(Ordering::Relaxed) {
+            0  => self.register(),
+            id => id,
+        }
+    }
+
+    #[cold]
+    fn register(&self) -> u32 {
+        let mut names = NAMES.lock().unwrap();
+        // another thread may have been first.
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+
+        names.push(self.name);
+        let id = names.len() as u32;
+        self.id.store(id, Ordering::Relaxed);
+        return id;
+    }
+
+    /// begins a scope with this name, like `trace_scope!`.
+    #[inline]
+    pub fn scope(&self) -> TraceScope {
+        self.begin(None)
+    }
+
+    #[inline]
+    pub fn scope_args(&self, args: std::fmt::Arguments) -> TraceScope {
+        self.begin(Some(args))
+    }
+
+    #[inline]
+    fn begin(&self, args: Option<std::fmt::Arguments>) -> TraceScope {
+        if !self.site.allows_static(filter::Level::Normal, self.name) {
+            return TraceScope { active: false };
+        }
+        crate::begin_interned(self, args)
+    }
+}
+
+
+static NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+// how many names are registered.
+pub(crate) fn count() -> u32 {
+    NAMES.lock().unwrap().len() as u32
+}
+
+// the dictionary events of ids `first..=last`.
+pub(crate) fn events(first: u32, last: u32) -> Vec<u8> {
+    let names = NAMES.lock().unwrap();
+
+    let mut events = Vec::new();
+    for id in first..=last {
+        let Some(name) = names.get(id as usize - 1) else { break };
+        let (name, truncated) = crate::truncate(name, 255);
+        let mark = if truncated { crate::args::TRUNCATION_MARK } else { "" };
+
+        push_as_bytes(&mut events, CustomDataEvent {
+            ty:   EventType::CustomData as u8,
+            size: (2*size_of::<u32>() + name.len() + mark.len()) as u32,
+        });
+        events.extend_from_slice(&TAG.to_le_bytes());
+        events.extend_from_slice(&id.to_le_bytes());
+        events.extend_from_slice(name.as_bytes());
+        events.extend_from_slice(mark.as_bytes());
+    }
+    return events;
+}
+
+// the id of a recorded name, if it is one.
+pub(crate) fn parse_id(name: &[u8]) -> Option<u32> {
+    match name {
+        [0, id @ ..] => Some(u32::from_le_bytes(id.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+// an entry of the dictionary, from a custom data payload.
+pub(crate) fn parse_entry(data: &[u8]) -> Option<(u32, &[u8])> {
+    let data = data.strip_prefix(&TAG.to_le_bytes())?;
+    let id = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    Some((id, &data[4..]))
+}
+
+// the dictionary of a trace file.
+pub(crate) fn dictionary(data: &[u8]) -> Result<HashMap<u32, &[u8]>, Error> {
+    let mut names = HashMap::new();
+    for event in Parser::new(data)? {
+        if let RawEvent::CustomData { data } = event? {
+            if let Some((id, name)) = parse_entry(data) {
+                names.insert(id, name);
+            }
+        }
+    }
+    return Ok(names);
+}
+
+// the name an id stands for, or the name itself.
+pub(crate) fn resolve<'a>(name: &'a [u8], names: &HashMap<u32, &'a [u8]>) -> &'a [u8] {
+    parse_id(name)
+        .and_then(|id| names.get(&id).copied())
+        .unwrap_or(name)
+}
+
+
+/// writes a copy of the trace at `input` to `output`,
+/// with ids replaced by their names, for the viewer.
+/// `reader::merge` also does this.
+pub fn expand(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<(), Error> {
+    crate::reader::merge(&[input], output)
+}
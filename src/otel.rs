@@ -0,0 +1,86 @@
+//! replaying traces as opentelemetry spans.
+//!
+//! each thread becomes a root span named `spall thread <tid>`, with the
+//! thread's scopes nested below it like they were recorded, so a trace
+//! captured in production can be sent to jaeger, tempo and friends through
+//! whatever exporter the tracer is configured with.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use opentelemetry::{Context, KeyValue};
+use opentelemetry::trace::{TraceContextExt, Tracer};
+
+use crate::reader::Trace;
+
+
+/// starts and ends a span for every scope in `trace`.
+///
+/// spall timestamps aren't absolute, so `start` is the wall clock time
+/// of the trace's first timestamp, like from `Trace::unix_time`.
+/// scope args become string attributes, next to `process.pid` and `thread.id`.
+pub fn replay<T>(trace: &Trace, tracer: &T, start: SystemTime)
+    where T: Tracer, T::Span: Send + Sync + 'static
+{
+    let Some((first, _)) = trace.time_range() else { return };
+    let at = |when: f64| start + Duration::from_secs_f64((when - first).max(0.0) / 1e6);
+
+    let scopes = trace.scopes();
+
+    let mut roots = HashMap::new();
+    for thread in trace.threads() {
+        let Some(&head) = thread.scopes.first() else { continue };
+        let begin = scopes[head].start;
+        let end   = thread.scopes.iter().map(|i| scopes[*i].end).fold(begin, f64::max);
+
+        let span = tracer.span_builder(format!("spall thread {}", thread.tid))
+            .with_start_time(at(begin))
+            .with_attributes(thread_attributes(thread.pid, thread.tid))
+            .start_with_context(tracer, &Context::new());
+
+        let cx = Context::new().with_span(span);
+        cx.span().end_with_timestamp(at(end));
+        roots.insert((thread.pid, thread.tid), cx);
+    }
+
+    // parents start no later than their children,
+    // and come first among scopes starting at the same time.
+    let mut order = (0..scopes.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        let (a, b) = (&scopes[*a], &scopes[*b]);
+        a.start.total_cmp(&b.start).then(a.depth.cmp(&b.depth))
+    });
+
+    let mut contexts = vec![None; scopes.len()];
+    for index in order {
+        let scope = &scopes[index];
+
+        let parent = match scope.parent {
+            Some(parent) => contexts[parent].as_ref(),
+            None         => roots.get(&(scope.pid, scope.tid)),
+        };
+        let Some(parent) = parent else { continue };
+
+        let mut attributes = thread_attributes(scope.pid, scope.tid);
+        for (key, value) in crate::args::parse(&scope.args) {
+            attributes.push(KeyValue::new(key.to_string(), Cow::into_owned(value)));
+        }
+
+        let span = tracer.span_builder(scope.name.clone())
+            .with_start_time(at(scope.start))
+            .with_attributes(attributes)
+            .start_with_context(tracer, parent);
+
+        let cx = Context::new().with_span(span);
+        cx.span().end_with_timestamp(at(scope.end));
+        contexts[index] = Some(cx);
+    }
+}
+
+fn thread_attributes(pid: u32, tid: u32) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("process.pid", pid as i64),
+        KeyValue::new("thread.id",   tid as i64),
+    ]
+}
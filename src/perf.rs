@@ -0,0 +1,254 @@
+//! import of linux `perf` profiles.
+//!
+//! `import_script` reads the output of `perf script`, with stacks
+//! (`perf record -g`), and turns the sampled stacks into scopes:
+//! a frame's scope lasts while consecutive samples of its thread have
+//! the same frames above and including it, like a flame chart over time.
+//! `import_folded` does the same for folded stacks without timestamps,
+//! like those of `perf report -g folded` or `stackcollapse-perf.pl`.
+//!
+//! perf's timestamps use the monotonic clock, or the one given with
+//! `perf record -k`. recorded with `-k monotonic_raw`, they line up
+//! with spall's default clock on linux, without `rdtsc` or
+//! `Options::rebase_timestamps`, and `reader::merge` can combine
+//! the imported profile with the application's trace.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::SpallWriter;
+
+
+/// converts `perf script` output to a spall trace at `output`.
+/// returns the number of samples imported.
+/// lines that aren't samples or frames are skipped.
+pub fn import_script(text: &str, output: impl AsRef<Path>) -> Result<usize, Error> {
+    let samples = parse_script(text);
+    if samples.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "no samples with timestamps in perf script output"));
+    }
+
+    let interval = median_interval(&samples);
+
+    let mut threads = HashMap::<(u32, u32), Vec<&Sample>>::new();
+    for sample in &samples {
+        threads.entry((sample.pid, sample.tid)).or_default().push(sample);
+    }
+    let mut threads = threads.into_iter().collect::<Vec<_>>();
+    threads.sort_by_key(|(thread, _)| *thread);
+
+    let mut out = SpallWriter::new(Vec::new(), 1.0);
+    for ((pid, tid), mut samples) in threads {
+        samples.sort_by(|a, b| a.when.total_cmp(&b.when));
+
+        let mut stacks = Stacks::default();
+        for (i, sample) in samples.iter().enumerate() {
+            // a sample lasts until the next one, unless the thread was idle.
+            let end = match samples.get(i + 1) {
+                Some(next) if next.when - sample.when <= 2.0*interval => next.when,
+                _ => sample.when + interval,
+            };
+            stacks.sample(&mut out, pid, tid, sample.when, end, &sample.frames)?;
+        }
+        stacks.close(&mut out, pid, tid)?;
+    }
+
+    std::fs::write(output, out.finish()?)?;
+    return Ok(samples.len());
+}
+
+/// converts folded stacks, lines like `main;parse;read 12` with frames
+/// from the root and a sample count, to a spall trace at `output`.
+/// the stacks are laid out one after another, each sample
+/// lasting `period_us` microseconds. returns the number of samples.
+pub fn import_folded(text: &str, period_us: f64, output: impl AsRef<Path>) -> Result<usize, Error> {
+    let mut out = SpallWriter::new(Vec::new(), 1.0);
+    let mut stacks = Stacks::default();
+
+    let mut when = 0.0;
+    let mut samples = 0;
+    for line in text.lines() {
+        let Some((stack, count)) = line.trim().rsplit_once(' ') else { continue };
+        let Ok(count) = count.parse::<u64>() else { continue };
+
+        let frames = stack.split(';')
+            .map(|name| Frame { name, dso: "" })
+            .collect::<Vec<_>>();
+
+        let end = when + count as f64 * period_us;
+        stacks.sample(&mut out, 0, 0, when, end, &frames)?;
+        when = end;
+        samples += count as usize;
+    }
+    stacks.close(&mut out, 0, 0)?;
+
+    if samples == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "no folded stacks"));
+    }
+
+    std::fs::write(output, out.finish()?)?;
+    return Ok(samples);
+}
+
+
+
+// parsing:
+
+struct Sample<'a> {
+    pid:  u32,
+    tid:  u32,
+    // microseconds.
+    when: f64,
+    // from the root.
+    frames: Vec<Frame<'a>>,
+}
+
+#[derive(Clone, Copy)]
+struct Frame<'a> {
+    name: &'a str,
+    dso:  &'a str,
+}
+
+// samples start with a line like
+// `worker 1234/1240 [003] 5123.456789: 250000 cpu-clock:`,
+// followed by indented frames like `7f3a1c2b parse+0x1c (/usr/bin/app)`,
+// from the leaf.
+fn parse_script(text: &str) -> Vec<Sample<'_>> {
+    let mut samples = Vec::new();
+    let mut current: Option<Sample> = None;
+
+    for line in text.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            if let Some(mut sample) = current.take() {
+                sample.frames.reverse();
+                samples.push(sample);
+            }
+            current = parse_header(line);
+            continue;
+        }
+
+        if let Some(sample) = &mut current {
+            sample.frames.push(parse_frame(line.trim()));
+        }
+    }
+
+    if let Some(mut sample) = current {
+        sample.frames.reverse();
+        samples.push(sample);
+    }
+    return samples;
+}
+
+fn parse_header(line: &str) -> Option<Sample<'_>> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+
+    // the first token like `5123.456789:`, the command may contain spaces.
+    let time = tokens.iter().position(|token| {
+        token.strip_suffix(':').is_some_and(|t| t.contains('.') && t.parse::<f64>().is_ok())
+    })?;
+    let seconds = tokens[time].strip_suffix(':')?.parse::<f64>().ok()?;
+
+    let ids = tokens[..time].iter().rev()
+        .find(|token| !token.starts_with('['))?;
+    let (pid, tid) = match ids.split_once('/') {
+        Some((pid, tid)) => (pid.parse().ok()?, tid.parse().ok()?),
+        None => {
+            let tid = ids.parse().ok()?;
+            (tid, tid)
+        }
+    };
+
+    Some(Sample { pid, tid, when: seconds * 1e6, frames: Vec::new() })
+}
+
+fn parse_frame(line: &str) -> Frame<'_> {
+    // skip the address.
+    let rest = line.split_once(char::is_whitespace).map(|(_, rest)| rest.trim()).unwrap_or(line);
+
+    let (symbol, dso) = match rest.rfind(" (") {
+        Some(paren) if rest.ends_with(')') => (&rest[..paren], &rest[paren + 2..rest.len() - 1]),
+        _ => (rest, ""),
+    };
+
+    // drop the offset, so samples of a function match.
+    let name = match symbol.rfind("+0x") {
+        Some(offset) => &symbol[..offset],
+        None         => symbol,
+    };
+    Frame { name, dso }
+}
+
+// the typical time between samples of a thread.
+fn median_interval(samples: &[Sample]) -> f64 {
+    let mut last = HashMap::new();
+    let mut intervals = Vec::new();
+    for sample in samples {
+        if let Some(prev) = last.insert((sample.pid, sample.tid), sample.when) {
+            let interval = sample.when - prev;
+            if interval > 0.0 {
+                intervals.push(interval);
+            }
+        }
+    }
+
+    if intervals.is_empty() {
+        // perf's default of 4000 Hz.
+        return 250.0;
+    }
+    intervals.sort_by(f64::total_cmp);
+    return intervals[intervals.len() / 2];
+}
+
+
+
+// the open scopes of a thread, from the root.
+#[derive(Default)]
+struct Stacks<'a> {
+    open: Vec<&'a str>,
+    end:  f64,
+}
+
+impl<'a> Stacks<'a> {
+    fn sample(&mut self, out: &mut SpallWriter<Vec<u8>>, pid: u32, tid: u32, start: f64, end: f64, frames: &[Frame<'a>]) -> Result<(), Error> {
+        // the previous sample ended before this one.
+        if start > self.end {
+            self.close(out, pid, tid)?;
+        }
+
+        let common = self.open.iter().zip(frames)
+            .take_while(|(open, frame)| **open == frame.name)
+            .count();
+
+        for _ in common..self.open.len() {
+            out.end(pid, tid, start)?;
+        }
+        self.open.truncate(common);
+
+        for frame in &frames[common..] {
+            match frame.dso {
+                ""  => out.begin(pid, tid, start, frame.name, "")?,
+                dso => {
+                    let args = crate::args::KeyValues(&[("dso", &dso)]).to_string();
+                    out.begin(pid, tid, start, frame.name, &args)?;
+                }
+            }
+            self.open.push(frame.name);
+        }
+
+        self.end = end;
+        return Ok(());
+    }
+
+    fn close(&mut self, out: &mut SpallWriter<Vec<u8>>, pid: u32, tid: u32) -> Result<(), Error> {
+        for _ in 0..self.open.len() {
+            out.end(pid, tid, self.end)?;
+        }
+        self.open.clear();
+        return Ok(());
+    }
+}
@@ -0,0 +1,194 @@
+//! export of traces as pprof profiles.
+//!
+//! scopes are aggregated by their stack of enclosing scope names, each
+//! stack weighted by its self time, so `go tool pprof` shows the usual
+//! flame graph, top and call graph views. the output is an uncompressed
+//! `profile.proto`, which pprof reads as is.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::Path;
+
+use crate::reader::Trace;
+
+
+/// writes the pprof profile of `trace` to `path`.
+pub fn export(trace: &Trace, path: impl AsRef<Path>) -> Result<(), Error> {
+    std::fs::write(path, encode(trace))
+}
+
+/// encodes `trace` as a pprof profile.
+///
+/// samples have two values, `scopes/count` and `self/nanoseconds`,
+/// and a numeric `tid` label.
+pub fn encode(trace: &Trace) -> Vec<u8> {
+    let scopes = trace.scopes();
+
+    let mut child_time = vec![0.0; scopes.len()];
+    for scope in scopes {
+        if let Some(parent) = scope.parent {
+            child_time[parent] += scope.duration();
+        }
+    }
+
+    let mut strings = Strings::default();
+    // ids are 1 based, as 0 means unset. one location per function.
+    let mut functions = HashMap::<&str, u64>::new();
+    let mut samples   = HashMap::<(Vec<u64>, u32), (i64, i64)>::new();
+
+    let mut stack = Vec::new();
+    for (index, scope) in scopes.iter().enumerate() {
+        // leaf first, as pprof expects.
+        stack.clear();
+        let mut at = Some(index);
+        while let Some(i) = at {
+            let next_id = functions.len() as u64 + 1;
+            let id = *functions.entry(&scopes[i].name).or_insert(next_id);
+            stack.push(id);
+            at = scopes[i].parent;
+        }
+
+        let self_time = (scope.duration() - child_time[index]).max(0.0);
+        let (count, nanos) = samples.entry((stack.clone(), scope.tid)).or_default();
+        *count += 1;
+        *nanos += (self_time * 1000.0).round() as i64;
+    }
+
+    let mut out = Vec::new();
+
+    // sample_type.
+    for (ty, unit) in [("scopes", "count"), ("self", "nanoseconds")] {
+        let mut value_type = Vec::new();
+        int(&mut value_type, 1, strings.index(ty));
+        int(&mut value_type, 2, strings.index(unit));
+        bytes(&mut out, 1, &value_type);
+    }
+
+    // sample, sorted for deterministic output.
+    let mut samples = samples.into_iter().collect::<Vec<_>>();
+    samples.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let tid_key = strings.index("tid");
+    for ((stack, tid), (count, nanos)) in samples {
+        let mut sample = Vec::new();
+        packed(&mut sample, 1, stack.iter().copied());
+        packed(&mut sample, 2, [count as u64, nanos as u64]);
+
+        let mut label = Vec::new();
+        int(&mut label, 1, tid_key);
+        int(&mut label, 3, tid as u64);
+        bytes(&mut sample, 3, &label);
+
+        bytes(&mut out, 2, &sample);
+    }
+
+    let mut functions = functions.into_iter().collect::<Vec<_>>();
+    functions.sort_unstable_by_key(|(_, id)| *id);
+
+    // location.
+    for (_, id) in &functions {
+        let mut line = Vec::new();
+        int(&mut line, 1, *id);
+
+        let mut location = Vec::new();
+        int(&mut location, 1, *id);
+        bytes(&mut location, 4, &line);
+        bytes(&mut out, 4, &location);
+    }
+
+    // function.
+    for (name, id) in &functions {
+        let name = strings.index(name);
+
+        let mut function = Vec::new();
+        int(&mut function, 1, *id);
+        int(&mut function, 2, name);
+        int(&mut function, 3, name);
+        bytes(&mut out, 5, &function);
+    }
+
+    // duration_nanos.
+    if let Some((first, last)) = trace.time_range() {
+        int(&mut out, 10, ((last - first) * 1000.0).round() as u64);
+    }
+
+    // period_type and period: one scope.
+    let mut period_type = Vec::new();
+    int(&mut period_type, 1, strings.index("scopes"));
+    int(&mut period_type, 2, strings.index("count"));
+    bytes(&mut out, 11, &period_type);
+    int(&mut out, 12, 1);
+
+    // default_sample_type.
+    int(&mut out, 14, strings.index("self"));
+
+    // string_table, last so it has everything. field order doesn't matter.
+    for string in &strings.table {
+        bytes(&mut out, 6, string.as_bytes());
+    }
+
+    return out;
+}
+
+
+
+// protobuf encoding:
+
+// the string table. index 0 must be the empty string.
+struct Strings {
+    table:   Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self {
+            table:   vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl Strings {
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(index) = self.indices.get(string) {
+            return *index;
+        }
+
+        let index = self.table.len() as u64;
+        self.table.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        return index;
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// varint field. zero values are the default, so they're omitted.
+fn int(out: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        varint(out, (field as u64) << 3);
+        varint(out, value);
+    }
+}
+
+// length delimited field.
+fn bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    varint(out, (field as u64) << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn packed(out: &mut Vec<u8>, field: u32, values: impl IntoIterator<Item = u64>) {
+    let mut data = Vec::new();
+    for value in values {
+        varint(&mut data, value);
+    }
+    bytes(out, field, &data);
+}
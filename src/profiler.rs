@@ -0,0 +1,176 @@
+//! sampling profiler.
+//!
+//! `start` makes the os interrupt threads with `SIGPROF` as they use cpu
+//! time. each interrupt captures the thread's backtrace into a small
+//! per-thread queue, without allocating or touching the trace buffer.
+//! when the thread next flushes, the samples are symbolized and recorded
+//! as `spall/sample` markers at the time they were taken, with the folded
+//! stack as args, like `main;app::update;app::physics::step`.
+//! stacks too long for the args are cut at the root, like `...;step`.
+//!
+//! samples are only recorded when the thread flushes, and each thread
+//! queues at most 32 samples in between. combine this with
+//! `Options::flush_interval` for long-running threads.
+
+use std::cell::UnsafeCell;
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+
+const MAX_FRAMES: usize = 48;
+const QUEUE_LEN:  usize = 32;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    when:   u64,
+    len:    usize,
+    frames: [usize; MAX_FRAMES],
+}
+
+// written by the signal handler, read when the same thread flushes.
+struct Queue {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    samples: UnsafeCell<[Sample; QUEUE_LEN]>,
+}
+
+thread_local! {
+    // const, so accessing it from the signal handler doesn't allocate.
+    static QUEUE: Queue = const { Queue {
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        samples: UnsafeCell::new([Sample { when: 0, len: 0, frames: [0; MAX_FRAMES] }; QUEUE_LEN]),
+    } };
+}
+
+
+/// starts sampling at about `frequency` samples per second of cpu time.
+/// replaces any existing `SIGPROF` handler.
+pub fn start(frequency: u32) -> Result<(), Error> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigprof as extern "C" fn(libc::c_int) as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGPROF, &action, std::ptr::null_mut()) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    let interval = Duration::from_secs(1) / frequency.max(1);
+    return set_timer(interval);
+}
+
+/// stops sampling. samples that were already taken are still recorded.
+pub fn stop() -> Result<(), Error> {
+    set_timer(Duration::ZERO)?;
+    unsafe {
+        libc::signal(libc::SIGPROF, libc::SIG_IGN);
+    }
+    return Ok(());
+}
+
+fn set_timer(interval: Duration) -> Result<(), Error> {
+    let interval = libc::timeval {
+        tv_sec:  interval.as_secs() as _,
+        tv_usec: interval.subsec_micros() as _,
+    };
+    let timer = libc::itimerval { it_interval: interval, it_value: interval };
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, std::ptr::null_mut()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    return Ok(());
+}
+
+extern "C" fn on_sigprof(_: libc::c_int) {
+    let when = crate::now();
+
+    _ = QUEUE.try_with(|queue| {
+        let head = queue.head.load(Ordering::Relaxed);
+        let tail = queue.tail.load(Ordering::Acquire);
+        if head - tail >= QUEUE_LEN {
+            return;
+        }
+
+        let sample = unsafe { &mut (*queue.samples.get())[head % QUEUE_LEN] };
+        sample.when = when;
+        sample.len  = 0;
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                sample.frames[sample.len] = frame.ip() as usize;
+                sample.len += 1;
+                sample.len < MAX_FRAMES
+            });
+        }
+
+        queue.head.store(head + 1, Ordering::Release);
+    });
+}
+
+
+// takes the queued samples of the current thread.
+// returns the timestamp and folded stack of each.
+pub(crate) fn take_samples() -> Vec<(u64, String)> {
+    let mut samples = Vec::new();
+    _ = QUEUE.try_with(|queue| {
+        let head = queue.head.load(Ordering::Acquire);
+        let mut tail = queue.tail.load(Ordering::Relaxed);
+        while tail != head {
+            let sample = unsafe { (*queue.samples.get())[tail % QUEUE_LEN] };
+            tail += 1;
+            queue.tail.store(tail, Ordering::Release);
+            samples.push(sample);
+        }
+    });
+
+    return samples.iter().map(|s| (s.when, fold(&s.frames[..s.len]))).collect();
+}
+
+// `root;...;leaf`, cut at the root to fit into the args.
+fn fold(frames: &[usize]) -> String {
+    let mut names = Vec::new();
+    for &ip in frames {
+        backtrace::resolve(ip as *mut _, |symbol| {
+            if let Some(name) = symbol.name() {
+                names.push(format!("{:#}", name));
+            }
+        });
+    }
+
+    // skip the handler's frames.
+    let skip = names.iter()
+        .rposition(|n| n.starts_with("spall::profiler::") || n.starts_with("backtrace::"))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let names = names.get(skip..).unwrap_or(&[]);
+    let names = match names.first() {
+        // the signal trampoline.
+        Some(n) if n.contains("restore_rt") || n.contains("sigtramp") => &names[1..],
+        _ => names,
+    };
+
+    let max_len = 255;
+    let mut len = 0;
+    let mut count = 0;
+    for name in names {
+        let next = len + name.len() + (count > 0) as usize;
+        if next > max_len - 4 {
+            break;
+        }
+        len = next;
+        count += 1;
+    }
+
+    let mut result = String::with_capacity(len + 4);
+    if count < names.len() {
+        result.push_str("...;");
+    }
+    for (i, name) in names[..count].iter().rev().enumerate() {
+        if i > 0 {
+            result.push(';');
+        }
+        result.push_str(name);
+    }
+    return result;
+}
@@ -0,0 +1,129 @@
+// writing on a background thread, for `Options::overflow`.
+//
+// threads hand their flushed buffers to a bounded queue, which one
+// writer thread drains in order, so events of a thread stay in order.
+// a thread whose buffer fills while the queue is full blocks, drops
+// events, or grows its buffer, see `Overflow`. each write keeps its
+// file open, so a file is only terminated after its queued writes.
+// the writer is quiet, so an error handler that records events can't
+// wait for the queue it drains.
+
+use std::collections::VecDeque;
+use std::io::Write as _;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::TraceFile;
+use crate::buffer::Buffer;
+
+
+// writes in flight or waiting, at most.
+const CAPACITY: usize = 16;
+
+pub(crate) enum Data {
+    // the first `len` bytes, padded already for direct i/o.
+    Buffer(Buffer, usize),
+    // padded when written, like by `write_file`.
+    Bytes(Vec<u8>),
+}
+
+pub(crate) struct Write {
+    pub file:   Arc<TraceFile>,
+    pub data:   Data,
+    pub silent: bool,
+}
+
+impl Write {
+    fn run(self) {
+        let res = match &self.data {
+            Data::Buffer(buffer, len) => (&self.file.file).write_all(buffer.head(*len)),
+            Data::Bytes(bytes)        => crate::write_file(&self.file.file, self.file.direct, bytes).map(drop),
+        };
+        if let Err(e) = res {
+            crate::report(self.silent, e.kind(), format_args!("spall file write failed {:?}", e));
+        }
+    }
+}
+
+
+struct Queue {
+    writes:  VecDeque<Write>,
+    // whether the writer is running a write.
+    busy:    bool,
+    started: bool,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    writes:  VecDeque::new(),
+    busy:    false,
+    started: false,
+});
+
+// signaled when a write is queued, and when one is done.
+static QUEUED: Condvar = Condvar::new();
+static DONE:   Condvar = Condvar::new();
+
+// held by `testing::hold_writer`, the writer waits for it before each write.
+pub(crate) static HOLD: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, Queue> {
+    QUEUE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn is_full() -> bool {
+    let queue = lock();
+    queue.writes.len() + queue.busy as usize >= CAPACITY
+}
+
+// queues a write, waiting while the queue is full.
+// writes right away if the writer can't be started.
+pub(crate) fn push(write: Write) {
+    let mut queue = lock();
+    if !queue.started {
+        let spawned = std::thread::Builder::new()
+            .name("spall/writer".into())
+            .spawn(run);
+        if let Err(e) = spawned {
+            drop(queue);
+            crate::report(write.silent, e.kind(), format_args!("spall writer thread failed to start {:?}", e));
+            write.run();
+            return;
+        }
+        queue.started = true;
+    }
+
+    while queue.writes.len() + queue.busy as usize >= CAPACITY {
+        queue = DONE.wait(queue).unwrap_or_else(|e| e.into_inner());
+    }
+    queue.writes.push_back(write);
+    QUEUED.notify_one();
+}
+
+// waits until all queued writes are done.
+pub(crate) fn sync() {
+    let mut queue = lock();
+    while !queue.writes.is_empty() || queue.busy {
+        queue = DONE.wait(queue).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+fn run() {
+    let _quiet = crate::quiet();
+
+    let mut queue = lock();
+    loop {
+        let Some(write) = queue.writes.pop_front() else {
+            queue = QUEUED.wait(queue).unwrap_or_else(|e| e.into_inner());
+            continue;
+        };
+        queue.busy = true;
+        drop(queue);
+
+        // may drop the last reference to the file, which terminates it.
+        drop(HOLD.lock().unwrap_or_else(|e| e.into_inner()));
+        write.run();
+
+        queue = lock();
+        queue.busy = false;
+        DONE.notify_all();
+    }
+}
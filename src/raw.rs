@@ -0,0 +1,75 @@
+//! writing raw event bytes into the current thread's buffer.
+//!
+//! for extending the format without forking spall, like attaching
+//! custom payloads with `custom_data`, or emitting events the regular
+//! api doesn't have. `write` gives direct access to the buffer,
+//! so nothing checks that the bytes make sense.
+
+use std::mem::size_of;
+
+use crate::{CustomDataEvent, EventType, ThreadState};
+
+
+/// how the current thread's events are recorded,
+/// for building events like spall's own.
+#[derive(Clone, Copy, Debug)]
+pub struct Thread {
+    pub pid: u32,
+    pub tid: u32,
+    time_base: u64,
+}
+
+impl Thread {
+    /// the `when` of an event at `ticks`, a value of `spall::now`.
+    #[inline]
+    pub fn when(&self, ticks: u64) -> f64 {
+        ticks.saturating_sub(self.time_base) as f64
+    }
+}
+
+/// reserves `size` bytes in the current thread's buffer
+/// and calls `f` to fill them in.
+///
+/// returns false without calling `f` if there is no trace, or `size`
+/// doesn't fit in the buffer. events recorded from within `f` are dropped.
+///
+/// # Safety
+///
+/// `f` must initialize all `size` bytes behind the pointer, and nothing
+/// beyond them. the bytes should be complete events, see the structs at
+/// the crate root. anything else makes the rest of the buffer unreadable,
+/// except for `Trace::parse_lossy`.
+#[inline]
+pub unsafe fn write(size: usize, f: impl FnOnce(*mut u8, &Thread)) -> bool {
+    ThreadState::with(|s| {
+        let thread = Thread { pid: s.pid, tid: s.tid, time_base: s.time_base };
+        unsafe { s.push_raw(size, |ptr| f(ptr, &thread)) }
+    }).unwrap_or(false)
+}
+
+/// records a `CustomData` event, with a payload of `tag`
+/// as 4 little endian bytes followed by `data`.
+///
+/// tags tell custom data of different tools apart,
+/// see `Trace::custom_data_tagged`.
+/// returns false if nothing was recorded.
+pub fn custom_data(tag: u32, data: &[u8]) -> bool {
+    let payload = size_of::<u32>() + data.len();
+    let Ok(event_size) = u32::try_from(payload) else { return false };
+
+    let size = size_of::<CustomDataEvent>() + payload;
+    unsafe {
+        write(size, |ptr, _| {
+            crate::write_to_ptr(ptr, CustomDataEvent {
+                ty:   EventType::CustomData as u8,
+                size: event_size,
+            });
+
+            let ptr = ptr.add(size_of::<CustomDataEvent>());
+            std::ptr::copy_nonoverlapping(tag.to_le_bytes().as_ptr(), ptr, size_of::<u32>());
+
+            let ptr = ptr.add(size_of::<u32>());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        })
+    }
+}
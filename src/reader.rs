@@ -0,0 +1,1425 @@
+//! reader for spall binary traces.
+//!
+//! `Parser` walks the raw event stream of a trace without allocating.
+//! `Trace` loads a whole file, reconstructs the per-thread scope stacks,
+//! and builds indexes so interactive tools can query time ranges, threads,
+//! and scope names without rescanning the file.
+//! large traces are parsed in chunks on several threads,
+//! see `Trace::parse_parallel`.
+//! `Trace::parse_lossy` recovers what it can from damaged traces,
+//! like those of crashed processes.
+//! `Mmap` and `scope_refs` stream the scopes of traces too large to load,
+//! borrowing their names and args from the mapped file.
+//! `merge` combines trace files, `trim` cuts a time window out of one.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::{read_at, SpallHeader, EventType, BeginEvent, EndEvent, OverwriteTimestampEvent, PadSkipEvent, CustomDataEvent, SpallWriter};
+
+
+#[inline]
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[inline]
+fn truncated(offset: usize) -> Error {
+    Error::new(ErrorKind::UnexpectedEof, format!("truncated event at offset {}", offset))
+}
+
+
+
+// raw parsing:
+
+/// an event as it appears in the file.
+/// names and args borrow from the trace bytes.
+/// `when` is in raw timestamp units, see `Parser::timestamp_unit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawEvent<'a> {
+    Begin {
+        category: u8,
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+        name: &'a [u8],
+        args: &'a [u8],
+    },
+
+    End {
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+    },
+
+    /// replaces the timestamp unit of the whole stream,
+    /// including the events before it.
+    OverwriteTimestamp {
+        timestamp_unit: f64,
+    },
+
+    /// an opaque payload, for tools that understand it.
+    CustomData {
+        data: &'a [u8],
+    },
+}
+
+#[derive(Clone)]
+pub struct Parser<'a> {
+    data:   &'a [u8],
+    offset: usize,
+    timestamp_unit: f64,
+    failed: bool,
+    finished: bool,
+}
+
+impl<'a> Parser<'a> {
+    /// validates the header and positions the parser at the first event.
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let header = read_at::<SpallHeader>(data, 0)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "spall header truncated"))?;
+
+        let magic   = header.magic_header;
+        let version = header.version;
+        let unit    = header.timestamp_unit;
+        let zero    = header.must_be_0;
+        if magic != 0x0BADF00D || zero != 0 {
+            return Err(invalid("not a spall trace".into()));
+        }
+        if version != 1 {
+            return Err(invalid(format!("unsupported spall version {}", version)));
+        }
+
+        Ok(Self {
+            data,
+            offset: size_of::<SpallHeader>(),
+            timestamp_unit: unit,
+            failed: false,
+            finished: false,
+        })
+    }
+
+    /// microseconds per raw timestamp unit, according to the header.
+    #[inline]
+    pub fn timestamp_unit(&self) -> f64 {
+        self.timestamp_unit
+    }
+
+    /// byte offset of the next event.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// whether the parser reached a `StreamOver` event.
+    /// traces without one weren't closed properly,
+    /// or are still being written.
+    #[inline]
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    // `None` for padding.
+    fn parse_event(&mut self) -> Result<Option<RawEvent<'a>>, Error> {
+        let data   = self.data;
+        let offset = self.offset;
+        let truncated = || truncated(offset);
+
+        let ty = data[offset];
+        if ty == EventType::Begin as u8 {
+            let begin = read_at::<BeginEvent>(data, offset).ok_or_else(truncated)?;
+            let name_begin = offset + size_of::<BeginEvent>();
+            let args_begin = name_begin + begin.name_len as usize;
+            let args_end   = args_begin + begin.args_len as usize;
+            if args_end > data.len() {
+                return Err(truncated());
+            }
+
+            self.offset = args_end;
+            return Ok(Some(RawEvent::Begin {
+                category: begin.category,
+                pid:  begin.pid,
+                tid:  begin.tid,
+                when: begin.when,
+                name: &data[name_begin..args_begin],
+                args: &data[args_begin..args_end],
+            }));
+        }
+        else if ty == EventType::End as u8 {
+            let end = read_at::<EndEvent>(data, offset).ok_or_else(truncated)?;
+            self.offset = offset + size_of::<EndEvent>();
+            return Ok(Some(RawEvent::End {
+                pid:  end.pid,
+                tid:  end.tid,
+                when: end.when,
+            }));
+        }
+        else if ty == EventType::OverwriteTimestamp as u8 {
+            let event = read_at::<OverwriteTimestampEvent>(data, offset).ok_or_else(truncated)?;
+            self.offset = offset + size_of::<OverwriteTimestampEvent>();
+            return Ok(Some(RawEvent::OverwriteTimestamp {
+                timestamp_unit: event.timestamp_unit,
+            }));
+        }
+        else if ty == EventType::PadSkip as u8 {
+            let event = read_at::<PadSkipEvent>(data, offset).ok_or_else(truncated)?;
+            let end = offset + size_of::<PadSkipEvent>() + event.size as usize;
+            if end > data.len() {
+                return Err(truncated());
+            }
+            self.offset = end;
+            return Ok(None);
+        }
+        else if ty == EventType::CustomData as u8 {
+            let event = read_at::<CustomDataEvent>(data, offset).ok_or_else(truncated)?;
+            let begin = offset + size_of::<CustomDataEvent>();
+            let end   = begin + event.size as usize;
+            if end > data.len() {
+                return Err(truncated());
+            }
+            self.offset = end;
+            return Ok(Some(RawEvent::CustomData { data: &data[begin..end] }));
+        }
+        else {
+            return Err(invalid(format!("unknown event type {} at offset {}", ty, offset)));
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    // steps over the next event, reading only its type and size,
+    // for `parse_events_parallel` to find event boundaries.
+    // false at the end of the events.
+    fn skip_event(&mut self) -> Result<bool, Error> {
+        let data   = self.data;
+        let offset = self.offset;
+        let truncated = || truncated(offset);
+
+        let Some(&ty) = data.get(offset) else { return Ok(false) };
+        let end = if ty == EventType::Begin as u8 {
+            let lens = std::mem::offset_of!(BeginEvent, name_len);
+            let lens = data.get(offset + lens .. offset + size_of::<BeginEvent>()).ok_or_else(truncated)?;
+            offset + size_of::<BeginEvent>() + lens[0] as usize + lens[1] as usize
+        }
+        else if ty == EventType::End as u8 {
+            offset + size_of::<EndEvent>()
+        }
+        else if ty == EventType::OverwriteTimestamp as u8 {
+            offset + size_of::<OverwriteTimestampEvent>()
+        }
+        else if ty == EventType::PadSkip as u8 {
+            let size = read_at::<u32>(data, offset + std::mem::offset_of!(PadSkipEvent, size)).ok_or_else(truncated)?;
+            offset + size_of::<PadSkipEvent>() + size as usize
+        }
+        else if ty == EventType::CustomData as u8 {
+            let size = read_at::<u32>(data, offset + std::mem::offset_of!(CustomDataEvent, size)).ok_or_else(truncated)?;
+            offset + size_of::<CustomDataEvent>() + size as usize
+        }
+        else if ty == EventType::StreamOver as u8 {
+            self.offset   = data.len();
+            self.finished = true;
+            return Ok(false);
+        }
+        else {
+            return Err(invalid(format!("unknown event type {} at offset {}", ty, offset)));
+        };
+        if end > data.len() {
+            return Err(truncated());
+        }
+
+        self.offset = end;
+        return Ok(true);
+    }
+
+    /// after an error, skips ahead to the next offset that looks like
+    /// the start of an event, so parsing can continue.
+    /// returns false and skips to the end if there is none.
+    pub fn resync(&mut self) -> bool {
+        self.failed = false;
+        for offset in self.offset + 1 .. self.data.len() {
+            if self.plausible_at(offset) {
+                self.offset = offset;
+                return true;
+            }
+        }
+        self.offset = self.data.len();
+        return false;
+    }
+
+    // whether a sane event starts at `offset`,
+    // followed by another event or the end of the data.
+    fn plausible_at(&self, offset: usize) -> bool {
+        let mut parser = Self { offset, ..self.clone() };
+        let sane = match parser.parse_event() {
+            Ok(Some(RawEvent::Begin { when, .. })) |
+            Ok(Some(RawEvent::End   { when, .. })) => when.is_finite() && when >= 0.0,
+
+            Ok(Some(RawEvent::OverwriteTimestamp { timestamp_unit })) =>
+                timestamp_unit.is_finite() && timestamp_unit > 0.0,
+
+            // sizes are unchecked, so these are only as plausible as what follows.
+            Ok(Some(RawEvent::CustomData { .. })) | Ok(None) => true,
+
+            Err(_) => false,
+        };
+        if !sane {
+            return false;
+        }
+
+        let Some(&next) = self.data.get(parser.offset) else { return true };
+        return is_event_type(next);
+    }
+}
+
+fn is_event_type(ty: u8) -> bool {
+    ty > EventType::Invalid as u8 && ty <= EventType::PadSkip as u8
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<RawEvent<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.failed || self.offset >= self.data.len() {
+                return None;
+            }
+
+            if self.data[self.offset] == EventType::StreamOver as u8 {
+                self.offset   = self.data.len();
+                self.finished = true;
+                return None;
+            }
+
+            match self.parse_event() {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => continue,
+
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+
+
+// loaded traces:
+
+/// an owned event. `when` is in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Begin {
+        category: u8,
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+        name: String,
+        args: String,
+    },
+
+    End {
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+    },
+}
+
+impl Event {
+    #[inline]
+    pub fn when(&self) -> f64 {
+        match self {
+            Event::Begin { when, .. } => *when,
+            Event::End   { when, .. } => *when,
+        }
+    }
+
+    /// `(pid, tid)`
+    #[inline]
+    pub fn thread(&self) -> (u32, u32) {
+        match self {
+            Event::Begin { pid, tid, .. } => (*pid, *tid),
+            Event::End   { pid, tid, .. } => (*pid, *tid),
+        }
+    }
+}
+
+
+/// a matched begin/end pair. times are in microseconds.
+///
+/// scopes that were never closed end at the last timestamp
+/// of their thread, like the viewer does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scope {
+    pub pid:   u32,
+    pub tid:   u32,
+    pub start: f64,
+    pub end:   f64,
+    pub depth: u32,
+    pub name:  String,
+    pub args:  String,
+    /// the begin event's category, the cpu with `Options::record_cpu`.
+    pub category: u8,
+    /// index of the enclosing scope in `Trace::scopes`.
+    pub parent: Option<usize>,
+}
+
+impl Scope {
+    #[inline]
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+
+    /// the id of a scope from `trace_scope_id`.
+    pub fn id(&self) -> Option<crate::ScopeId> {
+        crate::args::parse(&self.args).into_iter()
+            .find(|(key, _)| *key == "scope_id")
+            .and_then(|(_, id)| id.parse().ok())
+            .map(crate::ScopeId)
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Thread {
+    pub pid: u32,
+    pub tid: u32,
+    /// indices into `Trace::events`, ordered by time.
+    pub events: Vec<usize>,
+    /// indices into `Trace::scopes`, ordered by start time.
+    pub scopes: Vec<usize>,
+}
+
+
+// the events of a file, with timestamps converted using the final unit.
+struct Parsed {
+    unit:   f64,
+    events: Vec<Event>,
+    custom: Vec<Vec<u8>>,
+}
+
+// traces at least this large are parsed on several threads.
+const PARALLEL_MIN_SIZE: usize = 16 << 20;
+
+// more chunks than threads, so threads that finish early can take another.
+const CHUNKS_PER_THREAD: usize = 4;
+
+// the events of a file, or a part of it, with raw timestamps.
+// they're converted at the end, once the final unit is known,
+// and names recorded as ids are resolved, as the dictionary
+// may come after them.
+struct Chunk {
+    events: Vec<Event>,
+    custom: Vec<Vec<u8>>,
+    names:  HashMap<u32, String>,
+    // names recorded as ids, by event index.
+    ids:    Vec<(usize, u32)>,
+    // the last unit overwrite.
+    unit:   Option<f64>,
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk { events: Vec::new(), custom: Vec::new(), names: HashMap::new(), ids: Vec::new(), unit: None }
+    }
+
+    fn parse(parser: Parser) -> Result<Chunk, Error> {
+        let mut chunk = Chunk::new();
+        for event in parser {
+            chunk.push(event?);
+        }
+        return Ok(chunk);
+    }
+
+    fn push(&mut self, event: RawEvent) {
+        let event = match event {
+            RawEvent::Begin { category, pid, tid, when, name, args } => {
+                let name = match crate::name::parse_id(name) {
+                    Some(id) => {
+                        self.ids.push((self.events.len(), id));
+                        String::new()
+                    }
+                    None => String::from_utf8_lossy(name).into_owned(),
+                };
+                Event::Begin {
+                    category, pid, tid, when, name,
+                    args: String::from_utf8_lossy(args).into_owned(),
+                }
+            }
+
+            RawEvent::End { pid, tid, when } =>
+                Event::End { pid, tid, when },
+
+            RawEvent::OverwriteTimestamp { timestamp_unit } => {
+                self.unit = Some(timestamp_unit);
+                return;
+            }
+
+            RawEvent::CustomData { data } => {
+                if let Some((id, name)) = crate::name::parse_entry(data) {
+                    self.names.insert(id, String::from_utf8_lossy(name).into_owned());
+                }
+                self.custom.push(data.to_vec());
+                return;
+            }
+        };
+        self.events.push(event);
+    }
+}
+
+// names the events recorded with ids, and converts timestamps
+// to microseconds, once the whole file was read.
+fn resolve(events: &mut [Event], unit: f64, names: &HashMap<u32, String>, ids: Vec<(usize, u32)>) {
+    for (index, id) in ids {
+        if let Event::Begin { name, .. } = &mut events[index] {
+            *name = match names.get(&id) {
+                Some(known) => known.clone(),
+                None        => format!("spall/name/{}", id),
+            };
+        }
+    }
+
+    for event in events {
+        match event {
+            Event::Begin { when, .. } => *when *= unit,
+            Event::End   { when, .. } => *when *= unit,
+        }
+    }
+}
+
+impl Parsed {
+    fn into_trace(self) -> Trace {
+        let mut trace = Trace::from_events(self.unit, self.events);
+        trace.custom_data = self.custom;
+        return trace;
+    }
+}
+
+
+/// what `Trace::parse_lossy` recovered. sizes are in bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Salvage {
+    /// events that were read.
+    pub events: usize,
+    /// size of the events that were read.
+    pub recovered: usize,
+    /// corrupt ranges that were skipped.
+    pub skipped: Vec<Range<usize>>,
+    /// size of the torn event at the end, if any.
+    pub torn: usize,
+    /// what checkpoints confirm, by thread.
+    /// empty for traces recorded without `Options::checkpoints`.
+    pub threads: Vec<ThreadSalvage>,
+}
+
+impl Salvage {
+    /// whether the trace was undamaged.
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty() && self.torn == 0
+    }
+
+    pub fn lost(&self) -> usize {
+        self.torn + self.skipped.iter().map(|r| r.len()).sum::<usize>()
+    }
+}
+
+/// a thread's checkpoints in a damaged trace, see `checkpoint`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadSalvage {
+    pub pid: u32,
+    pub tid: u32,
+    /// the sequence number of the last checkpoint, 0 if there was none.
+    pub last_checkpoint: u64,
+    /// when the last complete flush started, in microseconds.
+    /// events the thread recorded after it may be lost.
+    pub complete_until: Option<f64>,
+    /// checkpoints missing before the last one, of flushes that were
+    /// corrupted or never written.
+    pub missing: u64,
+    /// flushes with a checkpoint, but corrupt data before it,
+    /// see `Salvage::skipped`.
+    pub damaged: u64,
+    /// events no checkpoint confirms, from a flush that didn't complete.
+    pub unconfirmed_events: usize,
+    /// their size.
+    pub unconfirmed_bytes: usize,
+}
+
+
+pub struct Trace {
+    timestamp_unit: f64,
+    events:  Vec<Event>,
+    custom_data: Vec<Vec<u8>>,
+    scopes:  Vec<Scope>,
+    threads: Vec<Thread>,
+
+    // indexes.
+    events_by_time:  Vec<usize>,
+    // running max of `end` over `scopes`.
+    scopes_max_end:  Vec<f64>,
+    scopes_by_name:  HashMap<String, Vec<usize>>,
+}
+
+impl Trace {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let data = std::fs::read(path)?;
+        Self::parse(&data)
+    }
+
+    /// loads several traces as one, like the per-thread files of a run.
+    /// the result uses the timestamp unit of the first trace.
+    pub fn open_many<P: AsRef<Path>>(paths: &[P]) -> Result<Self, Error> {
+        let mut unit   = None;
+        let mut events = Vec::new();
+        let mut custom = Vec::new();
+        for path in paths {
+            let data = std::fs::read(path)?;
+            let parsed = Self::parse_events(&data)?;
+            unit.get_or_insert(parsed.unit);
+            events.extend(parsed.events);
+            custom.extend(parsed.custom);
+        }
+        let mut trace = Self::from_events(unit.unwrap_or(1.0), events);
+        trace.custom_data = custom;
+        return Ok(trace);
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        return Ok(Self::parse_events(data)?.into_trace());
+    }
+
+    pub fn open_lossy(path: impl AsRef<Path>) -> Result<(Self, Salvage), Error> {
+        let data = std::fs::read(path)?;
+        Self::parse_lossy(&data)
+    }
+
+    /// like `parse`, but skips damaged data instead of failing,
+    /// as long as the header is intact.
+    /// a torn event at the end is dropped, and parsing resumes
+    /// after corrupt bytes at the next plausible event.
+    pub fn parse_lossy(data: &[u8]) -> Result<(Self, Salvage), Error> {
+        let mut salvage = Salvage::default();
+        let parsed = Self::parse_events_with(data, Some(&mut salvage))?;
+        return Ok((parsed.into_trace(), salvage));
+    }
+
+    /// like `parse`, on `threads` threads.
+    /// `parse` and `open` do this for large traces.
+    pub fn parse_parallel(data: &[u8], threads: usize) -> Result<Self, Error> {
+        return Ok(Self::parse_events_parallel(data, threads)?.into_trace());
+    }
+
+    fn parse_events(data: &[u8]) -> Result<Parsed, Error> {
+        if data.len() >= PARALLEL_MIN_SIZE {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            if threads > 1 {
+                return Self::parse_events_parallel(data, threads);
+            }
+        }
+        Self::parse_events_with(data, None)
+    }
+
+    // finds event boundaries to split the data at, with a quick pass
+    // that only skips over the events, parses the chunks between them
+    // on several threads, then concatenates their events in order.
+    fn parse_events_parallel(data: &[u8], threads: usize) -> Result<Parsed, Error> {
+        let mut parser = Parser::new(data)?;
+        let header_unit = parser.timestamp_unit();
+        let chunk_size = data.len() / (threads.max(1) * CHUNKS_PER_THREAD) + 1;
+
+        let mut bounds = vec![parser.offset()];
+        let mut end = parser.offset();
+        while parser.skip_event()? {
+            end = parser.offset();
+            if end - bounds.last().unwrap() >= chunk_size {
+                bounds.push(end);
+            }
+        }
+        if *bounds.last().unwrap() != end {
+            bounds.push(end);
+        }
+
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let chunks = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..threads.max(1).min(bounds.len() - 1) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if i + 1 >= bounds.len() {
+                        break;
+                    }
+
+                    let parser = Parser {
+                        data: &data[..bounds[i + 1]],
+                        offset: bounds[i],
+                        timestamp_unit: header_unit,
+                        failed: false,
+                        finished: false,
+                    };
+                    let chunk = Chunk::parse(parser);
+                    chunks.lock().unwrap().push((i, chunk));
+                });
+            }
+        });
+        let mut chunks = chunks.into_inner().unwrap();
+        chunks.sort_by_key(|(i, _)| *i);
+
+        let mut unit   = header_unit;
+        let mut events = Vec::with_capacity(chunks.iter().map(|(_, c)| c.as_ref().map_or(0, |c| c.events.len())).sum());
+        let mut custom = Vec::new();
+        let mut names  = HashMap::new();
+        let mut ids    = Vec::new();
+        for (_, chunk) in chunks {
+            let chunk = chunk?;
+            unit = chunk.unit.unwrap_or(unit);
+            ids.extend(chunk.ids.into_iter().map(|(index, id)| (events.len() + index, id)));
+            events.extend(chunk.events);
+            custom.extend(chunk.custom);
+            names.extend(chunk.names);
+        }
+
+        resolve(&mut events, unit, &names, ids);
+        return Ok(Parsed { unit, events, custom });
+    }
+
+    fn parse_events_with(data: &[u8], mut salvage: Option<&mut Salvage>) -> Result<Parsed, Error> {
+        let mut parser = Parser::new(data)?;
+        let mut chunk = Chunk::new();
+        // for `Salvage::threads`, events since the last checkpoint,
+        // as offset, size, and thread.
+        let mut unchecked = Vec::<(usize, usize, (u32, u32))>::new();
+        let mut threads = HashMap::<(u32, u32), ThreadSalvage>::new();
+        let mut any_checkpoints = false;
+        loop {
+            let offset = parser.offset();
+            let Some(event) = parser.next() else { break };
+
+            let event = match (event, salvage.as_deref_mut()) {
+                (Ok(event), salvage) => {
+                    if let Some(salvage) = salvage {
+                        salvage.events    += 1;
+                        salvage.recovered += parser.offset() - offset;
+
+                        match event {
+                            RawEvent::Begin { pid, tid, .. } | RawEvent::End { pid, tid, .. } =>
+                                unchecked.push((offset, parser.offset() - offset, (pid, tid))),
+
+                            RawEvent::CustomData { data } => {
+                                if let Some(checkpoint) = crate::checkpoint::parse(data) {
+                                    any_checkpoints = true;
+                                    let flushed = offset.saturating_sub(checkpoint.len as usize);
+                                    for (offset, size, thread) in unchecked.drain(..) {
+                                        if offset < flushed {
+                                            let thread = threads.entry(thread).or_default();
+                                            thread.unconfirmed_events += 1;
+                                            thread.unconfirmed_bytes  += size;
+                                        }
+                                    }
+
+                                    let thread = threads.entry((checkpoint.pid, checkpoint.tid)).or_default();
+                                    // numbered from 1 in each file.
+                                    let expected = thread.last_checkpoint + 1;
+                                    thread.missing += checkpoint.seq.saturating_sub(expected);
+                                    thread.last_checkpoint = checkpoint.seq;
+                                    if salvage.skipped.last().is_some_and(|skipped| skipped.end > flushed) {
+                                        thread.damaged += 1;
+                                    }
+                                    thread.complete_until = Some(checkpoint.when);
+                                }
+                            }
+
+                            RawEvent::OverwriteTimestamp { .. } => (),
+                        }
+                    }
+                    event
+                }
+
+                (Err(e), None) => return Err(e),
+
+                (Err(e), Some(salvage)) => {
+                    // nothing after a torn event can be valid.
+                    if e.kind() == ErrorKind::UnexpectedEof {
+                        salvage.torn = data.len() - offset;
+                        break;
+                    }
+                    parser.resync();
+                    salvage.skipped.push(offset..parser.offset());
+                    continue;
+                }
+            };
+
+            chunk.push(event);
+        }
+
+        let unit = chunk.unit.unwrap_or(parser.timestamp_unit());
+        let Chunk { mut events, custom, names, ids, .. } = chunk;
+        resolve(&mut events, unit, &names, ids);
+
+        if let Some(salvage) = salvage.filter(|_| any_checkpoints) {
+            for (_, size, thread) in unchecked {
+                let thread = threads.entry(thread).or_default();
+                thread.unconfirmed_events += 1;
+                thread.unconfirmed_bytes  += size;
+            }
+
+            let mut threads = threads.into_iter()
+                .map(|((pid, tid), thread)| ThreadSalvage {
+                    pid, tid,
+                    complete_until: thread.complete_until.map(|when| when * unit),
+                    ..thread
+                })
+                .collect::<Vec<_>>();
+            threads.sort_by_key(|thread| (thread.pid, thread.tid));
+            salvage.threads = threads;
+        }
+
+        return Ok(Parsed { unit, events, custom });
+    }
+
+    /// builds the scopes and indexes for a list of events.
+    /// events of a thread may come in any order, as long as
+    /// events with equal timestamps are in recording order.
+    pub fn from_events(timestamp_unit: f64, events: Vec<Event>) -> Self {
+        let mut events_by_time = (0..events.len()).collect::<Vec<_>>();
+        events_by_time.sort_by(|a, b| events[*a].when().total_cmp(&events[*b].when()));
+
+        let mut scopes  = Vec::<Scope>::new();
+        let mut threads = Vec::<Thread>::new();
+        let mut thread_map = HashMap::<(u32, u32), usize>::new();
+        let mut stacks = Vec::<Vec<usize>>::new();
+        let mut last_when = Vec::<f64>::new();
+
+        for &index in &events_by_time {
+            let event = &events[index];
+            let (pid, tid) = event.thread();
+            let thread = *thread_map.entry((pid, tid)).or_insert_with(|| {
+                threads.push(Thread { pid, tid, events: Vec::new(), scopes: Vec::new() });
+                stacks.push(Vec::new());
+                last_when.push(f64::NEG_INFINITY);
+                threads.len() - 1
+            });
+            threads[thread].events.push(index);
+            last_when[thread] = last_when[thread].max(event.when());
+
+            let stack = &mut stacks[thread];
+            match event {
+                Event::Begin { category, when, name, args, .. } => {
+                    let scope = scopes.len();
+                    scopes.push(Scope {
+                        pid, tid,
+                        start: *when,
+                        end:   f64::NAN,
+                        depth: stack.len() as u32,
+                        name:  name.clone(),
+                        args:  args.clone(),
+                        category: *category,
+                        parent: stack.last().copied(),
+                    });
+                    threads[thread].scopes.push(scope);
+                    stack.push(scope);
+                }
+
+                Event::End { when, .. } => {
+                    // unmatched ends are ignored.
+                    if let Some(scope) = stack.pop() {
+                        scopes[scope].end = *when;
+                    }
+                }
+            }
+        }
+
+        // close dangling scopes.
+        for (thread, stack) in stacks.iter().enumerate() {
+            for &scope in stack {
+                scopes[scope].end = last_when[thread];
+            }
+        }
+
+        // scopes are created in start order.
+        let mut scopes_max_end = Vec::with_capacity(scopes.len());
+        let mut max_end = f64::NEG_INFINITY;
+        for scope in &scopes {
+            max_end = max_end.max(scope.end);
+            scopes_max_end.push(max_end);
+        }
+
+        let mut scopes_by_name = HashMap::<String, Vec<usize>>::new();
+        for (index, scope) in scopes.iter().enumerate() {
+            scopes_by_name.entry(scope.name.clone()).or_default().push(index);
+        }
+
+        Self {
+            timestamp_unit,
+            events,
+            custom_data: Vec::new(),
+            scopes,
+            threads,
+            events_by_time,
+            scopes_max_end,
+            scopes_by_name,
+        }
+    }
+
+
+    /// the trace's timestamp unit, in microseconds.
+    /// this is the header's unit, unless the trace overwrote it.
+    #[inline]
+    pub fn timestamp_unit(&self) -> f64 {
+        self.timestamp_unit
+    }
+
+    /// all events, in recording order.
+    #[inline]
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// payloads of `CustomData` events, in recording order.
+    #[inline]
+    pub fn custom_data(&self) -> &[Vec<u8>] {
+        &self.custom_data
+    }
+
+    /// payloads of `CustomData` events recorded with `raw::custom_data`
+    /// and the given tag, without the tag.
+    pub fn custom_data_tagged(&self, tag: u32) -> impl Iterator<Item = &[u8]> + '_ {
+        self.custom_data.iter()
+            .filter_map(move |data| data.strip_prefix(&tag.to_le_bytes()))
+    }
+
+    /// the trace's `metadata` pairs, in recording order.
+    /// keys may repeat, like in merged traces.
+    pub fn metadata(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> + '_ {
+        self.custom_data_tagged(crate::metadata::TAG)
+            .filter_map(|data| {
+                let split = data.iter().position(|b| *b == 0)?;
+                Some((String::from_utf8_lossy(&data[..split]), String::from_utf8_lossy(&data[split + 1..])))
+            })
+    }
+
+    /// the last value of a `metadata` key.
+    pub fn metadata_value(&self, key: &str) -> Option<Cow<'_, str>> {
+        self.metadata().filter(|(k, _)| k == key).last().map(|(_, v)| v)
+    }
+
+    /// all scopes, ordered by start time.
+    #[inline]
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    #[inline]
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    /// the first thread with the given tid.
+    pub fn thread(&self, tid: u32) -> Option<&Thread> {
+        self.threads.iter().find(|t| t.tid == tid)
+    }
+
+    /// `(first, last)` timestamp of the trace.
+    pub fn time_range(&self) -> Option<(f64, f64)> {
+        let first = *self.events_by_time.first()?;
+        let last  = *self.events_by_time.last()?;
+        Some((self.events[first].when(), self.events[last].when()))
+    }
+
+    /// `(when, unix_us)` pairs, recorded at `init` and each flush,
+    /// ordered by time.
+    pub fn wall_clock_anchors(&self) -> Vec<(f64, f64)> {
+        let mut anchors = self.scopes_named("spall/wall_clock")
+            .chain(self.scopes_named("spall/flush"))
+            .filter_map(|scope| {
+                let unix = crate::args::parse(&scope.args).into_iter()
+                    .find(|(key, _)| *key == "unix_us")?.1
+                    .parse::<f64>().ok()?;
+                Some((scope.start, unix))
+            })
+            .collect::<Vec<_>>();
+        anchors.sort_by(|a, b| a.0.total_cmp(&b.0));
+        return anchors;
+    }
+
+    /// converts a timestamp of the trace to microseconds since the unix
+    /// epoch, using the closest earlier wall clock anchor, or the first one.
+    /// `None` for traces without anchors.
+    pub fn unix_time(&self, when: f64) -> Option<f64> {
+        let anchors = self.wall_clock_anchors();
+        let index = anchors.partition_point(|a| a.0 <= when).saturating_sub(1);
+        let (anchor, unix) = *anchors.get(index)?;
+        Some(unix + (when - anchor))
+    }
+
+    /// events with `t0 <= when < t1`, ordered by time.
+    pub fn events_between(&self, t0: f64, t1: f64) -> impl Iterator<Item = &Event> + '_ {
+        let range = self.time_index_range(t0, t1);
+        self.events_by_time[range].iter().map(|i| &self.events[*i])
+    }
+
+    /// scopes overlapping the half-open window `[t0, t1)`, ordered by
+    /// start time. scopes cover `[start, end)`, so those with
+    /// `start < t1 && end > t0`, and zero length ones with `t0 <= start < t1`.
+    /// nothing overlaps an empty window, with `t1 <= t0`.
+    pub fn scopes_between(&self, t0: f64, t1: f64) -> impl Iterator<Item = &Scope> + '_ {
+        // everything before `begin` ends before t0,
+        // everything from `end` on starts at t1 or later.
+        let begin = self.scopes_max_end.partition_point(|e| *e < t0);
+        let end   = if t0 < t1 { self.scopes.partition_point(|s| s.start < t1) } else { 0 };
+
+        self.scopes[begin..end.max(begin)].iter()
+            .filter(move |s| s.end > t0 || s.start >= t0)
+    }
+
+    /// scopes with the given name, ordered by start time.
+    pub fn scopes_named<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Scope> + 'a {
+        self.scopes_by_name.get(name)
+            .map(|s| s.as_slice()).unwrap_or(&[])
+            .iter().map(|i| &self.scopes[*i])
+    }
+
+    fn time_index_range(&self, t0: f64, t1: f64) -> Range<usize> {
+        let begin = self.events_by_time.partition_point(|e| self.events[*e].when() < t0);
+        let end   = self.events_by_time.partition_point(|e| self.events[*e].when() < t1);
+        begin..end.max(begin)
+    }
+}
+
+
+
+// memory-mapped traces:
+
+/// a trace file mapped into memory, for traces too large to load.
+/// derefs to the file's bytes, for `Parser` or `scope_refs`,
+/// which borrow names and args from it instead of copying them.
+/// the os pages the file in as it's read, and out under memory pressure.
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+    // for platforms without mmap, and empty files.
+    owned: Vec<u8>,
+}
+
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// maps the whole file, as it is now.
+    ///
+    /// # Safety
+    ///
+    /// the file must not be truncated or modified while mapped.
+    /// appending is fine, like to a trace that's still being recorded.
+    pub unsafe fn open(path: impl AsRef<Path>) -> Result<Self, Error> { unsafe {
+        let file = std::fs::File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| Error::new(ErrorKind::OutOfMemory, "trace too large to map"))?;
+        if len == 0 {
+            return Ok(Self { ptr: std::ptr::null(), len: 0, owned: Vec::new() });
+        }
+        return Self::map(file, len);
+    }}
+
+    #[cfg(unix)]
+    unsafe fn map(file: std::fs::File, len: usize) -> Result<Self, Error> { unsafe {
+        use std::os::fd::AsRawFd;
+
+        let ptr = libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0);
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        // mostly read front to back.
+        libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        return Ok(Self { ptr: ptr.cast(), len, owned: Vec::new() });
+    }}
+
+    #[cfg(windows)]
+    unsafe fn map(file: std::fs::File, len: usize) -> Result<Self, Error> { unsafe {
+        use std::ffi::c_void;
+        use std::os::windows::io::AsRawHandle;
+
+        extern "system" {
+            fn CreateFileMappingW(file: *mut c_void, attributes: *const c_void, protect: u32,
+                size_high: u32, size_low: u32, name: *const u16) -> *mut c_void;
+            fn MapViewOfFile(mapping: *mut c_void, access: u32, offset_high: u32, offset_low: u32, len: usize) -> *mut c_void;
+            fn CloseHandle(handle: *mut c_void) -> i32;
+        }
+        const PAGE_READONLY:  u32 = 0x02;
+        const FILE_MAP_READ:  u32 = 0x04;
+
+        let mapping = CreateFileMappingW(file.as_raw_handle().cast(), std::ptr::null(), PAGE_READONLY, 0, 0, std::ptr::null());
+        if mapping.is_null() {
+            return Err(Error::last_os_error());
+        }
+        // the view keeps the mapping alive.
+        let ptr = MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, len);
+        let error = Error::last_os_error();
+        CloseHandle(mapping);
+        if ptr.is_null() {
+            return Err(error);
+        }
+        return Ok(Self { ptr: ptr.cast(), len, owned: Vec::new() });
+    }}
+
+    #[cfg(not(any(unix, windows)))]
+    unsafe fn map(mut file: std::fs::File, len: usize) -> Result<Self, Error> {
+        use std::io::Read;
+
+        let mut owned = Vec::with_capacity(len);
+        file.read_to_end(&mut owned)?;
+        return Ok(Self { ptr: std::ptr::null(), len: 0, owned });
+    }
+
+    /// the scopes of the trace, see `scope_refs`.
+    pub fn scopes(&self) -> Result<ScopeRefs<'_>, Error> {
+        scope_refs(self)
+    }
+}
+
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &self.owned;
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.ptr.is_null() {
+            return;
+        }
+
+        #[cfg(unix)]
+        unsafe { libc::munmap(self.ptr as *mut _, self.len); }
+
+        #[cfg(windows)]
+        unsafe {
+            extern "system" {
+                fn UnmapViewOfFile(ptr: *const std::ffi::c_void) -> i32;
+            }
+            UnmapViewOfFile(self.ptr.cast());
+        }
+    }
+}
+
+
+/// a scope whose name and args borrow from the trace bytes.
+/// times are in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScopeRef<'a> {
+    pub pid:   u32,
+    pub tid:   u32,
+    pub start: f64,
+    pub end:   f64,
+    pub depth: u32,
+    pub name:  &'a str,
+    pub args:  &'a str,
+    pub category: u8,
+}
+
+impl ScopeRef<'_> {
+    #[inline]
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// iterates over the scopes of a trace without loading it,
+/// in the order they end. memory use only depends on
+/// the number of threads and open scopes, and names.
+/// scopes still open at the end of the trace come last,
+/// ending at their thread's last event.
+///
+/// the bytes are read twice, first for the final timestamp unit and
+/// the names of `ScopeName`s. names that aren't valid utf-8 are `"\u{fffd}"`,
+/// and names recorded as ids without a dictionary entry are empty.
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// let map = unsafe { spall::reader::Mmap::open("huge.spall")? };
+/// let mut total = 0.0;
+/// for scope in map.scopes()? {
+///     let scope = scope?;
+///     if scope.name == "parse" {
+///         total += scope.duration();
+///     }
+/// }
+/// # Ok(()) }
+/// ```
+pub fn scope_refs(data: &[u8]) -> Result<ScopeRefs<'_>, Error> {
+    let parser = Parser::new(data)?;
+
+    let mut unit  = parser.timestamp_unit();
+    let mut names = HashMap::new();
+    // stops at a torn event, like at the end of a trace being recorded.
+    for event in parser.clone() {
+        let Ok(event) = event else { break };
+        match event {
+            RawEvent::OverwriteTimestamp { timestamp_unit } => unit = timestamp_unit,
+
+            RawEvent::CustomData { data } => {
+                if let Some((id, name)) = crate::name::parse_entry(data) {
+                    names.insert(id, utf8(name));
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    return Ok(ScopeRefs { parser, unit, names, threads: HashMap::new(), dangling: Vec::new() });
+}
+
+pub struct ScopeRefs<'a> {
+    parser: Parser<'a>,
+    unit:   f64,
+    names:  HashMap<u32, &'a str>,
+    // open scopes, and the last timestamp, by thread.
+    threads:  HashMap<(u32, u32), (Vec<ScopeRef<'a>>, f64)>,
+    dangling: Vec<ScopeRef<'a>>,
+}
+
+impl<'a> ScopeRefs<'a> {
+    /// the final timestamp unit.
+    pub fn timestamp_unit(&self) -> f64 {
+        self.unit
+    }
+
+    fn thread(&mut self, pid: u32, tid: u32) -> &mut (Vec<ScopeRef<'a>>, f64) {
+        self.threads.entry((pid, tid)).or_insert_with(|| (Vec::new(), f64::NEG_INFINITY))
+    }
+}
+
+impl<'a> Iterator for ScopeRefs<'a> {
+    type Item = Result<ScopeRef<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = match self.parser.next() {
+                Some(Ok(event)) => event,
+                Some(Err(e))    => return Some(Err(e)),
+
+                None => {
+                    if !self.threads.is_empty() {
+                        for (_, (stack, last)) in self.threads.drain() {
+                            self.dangling.extend(stack.into_iter().rev().map(|scope| ScopeRef { end: last, ..scope }));
+                        }
+                        self.dangling.reverse();
+                    }
+                    return self.dangling.pop().map(Ok);
+                }
+            };
+
+            match event {
+                RawEvent::Begin { category, pid, tid, when, name, args } => {
+                    let when = when * self.unit;
+                    let name = match crate::name::parse_id(name) {
+                        Some(id) => self.names.get(&id).copied().unwrap_or(""),
+                        None     => utf8(name),
+                    };
+
+                    let (stack, last) = self.thread(pid, tid);
+                    *last = last.max(when);
+                    let depth = stack.len() as u32;
+                    stack.push(ScopeRef { pid, tid, start: when, end: f64::NAN, depth, name, args: utf8(args), category });
+                }
+
+                RawEvent::End { pid, tid, when } => {
+                    let when = when * self.unit;
+                    let (stack, last) = self.thread(pid, tid);
+                    *last = last.max(when);
+                    // unmatched ends are ignored.
+                    if let Some(scope) = stack.pop() {
+                        return Some(Ok(ScopeRef { end: when, ..scope }));
+                    }
+                }
+
+                _ => (),
+            }
+        }
+    }
+}
+
+fn utf8(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or("\u{fffd}")
+}
+
+
+
+// merging:
+
+/// combines several trace files into one, e.g. the per-thread files of a run.
+/// timestamps are converted to the unit of the first input,
+/// and names recorded as ids, see `ScopeName`, are expanded.
+pub fn merge<P: AsRef<Path>>(inputs: &[P], output: impl AsRef<Path>) -> Result<(), Error> {
+    merge_shifted(inputs, &[], output)
+}
+
+// like `merge`, adding `offsets[i]` microseconds to the timestamps of input `i`.
+pub(crate) fn merge_shifted<P: AsRef<Path>>(inputs: &[P], offsets: &[f64], output: impl AsRef<Path>) -> Result<(), Error> {
+    let mut out  = None;
+    let mut unit = None;
+
+    for (index, input) in inputs.iter().enumerate() {
+        let data = std::fs::read(input)?;
+        let parser = Parser::new(&data)?;
+
+        let mut input_unit = parser.timestamp_unit();
+        for event in Parser::new(&data)? {
+            if let RawEvent::OverwriteTimestamp { timestamp_unit } = event? {
+                input_unit = timestamp_unit;
+            }
+        }
+
+        let unit = *unit.get_or_insert(input_unit);
+        let scale = input_unit / unit;
+        let shift = offsets.get(index).copied().unwrap_or(0.0) / unit;
+
+        let out = match &mut out {
+            Some(out) => out,
+            None      => out.insert(SpallWriter::new(Vec::new(), unit)),
+        };
+
+        // ids differ between processes, so names are expanded.
+        let names = crate::name::dictionary(&data)?;
+
+        for event in parser {
+            match event? {
+                RawEvent::Begin { category, pid, tid, when, name, args } => {
+                    let name = crate::name::resolve(name, &names);
+                    out.begin_bytes(category, pid, tid, when * scale + shift, name, args)?;
+                }
+
+                RawEvent::End { pid, tid, when } =>
+                    out.end(pid, tid, when * scale + shift)?,
+
+                // the checkpoints' sizes don't match the merged file.
+                RawEvent::CustomData { data } => {
+                    if crate::name::parse_entry(data).is_none() && crate::checkpoint::parse(data).is_none() {
+                        out.custom_data(data)?;
+                    }
+                }
+
+                // already applied.
+                RawEvent::OverwriteTimestamp { .. } => (),
+            }
+        }
+    }
+
+    let out = match out {
+        Some(out) => out.finish()?,
+        None      => Vec::new(),
+    };
+    return std::fs::write(output, out);
+}
+
+
+
+// trimming:
+
+/// copies the events of `input` in the `window` to `output`, like a
+/// repro cut out of a long capture. the window is in microseconds since
+/// the first event. scopes that straddle its edges are cut at them,
+/// begun at the start or ended at the end of the window.
+/// names recorded as ids are expanded, like by `merge`.
+pub fn trim(input: impl AsRef<Path>, output: impl AsRef<Path>, window: Range<f64>) -> Result<(), Error> {
+    let data = std::fs::read(input)?;
+
+    let mut unit  = Parser::new(&data)?.timestamp_unit();
+    let mut first = f64::INFINITY;
+    for event in Parser::new(&data)? {
+        match event? {
+            RawEvent::Begin { when, .. } | RawEvent::End { when, .. } => first = first.min(when),
+            RawEvent::OverwriteTimestamp { timestamp_unit } => unit = timestamp_unit,
+            RawEvent::CustomData { .. } => (),
+        }
+    }
+    let from = first + window.start / unit;
+    let to   = first + window.end   / unit;
+
+    let names = crate::name::dictionary(&data)?;
+    let mut out = SpallWriter::new(std::fs::File::create(output)?, unit);
+
+    // the open scopes of each thread.
+    let mut threads = HashMap::<(u32, u32), (Vec<Trimmed>, f64)>::new();
+    for event in Parser::new(&data)? {
+        let event = event?;
+        let (pid, tid, when) = match event {
+            RawEvent::Begin { pid, tid, when, .. } | RawEvent::End { pid, tid, when } => (pid, tid, when),
+
+            RawEvent::CustomData { data } => {
+                if crate::name::parse_entry(data).is_none() && crate::checkpoint::parse(data).is_none() {
+                    out.custom_data(data)?;
+                }
+                continue;
+            }
+
+            RawEvent::OverwriteTimestamp { .. } => continue,
+        };
+
+        let (stack, last) = threads.entry((pid, tid)).or_default();
+        *last = when;
+
+        if when >= from {
+            for scope in stack.iter_mut() {
+                if let Trimmed::Before { category, name, args } = scope {
+                    out.begin_bytes(*category, pid, tid, from, name, args)?;
+                    *scope = Trimmed::Written;
+                }
+            }
+        }
+
+        match event {
+            RawEvent::Begin { category, name, args, .. } => {
+                let name = crate::name::resolve(name, &names);
+                if when < from {
+                    stack.push(Trimmed::Before { category, name: name.to_vec(), args: args.to_vec() });
+                }
+                else if when <= to {
+                    out.begin_bytes(category, pid, tid, when, name, args)?;
+                    stack.push(Trimmed::Written);
+                }
+                else {
+                    stack.push(Trimmed::After);
+                }
+            }
+
+            RawEvent::End { .. } => {
+                if let Some(Trimmed::Written) = stack.pop() {
+                    out.end(pid, tid, when.min(to))?;
+                }
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
+    // scopes that were never closed end at the thread's last timestamp.
+    for ((pid, tid), (stack, last)) in threads {
+        for scope in stack.iter().rev() {
+            if let Trimmed::Written = scope {
+                out.end(pid, tid, last.min(to))?;
+            }
+        }
+    }
+
+    out.finish()?;
+    return Ok(());
+}
+
+// an open scope.
+enum Trimmed {
+    // begun before the window, written if the thread reaches it.
+    Before { category: u8, name: Vec<u8>, args: Vec<u8> },
+    Written,
+    After,
+}
@@ -0,0 +1,416 @@
+//! redacted traces, for sharing traces from customer environments.
+//!
+//! with `Options::redact`, scope names and args are recorded as stable
+//! hashes like `#a3f1c0de9b2e4d17`, so the trace keeps its structure and
+//! timing, but no identifiers or user data. spall's own markers that only
+//! carry timing information are kept as is.
+//!
+//! names hash the same in every run, so a developer can record a
+//! `Dictionary` in their own runs with `Options::redact_dictionary`,
+//! and `restore` the names of shared traces with it.
+//! args aren't added to the dictionary.
+//!
+//! the hashes aren't keyed, so anyone can check a guess,
+//! and short or predictable values are easy to guess.
+//!
+//! traces recorded without `redact` can be anonymized afterwards with
+//! `anonymize`, which rewrites names and values by `Rules`, like hashing
+//! only user ids and keeping the rest readable.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::SpallWriter;
+use crate::reader::{Parser, RawEvent};
+
+
+/// the length of a hash, `#` and 16 hex digits.
+pub const HASH_LEN: usize = 17;
+
+/// the hash `bytes` are recorded as.
+pub fn hash(bytes: &[u8]) -> String {
+    let mut out = [0; HASH_LEN];
+    hash_into(bytes, &mut out);
+    return String::from_utf8_lossy(&out).into_owned();
+}
+
+// fnv-1a, which is stable across versions and platforms.
+pub(crate) fn hash_into(bytes: &[u8], out: &mut [u8; HASH_LEN]) {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash  = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    out[0] = b'#';
+    for i in 0..16 {
+        out[16 - i] = DIGITS[(hash >> (4*i)) as usize & 0xf];
+    }
+}
+
+// spall markers whose args are only timing and ids.
+pub(crate) fn keeps(name: &str) -> bool {
+    matches!(name, "spall/flush" | "spall/wall_clock" | "spall/clock_sync")
+}
+
+
+
+// dictionary recording:
+
+struct Recorder {
+    file: std::fs::File,
+    seen: HashSet<[u8; HASH_LEN]>,
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+pub(crate) fn set_dictionary(path: Option<&Path>) -> Result<(), Error> {
+    let recorder = match path {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            Some(Recorder { file, seen: HashSet::new() })
+        }
+        None => None,
+    };
+    *RECORDER.lock().unwrap() = recorder;
+    return Ok(());
+}
+
+// adds a name to the dictionary, if recording one.
+#[cold]
+pub(crate) fn record(name: &str, hashed: &[u8; HASH_LEN]) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let Some(recorder) = recorder.as_mut() else { return };
+    if recorder.seen.insert(*hashed) {
+        // a failed write only loses the entry.
+        let mut line = Vec::with_capacity(HASH_LEN + name.len() + 2);
+        line.extend_from_slice(hashed);
+        line.push(b'\t');
+        line.extend_from_slice(name.as_bytes());
+        line.push(b'\n');
+        _ = recorder.file.write_all(&line);
+    }
+}
+
+
+
+/// hashes and the names they stand for.
+#[derive(Clone, Debug, Default)]
+pub struct Dictionary {
+    names: HashMap<String, String>,
+}
+
+impl Dictionary {
+    /// loads a dictionary recorded with `Options::redact_dictionary`,
+    /// lines of a hash and a name, separated by a tab.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+
+        let mut result = Self::default();
+        for line in file.lines() {
+            let line = line?;
+            let Some((hash, name)) = line.split_once('\t') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid dictionary line {:?}", line)));
+            };
+            result.names.insert(hash.to_string(), name.to_string());
+        }
+        return Ok(result);
+    }
+
+    /// adds names from somewhere else, like a list of the names in the code.
+    pub fn insert(&mut self, name: &str) {
+        self.names.insert(hash(name.as_bytes()), name.to_string());
+    }
+
+    /// the name `hash` stands for.
+    pub fn get(&self, hash: &str) -> Option<&str> {
+        self.names.get(hash).map(String::as_str)
+    }
+}
+
+/// writes a copy of the trace at `input` to `output`,
+/// with the names found in `dictionary` restored.
+pub fn restore(input: impl AsRef<Path>, output: impl AsRef<Path>, dictionary: &Dictionary) -> Result<(), Error> {
+    let data = std::fs::read(input)?;
+    let parser = Parser::new(&data)?;
+
+    let mut out = SpallWriter::new(Vec::new(), parser.timestamp_unit());
+    for event in parser {
+        match event? {
+            RawEvent::Begin { category, pid, tid, when, name, args } => {
+                let restored = std::str::from_utf8(name).ok()
+                    .and_then(|name| dictionary.get(name));
+                let name = restored.map(str::as_bytes).unwrap_or(name);
+                out.begin_bytes(category, pid, tid, when, name, args)?;
+            }
+
+            RawEvent::End { pid, tid, when } =>
+                out.end(pid, tid, when)?,
+
+            // the checkpoints' sizes don't match the restored names.
+            RawEvent::CustomData { data } if crate::checkpoint::parse(data).is_some() => (),
+
+            RawEvent::CustomData { data } =>
+                out.custom_data(data)?,
+
+            RawEvent::OverwriteTimestamp { timestamp_unit } =>
+                out.overwrite_timestamp_unit(timestamp_unit)?,
+        }
+    }
+
+    return std::fs::write(output, out.finish()?);
+}
+
+
+
+// anonymizing:
+
+/// how `anonymize` rewrites a trace.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    /// for scope names. the first matching rule applies.
+    pub names: Vec<Rule>,
+    /// for the values of args and metadata, matched by their key.
+    /// words in args without a key have an empty key.
+    pub args: Vec<Rule>,
+    /// hash what no rule matches, instead of keeping it.
+    pub hash_rest: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    /// a glob pattern, `*` matches any sequence, `?` one character.
+    pub pattern: String,
+    pub replacement: Replacement,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Replacement {
+    Keep,
+    /// the hash of the whole value, like `Options::redact` records.
+    Hash,
+    /// `$1` stands for what the first `*` or `?` matched, `#1` for its
+    /// hash, and so on, up to `$9`. like `user/#1` for `user/*`.
+    Text(String),
+}
+
+impl Rules {
+    /// hashes all names and values, like recording with `Options::redact`.
+    pub fn hash_all() -> Self {
+        Self { hash_rest: true, ..Self::default() }
+    }
+
+    /// parses rules, one per line, as `name` or `arg`, a pattern and
+    /// a replacement, separated by whitespace. the replacements `hash`
+    /// and `keep` are `Replacement::Hash` and `Replacement::Keep`.
+    /// a `hash_rest` line sets `hash_rest`, `#` starts a comment line.
+    ///
+    /// ```text
+    /// # keep the structure of queries, but not the tables.
+    /// name  db/query/*  db/query/#1
+    /// arg   user        hash
+    /// arg   frame       keep
+    /// hash_rest
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut rules = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "hash_rest" {
+                rules.hash_rest = true;
+                continue;
+            }
+
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let (target, pattern, replacement) = match words[..] {
+                [target, pattern, replacement] => (target, pattern, replacement),
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("invalid rule {:?}", line))),
+            };
+
+            let replacement = match replacement {
+                "keep" => Replacement::Keep,
+                "hash" => Replacement::Hash,
+                text   => Replacement::Text(text.to_string()),
+            };
+            let rule = Rule { pattern: pattern.to_string(), replacement };
+            match target {
+                "name" => rules.names.push(rule),
+                "arg"  => rules.args.push(rule),
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("invalid rule {:?}", line))),
+            }
+        }
+        return Ok(rules);
+    }
+
+    /// loads rules from a file, see `parse`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// the anonymized `name`.
+    pub fn name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if keeps(name) {
+            return Cow::Borrowed(name);
+        }
+        self.apply(&self.names, name, name)
+    }
+
+    /// the anonymized value of an arg or metadata `key`.
+    pub fn value<'a>(&self, key: &str, value: &'a str) -> Cow<'a, str> {
+        self.apply(&self.args, key, value)
+    }
+
+    fn apply<'a>(&self, rules: &[Rule], key: &str, value: &'a str) -> Cow<'a, str> {
+        let mut captures = Vec::new();
+        let rule = rules.iter().find(|rule| {
+            captures.clear();
+            glob_captures(&rule.pattern, key, &mut captures)
+        });
+
+        let replacement = match rule {
+            Some(rule) => &rule.replacement,
+            None if self.hash_rest => &Replacement::Hash,
+            None => &Replacement::Keep,
+        };
+        match replacement {
+            Replacement::Keep => Cow::Borrowed(value),
+            Replacement::Hash => Cow::Owned(hash(value.as_bytes())),
+            Replacement::Text(text) => Cow::Owned(substitute(text, &captures)),
+        }
+    }
+
+    fn args(&self, args: &str) -> String {
+        let pairs = crate::args::parse(args).into_iter()
+            .map(|(key, value)| {
+                let value = match self.value(key, &value) {
+                    Cow::Borrowed(_) => value,
+                    Cow::Owned(v)    => Cow::Owned(v),
+                };
+                (key, value)
+            })
+            .collect::<Vec<_>>();
+        return crate::args::format(&pairs);
+    }
+}
+
+/// writes a copy of the trace at `input` to `output`, with names, args
+/// and metadata values rewritten by `rules`. structure and timing stay
+/// the same. spall's timing markers are kept, like with `Options::redact`.
+/// names recorded as ids are expanded.
+pub fn anonymize(input: impl AsRef<Path>, output: impl AsRef<Path>, rules: &Rules) -> Result<(), Error> {
+    let data = std::fs::read(input)?;
+    let parser = Parser::new(&data)?;
+    let names = crate::name::dictionary(&data)?;
+
+    let mut out = SpallWriter::new(Vec::new(), parser.timestamp_unit());
+    for event in parser {
+        match event? {
+            RawEvent::Begin { category, pid, tid, when, name, args } => {
+                let name = String::from_utf8_lossy(crate::name::resolve(name, &names));
+                let args = String::from_utf8_lossy(args);
+                let args = if keeps(&name) { args } else { Cow::Owned(rules.args(&args)) };
+                let name = rules.name(&name);
+
+                let name = crate::writer::truncated(&name);
+                let args = crate::writer::truncated(&args);
+                out.begin_bytes(category, pid, tid, when, name.as_bytes(), args.as_bytes())?;
+            }
+
+            RawEvent::End { pid, tid, when } =>
+                out.end(pid, tid, when)?,
+
+            // the names are expanded, and checkpoints' sizes don't match.
+            RawEvent::CustomData { data } if crate::name::parse_entry(data).is_some() || crate::checkpoint::parse(data).is_some() => (),
+
+            RawEvent::CustomData { data } => {
+                let metadata = data.strip_prefix(&crate::metadata::TAG.to_le_bytes())
+                    .and_then(|payload| {
+                        let split = payload.iter().position(|b| *b == 0)?;
+                        Some((String::from_utf8_lossy(&payload[..split]), String::from_utf8_lossy(&payload[split + 1..])))
+                    });
+                match metadata {
+                    Some((key, value)) => {
+                        let value = rules.value(&key, &value);
+                        let mut payload = crate::metadata::TAG.to_le_bytes().to_vec();
+                        payload.extend_from_slice(key.as_bytes());
+                        payload.push(0);
+                        payload.extend_from_slice(value.as_bytes());
+                        out.custom_data(&payload)?;
+                    }
+                    // unknown payloads could hold anything.
+                    None if rules.hash_rest => (),
+                    None => out.custom_data(data)?,
+                }
+            }
+
+            RawEvent::OverwriteTimestamp { timestamp_unit } =>
+                out.overwrite_timestamp_unit(timestamp_unit)?,
+        }
+    }
+
+    return std::fs::write(output, out.finish()?);
+}
+
+// matches a glob, with what each `*` and `?` matched.
+fn glob_captures<'a>(pattern: &str, text: &'a str, captures: &mut Vec<&'a str>) -> bool {
+    let mut chars = pattern.chars();
+    match chars.next() {
+        None => text.is_empty(),
+
+        Some('*') => {
+            let rest = chars.as_str();
+            // the shortest match first.
+            let ends = text.char_indices().map(|(i, _)| i).chain([text.len()]);
+            for end in ends {
+                let len = captures.len();
+                captures.push(&text[..end]);
+                if glob_captures(rest, &text[end..], captures) {
+                    return true;
+                }
+                captures.truncate(len);
+            }
+            false
+        }
+
+        Some(p) => {
+            let Some(c) = text.chars().next() else { return false };
+            if p != '?' && p != c {
+                return false;
+            }
+            let len = captures.len();
+            if p == '?' {
+                captures.push(&text[..c.len_utf8()]);
+            }
+            if glob_captures(chars.as_str(), &text[c.len_utf8()..], captures) {
+                return true;
+            }
+            captures.truncate(len);
+            false
+        }
+    }
+}
+
+fn substitute(text: &str, captures: &[&str]) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let capture = chars.peek()
+            .and_then(|d| d.to_digit(10))
+            .filter(|d| (c == '$' || c == '#') && *d >= 1)
+            .and_then(|d| captures.get(d as usize - 1));
+        match capture {
+            Some(capture) => {
+                chars.next();
+                if c == '$' { out.push_str(capture) } else { out.push_str(&hash(capture.as_bytes())) }
+            }
+            None => out.push(c),
+        }
+    }
+    return out;
+}
@@ -0,0 +1,87 @@
+//! context switch and migration markers, on linux.
+//!
+//! `scope` records a scope like `trace_scope!`, and when it ends,
+//! checks whether the os took the thread off its cpu in the meantime.
+//! if so, a `spall/descheduled` marker with args like
+//! `voluntary=1 involuntary=2` is recorded at the end of the scope,
+//! and a `spall/migrated` marker like `from=3 to=5` if the thread
+//! ended up on another cpu. involuntary switches are preemptions,
+//! voluntary ones are blocking, like on a lock or i/o.
+//! so a slow scope with markers may have been waiting, not working.
+//!
+//! the check costs a `getrusage` call at each end, and one at the start.
+
+use std::fmt;
+
+use crate::TraceScope;
+
+
+/// a checked scope, ended when dropped.
+#[must_use]
+pub struct Scope {
+    // ends after the markers.
+    _scope: TraceScope,
+    // `None` if the scope wasn't recorded.
+    start: Option<Sample>,
+}
+
+/// begins a checked scope.
+pub fn scope(name: &str) -> Scope {
+    let scope = crate::trace_scope_impl(name);
+    let start = if scope.active { Sample::now() } else { None };
+    Scope { _scope: scope, start }
+}
+
+/// begins a checked scope with args.
+pub fn scope_args(name: &str, args: fmt::Arguments) -> Scope {
+    let scope = crate::trace_scope_args_impl(name, args);
+    let start = if scope.active { Sample::now() } else { None };
+    Scope { _scope: scope, start }
+}
+
+impl Scope {
+    /// ends the scope before the guard goes out of scope.
+    #[inline]
+    pub fn end(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let Some(start) = self.start else { return };
+        let Some(end) = Sample::now() else { return };
+
+        let voluntary   = end.voluntary.saturating_sub(start.voluntary);
+        let involuntary = end.involuntary.saturating_sub(start.involuntary);
+        if voluntary > 0 || involuntary > 0 {
+            crate::marker("spall/descheduled", crate::trace_args!({ voluntary = voluntary, involuntary = involuntary }));
+        }
+        if end.cpu != start.cpu && start.cpu >= 0 && end.cpu >= 0 {
+            crate::marker("spall/migrated", crate::trace_args!({ from = start.cpu, to = end.cpu }));
+        }
+    }
+}
+
+
+#[derive(Clone, Copy)]
+struct Sample {
+    voluntary:   u64,
+    involuntary: u64,
+    cpu:         i32,
+}
+
+impl Sample {
+    fn now() -> Option<Sample> {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) } != 0 {
+            return None;
+        }
+
+        return Some(Sample {
+            voluntary:   usage.ru_nvcsw as u64,
+            involuntary: usage.ru_nivcsw as u64,
+            cpu:         unsafe { libc::sched_getcpu() },
+        });
+    }
+}
@@ -0,0 +1,39 @@
+//! signal-triggered flush and rotation for long-running services.
+//!
+//! after `install_handlers`, `kill -USR1 <pid>` makes all threads flush
+//! their buffers, and `kill -USR2 <pid>` rotates to a new trace file
+//! (see `spall::rotate`). the handlers only set flags; the actual work
+//! happens on each thread at its next event.
+
+use std::io::Error;
+
+
+/// installs handlers for `SIGUSR1` (flush) and `SIGUSR2` (rotate),
+/// replacing any existing handlers for those signals.
+pub fn install_handlers() -> Result<(), Error> {
+    install(libc::SIGUSR1, on_flush)?;
+    install(libc::SIGUSR2, on_rotate)?;
+    return Ok(());
+}
+
+extern "C" fn on_flush(_: libc::c_int) {
+    crate::request_flush();
+}
+
+extern "C" fn on_rotate(_: libc::c_int) {
+    crate::request_rotate();
+}
+
+fn install(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> Result<(), Error> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
@@ -0,0 +1,260 @@
+//! instrumented locks.
+//!
+//! drop-in wrappers around `std::sync::Mutex` and `RwLock` that record a
+//! `lock/wait` scope while a thread is blocked acquiring the lock.
+//! uncontended acquisitions record nothing.
+//! optionally, a `lock/hold` scope covers the lifetime of each guard.
+//! both have args like `lock=<name>`.
+//!
+//! hold scopes assume guards are dropped in reverse order of acquisition,
+//! like any other scope.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+use crate::TraceScope;
+
+
+fn wait_scope(name: &str) -> TraceScope {
+    crate::trace_scope_args_impl("lock/wait", format_args!("lock={}", name))
+}
+
+fn hold_scope(name: &str, hold: bool) -> Option<TraceScope> {
+    if !hold {
+        return None;
+    }
+    Some(crate::trace_scope_args_impl("lock/hold", format_args!("lock={}", name)))
+}
+
+fn map_lock<G, R>(result: LockResult<G>, f: impl FnOnce(G) -> R) -> LockResult<R> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(e)    => Err(PoisonError::new(f(e.into_inner()))),
+    }
+}
+
+fn map_try_lock<G, R>(result: TryLockResult<G>, f: impl FnOnce(G) -> R) -> TryLockResult<R> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(f(e.into_inner())))),
+        Err(TryLockError::WouldBlock)  => Err(TryLockError::WouldBlock),
+    }
+}
+
+// blocks only if `try_acquire` would, recording the wait.
+fn acquire<G>(
+    name: &str,
+    try_acquire: impl FnOnce() -> TryLockResult<G>,
+    acquire: impl FnOnce() -> LockResult<G>,
+) -> LockResult<G> {
+    match try_acquire() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(e)) => Err(e),
+        Err(TryLockError::WouldBlock) => {
+            let _wait = wait_scope(name);
+            acquire()
+        }
+    }
+}
+
+
+
+// mutex:
+
+pub struct Mutex<T: ?Sized> {
+    name: &'static str,
+    hold: bool,
+    inner: std::sync::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    // dropped before the scope ends.
+    inner: std::sync::MutexGuard<'a, T>,
+    _hold: Option<TraceScope>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("mutex", value)
+    }
+
+    pub const fn named(name: &'static str, value: T) -> Self {
+        Self { name, hold: false, inner: std::sync::Mutex::new(value) }
+    }
+
+    /// also record how long each guard is held.
+    pub const fn record_hold(mut self) -> Self {
+        self.hold = true;
+        self
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let result = acquire(self.name, || self.inner.try_lock(), || self.inner.lock());
+        map_lock(result, |inner| MutexGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        map_try_lock(self.inner.try_lock(), |inner| MutexGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+
+
+// rwlock:
+
+pub struct RwLock<T: ?Sized> {
+    name: &'static str,
+    hold: bool,
+    inner: std::sync::RwLock<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    inner: std::sync::RwLockReadGuard<'a, T>,
+    _hold: Option<TraceScope>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    inner: std::sync::RwLockWriteGuard<'a, T>,
+    _hold: Option<TraceScope>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("rwlock", value)
+    }
+
+    pub const fn named(name: &'static str, value: T) -> Self {
+        Self { name, hold: false, inner: std::sync::RwLock::new(value) }
+    }
+
+    /// also record how long each guard is held.
+    pub const fn record_hold(mut self) -> Self {
+        self.hold = true;
+        self
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let result = acquire(self.name, || self.inner.try_read(), || self.inner.read());
+        map_lock(result, |inner| RwLockReadGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let result = acquire(self.name, || self.inner.try_write(), || self.inner.write());
+        map_lock(result, |inner| RwLockWriteGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        map_try_lock(self.inner.try_read(), |inner| RwLockReadGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        map_try_lock(self.inner.try_write(), |inner| RwLockWriteGuard { inner, _hold: hold_scope(self.name, self.hold) })
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
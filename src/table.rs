@@ -0,0 +1,314 @@
+//! export of traces as tables, for polars, pandas, duckdb and the like.
+//!
+//! each scope becomes a row with the columns `pid`, `tid`, `name`,
+//! `start`, `duration`, `depth` and `args`, ordered by start time.
+//! times are in microseconds, `args` is the raw args string,
+//! see `args::parse` to split it.
+//! csv is the simplest to load anywhere, parquet is typed and much
+//! faster to load for large traces. the parquet files are uncompressed,
+//! with plain encoding, which every reader supports.
+
+use std::fmt::Write as _;
+use std::io::Error;
+use std::path::Path;
+
+use crate::reader::{Scope, Trace};
+
+
+/// writes the scopes of `trace` as csv to `path`.
+pub fn export_csv(trace: &Trace, path: impl AsRef<Path>) -> Result<(), Error> {
+    std::fs::write(path, encode_csv(trace))
+}
+
+/// encodes the scopes of `trace` as csv, with a header line.
+///
+/// names and args are quoted if they contain commas, quotes or newlines.
+pub fn encode_csv(trace: &Trace) -> Vec<u8> {
+    let mut out = String::new();
+    out.push_str("pid,tid,name,start,duration,depth,args\n");
+    for scope in rows(trace) {
+        _ = write!(out, "{},{},", scope.pid, scope.tid);
+        push_field(&mut out, &scope.name);
+        _ = write!(out, ",{},{},{},", scope.start, scope.duration(), scope.depth);
+        push_field(&mut out, &scope.args);
+        out.push('\n');
+    }
+    return out.into_bytes();
+}
+
+/// writes the scopes of `trace` as parquet to `path`.
+pub fn export_parquet(trace: &Trace, path: impl AsRef<Path>) -> Result<(), Error> {
+    std::fs::write(path, encode_parquet(trace))
+}
+
+/// encodes the scopes of `trace` as a parquet file.
+///
+/// `pid` and `tid` are `INT64`, `depth` is `INT32`, `start` and
+/// `duration` are `DOUBLE`, `name` and `args` are utf8 strings.
+/// all columns are required.
+pub fn encode_parquet(trace: &Trace) -> Vec<u8> {
+    let rows = rows(trace);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+
+    let mut row_groups = Vec::new();
+    for group in rows.chunks(ROWS_PER_GROUP) {
+        let mut columns = Vec::new();
+        for column in COLUMNS {
+            let mut values = Vec::new();
+            for scope in group {
+                match column.name {
+                    "pid"      => values.extend_from_slice(&(scope.pid as i64).to_le_bytes()),
+                    "tid"      => values.extend_from_slice(&(scope.tid as i64).to_le_bytes()),
+                    "name"     => byte_array(&mut values, &scope.name),
+                    "start"    => values.extend_from_slice(&scope.start.to_le_bytes()),
+                    "duration" => values.extend_from_slice(&scope.duration().to_le_bytes()),
+                    "depth"    => values.extend_from_slice(&(scope.depth as i32).to_le_bytes()),
+                    "args"     => byte_array(&mut values, &scope.args),
+                    _          => unreachable!(),
+                }
+            }
+
+            // one data page per column chunk. required columns
+            // without nesting have no levels, just the values.
+            let mut data_page_header = Thrift::default();
+            data_page_header.i32(1, group.len() as i32);
+            data_page_header.i32(2, PLAIN);
+            data_page_header.i32(3, RLE);
+            data_page_header.i32(4, RLE);
+
+            let mut page_header = Thrift::default();
+            page_header.i32(1, DATA_PAGE);
+            page_header.i32(2, values.len() as i32);
+            page_header.i32(3, values.len() as i32);
+            page_header.structure(5, data_page_header);
+            let page_header = page_header.finish();
+
+            let offset = out.len() as i64;
+            let size = (page_header.len() + values.len()) as i64;
+            out.extend_from_slice(&page_header);
+            out.extend_from_slice(&values);
+
+            let mut meta = Thrift::default();
+            meta.i32(1, column.ty);
+            // with the levels' encoding, like other writers.
+            meta.list(2, I32, 2);
+            meta.varint_i64(PLAIN as i64);
+            meta.varint_i64(RLE as i64);
+            meta.list(3, BINARY, 1);
+            meta.binary_value(column.name.as_bytes());
+            meta.i32(4, UNCOMPRESSED);
+            meta.i64(5, group.len() as i64);
+            meta.i64(6, size);
+            meta.i64(7, size);
+            meta.i64(9, offset);
+
+            let mut chunk = Thrift::default();
+            chunk.i64(2, offset);
+            chunk.structure(3, meta);
+            columns.push((chunk, size));
+        }
+
+        let mut row_group = Thrift::default();
+        row_group.list(1, STRUCT, columns.len());
+        let mut total = 0;
+        for (chunk, size) in columns {
+            row_group.list_struct(chunk);
+            total += size;
+        }
+        row_group.i64(2, total);
+        row_group.i64(3, group.len() as i64);
+        row_groups.push(row_group);
+    }
+
+    let mut meta = Thrift::default();
+    meta.i32(1, 1);
+
+    meta.list(2, STRUCT, 1 + COLUMNS.len());
+    let mut root = Thrift::default();
+    root.binary(4, b"schema");
+    root.i32(5, COLUMNS.len() as i32);
+    meta.list_struct(root);
+    for column in COLUMNS {
+        let mut element = Thrift::default();
+        element.i32(1, column.ty);
+        element.i32(3, REQUIRED);
+        element.binary(4, column.name.as_bytes());
+        if column.ty == BYTE_ARRAY {
+            element.i32(6, UTF8);
+        }
+        meta.list_struct(element);
+    }
+
+    meta.i64(3, rows.len() as i64);
+
+    meta.list(4, STRUCT, row_groups.len());
+    for row_group in row_groups {
+        meta.list_struct(row_group);
+    }
+
+    meta.binary(6, concat!("spall-rs ", env!("CARGO_PKG_VERSION")).as_bytes());
+
+    let meta = meta.finish();
+    out.extend_from_slice(&meta);
+    out.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    return out;
+}
+
+
+// the scopes, ordered by start time, then depth, like `chrome`.
+fn rows(trace: &Trace) -> Vec<&Scope> {
+    let mut rows = trace.scopes().iter().collect::<Vec<_>>();
+    rows.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.depth.cmp(&b.depth)));
+    return rows;
+}
+
+fn push_field(out: &mut String, value: &str) {
+    if !value.contains([',', '"', '\n', '\r']) {
+        out.push_str(value);
+        return;
+    }
+
+    out.push('"');
+    for c in value.chars() {
+        if c == '"' {
+            out.push('"');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+
+
+// parquet encoding:
+
+const MAGIC: &[u8] = b"PAR1";
+
+// pages are limited to 2 GiB, this keeps them well below with
+// the longest names and args.
+const ROWS_PER_GROUP: usize = 1 << 20;
+
+struct Column {
+    name: &'static str,
+    ty:   i32,
+}
+
+const COLUMNS: [Column; 7] = [
+    Column { name: "pid",      ty: INT64 },
+    Column { name: "tid",      ty: INT64 },
+    Column { name: "name",     ty: BYTE_ARRAY },
+    Column { name: "start",    ty: DOUBLE },
+    Column { name: "duration", ty: DOUBLE },
+    Column { name: "depth",    ty: INT32 },
+    Column { name: "args",     ty: BYTE_ARRAY },
+];
+
+// physical types.
+const INT32:      i32 = 1;
+const INT64:      i32 = 2;
+const DOUBLE:     i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+
+const REQUIRED:     i32 = 0;
+const UTF8:         i32 = 0;
+const PLAIN:        i32 = 0;
+const RLE:          i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE:    i32 = 0;
+
+fn byte_array(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+
+// thrift compact protocol types.
+const I32:    u8 = 5;
+const I64:    u8 = 6;
+const BINARY: u8 = 8;
+const LIST:   u8 = 9;
+const STRUCT: u8 = 12;
+
+// a struct in thrift's compact protocol, with fields in increasing order.
+#[derive(Default)]
+struct Thrift {
+    out:  Vec<u8>,
+    last: i16,
+}
+
+impl Thrift {
+    fn field(&mut self, id: i16, ty: u8) {
+        let delta = id - self.last;
+        if (1..=15).contains(&delta) {
+            self.out.push((delta as u8) << 4 | ty);
+        }
+        else {
+            self.out.push(ty);
+            self.varint_i64(id as i64);
+        }
+        self.last = id;
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.out.push(value as u8);
+    }
+
+    // zigzag encoded.
+    fn varint_i64(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.varint_i64(value as i64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.varint_i64(value);
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, BINARY);
+        self.binary_value(value);
+    }
+
+    fn binary_value(&mut self, value: &[u8]) {
+        self.varint(value.len() as u64);
+        self.out.extend_from_slice(value);
+    }
+
+    fn structure(&mut self, id: i16, value: Thrift) {
+        self.field(id, STRUCT);
+        self.out.extend_from_slice(&value.finish());
+    }
+
+    // the list header, the `len` elements follow.
+    fn list(&mut self, id: i16, ty: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.out.push((len as u8) << 4 | ty);
+        }
+        else {
+            self.out.push(0xf0 | ty);
+            self.varint(len as u64);
+        }
+    }
+
+    fn list_struct(&mut self, value: Thrift) {
+        self.out.extend_from_slice(&value.finish());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        // the stop field.
+        self.out.push(0);
+        return self.out;
+    }
+}
@@ -0,0 +1,130 @@
+//! async task tracing.
+//!
+//! `Traced` wraps a future and records each poll as a scope on the task's
+//! own track, so tasks show up as lanes next to the executor's threads.
+//! scopes recorded inside a poll stay on the polling thread's track.
+//! `trace_async!` records the polls on the polling thread's track instead,
+//! like a `trace_scope!` around each poll.
+//! works with any executor. with the `smol` or `async-std` features,
+//! `smol::spawn` and `async_std::spawn` spawn traced tasks directly.
+//!
+//! ```no_run
+//! use spall::task::TraceFuture;
+//!
+//! # async fn fetch() {}
+//! # fn spawn(_: impl std::future::Future) {}
+//! spawn(fetch().traced("fetch"));
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{Context, Poll};
+
+
+// os thread ids are small, so tasks take the upper half.
+static NEXT_TRACK: AtomicU32 = AtomicU32::new(0);
+
+fn new_track() -> u32 {
+    0x8000_0000 | (NEXT_TRACK.fetch_add(1, Ordering::Relaxed) & 0x7fff_ffff)
+}
+
+
+/// a future that records its polls, see `TraceFuture::traced`.
+/// poll scopes are named after the task, with args like `poll=3`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Traced<F> {
+    inner: F,
+    name:  &'static str,
+    // `None` for the polling thread's track.
+    track: Option<u32>,
+    polls: u64,
+}
+
+impl<F> Traced<F> {
+    pub fn new(name: &'static str, inner: F) -> Self {
+        Self { inner, name, track: Some(new_track()), polls: 0 }
+    }
+
+    /// records the polls on the polling thread's track.
+    pub fn on_thread(name: &'static str, inner: F) -> Self {
+        Self { inner, name, track: None, polls: 0 }
+    }
+
+    /// the tid of the task's track, if it has one.
+    pub fn track(&self) -> Option<u32> {
+        self.track
+    }
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // safety: `inner` is never moved out of the pinned `self`.
+        let this  = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        this.polls += 1;
+        let args = format_args!("poll={}", this.polls);
+
+        let Some(track) = this.track else {
+            let _scope = crate::trace_scope_args_impl(this.name, args);
+            return inner.poll(cx);
+        };
+
+        struct End(u32, bool);
+        impl Drop for End {
+            fn drop(&mut self) {
+                if self.1 {
+                    crate::end_on(self.0);
+                }
+            }
+        }
+        let _end = End(track, crate::begin_on(track, this.name, args));
+
+        inner.poll(cx)
+    }
+}
+
+pub trait TraceFuture: Future + Sized {
+    /// records each poll of this future on its own track.
+    fn traced(self, name: &'static str) -> Traced<Self> {
+        Traced::new(name, self)
+    }
+}
+
+impl<F: Future> TraceFuture for F {}
+
+
+
+#[cfg(feature = "smol")]
+pub mod smol {
+    use std::future::Future;
+
+    use super::Traced;
+
+    /// `smol::spawn`, with the task's polls traced.
+    pub fn spawn<T: Send + 'static>(
+        name: &'static str,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> ::smol::Task<T> {
+        ::smol::spawn(Traced::new(name, future))
+    }
+}
+
+#[cfg(feature = "async-std")]
+pub mod async_std {
+    use std::future::Future;
+
+    use super::Traced;
+
+    /// `async_std::task::spawn`, with the task's polls traced.
+    pub fn spawn<T: Send + 'static>(
+        name: &'static str,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> ::async_std::task::JoinHandle<T> {
+        ::async_std::task::spawn(Traced::new(name, future))
+    }
+}
@@ -0,0 +1,131 @@
+// trace path templates, see `init_with`.
+//
+// placeholders are expanded once, when parsing, except for `{seq}`,
+// which depends on the files already there.
+// paths aren't necessarily utf-8, so templates are handled as the
+// os string's bytes. they're only split at ascii characters, and only
+// utf-8 is inserted, which keeps them valid.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+
+pub(crate) struct Template {
+    parts: Vec<Part>,
+    // whether the file must not exist yet.
+    pub new: bool,
+}
+
+enum Part {
+    // the os string's encoded bytes.
+    Text(Vec<u8>),
+    Seq,
+}
+
+impl Template {
+    pub(crate) fn parse(template: &OsStr) -> Result<Template, Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+
+        let mut parts = Vec::new();
+        let mut text = Vec::new();
+        let mut new = false;
+        let micros = crate::unix_micros().to_string();
+
+        let mut bytes = template.as_encoded_bytes().iter().copied().peekable();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'{' if bytes.peek() == Some(&b'{') => { bytes.next(); text.push(b'{') }
+                b'}' if bytes.peek() == Some(&b'}') => { bytes.next(); text.push(b'}') }
+
+                b'{' => {
+                    let mut name = Vec::new();
+                    loop {
+                        match bytes.next() {
+                            Some(b'}') => break,
+                            Some(b)    => name.push(b),
+                            None       => return Err(invalid(format!("spall path has an unclosed `{{{}`",
+                                String::from_utf8_lossy(&name)))),
+                        }
+                    }
+
+                    match &name[..] {
+                        b"pid"      => text.extend_from_slice(std::process::id().to_string().as_bytes()),
+                        b"exe"      => text.extend_from_slice(exe().as_bytes()),
+                        b"date"     => text.extend_from_slice(date(unix_secs()).as_bytes()),
+                        b"hostname" => text.extend_from_slice(crate::metadata::hostname().unwrap_or_default().as_bytes()),
+                        b"seq"      => {
+                            parts.push(Part::Text(std::mem::take(&mut text)));
+                            parts.push(Part::Seq);
+                            new = true;
+                        }
+                        _ => return Err(invalid(format!("spall path has an unknown placeholder `{{{}}}`",
+                            String::from_utf8_lossy(&name)))),
+                    }
+                }
+
+                b'}' => return Err(invalid("spall path has an unmatched `}`, use `}}`".into())),
+
+                b'$' => {
+                    text.extend_from_slice(micros.as_bytes());
+                    new = true;
+                }
+
+                b => text.push(b),
+            }
+        }
+        parts.push(Part::Text(text));
+
+        return Ok(Template { parts, new });
+    }
+
+    pub(crate) fn has_seq(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Seq))
+    }
+
+    // the path, with `{seq}` as `seq`.
+    pub(crate) fn fill(&self, seq: u64) -> PathBuf {
+        let mut path = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => path.extend_from_slice(text),
+                Part::Seq        => path.extend_from_slice(format!("{:04}", seq).as_bytes()),
+            }
+        }
+        // split at ascii characters, and joined with utf-8, see above.
+        return PathBuf::from(unsafe { OsString::from_encoded_bytes_unchecked(path) });
+    }
+}
+
+
+fn exe() -> String {
+    let exe = std::env::current_exe().ok();
+    let stem = exe.as_ref().and_then(|exe| exe.file_stem());
+    return stem.map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+}
+
+fn unix_secs() -> i64 {
+    let time = std::time::SystemTime::now();
+    let unix = time.duration_since(std::time::UNIX_EPOCH)
+        .expect("system time can't be before unix epoch");
+    return unix.as_secs() as i64;
+}
+
+// like `20261014-153012`, in utc.
+fn date(unix_secs: i64) -> String {
+    let (days, secs) = (unix_secs.div_euclid(86400), unix_secs.rem_euclid(86400));
+
+    // days to civil, from howard hinnant's date algorithms.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era*400 + (month <= 2) as i64;
+
+    return format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, secs / 3600, secs / 60 % 60, secs % 60);
+}
@@ -0,0 +1,154 @@
+//! deterministic tests of traced code.
+//!
+//! `ManualClock` only moves when it's advanced, so tests can assert
+//! exact timestamps. its ticks are microseconds, the unit of traces,
+//! so scopes start and end at exactly the clock's values.
+//! `record` runs a closure in its own session with the process's
+//! manual clock and returns the trace. `hold_writer` lets the queue
+//! of `Options::overflow` fill, to test what overflowing threads do.
+//! `compiled_in` tells tests whether the scopes they record are.
+//!
+//! ```no_run
+//! let trace = spall::testing::record(Default::default(), |clock| {
+//!     let _scope = spall::trace_scope_impl("load");
+//!     clock.advance(std::time::Duration::from_millis(2));
+//! }).unwrap();
+//! let load = trace.scopes_named("load").next().unwrap();
+//! assert_eq!(load.duration(), 2000.0);
+//! ```
+
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use crate::{Clock, Options};
+use crate::filter::Level;
+use crate::reader::Trace;
+
+
+/// a clock that moves only when advanced, in microseconds.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    micros: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /// the current time, in microseconds.
+    pub fn get(&self) -> u64 {
+        self.micros.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, micros: u64) {
+        self.micros.store(micros, Ordering::Relaxed);
+    }
+
+    /// advances the clock, rounded down to whole microseconds.
+    pub fn advance(&self, by: Duration) {
+        self.advance_micros(by.as_micros() as u64);
+    }
+
+    pub fn advance_micros(&self, micros: u64) {
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.get()
+    }
+
+    fn frequency(&self) -> f64 {
+        1_000_000.0
+    }
+
+    fn name(&self) -> &str {
+        "manual"
+    }
+}
+
+
+/// the process's manual clock, used by `record`.
+///
+/// a process only has one clock, so once `record` ran, all sessions
+/// use this one, see `Options::clock`.
+pub fn clock() -> Arc<ManualClock> {
+    static CLOCK: OnceLock<Arc<ManualClock>> = OnceLock::new();
+    CLOCK.get_or_init(Default::default).clone()
+}
+
+/// records `f` into a new trace with `clock`, and returns the trace.
+///
+/// the clock is reset to 0 first. the trace is written to a temporary
+/// file, with `options` apart from `clock`, `per_thread_files`,
+/// `lazy_file` and `format`.
+/// calls are serialized, as there's one session at a time. spall must
+/// not be initialized otherwise. threads spawned by `f` must exit
+/// before it returns, or their events are missing. fails with
+/// `Unsupported` when spall is disabled, see `ENABLED`.
+pub fn record(options: Options, f: impl FnOnce(&ManualClock)) -> Result<Trace, Error> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let clock = clock();
+    clock.set(0);
+
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("spall-testing-{}-{}.spall", std::process::id(), seq));
+    let options = Options {
+        clock: Some(clock.clone()),
+        per_thread_files: false,
+        lazy_file: false,
+        format: crate::Format::Spall,
+        ..options
+    };
+    if !crate::ENABLED {
+        return Err(Error::new(ErrorKind::Unsupported, "spall is disabled"));
+    }
+    if !crate::init_with(&path, options)? {
+        return Err(Error::new(ErrorKind::AlreadyExists, "spall is already initialized"));
+    }
+
+    // ends the session on panics too, so later tests can record.
+    struct Shutdown;
+    impl Drop for Shutdown {
+        fn drop(&mut self) {
+            crate::shutdown();
+        }
+    }
+    let shutdown = Shutdown;
+    f(&clock);
+    drop(shutdown);
+
+    let trace = Trace::open(&path);
+    _ = std::fs::remove_file(&path);
+    return trace;
+}
+
+
+/// whether scopes of `level` are compiled in, see `ENABLED` and
+/// `filter::MAX_LEVEL`, so tests of them can return early with the
+/// `disable` and `max-level-*` features.
+pub fn compiled_in(level: Level) -> bool {
+    return crate::ENABLED && level <= crate::filter::MAX_LEVEL;
+}
+
+
+/// holds the writer thread of `Options::overflow` until dropped, so
+/// its queue fills and `Overflow` applies, see `hold_writer`.
+pub struct WriterHold {
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// stops the writer thread before its next write, until the hold is
+/// dropped. threads that flush into the full queue wait for it, like
+/// all threads at exit, so the hold must be dropped before they're joined.
+pub fn hold_writer() -> WriterHold {
+    WriterHold { _guard: crate::queue::HOLD.lock().unwrap_or_else(|e| e.into_inner()) }
+}
@@ -0,0 +1,90 @@
+//! traced threads.
+//!
+//! `spawn` and `Builder` work like their `std::thread` counterparts,
+//! but label the new thread in the trace and flush its buffer
+//! before it exits, even when it panics.
+//!
+//! the parent records a `spall/spawn` marker with args like
+//! `tid=12 name=worker`, and the new thread starts with a `spall/thread`
+//! marker like `name=worker parent=1`, which tools can use to link them.
+
+use std::io::Error;
+use std::thread::JoinHandle;
+
+
+/// `std::thread::spawn`, for a traced thread.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    stack_size: Option<usize>,
+    buffer_size: Option<usize>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// the size of the thread's event buffer, see `set_thread_buffer_size`.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = Some(size);
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, Error>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+        let mut builder = std::thread::Builder::new();
+        if let Some(name) = self.name.clone() {
+            builder = builder.name(name);
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+
+        let parent = crate::thread_tid();
+        let tid = crate::reserve_tid();
+        let name = self.name.unwrap_or_default();
+
+        let thread_name = name.clone();
+        let buffer_size = self.buffer_size;
+        let handle = builder.spawn(move || {
+            struct Flush;
+            impl Drop for Flush {
+                fn drop(&mut self) {
+                    crate::flush();
+                }
+            }
+            let _flush = Flush;
+
+            if let Some(tid) = tid {
+                crate::set_thread_tid(tid);
+            }
+            if let Some(size) = buffer_size {
+                crate::set_thread_buffer_size(size);
+            }
+
+            crate::marker("spall/thread", crate::trace_args!({ name = thread_name, parent = parent }));
+            f()
+        })?;
+
+        let tid = tid.unwrap_or_else(|| crate::id_tid(handle.thread().id()));
+        crate::marker("spall/spawn", crate::trace_args!({ tid = tid, name = name }));
+
+        return Ok(handle);
+    }
+}
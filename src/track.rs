@@ -0,0 +1,104 @@
+//! virtual tracks.
+//!
+//! `track` attributes the events a thread records while the guard lives
+//! to a track of their own, instead of the thread's, for lanes that
+//! aren't threads, like a gpu queue, a state machine instance or
+//! a connection. any thread can record on any track, one at a time.
+//!
+//! ```no_run
+//! # let (conn_id, bytes) = (7, 512);
+//! let _track = spall::track(conn_id, "connection");
+//! spall::trace_scope!("read", "bytes={}", bytes);
+//! ```
+//!
+//! each id gets its own tid, and each session names the track with a
+//! `spall/thread_start` marker like a thread, when it's first entered
+//! while the thread records.
+//! scopes must end on the track they began on, so scopes already open
+//! when entering stay open until after the guard is dropped.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+
+// below the tracks of tasks, and above the tids of threads.
+const FIRST_TID: u32 = 0x4000_0000;
+
+// by id and kind.
+static TRACKS: Mutex<Option<HashMap<(u64, Kind), Registered>>> = Mutex::new(None);
+
+// what a track is for, each has its own ids.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Kind {
+    Track,
+    Fiber,
+    Gpu,
+}
+
+struct Registered {
+    tid: u32,
+    // the session it was last named in.
+    named: u64,
+}
+
+
+/// records the thread's events on the track `id` until the guard is dropped.
+/// `name` is the track's name, only its first one per session is recorded.
+/// tracks nest, and the guard must be dropped on the same thread.
+pub fn track(id: u64, name: &str) -> Track {
+    let (tid, unnamed) = resolve(id, Kind::Track);
+    let previous = crate::enter_track(tid, unnamed.and(Some(name)));
+    if let (Some(session), Some(_)) = (unnamed, previous) {
+        named(id, Kind::Track, session);
+    }
+    Track { tid, previous, _thread: std::marker::PhantomData }
+}
+
+/// see `track`.
+pub struct Track {
+    tid: u32,
+    // `None` if the thread wasn't recording.
+    previous: Option<u32>,
+    // dropped on the thread that entered it.
+    _thread: std::marker::PhantomData<*const ()>,
+}
+
+impl Track {
+    /// the tid events on the track are recorded with.
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+}
+
+impl Drop for Track {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            crate::exit_track(self.tid, previous);
+        }
+    }
+}
+
+
+// the tid of a track, and the current session if the track isn't
+// named in it yet. it is once `named` records that its marker was.
+pub(crate) fn resolve(id: u64, kind: Kind) -> (u32, Option<u64>) {
+    let session = crate::session();
+    with_track(id, kind, |track| (track.tid, (track.named != session).then_some(session)))
+}
+
+// after the track's `spall/thread_start` marker was recorded in `session`.
+pub(crate) fn named(id: u64, kind: Kind, session: u64) {
+    with_track(id, kind, |track| track.named = session);
+}
+
+// the tid of a track, without using it.
+pub(crate) fn resolve_tid(id: u64, kind: Kind) -> u32 {
+    with_track(id, kind, |track| track.tid)
+}
+
+fn with_track<R>(id: u64, kind: Kind, f: impl FnOnce(&mut Registered) -> R) -> R {
+    let mut tracks = TRACKS.lock().unwrap();
+    let tracks = tracks.get_or_insert_with(Default::default);
+    let next = FIRST_TID | (tracks.len() as u32 & 0x3fff_ffff);
+    f(tracks.entry((id, kind)).or_insert(Registered { tid: next, named: 0 }))
+}
@@ -0,0 +1,339 @@
+// flushing with io_uring on linux, for the `io-uring` feature.
+//
+// each thread gets a ring and a second buffer, both registered, once
+// its buffer first fills. threads that only flush at exit write directly.
+// a flush submits a write of the full buffer and records into the other,
+// so the thread only waits if the previous write is still running at
+// its next flush. one write is in flight per thread, so its events stay
+// in order. the file is opened with `O_APPEND`, so writes append,
+// like `write(2)`. failed writes are retried with `write(2)`.
+// the buffer in flight is owned by the ring until the write is done.
+
+use std::io::{Error, Write};
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::TraceFile;
+use crate::buffer::Buffer;
+
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES:    libc::off_t = 0x10000000;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_ENTER_GETEVENTS:  u32 = 1 << 0;
+const IORING_REGISTER_BUFFERS: u32 = 0;
+
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_OP_WRITE:       u8 = 23;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    flags:        u32,
+    dropped:      u32,
+    array:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    overflow:     u32,
+    cqes:         u32,
+    flags:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries:     u32,
+    cq_entries:     u32,
+    flags:          u32,
+    sq_thread_cpu:  u32,
+    sq_thread_idle: u32,
+    features:       u32,
+    wq_fd:          u32,
+    resv:           [u32; 3],
+    sq_off:         SqOffsets,
+    cq_off:         CqOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode:       u8,
+    flags:        u8,
+    ioprio:       u16,
+    fd:           i32,
+    off:          u64,
+    addr:         u64,
+    len:          u32,
+    rw_flags:     u32,
+    user_data:    u64,
+    buf_index:    u16,
+    personality:  u16,
+    splice_fd_in: i32,
+    addr3:        u64,
+    pad:          u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res:       i32,
+    flags:     u32,
+}
+
+
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: libc::c_int, len: usize, offset: libc::off_t) -> Result<Map, Error> {
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        return Ok(Map { ptr: ptr.cast(), len });
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T { unsafe {
+        self.ptr.add(offset as usize).cast()
+    }}
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+
+// the write in flight.
+struct Pending {
+    // keeps the file open until the write is done.
+    file:   Arc<TraceFile>,
+    buffer: Buffer,
+    len:    usize,
+}
+
+pub(crate) struct Ring {
+    fd: libc::c_int,
+
+    sq_head:  *const AtomicU32,
+    sq_tail:  *const AtomicU32,
+    sq_mask:  u32,
+    sq_array: *mut u32,
+    sqes:     *mut Sqe,
+
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes:    *const Cqe,
+
+    // the addresses of the thread's buffer and the spare.
+    buffers: [*mut u8; 2],
+    // whether the buffers are registered.
+    fixed: bool,
+
+    // `None` while in flight, or if a write may still be running
+    // after a failed wait.
+    spare:   Option<Buffer>,
+    pending: Option<Pending>,
+    silent:  bool,
+
+    // unmapped after closing.
+    _maps: Vec<Map>,
+}
+
+impl Ring {
+    // registers the thread's `buffer`, and allocates the spare like it.
+    pub(crate) fn new(buffer: &Buffer, silent: bool) -> Result<Ring, Error> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 2 as libc::c_uint, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = fd as libc::c_int;
+
+        match unsafe { Self::map(fd, &params, buffer, silent) } {
+            Ok(ring) => Ok(ring),
+
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn map(fd: libc::c_int, params: &Params, buffer: &Buffer, silent: bool) -> Result<Ring, Error> { unsafe {
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes  as usize + params.cq_entries as usize * size_of::<Cqe>();
+
+        let mut maps = Vec::new();
+        if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            maps.push(Map::new(fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?);
+        }
+        else {
+            maps.push(Map::new(fd, sq_len, IORING_OFF_SQ_RING)?);
+            maps.push(Map::new(fd, cq_len, IORING_OFF_CQ_RING)?);
+        }
+        let sqes = Map::new(fd, params.sq_entries as usize * size_of::<Sqe>(), IORING_OFF_SQES)?;
+
+        let Some(spare) = Buffer::like(buffer) else {
+            return Err(Error::new(std::io::ErrorKind::OutOfMemory, "failed to allocate buffer"));
+        };
+        let buffers = [buffer.as_ptr(), spare.as_ptr()];
+
+        // older kernels limit registered memory, plain writes work too.
+        let iovecs = buffers.map(|base| libc::iovec { iov_base: base.cast(), iov_len: buffer.capacity() });
+        let fixed = libc::syscall(libc::SYS_io_uring_register, fd, IORING_REGISTER_BUFFERS,
+            iovecs.as_ptr(), iovecs.len() as libc::c_uint) == 0;
+
+        let (sq, cq) = (&maps[0], maps.last().unwrap());
+        return Ok(Ring {
+            fd,
+            sq_head:  sq.at(params.sq_off.head),
+            sq_tail:  sq.at(params.sq_off.tail),
+            sq_mask:  *sq.at::<u32>(params.sq_off.ring_mask),
+            sq_array: sq.at(params.sq_off.array),
+            sqes:     sqes.ptr.cast(),
+            cq_head:  cq.at(params.cq_off.head),
+            cq_tail:  cq.at(params.cq_off.tail),
+            cq_mask:  *cq.at::<u32>(params.cq_off.ring_mask),
+            cqes:     cq.at(params.cq_off.cqes),
+            buffers,
+            fixed,
+            spare: Some(spare),
+            pending: None,
+            silent,
+            _maps: maps.into_iter().chain([sqes]).collect(),
+        });
+    }}
+
+    // starts appending the first `len` bytes of `buffer` to `file`,
+    // and swaps it for the buffer to record into next.
+    pub(crate) fn submit(&mut self, file: &Arc<TraceFile>, buffer: &mut Buffer, len: usize) {
+        self.wait();
+
+        let index = self.buffers.iter().position(|b| *b == buffer.as_ptr());
+        let Some(index) = index.filter(|_| self.spare.is_some()) else {
+            self.write_sync(file, buffer.head(len));
+            return;
+        };
+        if len == 0 {
+            return;
+        }
+        let addr = buffer.head(len).as_ptr();
+
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let slot = tail & self.sq_mask;
+            self.sqes.add(slot as usize).write(Sqe {
+                opcode:       if self.fixed { IORING_OP_WRITE_FIXED } else { IORING_OP_WRITE },
+                flags:        0,
+                ioprio:       0,
+                fd:           file.file.as_raw_fd(),
+                // the file position, the end with `O_APPEND`.
+                off:          u64::MAX,
+                addr:         addr as u64,
+                len:          len as u32,
+                rw_flags:     0,
+                user_data:    0,
+                buf_index:    index as u16,
+                personality:  0,
+                splice_fd_in: 0,
+                addr3:        0,
+                pad:          0,
+            });
+            self.sq_array.add(slot as usize).write(slot);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+
+            let res = libc::syscall(libc::SYS_io_uring_enter, self.fd, 1 as libc::c_uint, 0 as libc::c_uint,
+                0 as libc::c_uint, std::ptr::null::<libc::sigset_t>(), 0 as libc::size_t);
+
+            let spare = self.spare.take().unwrap();
+            let buffer = std::mem::replace(buffer, spare);
+            self.pending = Some(Pending { file: file.clone(), buffer, len });
+            if res != 1 {
+                // `wait` submits it again.
+                self.wait();
+            }
+        }
+    }
+
+    // waits for the write in flight, if any.
+    pub(crate) fn wait(&mut self) {
+        let Some(pending) = self.pending.take() else { return };
+
+        let res = loop {
+            unsafe {
+                let head = (*self.cq_head).load(Ordering::Relaxed);
+                if head != (*self.cq_tail).load(Ordering::Acquire) {
+                    let cqe = self.cqes.add((head & self.cq_mask) as usize).read();
+                    (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+                    break cqe.res;
+                }
+
+                // including the entry, if submitting it failed.
+                let unsubmitted = (*self.sq_tail).load(Ordering::Relaxed)
+                    .wrapping_sub((*self.sq_head).load(Ordering::Acquire));
+                let res = libc::syscall(libc::SYS_io_uring_enter, self.fd, unsubmitted as libc::c_uint, 1 as libc::c_uint,
+                    IORING_ENTER_GETEVENTS, std::ptr::null::<libc::sigset_t>(), 0 as libc::size_t);
+                if res < 0 {
+                    let e = Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        // can't tell what was written, so nothing is retried.
+                        // the kernel may still read the buffer, so it's leaked,
+                        // and later flushes write synchronously.
+                        crate::report(self.silent, e.kind(), format_args!("spall io_uring wait failed {:?}", e));
+                        std::mem::forget(pending.buffer);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let written = res.max(0) as usize;
+        if written < pending.len {
+            self.write_sync(&pending.file, &pending.buffer.head(pending.len)[written..]);
+        }
+
+        let mut buffer = pending.buffer;
+        buffer.clear();
+        self.spare = Some(buffer);
+    }
+
+    fn write_sync(&self, file: &TraceFile, bytes: &[u8]) {
+        if let Err(e) = (&file.file).write_all(bytes) {
+            crate::report(self.silent, e.kind(), format_args!("spall file write failed {:?}", e));
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        self.wait();
+        unsafe { libc::close(self.fd) };
+    }
+}
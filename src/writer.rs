@@ -0,0 +1,255 @@
+//! writing spall traces without the global state.
+//!
+//! `SpallWriter` encodes events into a buffer and writes them to any
+//! `Write`, for tools that produce traces themselves, like converters,
+//! tests, or servers replaying recorded data. pids, tids and timestamps
+//! are up to the caller.
+
+use std::borrow::Cow;
+use std::io::{Error, Write};
+use std::mem::size_of;
+
+use crate::{BeginEvent, CustomDataEvent, EndEvent, EventType, OverwriteTimestampEvent, SpallHeader, push_as_bytes};
+
+
+/// an event for `SpallWriter::write_events`, like the arguments
+/// of the methods recording one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventRecord<'a> {
+    Begin {
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+        name: &'a str,
+        args: &'a str,
+    },
+
+    End {
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+    },
+
+    Instant {
+        pid:  u32,
+        tid:  u32,
+        when: f64,
+        name: &'a str,
+        args: &'a str,
+    },
+
+    CustomData {
+        data: &'a [u8],
+    },
+
+    OverwriteTimestamp {
+        timestamp_unit: f64,
+    },
+}
+
+pub struct SpallWriter<W: Write> {
+    // `None` once finished.
+    out: Option<W>,
+    buffer: Vec<u8>,
+    buffer_size: usize,
+}
+
+impl<W: Write> SpallWriter<W> {
+    /// starts a trace with the given timestamp unit, in microseconds.
+    /// the header is written with the first flush.
+    pub fn new(out: W, timestamp_unit: f64) -> Self {
+        Self::with_buffer_size(out, timestamp_unit, 64*1024)
+    }
+
+    /// like `new`, but buffers up to about `buffer_size` bytes between writes.
+    pub fn with_buffer_size(out: W, timestamp_unit: f64, buffer_size: usize) -> Self {
+        let mut buffer = Vec::with_capacity(buffer_size);
+        push_as_bytes(&mut buffer, SpallHeader::new(timestamp_unit));
+        Self { out: Some(out), buffer, buffer_size }
+    }
+
+    /// begins a scope. `when` is in timestamp units.
+    /// names and args are truncated to 255 bytes,
+    /// ending in `args::TRUNCATION_MARK`.
+    #[inline]
+    pub fn begin(&mut self, pid: u32, tid: u32, when: f64, name: &str, args: &str) -> Result<(), Error> {
+        let name = truncated(name);
+        let args = truncated(args);
+        self.begin_bytes(0, pid, tid, when, name.as_bytes(), args.as_bytes())
+    }
+
+    // as read from a trace, which may not be utf-8. cut off after 255 bytes.
+    pub(crate) fn begin_bytes(&mut self, category: u8, pid: u32, tid: u32, when: f64, name: &[u8], args: &[u8]) -> Result<(), Error> {
+        self.push_begin_bytes(category, pid, tid, when, name, args);
+        return self.flush_if_full();
+    }
+
+    /// ends the innermost open scope of the thread.
+    #[inline]
+    pub fn end(&mut self, pid: u32, tid: u32, when: f64) -> Result<(), Error> {
+        self.push_end(pid, tid, when);
+        return self.flush_if_full();
+    }
+
+    /// a zero length scope, which is how spall records instants.
+    pub fn instant(&mut self, pid: u32, tid: u32, when: f64, name: &str, args: &str) -> Result<(), Error> {
+        self.begin(pid, tid, when, name, args)?;
+        self.end(pid, tid, when)
+    }
+
+    /// a `CustomData` event with `data` as its payload.
+    /// see `raw::custom_data` for the tagged payloads spall's reader understands.
+    pub fn custom_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "custom data too large"))?;
+
+        push_as_bytes(&mut self.buffer, CustomDataEvent {
+            ty: EventType::CustomData as u8,
+            size,
+        });
+        self.buffer.extend_from_slice(data);
+        return self.flush_if_full();
+    }
+
+    /// changes the timestamp unit.
+    /// readers apply the last unit to the whole trace.
+    pub fn overwrite_timestamp_unit(&mut self, timestamp_unit: f64) -> Result<(), Error> {
+        push_as_bytes(&mut self.buffer, OverwriteTimestampEvent {
+            ty: EventType::OverwriteTimestamp as u8,
+            timestamp_unit,
+        });
+        return self.flush_if_full();
+    }
+
+    /// encodes all of `events` in one pass, for events that are already
+    /// in memory, like in importers. the same as calling the methods
+    /// for each, but the buffer only grows once and is only flushed
+    /// at the end. nothing is written if custom data is too large.
+    pub fn write_events(&mut self, events: &[EventRecord]) -> Result<(), Error> {
+        let mut size = 0;
+        for event in events {
+            size += match event {
+                EventRecord::Begin { name, args, .. } =>
+                    size_of::<BeginEvent>() + name.len().min(255) + args.len().min(255),
+
+                EventRecord::End { .. } => size_of::<EndEvent>(),
+
+                EventRecord::Instant { name, args, .. } =>
+                    size_of::<BeginEvent>() + name.len().min(255) + args.len().min(255) + size_of::<EndEvent>(),
+
+                EventRecord::CustomData { data } => {
+                    if u32::try_from(data.len()).is_err() {
+                        return Err(Error::new(std::io::ErrorKind::InvalidInput, "custom data too large"));
+                    }
+                    size_of::<CustomDataEvent>() + data.len()
+                }
+
+                EventRecord::OverwriteTimestamp { .. } => size_of::<OverwriteTimestampEvent>(),
+            };
+        }
+        self.buffer.reserve(size);
+
+        for event in events {
+            match *event {
+                EventRecord::Begin { pid, tid, when, name, args } => self.push_begin(pid, tid, when, name, args),
+
+                EventRecord::End { pid, tid, when } => self.push_end(pid, tid, when),
+
+                EventRecord::Instant { pid, tid, when, name, args } => {
+                    self.push_begin(pid, tid, when, name, args);
+                    self.push_end(pid, tid, when);
+                }
+
+                EventRecord::CustomData { data } => {
+                    push_as_bytes(&mut self.buffer, CustomDataEvent {
+                        ty: EventType::CustomData as u8,
+                        size: data.len() as u32,
+                    });
+                    self.buffer.extend_from_slice(data);
+                }
+
+                EventRecord::OverwriteTimestamp { timestamp_unit } => {
+                    push_as_bytes(&mut self.buffer, OverwriteTimestampEvent {
+                        ty: EventType::OverwriteTimestamp as u8,
+                        timestamp_unit,
+                    });
+                }
+            }
+        }
+        return self.flush_if_full();
+    }
+
+    /// writes out the buffered events.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let Some(out) = self.out.as_mut() else { return Ok(()) };
+        out.write_all(&self.buffer)?;
+        self.buffer.clear();
+        return out.flush();
+    }
+
+    /// ends the stream and returns the output.
+    /// dropping the writer does the same, but ignores errors.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.buffer.push(EventType::StreamOver as u8);
+        let result = self.flush();
+        let out = self.out.take().unwrap();
+        return result.map(|()| out);
+    }
+
+    fn push_begin(&mut self, pid: u32, tid: u32, when: f64, name: &str, args: &str) {
+        let name = truncated(name);
+        let args = truncated(args);
+        self.push_begin_bytes(0, pid, tid, when, name.as_bytes(), args.as_bytes());
+    }
+
+    fn push_begin_bytes(&mut self, category: u8, pid: u32, tid: u32, when: f64, name: &[u8], args: &[u8]) {
+        let name = &name[..name.len().min(255)];
+        let args = &args[..args.len().min(255)];
+
+        push_as_bytes(&mut self.buffer, BeginEvent {
+            ty: EventType::Begin as u8,
+            category,
+            pid,
+            tid,
+            when,
+            name_len: name.len() as u8,
+            args_len: args.len() as u8,
+        });
+        self.buffer.extend_from_slice(name);
+        self.buffer.extend_from_slice(args);
+    }
+
+    #[inline]
+    fn push_end(&mut self, pid: u32, tid: u32, when: f64) {
+        push_as_bytes(&mut self.buffer, EndEvent {
+            ty: EventType::End as u8,
+            pid,
+            tid,
+            when,
+        });
+    }
+
+    #[inline]
+    fn flush_if_full(&mut self) -> Result<(), Error> {
+        if self.buffer.len() >= self.buffer_size {
+            return self.flush();
+        }
+        return Ok(());
+    }
+}
+
+impl<W: Write> Drop for SpallWriter<W> {
+    fn drop(&mut self) {
+        if self.out.is_some() {
+            self.buffer.push(EventType::StreamOver as u8);
+            _ = self.flush();
+        }
+    }
+}
+
+pub(crate) fn truncated(s: &str) -> Cow<'_, str> {
+    match crate::truncate(s, 255) {
+        (s, false) => Cow::Borrowed(s),
+        (s, true)  => Cow::Owned(format!("{}{}", s, crate::args::TRUNCATION_MARK)),
+    }
+}
@@ -0,0 +1,150 @@
+use spall::analysis::{self, NameStats, Rank};
+use spall::filter::Level;
+use spall::reader::Trace;
+use spall::testing::{compiled_in, record, ManualClock};
+
+
+fn scope(clock: &ManualClock, name: &str, before: u64, f: impl FnOnce(), after: u64) {
+    let scope = spall::trace_scope_impl(name);
+    clock.advance_micros(before);
+    f();
+    clock.advance_micros(after);
+    scope.end();
+}
+
+fn stats<'a>(stats: &'a [NameStats], name: &str) -> &'a NameStats {
+    stats.iter().find(|s| s.name == name).unwrap()
+}
+
+#[test]
+fn name_stats() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        scope(clock, "frame", 0, || {
+            scope(clock, "update", 40, || scope(clock, "physics", 20, || (), 0), 0);
+            scope(clock, "render", 30, || (), 0);
+        }, 10);
+        scope(clock, "frame", 0, || scope(clock, "update", 10, || (), 0), 40);
+        // counted once per level.
+        scope(clock, "walk", 5, || {
+            scope(clock, "walk", 5, || scope(clock, "walk", 10, || (), 0), 15);
+        }, 5);
+    }).unwrap();
+    let all = analysis::name_stats(&trace);
+
+    let frame = stats(&all, "frame");
+    assert_eq!((frame.count, frame.total, frame.self_time), (2, 150.0, 50.0));
+    assert_eq!((frame.min, frame.max, frame.mean, frame.p95), (50.0, 100.0, 75.0, 100.0));
+
+    let update = stats(&all, "update");
+    assert_eq!((update.total, update.self_time), (70.0, 50.0));
+
+    let walk = stats(&all, "walk");
+    assert_eq!((walk.count, walk.total, walk.self_time), (3, 80.0, 40.0));
+    assert_eq!((walk.min, walk.max, walk.p95), (10.0, 40.0, 40.0));
+
+    let render = stats(&all, "render");
+    assert_eq!((render.count, render.total, render.self_time), (1, 30.0, 30.0));
+    assert_eq!((render.min, render.max, render.mean, render.p95), (30.0, 30.0, 30.0, 30.0));
+
+    // ordered by total time, without spall's markers.
+    let names = all.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["frame", "walk", "update", "render", "physics"]);
+}
+
+#[test]
+fn top() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        scope(clock, "long", 30, || (), 0);
+        for _ in 0..3 {
+            scope(clock, "short", 5, || (), 0);
+            // more flushes than any scope.
+            for _ in 0..4 {
+                spall::flush();
+            }
+        }
+        scope(clock, "nested", 5, || scope(clock, "short", 1, || (), 0), 5);
+    }).unwrap();
+    let names = |rank| analysis::top(&trace, 2, rank).into_iter().map(|s| s.name).collect::<Vec<_>>();
+
+    assert_eq!(names(Rank::Total),    ["long", "short"]);
+    assert_eq!(names(Rank::SelfTime), ["long", "short"]);
+    assert_eq!(names(Rank::Count),    ["short", "long"]);
+    assert_eq!(names(Rank::Mean),     ["long", "nested"]);
+}
+
+#[test]
+fn percentile() {
+    let values = (1..=20).map(|v| v as f64).collect::<Vec<_>>();
+    assert_eq!(analysis::percentile(&values, 0.95), 19.0);
+    assert_eq!(analysis::percentile(&values, 0.5), 10.0);
+    assert_eq!(analysis::percentile(&values, 0.0), 1.0);
+    assert_eq!(analysis::percentile(&values, 1.0), 20.0);
+    assert_eq!(analysis::percentile(&[7.0], 0.95), 7.0);
+    assert!(analysis::percentile(&[], 0.95).is_nan());
+}
+
+
+// a trace with a `work` scope of each duration.
+fn work(durations: &[u64]) -> Trace {
+    record(Default::default(), |clock| {
+        for duration in durations {
+            scope(clock, "work", *duration, || (), 0);
+        }
+    }).unwrap()
+}
+
+fn diff(before: &Trace, after: &Trace, stack: &str) -> analysis::ScopeDiff {
+    analysis::diff(before, after).into_iter().find(|d| d.stack == stack).unwrap()
+}
+
+#[test]
+fn welch_p_values() {
+    if !compiled_in(Level::Normal) { return }
+
+    let same = diff(&work(&[10, 12, 14, 11]), &work(&[11, 14, 10, 12]), "work");
+    assert!((same.p_value - 1.0).abs() < 1e-9, "{}", same.p_value);
+    assert_eq!(same.change, 0.0);
+
+    let shifted = diff(&work(&[10, 11, 10, 12, 11, 10]), &work(&[20, 21, 22, 20, 21, 22]), "work");
+    assert!(shifted.p_value < 0.01, "{}", shifted.p_value);
+    assert!(shifted.is_significant(0.01));
+    assert_eq!(shifted.delta_total, 126.0 - 64.0);
+
+    // references from mpmath's regularized incomplete beta,
+    // with df of about 5.88 and 2.94.
+    let d = diff(&work(&[1, 2, 3, 4, 5]), &work(&[2, 4, 6, 8, 10]), "work");
+    assert!((d.p_value - 0.10753119493062728).abs() < 1e-9, "{}", d.p_value);
+    assert_eq!(d.change, 1.0);
+    let d = diff(&work(&[10, 11, 12]), &work(&[13, 15, 17]), "work");
+    assert!((d.p_value - 0.054_786_766_041_076_43).abs() < 1e-9, "{}", d.p_value);
+}
+
+#[test]
+fn degenerate_diffs() {
+    if !compiled_in(Level::Normal) { return }
+
+    // no variance on either side.
+    assert_eq!(diff(&work(&[5, 5]), &work(&[5, 5, 5]), "work").p_value, 1.0);
+    assert_eq!(diff(&work(&[5, 5]), &work(&[6, 6]), "work").p_value, 0.0);
+
+    // fewer than two scopes on a side.
+    let d = diff(&work(&[5]), &work(&[6, 7]), "work");
+    assert!(d.p_value.is_nan());
+    assert!(!d.is_significant(0.01));
+
+    // from nothing.
+    let d = diff(&work(&[0, 0]), &work(&[5, 5]), "work");
+    assert_eq!(d.change, f64::INFINITY);
+
+    // stacks in one trace only.
+    let d = diff(&work(&[]), &work(&[5, 6]), "work");
+    assert_eq!((d.before_count, d.after_count, d.delta_total), (0, 2, 11.0));
+    assert!(d.before_mean.is_nan() && d.change.is_nan() && d.p_value.is_nan());
+    let d = diff(&work(&[5, 6]), &work(&[]), "work");
+    assert_eq!((d.before_count, d.after_count, d.delta_total), (2, 0, -11.0));
+    assert!(d.after_mean.is_nan() && d.change.is_nan() && d.p_value.is_nan());
+}
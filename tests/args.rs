@@ -0,0 +1,47 @@
+use std::fmt;
+
+use spall::args::{self, KeyValues};
+use spall::filter::Level;
+use spall::testing::compiled_in;
+
+
+// writes its parts one at a time.
+struct Parts(&'static [&'static str]);
+
+impl fmt::Display for Parts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in self.0 {
+            f.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn key_values() {
+    if !compiled_in(Level::Normal) { return }
+
+    let pairs: [(&'static str, &dyn fmt::Display); 7] = [
+        ("n",     &42),
+        ("path",  &"assets/a.png"),
+        ("empty", &""),
+        ("space", &"a b"),
+        ("quote", &r#"say "hi""#),
+        ("slash", &r"c:\x"),
+        // quoted for a later part.
+        ("parts", &Parts(&["abc", "", "d\"e", " f"])),
+    ];
+    let text = KeyValues(&pairs).to_string();
+    assert_eq!(text, r#"n=42 path=assets/a.png empty="" space="a b" quote="say \"hi\"" slash="c:\\x" parts="abcd\"e f""#);
+
+    let parsed = args::parse(&text).into_iter().map(|(k, v)| (k, v.into_owned())).collect::<Vec<_>>();
+    let expected = pairs.iter().map(|(k, v)| (*k, v.to_string())).collect::<Vec<_>>();
+    assert_eq!(parsed, expected);
+
+    // through the macro too.
+    let trace = spall::testing::record(Default::default(), |_| {
+        let (path, size) = ("my file.txt", 10);
+        spall::trace_scope!("load", { path = path, size = size });
+    }).unwrap();
+    assert_eq!(trace.scopes_named("load").next().unwrap().args, r#"path="my file.txt" size=10"#);
+}
@@ -0,0 +1,29 @@
+use std::io::ErrorKind;
+
+use spall::clock_sync;
+use spall::reader::Trace;
+
+
+// a trace of `spall/clock_sync` markers, as `(when, id, dir)`.
+fn trace(messages: &[(f64, u64, &str)]) -> Trace {
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    for (when, id, dir) in messages {
+        writer.instant(1, 1, *when, "spall/clock_sync", &format!("id={} dir={}", id, dir)).unwrap();
+    }
+    Trace::parse(&writer.finish().unwrap()).unwrap()
+}
+
+#[test]
+fn align() {
+    // b's clock is 50 behind, messages take 10.
+    let a = trace(&[(100.0, 1, "send"), (300.0, 2, "recv")]);
+    let b = trace(&[(60.0, 1, "recv"), (240.0, 2, "send")]);
+    let alignments = clock_sync::align(&[a, b]).unwrap();
+    assert_eq!(alignments[1], clock_sync::Alignment { offset: 50.0, error: 10.0 });
+
+    // a message that arrived before the other was sent.
+    let a = trace(&[(100.0, 1, "send"), (300.0, 2, "recv")]);
+    let b = trace(&[(40.0, 1, "recv"), (280.0, 2, "send")]);
+    let err = clock_sync::align(&[a, b]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
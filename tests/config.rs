@@ -0,0 +1,65 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use spall::config::{self, Config};
+use spall::filter::Level;
+
+
+#[test]
+fn parse() {
+    let config = Config::parse(r#"
+        path = "traces/game_$.spall"
+        buffer_size = 65536
+        overflow = { grow = { max_buffer_size = 1048576 } }
+        filter = "render/*"
+        level = "verbose"
+        min_duration_us = 1.5
+        flush_interval_ms = 100
+        format = "chrome_json"
+
+        [live]
+        addr = "127.0.0.1:9099"
+    "#).unwrap();
+    assert_eq!(config.path.as_deref(), Some("traces/game_$.spall"));
+    assert_eq!(config.live.as_ref().unwrap().queue_buffers, 64);
+
+    let options = config.options();
+    assert_eq!(options.buffer_size, 65536);
+    assert_eq!(options.overflow, spall::Overflow::Grow { max_buffer_size: 1 << 20 });
+    assert_eq!(options.filter.as_deref(), Some("render/*"));
+    assert_eq!(options.level, Level::Verbose);
+    assert_eq!(options.min_duration, Some(Duration::from_micros(1) + Duration::from_nanos(500)));
+    assert_eq!(options.flush_interval, Some(Duration::from_millis(100)));
+    assert_eq!(options.format, spall::Format::ChromeJson);
+
+    // unset keys are the defaults.
+    let options = Config::parse("").unwrap().options();
+    let default = spall::Options::default();
+    assert_eq!((options.buffer_size, options.level, options.sample_rate), (default.buffer_size, default.level, default.sample_rate));
+}
+
+#[test]
+fn errors() {
+    for text in [
+        "path = ",
+        "path = 1",
+        "unknown = true",
+        "level = \"loud\"",
+        "overflow = \"spill\"",
+        "[live]\nqueue_buffers = 8",
+        "[live]\naddr = \"x\"\nport = 1",
+    ] {
+        let err = Config::parse(text).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", text);
+    }
+
+    // errors of the file, before initializing.
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("spall-config-test-{}.toml", std::process::id()));
+    std::fs::write(&path, "buffer_size = \"big\"").unwrap();
+    assert_eq!(config::init_from_file(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+    std::fs::write(&path, "buffer_size = 1024").unwrap();
+    assert_eq!(config::init_from_file(&path).unwrap_err().kind(), ErrorKind::InvalidInput);
+    _ = std::fs::remove_file(&path);
+    assert_eq!(config::init_from_file(&path).unwrap_err().kind(), ErrorKind::NotFound);
+}
@@ -0,0 +1,33 @@
+use std::cell::Cell;
+
+
+// counts evaluations.
+fn counted<T>(count: &Cell<u32>, value: T) -> T {
+    count.set(count.get() + 1);
+    value
+}
+
+#[test]
+fn nothing_is_evaluated() {
+    // `force-enable` wins.
+    if spall::ENABLED { return }
+
+    assert!(!spall::init(std::env::temp_dir().join("spall-disabled-test.spall")).unwrap());
+
+    let count = Cell::new(0);
+    let name = || counted(&count, "name");
+    spall::trace_scope!(name());
+    spall::trace_scope!(name(), "{}", counted(&count, 1));
+    spall::trace_scope!(name(), { n = counted(&count, 1) });
+    spall::trace_scope!(level = Coarse, name(), "{}", counted(&count, 1));
+    spall::trace_scope_if!(counted(&count, true), name(), "{}", counted(&count, 1));
+    spall::trace_scope_if!(|| counted(&count, true), name());
+    spall::trace_scope_sampled!(every = counted(&count, 1), name(), "{}", counted(&count, 1));
+    spall::trace_scope_sampled!(probability = counted(&count, 1.0), name());
+    spall::trace_log!("{}", counted(&count, 1));
+    spall::trace_log!(level = Coarse, "{}", counted(&count, 1));
+    assert_eq!(count.get(), 0);
+
+    let err = spall::testing::record(Default::default(), |_| ()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
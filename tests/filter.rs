@@ -0,0 +1,104 @@
+use spall::filter::{self, Level};
+use spall::testing::{compiled_in, record};
+
+
+fn with_filter(filter: &str) -> spall::Options {
+    spall::Options { filter: Some(filter.into()), ..Default::default() }
+}
+
+fn scope(name: &str) {
+    spall::trace_scope!(name);
+}
+
+#[test]
+fn globs() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(with_filter("render/*,!render/particles"), |_| {
+        for name in ["render/mesh", "render/particles", "render/ui", "audio"] {
+            scope(name);
+        }
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("render/mesh").count(), 1);
+    assert_eq!(trace.scopes_named("render/ui").count(), 1);
+    assert_eq!(trace.scopes_named("render/particles").count(), 0);
+    // names matching no pattern are dropped with including ones.
+    assert_eq!(trace.scopes_named("audio").count(), 0);
+}
+
+#[test]
+fn excludes_only() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(with_filter("!noise*, !a?c"), |_| {
+        for name in ["noise", "noisy", "noise/x", "abc", "abbc", "signal"] {
+            scope(name);
+        }
+    }).unwrap();
+
+    let mut names = trace.scopes().iter().filter(|s| !s.name.starts_with("spall/")).map(|s| s.name.as_str()).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["abbc", "noisy", "signal"]);
+}
+
+#[test]
+fn levels_with_filter() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { level: Level::Coarse, ..with_filter("*") };
+    let trace = record(options, |_| {
+        let frame = || {
+            spall::trace_scope!(level = Coarse, "frame");
+            spall::trace_scope!("update");
+            spall::trace_scope!(level = Verbose, "particle");
+        };
+        frame();
+        // the call sites decide again.
+        filter::set_level(Level::Normal);
+        frame();
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("frame").count(), 2);
+    assert_eq!(trace.scopes_named("update").count(), 1);
+    assert_eq!(trace.scopes_named("particle").count(), 0);
+}
+
+#[test]
+fn filter_changes() {
+    if !compiled_in(Level::Normal) { return }
+
+    static NAME: spall::ScopeName = spall::ScopeName::new("load");
+
+    let trace = record(with_filter("load"), |_| {
+        scope("load");
+        drop(NAME.scope());
+
+        filter::set_filter(Some("!load"));
+        scope("load");
+        drop(NAME.scope());
+
+        filter::set_filter(None);
+        scope("load");
+        drop(NAME.scope());
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("load").count(), 4);
+}
+
+#[test]
+fn dynamic_names() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(with_filter("job 1"), |_| {
+        for _ in 0..3 {
+            for i in 0..3 {
+                // reuses the allocation of the last name.
+                spall::trace_scope!(format!("job {}", i));
+            }
+        }
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("job 1").count(), 3);
+    assert_eq!(trace.scopes().iter().filter(|s| s.name.starts_with("job")).count(), 3);
+}
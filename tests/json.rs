@@ -0,0 +1,64 @@
+use spall::args::{self, Json};
+use spall::filter::Level;
+use spall::testing::{compiled_in, record};
+
+
+// a json string of `len` bytes.
+fn string(len: usize) -> String {
+    "a".repeat(len - 2)
+}
+
+#[test]
+fn boundaries() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |_| {
+        for (tag, len) in [("fits", 0), ("over", 1)] {
+            spall::trace_scope!(tag, "{}", Json(&string(255 + len)));
+            spall::trace_scope!(tag, "n={}", Json(&string(253 + len)));
+            // `k="` and `"`, and a `\` for each quote of the json.
+            spall::trace_scope!(tag, { k = Json(&string(249 + len)) });
+            // after `sample_rate=1 `.
+            spall::trace_scope_sampled!(every = 1, tag, "{}", Json(&string(241 + len)));
+        }
+    }).unwrap();
+
+    let jsons = |name| trace.scopes_named(name).map(|s| {
+        let args = &s.args;
+        let json = if let Some(json) = args.strip_prefix("sample_rate=1 ") {
+            json.to_string()
+        }
+        else if let Some(json) = args.strip_prefix("n=") {
+            json.to_string()
+        }
+        else if args.starts_with("k=") {
+            args::parse(args)[0].1.to_string()
+        }
+        else {
+            args.to_string()
+        };
+        assert!(args.len() <= args::MAX_LEN, "{}", args);
+        json
+    }).collect::<Vec<_>>();
+
+    let fits = jsons("fits");
+    let lens = fits.iter().map(|json| json.len()).collect::<Vec<_>>();
+    assert_eq!(lens, [255, 253, 249, 241]);
+    assert!(fits.iter().all(|json| json.starts_with('"') && json.ends_with('"')));
+
+    let over = jsons("over");
+    assert_eq!(over, [
+        r#"{"spall_truncated":256}"#,
+        r#"{"spall_truncated":254}"#,
+        r#"{"spall_truncated":250}"#,
+        r#"{"spall_truncated":242}"#,
+    ]);
+}
+
+#[test]
+fn outside_scopes() {
+    let long = string(1000);
+    assert_eq!(Json(&long).to_string(), format!("\"{}\"", long));
+    // `k="`, escaped quotes, and `"`.
+    assert_eq!(args::KeyValues(&[("k", &Json(&long))]).to_string().len(), 1000 + 6);
+}
@@ -0,0 +1,37 @@
+use std::cell::Cell;
+
+use spall::filter::{self, Level};
+
+
+// counts evaluations.
+fn counted<T>(count: &Cell<u32>, value: T) -> T {
+    count.set(count.get() + 1);
+    value
+}
+
+#[test]
+fn above_max_level() {
+    if !spall::testing::compiled_in(Level::Coarse) { return }
+
+    assert_eq!(filter::MAX_LEVEL, Level::Coarse);
+
+    let count = Cell::new(0);
+    let trace = spall::testing::record(Default::default(), |_| {
+        let name = |name| counted(&count, name);
+        spall::trace_scope!(name("normal"));
+        spall::trace_scope!(name("normal"), "{}", counted(&count, 1));
+        spall::trace_scope!(level = Verbose, name("verbose"), { n = counted(&count, 1) });
+        spall::trace_scope_if!(counted(&count, true), name("normal"));
+        spall::trace_scope_sampled!(every = counted(&count, 1), name("normal"));
+        spall::trace_log!("{}", counted(&count, 1));
+        assert_eq!(count.get(), 0);
+
+        spall::trace_scope!(level = Coarse, name("coarse"), "{}", counted(&count, 1));
+        spall::trace_log!(level = Coarse, "{}", counted(&count, 1));
+    }).unwrap();
+
+    assert_eq!(count.get(), 3);
+    assert_eq!(trace.scopes_named("coarse").next().unwrap().args, "1");
+    assert_eq!(trace.scopes_named("log").count(), 1);
+    assert_eq!(trace.scopes_named("normal").count(), 0);
+}
@@ -0,0 +1,59 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use spall::filter::Level;
+use spall::testing::compiled_in;
+
+
+fn files(dir: &Path) -> Vec<String> {
+    let mut files = std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn record(path: &Path) {
+    assert!(spall::init(path).unwrap());
+    spall::trace_scope!("work");
+    assert!(spall::shutdown());
+}
+
+// sessions of their own, so in a binary of its own.
+#[test]
+fn templates() {
+    if !compiled_in(Level::Normal) { return }
+
+    let dir = std::env::temp_dir().join(format!("spall-paths-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    record(&dir.join("{pid}-{date}.spall"));
+    let created = files(&dir);
+    let [name] = &created[..] else { panic!("{:?}", created) };
+    let (pid, date) = name.strip_suffix(".spall").unwrap().split_once('-').unwrap();
+    assert_eq!(pid, std::process::id().to_string());
+    // like `20261014-153012`.
+    assert_eq!(date.len(), 15);
+    assert!(date.char_indices().all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() }), "{}", date);
+    let trace = spall::reader::Trace::open(dir.join(name)).unwrap();
+    assert_eq!(trace.scopes_named("work").count(), 1);
+    std::fs::remove_file(dir.join(name)).unwrap();
+
+    // the lowest unused number.
+    record(&dir.join("run-{seq}.spall"));
+    record(&dir.join("run-{seq}.spall"));
+    std::fs::remove_file(dir.join("run-0001.spall")).unwrap();
+    record(&dir.join("run-{seq}.spall"));
+    assert_eq!(files(&dir), ["run-0001.spall", "run-0002.spall"]);
+
+    record(&dir.join("{{literal}}.spall"));
+    assert!(dir.join("{literal}.spall").exists());
+
+    for template in ["{nope}.spall", "{pid.spall", "pid}.spall"] {
+        let err = spall::init(dir.join(template)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", template);
+    }
+    assert!(!spall::shutdown());
+
+    _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,74 @@
+use spall::reader::Trace;
+
+
+const SCRIPT: &str = "\
+# captured on: a test
+app 1234/1240 [003] 5000.000100: 250000 cpu-clock:
+\t    55d0a1 read+0x1c (/usr/bin/app)
+\t    55d0b2 parse+0x40 (/usr/bin/app)
+\t    55d0c3 main+0x8 (/usr/bin/app)
+
+app 1234/1240 [003] 5000.000350: 250000 cpu-clock:
+\t    55d0a1 read+0x20 (/usr/bin/app)
+\t    55d0b2 parse+0x40 (/usr/bin/app)
+\t    55d0c3 main+0x8 (/usr/bin/app)
+
+worker thread 77 [001] 5000.000200: 250000 cpu-clock:
+\tffffffff [unknown] ([kernel.kallsyms])
+
+app 1234/1240 [003] 5000.000600: 250000 cpu-clock:
+\t    55d0d4 render (/usr/bin/app)
+\t    55d0c3 main+0x8 (/usr/bin/app)
+
+app 1234/1240 [003] 5000.002000: 250000 cpu-clock:
+\t    55d0c3 main+0x10 (/usr/bin/app)
+";
+
+// the scopes of a trace, timestamps in whole microseconds since `zero`.
+fn scopes(trace: &Trace, zero: f64) -> Vec<(u32, &str, &str, i64, i64, u32)> {
+    let mut scopes = trace.scopes().iter()
+        .map(|s| (s.tid, s.name.as_str(), s.args.as_str(), (s.start - zero).round() as i64, (s.end - zero).round() as i64, s.depth))
+        .collect::<Vec<_>>();
+    scopes.sort_by_key(|s| (s.0, s.3, s.5));
+    scopes
+}
+
+#[test]
+fn script() {
+    let path = std::env::temp_dir().join(format!("spall-perf-script-{}.spall", std::process::id()));
+    assert_eq!(spall::perf::import_script(SCRIPT, &path).unwrap(), 5);
+    let trace = Trace::open(&path).unwrap();
+    _ = std::fs::remove_file(&path);
+
+    // samples last 250us, the median interval, unless the thread was idle.
+    let app = "dso=/usr/bin/app";
+    assert_eq!(scopes(&trace, 5000e6), [
+        (77,   "[unknown]", "dso=[kernel.kallsyms]", 200, 450, 0),
+        (1240, "main",      app,                     100, 850, 0),
+        (1240, "parse",     app,                     100, 600, 1),
+        (1240, "read",      app,                     100, 600, 2),
+        (1240, "render",    app,                     600, 850, 1),
+        (1240, "main",      app,                     2000, 2250, 0),
+    ]);
+    assert!(trace.scopes().iter().all(|s| s.pid == if s.tid == 77 { 77 } else { 1234 }));
+
+    let empty = std::env::temp_dir().join(format!("spall-perf-empty-{}.spall", std::process::id()));
+    let err = spall::perf::import_script("# nothing\n", &empty).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn folded() {
+    let path = std::env::temp_dir().join(format!("spall-perf-folded-{}.spall", std::process::id()));
+    let text = "main;parse;read 2\nmain;parse 1\nnot a stack\nmain;render 1\n";
+    assert_eq!(spall::perf::import_folded(text, 10.0, &path).unwrap(), 4);
+    let trace = Trace::open(&path).unwrap();
+    _ = std::fs::remove_file(&path);
+
+    assert_eq!(scopes(&trace, 0.0), [
+        (0, "main",   "", 0,  40, 0),
+        (0, "parse",  "", 0,  30, 1),
+        (0, "read",   "", 0,  20, 2),
+        (0, "render", "", 30, 40, 1),
+    ]);
+}
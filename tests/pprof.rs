@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use spall::filter::Level;
+use spall::testing::{compiled_in, record, ManualClock};
+
+
+// a protobuf field, varint or length delimited.
+#[derive(Clone, Copy, Debug)]
+enum Value<'a> {
+    Int(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn int(self) -> u64 {
+        match self { Value::Int(v) => v, Value::Bytes(_) => panic!("not a varint") }
+    }
+
+    fn bytes(self) -> &'a [u8] {
+        match self { Value::Bytes(v) => v, Value::Int(_) => panic!("not length delimited") }
+    }
+}
+
+fn varint(data: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = data.split_first().unwrap();
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+fn fields(mut data: &[u8]) -> Vec<(u64, Value<'_>)> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = varint(&mut data);
+        let value = match key & 7 {
+            0 => Value::Int(varint(&mut data)),
+            2 => {
+                let len = varint(&mut data) as usize;
+                let (value, rest) = data.split_at(len);
+                data = rest;
+                Value::Bytes(value)
+            }
+            ty => panic!("unexpected wire type {}", ty),
+        };
+        fields.push((key >> 3, value));
+    }
+    fields
+}
+
+fn field(data: &[u8], number: u64) -> Option<Value<'_>> {
+    fields(data).into_iter().find(|(n, _)| *n == number).map(|(_, v)| v)
+}
+
+fn packed(mut data: &[u8]) -> Vec<u64> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        values.push(varint(&mut data));
+    }
+    values
+}
+
+
+fn scope(clock: &ManualClock, name: &str, before: u64, f: impl FnOnce(), after: u64) {
+    let scope = spall::trace_scope_impl(name);
+    clock.advance_micros(before);
+    f();
+    clock.advance_micros(after);
+    scope.end();
+}
+
+#[test]
+fn round_trip() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        for _ in 0..2 {
+            scope(clock, "frame", 0, || {
+                scope(clock, "update", 40, || scope(clock, "physics", 20, || (), 0), 0);
+                scope(clock, "render", 30, || (), 0);
+            }, 10);
+        }
+    }).unwrap();
+    let profile = spall::pprof::encode(&trace);
+    let profile = fields(&profile);
+
+    let strings = profile.iter()
+        .filter(|(n, _)| *n == 6)
+        .map(|(_, v)| std::str::from_utf8(v.bytes()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(strings[0], "");
+    let string = |v: Value| strings[v.int() as usize];
+
+    let types = profile.iter()
+        .filter(|(n, _)| *n == 1)
+        .map(|(_, v)| (string(field(v.bytes(), 1).unwrap()), string(field(v.bytes(), 2).unwrap())))
+        .collect::<Vec<_>>();
+    assert_eq!(types, [("scopes", "count"), ("self", "nanoseconds")]);
+
+    // location id to function name, through the location's line.
+    let functions = profile.iter()
+        .filter(|(n, _)| *n == 5)
+        .map(|(_, v)| (field(v.bytes(), 1).unwrap().int(), string(field(v.bytes(), 2).unwrap())))
+        .collect::<HashMap<_, _>>();
+    let locations = profile.iter()
+        .filter(|(n, _)| *n == 4)
+        .map(|(_, v)| {
+            let line = field(v.bytes(), 4).unwrap().bytes();
+            (field(v.bytes(), 1).unwrap().int(), functions[&field(line, 1).unwrap().int()])
+        })
+        .collect::<HashMap<_, _>>();
+
+    // stacks, root first, with their count and self time.
+    let tid = trace.scopes_named("frame").next().unwrap().tid as u64;
+    let mut samples = profile.iter()
+        .filter(|(n, _)| *n == 2)
+        .map(|(_, v)| {
+            let sample = v.bytes();
+            let stack = packed(field(sample, 1).unwrap().bytes()).iter().rev().map(|id| locations[id]).collect::<Vec<_>>();
+            let label = field(sample, 3).unwrap().bytes();
+            assert_eq!((string(field(label, 1).unwrap()), field(label, 3).unwrap().int()), ("tid", tid));
+            (stack.join(";"), packed(field(sample, 2).unwrap().bytes()))
+        })
+        .filter(|(stack, _)| !stack.starts_with("spall/"))
+        .collect::<Vec<_>>();
+    samples.sort();
+    assert_eq!(samples, [
+        ("frame".to_string(),                vec![2, 20_000]),
+        ("frame;render".to_string(),         vec![2, 60_000]),
+        ("frame;update".to_string(),         vec![2, 80_000]),
+        ("frame;update;physics".to_string(), vec![2, 40_000]),
+    ]);
+
+    let default_type = profile.iter().find(|(n, _)| *n == 14).unwrap().1;
+    assert_eq!(string(default_type), "self");
+}
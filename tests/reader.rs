@@ -0,0 +1,458 @@
+use std::mem::size_of;
+
+use spall::filter::Level;
+use spall::reader::{Parser, RawEvent, Trace};
+use spall::testing::compiled_in;
+use spall::writer::EventRecord;
+use spall::{BeginEvent, CustomDataEvent, EndEvent, EventType, OverwriteTimestampEvent, PadSkipEvent, SpallHeader};
+use zerocopy::{Immutable, IntoBytes};
+
+
+fn push<T: IntoBytes + Immutable>(out: &mut Vec<u8>, v: T) {
+    out.extend_from_slice(v.as_bytes());
+}
+
+fn header(out: &mut Vec<u8>, timestamp_unit: f64) {
+    push(out, SpallHeader { magic_header: 0x0BADF00D, version: 1, timestamp_unit, must_be_0: 0 });
+}
+
+fn begin(out: &mut Vec<u8>, tid: u32, when: f64, name: &str, args: &str) {
+    push(out, BeginEvent {
+        ty: EventType::Begin as u8, category: 0, pid: 1, tid, when,
+        name_len: name.len() as u8, args_len: args.len() as u8,
+    });
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(args.as_bytes());
+}
+
+fn end(out: &mut Vec<u8>, tid: u32, when: f64) {
+    push(out, EndEvent { ty: EventType::End as u8, pid: 1, tid, when });
+}
+
+
+#[test]
+fn all_event_types() {
+    let mut data = Vec::new();
+    header(&mut data, 1.0);
+    begin(&mut data, 7, 10.0, "outer", "k=v");
+    push(&mut data, PadSkipEvent { ty: EventType::PadSkip as u8, size: 5 });
+    data.extend_from_slice(&[0xff; 5]);
+    push(&mut data, CustomDataEvent { ty: EventType::CustomData as u8, size: 3 });
+    data.extend_from_slice(b"abc");
+    end(&mut data, 7, 30.0);
+    push(&mut data, OverwriteTimestampEvent { ty: EventType::OverwriteTimestamp as u8, timestamp_unit: 0.5 });
+    data.push(EventType::StreamOver as u8);
+    // ignored after the end of the stream.
+    data.extend_from_slice(&[0xee; 9]);
+
+    let mut parser = Parser::new(&data).unwrap();
+    let events = (&mut parser).collect::<Result<Vec<_>, _>>().unwrap();
+    assert!(parser.finished());
+    assert_eq!(events.len(), 4);
+    assert!(matches!(events[0], RawEvent::Begin { tid: 7, name: b"outer", args: b"k=v", .. }));
+    assert_eq!(events[1], RawEvent::CustomData { data: b"abc" });
+    assert!(matches!(events[2], RawEvent::End { tid: 7, .. }));
+    assert_eq!(events[3], RawEvent::OverwriteTimestamp { timestamp_unit: 0.5 });
+
+    // the overwritten unit applies to the whole stream.
+    let trace = Trace::parse(&data).unwrap();
+    assert_eq!(trace.timestamp_unit(), 0.5);
+    assert_eq!(trace.custom_data(), &[b"abc".to_vec()]);
+    let scope = &trace.scopes()[0];
+    assert_eq!((scope.start, scope.end), (5.0, 15.0));
+}
+
+#[test]
+fn torn_and_corrupt() {
+    let mut data = Vec::new();
+    header(&mut data, 1.0);
+    for i in 0..4 {
+        begin(&mut data, 1, i as f64 * 10.0, "s", "");
+        end(&mut data, 1, i as f64 * 10.0 + 5.0);
+    }
+    let second = size_of::<SpallHeader>() + size_of::<BeginEvent>() + 1 + size_of::<EndEvent>();
+
+    let mut torn = data.clone();
+    torn.truncate(torn.len() - 3);
+    assert!(Trace::parse(&torn).is_err());
+    let (trace, salvage) = Trace::parse_lossy(&torn).unwrap();
+    assert_eq!(salvage.torn, size_of::<EndEvent>() - 3);
+    assert!(salvage.skipped.is_empty());
+    assert_eq!(trace.scopes().len(), 4);
+
+    let mut corrupt = data.clone();
+    corrupt[second] = 0xcc;
+    let (trace, salvage) = Trace::parse_lossy(&corrupt).unwrap();
+    assert_eq!(salvage.skipped.len(), 1);
+    assert_eq!(salvage.skipped[0].start, second);
+    assert_eq!(salvage.torn, 0);
+    assert_eq!(trace.scopes().len(), 3);
+    assert_eq!(salvage.recovered + salvage.lost() + size_of::<SpallHeader>(), corrupt.len());
+}
+
+#[test]
+fn checkpoints() {
+    fn checkpoint(out: &mut Vec<u8>, tid: u32, seq: u64, start: usize, when: f64) {
+        let len = (out.len() - start) as u32;
+        push(out, CustomDataEvent { ty: EventType::CustomData as u8, size: 32 });
+        out.extend_from_slice(&spall::checkpoint::TAG.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&tid.to_le_bytes());
+        out.extend_from_slice(&seq.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&when.to_le_bytes());
+    }
+
+    let mut data = Vec::new();
+    header(&mut data, 2.0);
+    let start = data.len();
+    begin(&mut data, 1, 0.0, "a", "");
+    end(&mut data, 1, 1.0);
+    checkpoint(&mut data, 1, 1, start, 2.0);
+    let start = data.len();
+    begin(&mut data, 2, 0.0, "b", "");
+    end(&mut data, 2, 1.0);
+    checkpoint(&mut data, 2, 1, start, 2.0);
+    // the second flush of thread 1 was lost.
+    let start = data.len();
+    begin(&mut data, 1, 5.0, "c", "");
+    end(&mut data, 1, 6.0);
+    checkpoint(&mut data, 1, 3, start, 7.0);
+    // thread 2's last flush was cut off.
+    begin(&mut data, 2, 5.0, "d", "");
+
+    let (trace, salvage) = Trace::parse_lossy(&data).unwrap();
+    assert_eq!(trace.scopes().len(), 4);
+    let [one, two] = &salvage.threads[..] else { panic!("{:?}", salvage.threads) };
+    assert_eq!((one.tid, one.last_checkpoint, one.missing, one.unconfirmed_events), (1, 3, 1, 0));
+    assert_eq!(one.complete_until, Some(14.0));
+    assert_eq!((two.tid, two.last_checkpoint, two.missing, two.unconfirmed_events), (2, 1, 0, 1));
+    assert_eq!(two.unconfirmed_bytes, size_of::<BeginEvent>() + 1);
+}
+
+#[test]
+fn writer_round_trip() {
+    if !compiled_in(Level::Normal) { return }
+
+    let dir = std::env::temp_dir().join(format!("spall-reader-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("trace.spall");
+    assert!(spall::init_with(path.to_str().unwrap(), spall::Options {
+        buffer_size: 1024,
+        ..Default::default()
+    }).unwrap());
+
+    static PARSE: spall::ScopeName = spall::name!("parse");
+
+    // a small buffer, so the thread flushes many times.
+    std::thread::spawn(|| {
+        for i in 0..200 {
+            spall::trace_scope!("outer", "i={}", i);
+            spall::trace_scope!("inner", { i = i, name = "a b" });
+            let _parse = PARSE.scope_args(format_args!("i={}", i));
+        }
+    }).join().unwrap();
+
+    let trace = Trace::open(&path).unwrap();
+    let outer = trace.scopes_named("outer").collect::<Vec<_>>();
+    let inner = trace.scopes_named("inner").collect::<Vec<_>>();
+    assert_eq!((outer.len(), inner.len()), (200, 200));
+    assert!(trace.scopes_named("spall/flush").count() > 1);
+
+    for (i, (outer, inner)) in outer.iter().zip(&inner).enumerate() {
+        assert_eq!(outer.args, format!("i={}", i));
+        assert_eq!(inner.args, format!("i={} name=\"a b\"", i));
+        assert_eq!(inner.depth, outer.depth + 1);
+        assert!(outer.start <= inner.start && inner.end <= outer.end);
+    }
+
+    // recorded as ids, resolved by the reader.
+    let parse = trace.scopes_named("parse").collect::<Vec<_>>();
+    assert_eq!(parse.len(), 200);
+    assert_eq!(parse[7].args, "i=7");
+    assert_eq!(parse[7].depth, inner[7].depth + 1);
+
+    let expanded = dir.join("expanded.spall");
+    spall::name::expand(&path, &expanded).unwrap();
+    let data = std::fs::read(&expanded).unwrap();
+    let names = Parser::new(&data).unwrap()
+        .filter_map(|event| match event.unwrap() {
+            RawEvent::Begin { name: b"parse", .. } => Some(()),
+            _ => None,
+        })
+        .count();
+    assert_eq!(names, 200);
+
+    // a second session, in a new file.
+    assert!(spall::shutdown());
+    let data = std::fs::read(&path).unwrap();
+    let mut parser = Parser::new(&data).unwrap();
+    assert!(parser.by_ref().all(|event| event.is_ok()));
+    assert!(parser.finished());
+
+    let second = dir.join("second.spall");
+    assert!(spall::init_with(second.to_str().unwrap(), Default::default()).unwrap());
+    std::thread::spawn(|| {
+        spall::trace_scope!("again");
+    }).join().unwrap();
+    assert!(spall::shutdown());
+    assert!(!spall::shutdown());
+
+    let trace = Trace::open(&second).unwrap();
+    assert_eq!(trace.scopes_named("again").count(), 1);
+    assert_eq!(trace.scopes_named("outer").count(), 0);
+    assert_eq!(Trace::open(&path).unwrap().scopes_named("again").count(), 0);
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn spall_writer() {
+    let mut writer = spall::SpallWriter::with_buffer_size(Vec::new(), 2.0, 64);
+    for i in 0..20 {
+        writer.begin(1, 3, i as f64 * 10.0, "outer", &format!("i={}", i)).unwrap();
+        writer.instant(1, 3, i as f64 * 10.0 + 1.0, "mark", "").unwrap();
+        writer.end(1, 3, i as f64 * 10.0 + 5.0).unwrap();
+    }
+    writer.custom_data(b"abc").unwrap();
+    let data = writer.finish().unwrap();
+
+    let mut parser = Parser::new(&data).unwrap();
+    assert_eq!(parser.by_ref().count(), 20 * 4 + 1);
+    assert!(parser.finished());
+
+    let trace = Trace::parse(&data).unwrap();
+    let outer = trace.scopes_named("outer").collect::<Vec<_>>();
+    assert_eq!(outer.len(), 20);
+    assert_eq!((outer[3].start, outer[3].end, outer[3].args.as_str()), (60.0, 70.0, "i=3"));
+    assert_eq!(trace.scopes_named("mark").next().unwrap().depth, outer[0].depth + 1);
+    assert_eq!(trace.custom_data(), [b"abc".to_vec()]);
+
+    // the same events in one call.
+    let args = (0..20).map(|i| format!("i={}", i)).collect::<Vec<_>>();
+    let mut events = Vec::new();
+    for (i, args) in args.iter().enumerate() {
+        let (pid, tid, when) = (1, 3, i as f64 * 10.0);
+        events.push(EventRecord::Begin { pid, tid, when, name: "outer", args });
+        events.push(EventRecord::Instant { pid, tid, when: when + 1.0, name: "mark", args: "" });
+        events.push(EventRecord::End { pid, tid, when: when + 5.0 });
+    }
+    events.push(EventRecord::CustomData { data: b"abc" });
+    let mut writer = spall::SpallWriter::with_buffer_size(Vec::new(), 2.0, 64);
+    writer.write_events(&events).unwrap();
+    assert_eq!(writer.finish().unwrap(), data);
+}
+
+#[test]
+fn between() {
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    writer.begin(1, 1, 0.0, "before", "").unwrap();
+    writer.end(1, 1, 10.0).unwrap();
+    writer.instant(1, 1, 10.0, "at_start", "").unwrap();
+    writer.begin(1, 2, 5.0, "across", "").unwrap();
+    writer.begin(1, 1, 12.0, "inside", "").unwrap();
+    writer.end(1, 1, 14.0).unwrap();
+    writer.end(1, 2, 15.0).unwrap();
+    writer.begin(1, 1, 20.0, "at_end", "").unwrap();
+    writer.end(1, 1, 25.0).unwrap();
+    writer.instant(1, 2, 20.0, "mark_at_end", "").unwrap();
+    let trace = Trace::parse(&writer.finish().unwrap()).unwrap();
+
+    // `[10, 20)`, with scopes covering `[start, end)`.
+    let names = trace.scopes_between(10.0, 20.0).map(|s| s.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["across", "at_start", "inside"]);
+
+    let times = trace.events_between(10.0, 20.0).map(|e| e.when()).collect::<Vec<_>>();
+    assert_eq!(times, [10.0, 10.0, 10.0, 12.0, 14.0, 15.0]);
+
+    assert_eq!(trace.scopes_between(10.0, 10.0).count(), 0);
+
+    // with nothing else open at t0.
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    writer.begin(1, 1, 0.0, "before", "").unwrap();
+    writer.end(1, 1, 10.0).unwrap();
+    writer.instant(1, 1, 10.0, "at_start", "").unwrap();
+    let trace = Trace::parse(&writer.finish().unwrap()).unwrap();
+    let names = trace.scopes_between(10.0, 20.0).map(|s| s.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["at_start"]);
+}
+
+#[test]
+fn mapped_scope_refs() {
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    writer.begin(1, 3, 10.0, "outer", "k=v").unwrap();
+    writer.begin(1, 3, 11.0, "inner", "").unwrap();
+    writer.end(1, 3, 12.0).unwrap();
+    writer.end(1, 3, 20.0).unwrap();
+    writer.begin(1, 4, 15.0, "open", "").unwrap();
+    writer.instant(1, 4, 17.0, "mark", "").unwrap();
+    let data = writer.finish().unwrap();
+
+    let path = std::env::temp_dir().join(format!("spall-mapped-test-{}.spall", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    let map = unsafe { spall::reader::Mmap::open(&path).unwrap() };
+    assert_eq!(&map[..], &data[..]);
+
+    let scopes = map.scopes().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    let names = scopes.iter().map(|s| (s.name, s.depth, s.start, s.end)).collect::<Vec<_>>();
+    assert_eq!(names, [("inner", 1, 11.0, 12.0), ("outer", 0, 10.0, 20.0), ("mark", 1, 17.0, 17.0), ("open", 0, 15.0, 17.0)]);
+    assert_eq!(scopes[1].args, "k=v");
+
+    drop(map);
+    _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn parallel() {
+    let mut data = Vec::new();
+    header(&mut data, 1.0);
+    for i in 0..1000 {
+        begin(&mut data, i % 3, i as f64 * 10.0, "outer", &format!("i={}", i));
+        begin(&mut data, i % 3, i as f64 * 10.0 + 1.0, "inner", "");
+        end(&mut data, i % 3, i as f64 * 10.0 + 2.0);
+        end(&mut data, i % 3, i as f64 * 10.0 + 5.0);
+    }
+    push(&mut data, CustomDataEvent { ty: EventType::CustomData as u8, size: 3 });
+    data.extend_from_slice(b"abc");
+    push(&mut data, PadSkipEvent { ty: EventType::PadSkip as u8, size: 5 });
+    data.extend_from_slice(&[0; 5]);
+    push(&mut data, OverwriteTimestampEvent { ty: EventType::OverwriteTimestamp as u8, timestamp_unit: 0.5 });
+    data.push(EventType::StreamOver as u8);
+
+    let trace = Trace::parse(&data).unwrap();
+    for threads in [1, 2, 7] {
+        let parallel = Trace::parse_parallel(&data, threads).unwrap();
+        assert_eq!(parallel.timestamp_unit(), 0.5);
+        assert_eq!(parallel.events(), trace.events());
+        assert_eq!(parallel.scopes(), trace.scopes());
+        assert_eq!(parallel.custom_data(), trace.custom_data());
+    }
+
+    // errors anywhere in the file fail the parse.
+    let mut torn = data[..data.len() - 22].to_vec();
+    torn[40] = 0xee;
+    assert!(Trace::parse_parallel(&torn, 4).is_err());
+}
+
+#[test]
+fn trim() {
+    let mut writer = spall::SpallWriter::new(Vec::new(), 2.0);
+    writer.begin(1, 3, 100.0, "outer", "k=v").unwrap();
+    writer.instant(1, 3, 110.0, "early", "").unwrap();
+    writer.begin(1, 3, 120.0, "inner", "").unwrap();
+    writer.end(1, 3, 130.0).unwrap();
+    writer.instant(1, 3, 160.0, "late", "").unwrap();
+    writer.end(1, 3, 200.0).unwrap();
+    writer.begin(1, 4, 100.0, "open", "").unwrap();
+    writer.instant(1, 4, 190.0, "mark", "").unwrap();
+    let data = writer.finish().unwrap();
+
+    let dir = std::env::temp_dir();
+    let input  = dir.join(format!("spall-trim-in-{}.spall", std::process::id()));
+    let output = dir.join(format!("spall-trim-out-{}.spall", std::process::id()));
+    std::fs::write(&input, &data).unwrap();
+
+    // raw 115..150, with the first event at 100.
+    spall::reader::trim(&input, &output, 30.0..100.0).unwrap();
+    let trace = Trace::open(&output).unwrap();
+    let scopes = trace.scopes().iter()
+        .map(|s| (s.tid, s.name.as_str(), s.start, s.end))
+        .collect::<Vec<_>>();
+    assert_eq!(scopes, [(3, "outer", 230.0, 300.0), (4, "open", 230.0, 300.0), (3, "inner", 240.0, 260.0)]);
+    assert_eq!(trace.scopes_named("outer").next().unwrap().args, "k=v");
+
+    _ = std::fs::remove_file(&input);
+    _ = std::fs::remove_file(&output);
+}
+
+#[test]
+fn trim_edges() {
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    writer.begin(1, 1, 0.0, "across_start", "").unwrap();
+    writer.begin(1, 2, 0.0, "before", "").unwrap();
+    writer.end(1, 2, 10.0).unwrap();
+    writer.begin(1, 3, 5.0, "across_both", "").unwrap();
+    writer.begin(1, 4, 30.0, "across_end", "").unwrap();
+    writer.begin(1, 1, 40.0, "inside", "").unwrap();
+    writer.end(1, 1, 45.0).unwrap();
+    writer.end(1, 1, 50.0).unwrap();
+    writer.begin(1, 2, 85.0, "after", "").unwrap();
+    writer.end(1, 2, 95.0).unwrap();
+    writer.end(1, 4, 90.0).unwrap();
+    writer.end(1, 3, 100.0).unwrap();
+    let data = writer.finish().unwrap();
+
+    let dir = std::env::temp_dir();
+    let input  = dir.join(format!("spall-trim-edges-in-{}.spall", std::process::id()));
+    let output = dir.join(format!("spall-trim-edges-out-{}.spall", std::process::id()));
+    std::fs::write(&input, &data).unwrap();
+    spall::reader::trim(&input, &output, 20.0..80.0).unwrap();
+    let trimmed = std::fs::read(&output).unwrap();
+    _ = std::fs::remove_file(&input);
+    _ = std::fs::remove_file(&output);
+
+    // the cut scopes begin and end at the window's edges.
+    let trace = Trace::parse(&trimmed).unwrap();
+    let mut scopes = trace.scopes().iter()
+        .map(|s| (s.name.as_str(), s.start, s.end, s.depth))
+        .collect::<Vec<_>>();
+    scopes.sort_by(|a, b| a.0.cmp(b.0));
+    assert_eq!(scopes, [
+        ("across_both",  20.0, 80.0, 0),
+        ("across_end",   30.0, 80.0, 0),
+        ("across_start", 20.0, 50.0, 0),
+        ("inside",       40.0, 45.0, 1),
+    ]);
+
+    // with a begin and an end event each.
+    let events = Parser::new(&trimmed).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    let begins = events.iter().filter(|e| matches!(e, RawEvent::Begin { .. })).count();
+    let ends   = events.iter().filter(|e| matches!(e, RawEvent::End { .. })).count();
+    assert_eq!((begins, ends), (4, 4));
+}
+
+#[test]
+fn merge() {
+    // both files use id 1, for different names.
+    fn file(unit: f64, name: &str, pid: u32) -> Vec<u8> {
+        let mut entry = spall::name::TAG.to_le_bytes().to_vec();
+        entry.extend_from_slice(&1u32.to_le_bytes());
+        entry.extend_from_slice(name.as_bytes());
+
+        let mut writer = spall::SpallWriter::new(Vec::new(), unit);
+        writer.custom_data(&entry).unwrap();
+        writer.begin(pid, 1, 10.0, "\0\x01\0\0\0", "k=v").unwrap();
+        writer.end(pid, 1, 20.0).unwrap();
+        writer.begin(pid, 1, 30.0, "plain", "").unwrap();
+        writer.end(pid, 1, 40.0).unwrap();
+        writer.custom_data(b"abc").unwrap();
+        writer.finish().unwrap()
+    }
+
+    let dir = std::env::temp_dir();
+    let inputs = [1, 2].map(|i| dir.join(format!("spall-merge-in-{}-{}.spall", i, std::process::id())));
+    let output = dir.join(format!("spall-merge-out-{}.spall", std::process::id()));
+    std::fs::write(&inputs[0], file(1.0, "parse", 1)).unwrap();
+    std::fs::write(&inputs[1], file(2.0, "render", 2)).unwrap();
+    spall::reader::merge(&inputs, &output).unwrap();
+    let merged = std::fs::read(&output).unwrap();
+    for path in inputs.iter().chain([&output]) {
+        _ = std::fs::remove_file(path);
+    }
+
+    // in the first file's unit.
+    let trace = Trace::parse(&merged).unwrap();
+    assert_eq!(trace.timestamp_unit(), 1.0);
+    let scopes = trace.scopes().iter()
+        .map(|s| (s.pid, s.name.as_str(), s.args.as_str(), s.start, s.end))
+        .collect::<Vec<_>>();
+    assert_eq!(scopes, [
+        (1, "parse", "k=v", 10.0, 20.0), (2, "render", "k=v", 20.0, 40.0),
+        (1, "plain", "",    30.0, 40.0), (2, "plain",  "",    60.0, 80.0),
+    ]);
+
+    // the names are expanded, and the dictionaries are dropped.
+    let events = Parser::new(&merged).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    assert!(events.iter().all(|event| !matches!(event, RawEvent::Begin { name: [0, ..], .. })));
+    assert_eq!(trace.custom_data(), [b"abc".to_vec(), b"abc".to_vec()]);
+}
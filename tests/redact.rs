@@ -0,0 +1,70 @@
+use spall::reader::Trace;
+use spall::redact::{self, Rules};
+
+
+const RULES: &str = "
+# keep the structure of queries, but not the tables.
+name  db/query/*  db/query/#1
+arg   user        hash
+arg   frame       keep
+hash_rest
+";
+
+fn anonymized(data: &[u8], rules: &Rules) -> Trace {
+    let dir = std::env::temp_dir();
+    let input  = dir.join(format!("spall-anonymize-in-{}.spall", std::process::id()));
+    let output = dir.join(format!("spall-anonymize-out-{}.spall", std::process::id()));
+    std::fs::write(&input, data).unwrap();
+    redact::anonymize(&input, &output, rules).unwrap();
+    let trace = Trace::open(&output).unwrap();
+    _ = std::fs::remove_file(&input);
+    _ = std::fs::remove_file(&output);
+    trace
+}
+
+#[test]
+fn anonymize() {
+    let mut meta = spall::metadata::TAG.to_le_bytes().to_vec();
+    meta.extend_from_slice(b"user\0alice");
+
+    let mut writer = spall::SpallWriter::new(Vec::new(), 1.0);
+    writer.custom_data(&meta).unwrap();
+    writer.begin(1, 1, 10.0, "db/query/users", "user=alice frame=1").unwrap();
+    writer.begin(1, 1, 11.0, "parse", "").unwrap();
+    writer.end(1, 1, 12.0).unwrap();
+    writer.end(1, 1, 20.0).unwrap();
+    writer.begin(1, 2, 15.0, "db/query/users", "user=alice frame=2").unwrap();
+    writer.end(1, 2, 25.0).unwrap();
+    writer.begin(1, 2, 30.0, "db/query/orders", "user=bob frame=3 note=\"a b\"").unwrap();
+    writer.end(1, 2, 35.0).unwrap();
+    writer.begin(1, 2, 40.0, "spall/flush", "bytes=100").unwrap();
+    writer.end(1, 2, 41.0).unwrap();
+    let data = writer.finish().unwrap();
+
+    let trace = anonymized(&data, &Rules::parse(RULES).unwrap());
+    let scopes = trace.scopes().iter()
+        .map(|s| (s.tid, s.name.as_str(), s.args.as_str(), s.start, s.end, s.depth))
+        .collect::<Vec<_>>();
+
+    // the same names and values are rewritten the same, in every event.
+    let users  = format!("db/query/{}", redact::hash(b"users"));
+    let orders = format!("db/query/{}", redact::hash(b"orders"));
+    let (alice, bob) = (redact::hash(b"alice"), redact::hash(b"bob"));
+    let parse = redact::hash(b"parse");
+    assert_eq!(scopes, [
+        (1, users.as_str(),  &*format!("user={} frame=1", alice), 10.0, 20.0, 0),
+        (1, parse.as_str(),  "",                                  11.0, 12.0, 1),
+        (2, users.as_str(),  &*format!("user={} frame=2", alice), 15.0, 25.0, 0),
+        (2, orders.as_str(), &*format!("user={} frame=3 note={}", bob, redact::hash(b"a b")), 30.0, 35.0, 0),
+        (2, "spall/flush",   "bytes=100",                         40.0, 41.0, 0),
+    ]);
+    assert_eq!(trace.metadata_value("user").unwrap(), alice);
+
+    // without rules, everything else is kept.
+    let kept = anonymized(&data, &Rules::default());
+    assert_eq!(kept.scopes(), Trace::parse(&data).unwrap().scopes());
+
+    let hashed = anonymized(&data, &Rules::hash_all());
+    assert_eq!(hashed.scopes_named(&redact::hash(b"db/query/users")).count(), 2);
+    assert_eq!(hashed.scopes_named(&redact::hash(b"db/query/orders")).count(), 1);
+}
@@ -0,0 +1,45 @@
+use spall::filter::Level;
+use spall::reader::Trace;
+use spall::testing::compiled_in;
+
+
+// a session of its own, which `testing::record` doesn't rotate.
+#[test]
+fn rotate_mid_scope() {
+    if !compiled_in(Level::Normal) { return }
+
+    let dir = std::env::temp_dir().join(format!("spall-rotate-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("trace.spall");
+
+    assert!(spall::init(&path).unwrap());
+    let outer = spall::trace_scope_impl("outer");
+    spall::trace_scope_impl("before").end();
+    let rotated = spall::rotate().unwrap().unwrap();
+    spall::trace_scope_impl("after").end();
+    outer.end();
+    spall::shutdown();
+
+    assert_eq!(rotated, dir.join("trace.0001.spall"));
+    let first  = Trace::open(&path).unwrap();
+    let second = Trace::open(&rotated).unwrap();
+
+    // the open scope ends with its file, and its end is ignored in the next.
+    assert_eq!(first.scopes_named("outer").count(), 1);
+    assert_eq!(first.scopes_named("before").count(), 1);
+    assert_eq!(first.scopes_named("after").count(), 0);
+    assert_eq!(second.scopes_named("outer").count(), 0);
+    assert_eq!(second.scopes_named("before").count(), 0);
+    assert_eq!(second.scopes_named("after").count(), 1);
+
+    // merged, the scope spans both.
+    let merged = dir.join("merged.spall");
+    spall::reader::merge(&[&path, &rotated], &merged).unwrap();
+    let merged = Trace::open(&merged).unwrap();
+    let outer = merged.scopes_named("outer").next().unwrap();
+    let after = merged.scopes_named("after").next().unwrap();
+    assert!(outer.start < after.start && after.end <= outer.end);
+    assert_eq!(after.depth, outer.depth + 1);
+
+    _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,62 @@
+// thread names are read from /proc.
+#![cfg(target_os = "linux")]
+
+use std::time::{Duration, Instant};
+
+use spall::filter::Level;
+use spall::reader::Trace;
+use spall::testing::compiled_in;
+
+
+// the threads of the process named `name`.
+fn threads(name: &str) -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap()
+        .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+        .filter(|comm| comm.trim_end() == name)
+        .count()
+}
+
+// waits for `count` threads named `name`, as they name themselves,
+// and samplers of ended sessions exit after their sleep.
+fn wait_for(name: &str, count: usize) -> bool {
+    let start = Instant::now();
+    while threads(name) != count {
+        if start.elapsed() > Duration::from_secs(5) {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+// sessions of its own, as samplers write into the file as they exit.
+#[test]
+fn samplers_end_with_the_session() {
+    if !compiled_in(Level::Normal) { return }
+
+    let start = || {
+        spall::memory::sample(Duration::from_millis(1)).unwrap();
+        spall::cpu::sample(Duration::from_millis(1)).unwrap();
+    };
+    let names = ["spall/memory", "spall/cpu"];
+
+    // nothing to sample into.
+    start();
+    assert!(names.iter().all(|name| threads(name) == 0));
+
+    let path = std::env::temp_dir().join(format!("spall-sampling-test-{}.spall", std::process::id()));
+    for _ in 0..2 {
+        assert!(spall::init(&path).unwrap());
+        start();
+        start();
+        assert!(names.iter().all(|name| wait_for(name, 1)));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(names.iter().all(|name| threads(name) == 1));
+        spall::shutdown();
+
+        assert!(names.iter().all(|name| wait_for(name, 0)));
+        let trace = Trace::open(&path).unwrap();
+        assert!(names.iter().all(|name| trace.scopes_named(name).count() > 0));
+    }
+    _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,63 @@
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::RowAccessor;
+
+use spall::filter::Level;
+use spall::testing::{compiled_in, record};
+
+
+fn trace() -> spall::reader::Trace {
+    record(Default::default(), |clock| {
+        clock.advance_micros(10);
+        let outer = spall::trace_scope_impl("load, \"quoted\"");
+        clock.advance_micros(5);
+        spall::trace_scope!("parse", "bytes={}", 512);
+        clock.advance_micros(20);
+        outer.end();
+    }).unwrap()
+}
+
+#[test]
+fn csv() {
+    if !compiled_in(Level::Normal) { return }
+
+    let csv = String::from_utf8(spall::table::encode_csv(&trace())).unwrap();
+    let lines = csv.lines().filter(|l| !l.contains("spall/")).collect::<Vec<_>>();
+    assert_eq!(lines[0], "pid,tid,name,start,duration,depth,args");
+    assert!(lines[1].ends_with(",\"load, \"\"quoted\"\"\",10,25,0,"), "{}", lines[1]);
+    assert!(lines[2].ends_with(",parse,15,20,1,bytes=512"), "{}", lines[2]);
+}
+
+#[test]
+fn parquet_round_trip() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = trace();
+    let path = std::env::temp_dir().join(format!("spall-table-{}.parquet", std::process::id()));
+    spall::table::export_parquet(&trace, &path).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    let reader = SerializedFileReader::new(file).unwrap();
+    _ = std::fs::remove_file(&path);
+
+    let meta = reader.metadata().file_metadata();
+    assert_eq!(meta.num_rows() as usize, trace.scopes().len());
+    let columns = meta.schema_descr().columns().iter().map(|c| c.name().to_string()).collect::<Vec<_>>();
+    assert_eq!(columns, ["pid", "tid", "name", "start", "duration", "depth", "args"]);
+
+    let rows = reader.get_row_iter(None).unwrap()
+        .map(|row| row.unwrap())
+        .filter(|row| !row.get_string(2).unwrap().starts_with("spall/"))
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+
+    let scope = &trace.scopes_named("parse").next().unwrap();
+    let parse = &rows[1];
+    assert_eq!(parse.get_long(0).unwrap(), scope.pid as i64);
+    assert_eq!(parse.get_long(1).unwrap(), scope.tid as i64);
+    assert_eq!(parse.get_string(2).unwrap(), "parse");
+    assert_eq!(parse.get_double(3).unwrap(), 15.0);
+    assert_eq!(parse.get_double(4).unwrap(), 20.0);
+    assert_eq!(parse.get_int(5).unwrap(), 1);
+    assert_eq!(parse.get_string(6).unwrap(), "bytes=512");
+    assert_eq!(rows[0].get_string(2).unwrap(), "load, \"quoted\"");
+    assert_eq!(rows[0].get_int(5).unwrap(), 0);
+}
@@ -0,0 +1,454 @@
+use std::time::Duration;
+
+use spall::filter::Level;
+use spall::testing::{compiled_in, record};
+
+
+#[test]
+fn manual_clock() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        clock.advance_micros(10);
+        let outer = spall::trace_scope_impl("outer");
+        clock.advance(Duration::from_micros(5));
+        {
+            let _inner = spall::trace_scope_impl("inner");
+            clock.advance_micros(20);
+        }
+        clock.advance_micros(1);
+        outer.end();
+    }).unwrap();
+
+    let outer = trace.scopes_named("outer").next().unwrap();
+    let inner = trace.scopes_named("inner").next().unwrap();
+    assert_eq!((outer.start, outer.end), (10.0, 36.0));
+    assert_eq!((inner.start, inner.end), (15.0, 35.0));
+    assert_eq!(inner.depth, outer.depth + 1);
+
+    // a new session starts at 0 again.
+    let trace = record(Default::default(), |clock| {
+        clock.advance_micros(3);
+        spall::trace_scope!("again");
+    }).unwrap();
+    let again = trace.scopes_named("again").next().unwrap();
+    assert_eq!((again.start, again.end), (3.0, 3.0));
+    assert_eq!(trace.scopes_named("outer").count(), 0);
+}
+
+#[test]
+fn owned_names() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |_| {
+        for i in 0..2 {
+            spall::trace_scope!(format!("job {}", i));
+        }
+        let name: std::borrow::Cow<str> = "borrowed".into();
+        spall::trace_scope!(name, "i={}", 2);
+        let _scope = spall::trace_scope_impl(String::from("owned"));
+    }).unwrap();
+
+    for name in ["job 0", "job 1", "borrowed", "owned"] {
+        assert_eq!(trace.scopes_named(name).count(), 1, "{}", name);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn counted_names() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |_| {
+        drop(spall::counters::scope("static"));
+        drop(spall::counters::scope(format!("job {}", 1)));
+        let name = String::from("borrowed");
+        drop(spall::counters::scope(name.as_str()));
+        drop(spall::counters::scope(std::borrow::Cow::from("cow")));
+    }).unwrap();
+
+    for name in ["static", "job 1", "borrowed", "cow"] {
+        assert_eq!(trace.scopes_named(name).count(), 1, "{}", name);
+    }
+}
+
+#[test]
+fn lazy_names_and_args() {
+    if !compiled_in(Level::Normal) { return }
+
+    let calls = std::cell::Cell::new(0);
+    let trace = record(Default::default(), |_| {
+        spall::pause();
+        {
+            let _scope = spall::trace_scope_args_with(|| { calls.set(calls.get() + 1); "paused" }, || "");
+        }
+        spall::resume();
+
+        let _scope = spall::trace_scope_args_with(|| String::from("lazy"), || format!("n={}", 3));
+    }).unwrap();
+
+    assert_eq!(calls.get(), 0);
+    let lazy = trace.scopes_named("lazy").next().unwrap();
+    assert_eq!(lazy.args, "n=3");
+}
+
+#[test]
+fn levels() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { level: Level::Normal, ..Default::default() };
+    let trace = record(options, |_| {
+        spall::trace_scope!(level = Coarse, "frame");
+        spall::trace_scope!("update");
+        spall::trace_scope!(level = Verbose, "particle", "id={}", 3);
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("frame").count(), 1);
+    assert_eq!(trace.scopes_named("update").count(), 1);
+    assert_eq!(trace.scopes_named("particle").count(), 0);
+}
+
+mod render {
+    pub fn draw() {
+        spall::trace_scope!(level = Verbose, "draw");
+    }
+}
+
+#[test]
+fn target_filter() {
+    if !compiled_in(Level::Verbose) { return }
+
+    let options = spall::Options {
+        filter: Some("testing=off,testing::render=verbose".into()),
+        level:  Level::Normal,
+        ..Default::default()
+    };
+    let trace = record(options, |_| {
+        spall::trace_scope!(level = Coarse, "frame");
+        render::draw();
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("frame").count(), 0);
+    assert_eq!(trace.scopes_named("draw").count(), 1);
+}
+
+#[test]
+fn scope_ids() {
+    if !compiled_in(Level::Normal) { return }
+
+    let mut ids = Vec::new();
+    let trace = record(Default::default(), |_| {
+        for _ in 0..2 {
+            let scope = spall::trace_scope_id_args("request", format_args!("path=/"));
+            ids.push(scope.id().unwrap());
+        }
+    }).unwrap();
+
+    assert_ne!(ids[0], ids[1]);
+    let recorded = trace.scopes_named("request").map(|s| s.id()).collect::<Vec<_>>();
+    assert_eq!(recorded, [Some(ids[0]), Some(ids[1])]);
+}
+
+#[test]
+fn virtual_tracks() {
+    if !compiled_in(Level::Normal) { return }
+
+    let mut tids = (0, 0);
+    let trace = record(Default::default(), |_| {
+        spall::trace_scope!("outside");
+        for _ in 0..2 {
+            let track = spall::track(7, "queue");
+            tids.0 = track.tid();
+            spall::trace_scope!("submit");
+        }
+        tids.1 = spall::track(8, "other").tid();
+
+        // named when it's first entered while recording.
+        spall::pause();
+        drop(spall::track(9, "paused"));
+        let queue = spall::gpu::Queue::new(9, "paused gpu", spall::gpu::Calibration { gpu: 0, cpu: 0, period_ns: 1.0 });
+        queue.interval("draw", 0, 0);
+        spall::resume();
+        drop(spall::track(9, "paused"));
+        queue.interval("draw", 0, 0);
+    }).unwrap();
+
+    assert_ne!(tids.0, tids.1);
+    let submits = trace.scopes_named("submit").collect::<Vec<_>>();
+    assert_eq!(submits.len(), 2);
+    assert!(submits.iter().all(|s| s.tid == tids.0));
+    assert_ne!(trace.scopes_named("outside").next().unwrap().tid, tids.0);
+
+    let names = trace.scopes_named("spall/thread_start").filter(|s| s.tid == tids.0).collect::<Vec<_>>();
+    assert_eq!(names.len(), 1);
+    assert_eq!(names[0].args, "name=queue");
+
+    let names = trace.scopes_named("spall/thread_start").map(|s| s.args.as_str()).filter(|a| a.contains("paused")).collect::<Vec<_>>();
+    assert_eq!(names, ["name=paused", r#"name="paused gpu""#]);
+}
+
+#[test]
+fn gpu_intervals() {
+    if !compiled_in(Level::Normal) { return }
+
+    let mut tid = 0;
+    let trace = record(Default::default(), |clock| {
+        clock.advance_micros(100);
+        // gpu ticks of 10 ns.
+        let calibration = spall::gpu::Calibration { gpu: 5000, cpu: spall::now(), period_ns: 10.0 };
+        let queue = spall::gpu::Queue::new(0, "gpu graphics", calibration);
+        tid = queue.tid();
+
+        spall::trace_scope!("submit");
+        clock.advance_micros(50);
+        queue.interval("shadows", 6000, 7000);
+        queue.interval_args("lighting", 7000, 9000, format_args!("lights={}", 3));
+    }).unwrap();
+
+    let shadows = trace.scopes_named("shadows").next().unwrap();
+    assert_eq!((shadows.start, shadows.end), (110.0, 120.0));
+    let lighting = trace.scopes_named("lighting").next().unwrap();
+    assert_eq!((lighting.start, lighting.end, lighting.args.as_str()), (120.0, 140.0, "lights=3"));
+    assert!(shadows.tid == tid && lighting.tid == tid);
+    assert_ne!(trace.scopes_named("submit").next().unwrap().tid, tid);
+
+    let names = trace.scopes_named("spall/thread_start").filter(|s| s.tid == tid).collect::<Vec<_>>();
+    assert_eq!(names.len(), 1);
+    assert_eq!(names[0].args, "name=\"gpu graphics\"");
+}
+
+#[test]
+fn fibers() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        let outer = spall::trace_scope_impl("thread");
+        spall::fiber_switch(0, 1);
+        let a = spall::trace_scope_impl("a");
+        spall::fiber_switch(1, 2);
+        let b = spall::trace_scope_impl("b");
+        spall::fiber_switch(2, 1);
+        // ends on fiber 1's track, while fiber 2's scope stays open.
+        clock.advance_micros(1);
+        a.end();
+        spall::fiber_switch(1, 2);
+        clock.advance_micros(1);
+        b.end();
+        spall::fiber_switch(2, 0);
+        clock.advance_micros(1);
+        outer.end();
+    }).unwrap();
+
+    let scope = |name| trace.scopes_named(name).next().unwrap();
+    let (thread, a, b) = (scope("thread"), scope("a"), scope("b"));
+    assert!(thread.tid != a.tid && a.tid != b.tid && thread.tid != b.tid);
+    assert!(a.end < b.end && b.end < thread.end);
+    assert_eq!((thread.depth, a.depth, b.depth), (0, 0, 0));
+}
+
+#[test]
+fn log_messages() {
+    if !compiled_in(Level::Normal) { return }
+
+    let trace = record(Default::default(), |clock| {
+        let key = "a.png";
+        clock.advance_micros(4);
+        spall::trace_log!("cache miss for {key}");
+    }).unwrap();
+
+    let log = trace.scopes_named("log").next().unwrap();
+    assert_eq!(log.args, "cache miss for a.png");
+    assert_eq!((log.start, log.end), (4.0, 4.0));
+}
+
+#[test]
+fn dropped_markers() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { filter: Some("!noise".into()), ..Default::default() };
+    let trace = record(options, |_| {
+        for _ in 0..3 {
+            spall::trace_scope!("noise");
+        }
+        spall::trace_scope!("signal");
+    }).unwrap();
+
+    let dropped = trace.scopes_named("spall/dropped").map(|s| s.args.as_str()).collect::<Vec<_>>();
+    assert_eq!(dropped, ["filtered=3"]);
+}
+
+#[test]
+fn queued_writes() {
+    if !compiled_in(Level::Normal) { return }
+
+    let overflow = spall::Overflow::Grow { max_buffer_size: 8192 };
+    let options = spall::Options { buffer_size: 1024, overflow, ..Default::default() };
+    let trace = record(options, |clock| {
+        let outer = spall::trace_scope_impl("outer");
+        for _ in 0..1000 {
+            spall::trace_scope!("inner");
+            clock.advance_micros(1);
+        }
+        outer.end();
+    }).unwrap();
+
+    assert_eq!(trace.scopes_named("inner").count(), 1000);
+    let outer = trace.scopes_named("outer").next().unwrap();
+    assert_eq!(outer.duration(), 1000.0);
+}
+
+#[test]
+fn overflow_drop() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { buffer_size: 1024, overflow: spall::Overflow::Drop, ..Default::default() };
+    let trace = record(options, |_| {
+        let hold = spall::testing::hold_writer();
+        // fills the queue.
+        let outer = spall::trace_scope_impl("outer");
+        for _ in 0..1000 {
+            spall::trace_scope!("main");
+        }
+
+        // drops from the new thread's first buffer.
+        let (done, wait) = std::sync::mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            for _ in 0..100 {
+                spall::trace_scope!("worker");
+            }
+            spall::trace_scope_impl("open");
+            done.send(()).unwrap();
+        });
+        wait.recv().unwrap();
+        drop(hold);
+        worker.join().unwrap();
+        outer.end();
+    }).unwrap();
+
+    let worker = trace.scopes_named("open").next().unwrap().tid;
+    assert_eq!(trace.scopes_named("spall/thread_start").filter(|s| s.tid == worker).count(), 1);
+    assert!(trace.scopes_named("worker").count() < 100);
+    assert!(trace.scopes_named("main").count() < 1000);
+    assert_eq!(trace.scopes_named("outer").count(), 1);
+
+    let dropped = trace.scopes_named("spall/dropped")
+        .filter_map(|s| s.args.strip_prefix("overflow="))
+        .map(|n| n.parse::<usize>().unwrap())
+        .sum::<usize>();
+    let recorded = trace.scopes_named("worker").count() + trace.scopes_named("main").count();
+    assert_eq!(dropped + recorded, 1100);
+}
+
+#[test]
+fn thread_buffer_sizes() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { buffer_size: 1024, ..Default::default() };
+    let trace = record(options, |_| {
+        let worker = |size| spall::thread::Builder::new().buffer_size(size).spawn(|| {
+            for _ in 0..200 {
+                spall::trace_scope!("work");
+            }
+            spall::trace_scope_impl("done");
+        }).unwrap();
+        let (small, large) = (worker(1024), worker(64*1024));
+        small.join().unwrap();
+        large.join().unwrap();
+    }).unwrap();
+
+    let flushes = |tid| trace.scopes_named("spall/flush").filter(|s| s.tid == tid).count();
+    let mut done = trace.scopes_named("done").map(|s| flushes(s.tid)).collect::<Vec<_>>();
+    done.sort();
+    // the large one only flushes as it exits.
+    assert_eq!(done[0], 1);
+    assert!(done[1] > 1);
+}
+
+#[test]
+fn sequential_tids() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { sequential_tids: true, ..Default::default() };
+    let trace = record(options, |_| {
+        spall::trace_scope!("main");
+        spall::thread::Builder::new().spawn(|| { spall::trace_scope!("spawned"); }).unwrap().join().unwrap();
+        std::thread::spawn(|| { spall::trace_scope!("std"); }).join().unwrap();
+        std::thread::spawn(|| { spall::trace_scope!("std"); }).join().unwrap();
+    }).unwrap();
+
+    let tid = |name| trace.scopes_named(name).map(|s| s.tid).collect::<Vec<_>>();
+    let (main, spawned, std) = (tid("main"), tid("spawned"), tid("std"));
+    let mut tids = [main[0], spawned[0], std[0], std[1]];
+    tids.sort();
+    assert!(tids.windows(2).all(|w| w[0] < w[1]), "{:?}", tids);
+    // from 1, in the order threads first record, shared by the tests of this binary.
+    assert!(tids[3] < 100, "{:?}", tids);
+
+    // the parent records the tid the thread records with.
+    let spawn = trace.scopes_named("spall/spawn").next().unwrap();
+    assert!(spawn.args.starts_with(&format!("tid={} ", spawned[0])), "{}", spawn.args);
+    assert_eq!(trace.scopes_named("spall/tid").count(), 4);
+}
+
+#[test]
+fn set_pid() {
+    if !compiled_in(Level::Normal) { return }
+
+    let options = spall::Options { buffer_size: 1024, ..Default::default() };
+    let trace = record(options, |_| {
+        spall::set_pid(7);
+        spall::trace_scope_impl("before").end();
+        // fills a few buffers.
+        for _ in 0..100 {
+            spall::trace_scope!("after");
+        }
+        std::thread::spawn(|| spall::trace_scope_impl("thread").end()).join().unwrap();
+    }).unwrap();
+    let pid = |name| trace.scopes_named(name).map(|s| s.pid).collect::<Vec<_>>();
+
+    assert_eq!(pid("before"), [std::process::id()]);
+    let after = pid("after");
+    assert_eq!(after[0], std::process::id());
+    assert_eq!(after.last(), Some(&7));
+    // one switch, at a flush.
+    assert_eq!(after.windows(2).filter(|w| w[0] != w[1]).count(), 1);
+    assert_eq!(pid("thread"), [7]);
+
+    // the next session starts over.
+    let trace = record(Default::default(), |_| spall::trace_scope_impl("again").end()).unwrap();
+    assert_eq!(trace.scopes_named("again").next().unwrap().pid, std::process::id());
+}
+
+#[test]
+fn utf8_truncation() {
+    if !compiled_in(Level::Normal) { return }
+
+    // moves the limit through each byte of the 3 byte chars.
+    let texts = (0..3).map(|pad| "x".repeat(pad) + &"€".repeat(100)).collect::<Vec<_>>();
+    let trace = record(Default::default(), |_| {
+        for text in &texts {
+            spall::trace_scope!("args", "{}", text);
+            spall::trace_scope_impl(text);
+        }
+    }).unwrap();
+
+    let check = |text: &str, full: &str| {
+        assert!(text.len() <= spall::args::MAX_LEN, "{}", text.len());
+        assert!(text.ends_with(spall::args::TRUNCATION_MARK), "{:?}", text);
+        assert!(!text.contains('\u{fffd}'), "{:?}", text);
+        let kept = text.strip_suffix(spall::args::TRUNCATION_MARK).unwrap();
+        assert!(full.starts_with(kept));
+        // only cut as far as needed for the mark.
+        assert!(kept.len() + spall::args::TRUNCATION_MARK.len() > spall::args::MAX_LEN - 3);
+    };
+    let args = trace.scopes_named("args").collect::<Vec<_>>();
+    assert_eq!(args.len(), 3);
+    for (scope, text) in args.iter().zip(&texts) {
+        check(&scope.args, text);
+    }
+    let names = trace.scopes().iter().filter(|s| s.name.contains('€')).collect::<Vec<_>>();
+    assert_eq!(names.len(), 3);
+    for (scope, text) in names.iter().zip(&texts) {
+        check(&scope.name, text);
+    }
+}